@@ -0,0 +1,185 @@
+// XDP prefilter loaded by mortis with `--xdp-interface`, see src/xdp.rs. Build with
+//
+//     clang -O2 -g -target bpf -c bpf/mortis.bpf.c -o mortis.bpf.o
+//
+// UDP packets to the protected ports are held to the same limits as in the mortis chains,
+// before the kernel allocates anything for them. Whatever passes still goes through the
+// chains, so this only has to be right about what it drops. IPv6 always passes.
+
+#include <linux/bpf.h>
+#include <linux/if_ether.h>
+#include <linux/in.h>
+#include <linux/ip.h>
+#include <linux/udp.h>
+#include <bpf/bpf_endian.h>
+#include <bpf/bpf_helpers.h>
+
+#define MAX_PORT_RANGES 16
+#define NSEC_PER_SEC 1000000000ULL
+
+// Laid out like `Config` in src/xdp.rs
+struct config {
+	// Inclusive, in host byte order
+	__u16 ports[MAX_PORT_RANGES][2];
+	__u32 port_ranges;
+	// UDP source ports of reflection attacks, dropped from every source not in the allow set
+	__u16 amplification[MAX_PORT_RANGES][2];
+	__u32 amplification_ranges;
+	__u32 whitelist_limit;
+	// 0 when there is no grace set
+	__u32 grace_limit;
+	// 0 drops every source that isn't whitelisted
+	__u32 unknown_limit;
+	__u32 burst;
+};
+
+struct rate_key {
+	__u32 saddr;
+	__u16 dport;
+	__u16 pad;
+};
+
+// Token bucket, one packet costs NSEC_PER_SEC tokens
+struct bucket {
+	__u64 tokens;
+	__u64 updated;
+};
+
+struct {
+	__uint(type, BPF_MAP_TYPE_ARRAY);
+	__uint(max_entries, 1);
+	__type(key, __u32);
+	__type(value, struct config);
+} config SEC(".maps");
+
+// Source address in network byte order to the CLOCK_MONOTONIC nanosecond it expires at, 0 for
+// entries without a timeout
+struct {
+	__uint(type, BPF_MAP_TYPE_LRU_HASH);
+	__uint(max_entries, 262144);
+	__type(key, __u32);
+	__type(value, __u64);
+} whitelist SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 4096);
+	__type(key, __u32);
+	__type(value, __u64);
+} allow SEC(".maps");
+
+// Networks of the allow set, longest prefix match on the source address
+struct allow_net {
+	__u32 prefixlen;
+	__u32 addr;
+};
+
+struct {
+	__uint(type, BPF_MAP_TYPE_LPM_TRIE);
+	__uint(max_entries, 4096);
+	__uint(map_flags, BPF_F_NO_PREALLOC);
+	__type(key, struct allow_net);
+	__type(value, __u8);
+} allow_nets SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_LRU_HASH);
+	__uint(max_entries, 65536);
+	__type(key, __u32);
+	__type(value, __u64);
+} grace SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_LRU_HASH);
+	__uint(max_entries, 524288);
+	__type(key, struct rate_key);
+	__type(value, struct bucket);
+} rates SEC(".maps");
+
+static __always_inline int contains(void *set, __u32 saddr, __u64 now)
+{
+	__u64 *expires = bpf_map_lookup_elem(set, &saddr);
+	return expires && (*expires == 0 || now < *expires);
+}
+
+static __always_inline int in_ranges(const __u16 ranges[MAX_PORT_RANGES][2], __u32 count, __u16 port)
+{
+	for (int i = 0; i < MAX_PORT_RANGES; i++) {
+		if (i >= count)
+			break;
+		if (port >= ranges[i][0] && port <= ranges[i][1])
+			return 1;
+	}
+	return 0;
+}
+
+// Whether the source goes over `limit` packets per second to `dport`
+static __always_inline int above(__u32 saddr, __u16 dport, __u32 limit, __u32 burst, __u64 now)
+{
+	struct rate_key key = { .saddr = saddr, .dport = dport };
+	__u64 cap = (__u64)burst * NSEC_PER_SEC;
+	struct bucket *bucket = bpf_map_lookup_elem(&rates, &key);
+	if (!bucket) {
+		struct bucket fresh = { .tokens = cap - NSEC_PER_SEC, .updated = now };
+		bpf_map_update_elem(&rates, &key, &fresh, BPF_ANY);
+		return 0;
+	}
+
+	__u64 tokens = bucket->tokens + (now - bucket->updated) * limit;
+	if (tokens > cap)
+		tokens = cap;
+	bucket->updated = now;
+	if (tokens < NSEC_PER_SEC) {
+		bucket->tokens = tokens;
+		return 1;
+	}
+	bucket->tokens = tokens - NSEC_PER_SEC;
+	return 0;
+}
+
+SEC("xdp")
+int mortis(struct xdp_md *ctx)
+{
+	void *data = (void *)(long)ctx->data;
+	void *data_end = (void *)(long)ctx->data_end;
+
+	struct ethhdr *eth = data;
+	if ((void *)(eth + 1) > data_end || eth->h_proto != bpf_htons(ETH_P_IP))
+		return XDP_PASS;
+	struct iphdr *ip = (void *)(eth + 1);
+	if ((void *)(ip + 1) > data_end || ip->protocol != IPPROTO_UDP)
+		return XDP_PASS;
+	// Fragments carry no UDP header past the first one, the chains deal with them
+	if (ip->frag_off & bpf_htons(0x3fff))
+		return XDP_PASS;
+	struct udphdr *udp = (void *)ip + ip->ihl * 4;
+	if ((void *)(udp + 1) > data_end)
+		return XDP_PASS;
+
+	__u32 zero = 0;
+	struct config *config = bpf_map_lookup_elem(&config, &zero);
+	__u16 dport = bpf_ntohs(udp->dest);
+	if (!config || !in_ranges(config->ports, config->port_ranges, dport))
+		return XDP_PASS;
+
+	__u64 now = bpf_ktime_get_ns();
+	__u32 saddr = ip->saddr;
+	struct allow_net net = { .prefixlen = 32, .addr = saddr };
+	if (contains(&allow, saddr, now) || bpf_map_lookup_elem(&allow_nets, &net))
+		return XDP_PASS;
+
+	if (in_ranges(config->amplification, config->amplification_ranges, bpf_ntohs(udp->source)))
+		return XDP_DROP;
+
+	__u32 limit = config->unknown_limit;
+	if (contains(&whitelist, saddr, now))
+		limit = config->whitelist_limit;
+	else if (config->grace_limit && contains(&grace, saddr, now))
+		limit = config->grace_limit;
+	else if (limit == 0)
+		return XDP_DROP;
+
+	return above(saddr, dport, limit, config->burst, now) ? XDP_DROP : XDP_PASS;
+}
+
+char LICENSE[] SEC("license") = "GPL";
@@ -0,0 +1,240 @@
+//! Certificates for the public listener from an ACME CA such as Let's Encrypt, with
+//! `--acme-domain` instead of `--tls-cert`. Validation is by HTTP-01, answered on
+//! `--acme-http-listen`, which the CA reaches on port 80 of the domain. The account, the
+//! certificate and its key are kept in `--acme-dir`, and the certificate is renewed once a
+//! third of its 90 days is left.
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result, bail};
+use axum::{
+    Router,
+    extract::{Path as UrlPath, State},
+    http::StatusCode,
+    routing::get,
+};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+    NewAccount, NewOrder, OrderStatus,
+};
+use rcgen::{CertificateParams, KeyPair};
+
+use crate::{snapshot::write_atomic, tls::Certificates};
+
+/// Certificates older than this are renewed, Let's Encrypt issues them for 90 days.
+const RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 3600);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+/// Times the order is polled while the CA validates it, backing off from a second.
+const POLLS: u32 = 10;
+
+/// Key authorizations of pending HTTP-01 challenges by token.
+type Challenges = Arc<Mutex<HashMap<String, String>>>;
+
+pub struct Acme {
+    domain: String,
+    dir: PathBuf,
+    account: Account,
+    challenges: Challenges,
+}
+
+impl Acme {
+    /// Load the account in `dir` or register one, and start answering challenges on `listen`.
+    pub async fn new(
+        domain: String,
+        email: Option<&str>,
+        dir: &Path,
+        staging: bool,
+        listen: std::net::SocketAddr,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create ACME directory {}", dir.display()))?;
+        // The account key and the certificate's key are kept in there
+        #[cfg(unix)]
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+            .with_context(|| format!("Failed to restrict access to {}", dir.display()))?;
+
+        let credentials = dir.join("account.json");
+        let account = match std::fs::read(&credentials) {
+            Ok(data) => {
+                let saved: AccountCredentials =
+                    serde_json::from_slice(&data).with_context(|| {
+                        format!("Invalid ACME account in {}", credentials.display())
+                    })?;
+                Account::from_credentials(saved)
+                    .await
+                    .context("Failed to load the ACME account")?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let contact = email.map(|email| format!("mailto:{}", email));
+                let contact: Vec<&str> = contact.iter().map(String::as_str).collect();
+                let server = match staging {
+                    true => LetsEncrypt::Staging.url(),
+                    false => LetsEncrypt::Production.url(),
+                };
+                let (account, saved) = Account::create(
+                    &NewAccount {
+                        contact: &contact,
+                        terms_of_service_agreed: true,
+                        only_return_existing: false,
+                    },
+                    server,
+                    None,
+                )
+                .await
+                .context("Failed to register an ACME account")?;
+                write_atomic(&credentials, &serde_json::to_vec(&saved)?).await?;
+                tracing::info!("Registered an ACME account for {}", domain);
+                account
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", credentials.display()));
+            }
+        };
+
+        let challenges = Challenges::default();
+        let listener = tokio::net::TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("Failed to bind the ACME challenge listener to {}", listen))?;
+        let app = Router::new()
+            .route("/.well-known/acme-challenge/{token}", get(challenge))
+            .with_state(challenges.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Ok(Self {
+            domain,
+            dir: dir.to_path_buf(),
+            account,
+            challenges,
+        })
+    }
+
+    pub fn cert_path(&self) -> PathBuf {
+        self.dir.join("cert.pem")
+    }
+
+    pub fn key_path(&self) -> PathBuf {
+        self.dir.join("key.pem")
+    }
+
+    /// Whether there is no certificate yet or it is due for renewal.
+    pub fn due(&self) -> bool {
+        let age = std::fs::metadata(self.cert_path())
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| {
+                SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default()
+            });
+        !age.is_ok_and(|age| age < RENEW_AFTER) || !self.key_path().exists()
+    }
+
+    /// Order a certificate for the domain and write it and its key to the directory.
+    pub async fn issue(&self) -> Result<()> {
+        let identifiers = [Identifier::Dns(self.domain.clone())];
+        let mut order = self
+            .account
+            .new_order(&NewOrder {
+                identifiers: &identifiers,
+            })
+            .await
+            .context("Failed to place the ACME order")?;
+
+        let result = self.validate(&mut order).await;
+        self.challenges.lock().unwrap().clear();
+        result?;
+
+        let key = KeyPair::generate()?;
+        let csr = CertificateParams::new(vec![self.domain.clone()])?.serialize_request(&key)?;
+        order
+            .finalize(csr.der())
+            .await
+            .context("Failed to finalize the ACME order")?;
+        let mut chain = None;
+        for _ in 0..POLLS {
+            chain = order.certificate().await?;
+            if chain.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        let chain = chain.context("The ACME server took too long to issue the certificate")?;
+
+        // The key goes first, a certificate without its key is of no use
+        write_atomic(&self.key_path(), key.serialize_pem().as_bytes()).await?;
+        write_atomic(&self.cert_path(), chain.as_bytes()).await?;
+        tracing::info!("Obtained a certificate for {}", self.domain);
+        Ok(())
+    }
+
+    /// Answer the HTTP-01 challenges of the order and wait for the CA to check them.
+    async fn validate(&self, order: &mut instant_acme::Order) -> Result<()> {
+        for authorization in order.authorizations().await? {
+            if authorization.status == AuthorizationStatus::Valid {
+                continue;
+            }
+            let challenge = authorization
+                .challenges
+                .iter()
+                .find(|challenge| challenge.r#type == ChallengeType::Http01)
+                .context("The ACME server offered no HTTP-01 challenge")?;
+            let key_authorization = order.key_authorization(challenge);
+            self.challenges.lock().unwrap().insert(
+                challenge.token.clone(),
+                key_authorization.as_str().to_string(),
+            );
+            order.set_challenge_ready(&challenge.url).await?;
+        }
+
+        let mut delay = Duration::from_secs(1);
+        for _ in 0..POLLS {
+            tokio::time::sleep(delay).await;
+            match order.refresh().await?.status {
+                OrderStatus::Ready | OrderStatus::Valid => return Ok(()),
+                OrderStatus::Invalid => bail!("The ACME server could not validate {}", self.domain),
+                OrderStatus::Pending | OrderStatus::Processing => delay *= 2,
+            }
+        }
+        bail!("The ACME server took too long to validate {}", self.domain)
+    }
+}
+
+async fn challenge(
+    UrlPath(token): UrlPath<String>,
+    State(challenges): State<Challenges>,
+) -> Result<String, StatusCode> {
+    challenges
+        .lock()
+        .unwrap()
+        .get(&token)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Renew the certificate when it is due and swap it into the listener. Failures are retried at
+/// the next check, the current certificate stays valid for weeks after it is due.
+pub async fn renew_task(acme: Acme, certificates: Arc<Certificates>) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        if !acme.due() {
+            continue;
+        }
+        let renewed = match acme.issue().await {
+            Ok(()) => certificates.reload(),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = renewed {
+            tracing::error!(
+                "Failed to renew the certificate of {}: {:#}",
+                acme.domain,
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,621 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    net::IpAddr,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    Json, Router,
+    extract::{Path, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{
+        IntoResponse, Response,
+        sse::{self, KeepAlive, Sse},
+    },
+    routing::{delete, get, post, put},
+};
+use futures_util::{Stream, stream};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    AppError, allow,
+    blacklist::{self, Ban},
+    capacity,
+    capture::{self, CaptureRequest, CaptureStarted, Start},
+    cidr::Cidr,
+    cleaner, client, conntrack,
+    counters::Stats,
+    dashboard, drops,
+    journal::{Event, Update},
+    metrics,
+    monitor::Report,
+    notify, openapi,
+    overload::{self, Tier},
+    pins, pipeline,
+    refresh::SessionInfo,
+    sampling::{self, SamplingRequest},
+    snapshot::{self, RestoreSummary, Snapshot},
+    state::AppState,
+    status::{self, Status},
+    tokens::Tokens,
+};
+
+/// The bearer token in `path`, which every admin request has to carry when set.
+pub fn token(path: &std::path::Path) -> Result<String> {
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let token = token.trim_end();
+    if token.is_empty() {
+        anyhow::bail!("{} holds no token", path.display());
+    }
+    Ok(token.to_string())
+}
+
+/// Refuse requests without `Authorization: Bearer <token>`.
+async fn authorize(State(token): State<Arc<Tokens>>, request: Request, next: Next) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if presented.is_some_and(|presented| token.verify(presented).is_some()) {
+        return next.run(request).await;
+    }
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+    )
+        .into_response()
+}
+
+/// With a `token`, every route needs it, including the kill switch and the metrics, but the
+/// dashboard page and the API docs. Routes are described in `openapi.json`, see
+/// [`crate::openapi`].
+pub fn router(state: Arc<AppState>, token: Option<String>) -> Router {
+    let api = Router::new()
+        .route("/admin/allow", get(list_allowed).put(replace_allowed))
+        .route("/admin/allow/{*net}", put(allow_net).delete(disallow_net))
+        .route("/admin/bans", get(list_bans).post(ban).put(replace_bans))
+        .route("/admin/bans/{ip}", delete(pardon))
+        .route("/admin/capture", post(start_capture).delete(stop_capture))
+        .route("/admin/drops", get(drop_report))
+        .route("/admin/events", get(events))
+        .route("/admin/flows/{ip}", get(flows))
+        .route("/admin/history/{ip}", get(history))
+        .route("/admin/lookup/{ip}", get(lookup))
+        .route("/admin/monitor", get(monitor_report))
+        .route("/admin/pins", get(list_pins))
+        .route("/admin/pins/{ip}", put(pin).delete(unpin))
+        .route("/admin/quota/exempt", get(list_exempt))
+        .route("/admin/quota/exempt/{ip}", put(exempt).delete(unexempt))
+        .route("/admin/restore", post(restore))
+        .route("/admin/rulesets", get(list_rulesets))
+        .route("/admin/rulesets/active", put(select_ruleset))
+        .route(
+            "/admin/sampling",
+            post(start_sampling).delete(stop_sampling),
+        )
+        .route(
+            "/admin/sessions/{session}",
+            get(session).delete(revoke_session),
+        )
+        .route("/admin/status", get(status))
+        .route("/admin/whitelist", get(list_whitelist))
+        .route(
+            "/admin/whitelist/{ip}",
+            put(whitelist).delete(remove_from_whitelist),
+        )
+        .route("/stats/firewall", get(firewall_stats))
+        .layer(middleware::from_fn_with_state(
+            (state.clone(), Tier::ShedAdmin),
+            overload::shed,
+        ));
+    let scrape =
+        Router::new()
+            .route("/metrics", get(metrics))
+            .layer(middleware::from_fn_with_state(
+                (state.clone(), Tier::ShedMetrics),
+                overload::shed,
+            ));
+
+    // The kill switch is never shed, it's what operators reach for when things go wrong
+    let router = Router::new()
+        .route("/admin/killswitch", post(killswitch))
+        .route("/admin/rearm", post(rearm))
+        .merge(api)
+        .merge(scrape);
+    let router = match token {
+        Some(token) => router.layer(middleware::from_fn_with_state(
+            Arc::new(Tokens::new(&BTreeMap::from([("admin".to_string(), token)]))),
+            authorize,
+        )),
+        None => router,
+    };
+    let router = router
+        .route("/admin/dashboard", get(dashboard::page))
+        .route("/openapi.json", get(openapi::spec));
+    let router = match state.args.admin_docs {
+        true => router.route("/admin/docs", get(openapi::docs)),
+        false => router,
+    };
+    router
+        .layer(middleware::from_fn_with_state(
+            (state.clone(), "admin"),
+            metrics::track_requests,
+        ))
+        .with_state(state)
+}
+
+#[derive(Serialize)]
+struct FlowsResponse {
+    ip: IpAddr,
+    flows: Vec<conntrack::Flow>,
+}
+
+async fn flows(
+    Path(ip): Path<IpAddr>,
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<Json<FlowsResponse>, AppError> {
+    let flows = conntrack::flows(ip, &state.args.protected_ports()).await?;
+
+    Ok(Json(FlowsResponse { ip, flows }))
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    ip: IpAddr,
+    events: Vec<Event>,
+}
+
+async fn history(
+    Path(ip): Path<IpAddr>,
+    State(state): State<Arc<AppState>>,
+) -> Json<HistoryResponse> {
+    let ip = client::unit(ip, state.args.ipv6_prefix);
+    Json(HistoryResponse {
+        ip,
+        events: state.journal.history(ip),
+    })
+}
+
+/// Server-sent events of everything the journal records, as `event` messages, and of repairs
+/// by the watchdog, as `repair` messages. A subscriber too slow to keep up gets a `lagged`
+/// message with the number of updates it missed.
+async fn events(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<sse::Event, axum::Error>>> {
+    let updates = stream::unfold(state.journal.subscribe(), |mut receiver| async move {
+        let message = match receiver.recv().await {
+            Ok(Update::Event(event)) => sse::Event::default().event("event").json_data(event),
+            Ok(Update::Repair(repair)) => sse::Event::default().event("repair").json_data(repair),
+            Err(RecvError::Lagged(missed)) => Ok(sse::Event::default()
+                .event("lagged")
+                .data(missed.to_string())),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((message, receiver))
+    });
+    Sse::new(updates).keep_alive(KeepAlive::default())
+}
+
+async fn metrics(
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    capacity::collect(&state).await;
+    let body = state.metrics.render()?;
+
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body))
+}
+
+async fn status(State(state): State<Arc<AppState>>) -> Json<Status> {
+    Json(status::collect(&state).await)
+}
+
+/// Stop filtering the protected ports without forgetting any whitelist state.
+async fn killswitch(
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<StatusCode, AppError> {
+    state
+        .with_firewall(|firewall| {
+            firewall
+                .disarm()
+                .map_err(|e| anyhow::anyhow!("Failed to remove the mortis jump: {}", e))
+        })
+        .await?;
+    let message = format!(
+        "Kill switch engaged, {} is unprotected",
+        state.args.protected_ports()
+    );
+    tracing::warn!("{}", message);
+    state
+        .notifier
+        .notify(notify::Kind::KillswitchEngaged, message);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn rearm(State(state): State<Arc<AppState>>) -> std::result::Result<StatusCode, AppError> {
+    state
+        .with_firewall(|firewall| {
+            firewall
+                .arm()
+                .map_err(|e| anyhow::anyhow!("Failed to restore the mortis jump: {}", e))
+        })
+        .await?;
+    let message = format!(
+        "Kill switch released, {} is protected again",
+        state.args.protected_ports()
+    );
+    tracing::info!("{}", message);
+    state
+        .notifier
+        .notify(notify::Kind::KillswitchReleased, message);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Whitelist check for reverse proxies, e.g. nginx `auth_request` pointed at
+/// `/admin/lookup/$remote_addr`.
+async fn lookup(Path(ip): Path<IpAddr>, State(state): State<Arc<AppState>>) -> StatusCode {
+    let ip = client::unit(ip, state.args.ipv6_prefix);
+    if cleaner::is_whitelisted(&state, ip).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::FORBIDDEN
+    }
+}
+
+async fn drop_report(
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<Json<drops::Report>, StatusCode> {
+    state
+        .drops
+        .as_ref()
+        .map(|drops| Json(drops.report()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn monitor_report(
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<Json<Report>, StatusCode> {
+    state
+        .monitor
+        .as_ref()
+        .map(|monitor| Json(monitor.report()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn firewall_stats(State(state): State<Arc<AppState>>) -> Json<Stats> {
+    Json(state.counters.stats())
+}
+
+async fn list_allowed(State(state): State<Arc<AppState>>) -> Json<BTreeSet<Cidr>> {
+    Json(allow::list(&state).await)
+}
+
+/// Allow exactly the networks of the body, e.g. `["10.0.0.0/8", "192.0.2.1"]`.
+async fn replace_allowed(
+    State(state): State<Arc<AppState>>,
+    Json(nets): Json<BTreeSet<Cidr>>,
+) -> std::result::Result<StatusCode, AppError> {
+    allow::replace(&state, nets).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `net` is the rest of the path, e.g. `/admin/allow/10.0.0.0/8`.
+async fn allow_net(
+    Path(net): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<StatusCode, AppError> {
+    let Ok(net) = net.parse() else {
+        return Ok(StatusCode::BAD_REQUEST);
+    };
+    allow::allow(&state, net).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn disallow_net(
+    Path(net): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<StatusCode, AppError> {
+    let Ok(net) = net.parse() else {
+        return Ok(StatusCode::BAD_REQUEST);
+    };
+    if allow::disallow(&state, net).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+    ip: IpAddr,
+    /// Seconds, bans without one last until the source is pardoned
+    duration: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct BansRequest {
+    ips: Vec<IpAddr>,
+    /// Seconds, for all of them
+    duration: Option<u64>,
+}
+
+async fn replace_bans(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<BansRequest>,
+) -> std::result::Result<StatusCode, AppError> {
+    let duration = request.duration.map(std::time::Duration::from_secs);
+    blacklist::replace(&state, request.ips, duration).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_bans(State(state): State<Arc<AppState>>) -> Json<Vec<Ban>> {
+    Json(blacklist::list(&state).await)
+}
+
+async fn ban(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<BanRequest>,
+) -> std::result::Result<StatusCode, AppError> {
+    let duration = request.duration.map(std::time::Duration::from_secs);
+    blacklist::ban(&state, request.ip, duration).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn pardon(
+    Path(ip): Path<IpAddr>,
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<StatusCode, AppError> {
+    if blacklist::pardon(&state, ip).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+#[derive(Serialize)]
+struct WhitelistEntry {
+    ip: IpAddr,
+    pinned: bool,
+    /// Seconds until the entry expires, unset for pinned ones
+    ttl: Option<u64>,
+    /// Unix timestamp of the admission, unset when the journal no longer holds it
+    first_seen: Option<u64>,
+    /// Unix timestamp of the last admission or refresh
+    last_seen: u64,
+}
+
+/// Live whitelist entries, the most recently seen first.
+async fn list_whitelist(State(state): State<Arc<AppState>>) -> Json<Vec<WhitelistEntry>> {
+    let admitted = state.journal.admitted_at();
+    let now = snapshot::unix_now();
+    let whitelist = state.whitelist.lock().await;
+    let pinned = state.pinned.lock().await;
+
+    let mut entries: Vec<WhitelistEntry> = whitelist
+        .iter()
+        .filter(|(ip, last_seen)| cleaner::is_live(**last_seen, pinned.contains(ip)))
+        .map(|(ip, last_seen)| {
+            let pinned = pinned.contains(ip);
+            WhitelistEntry {
+                ip: *ip,
+                pinned,
+                ttl: (!pinned).then(|| cleaner::timeout_left(*last_seen, false).into()),
+                first_seen: admitted.get(ip).copied(),
+                last_seen: now.saturating_sub(last_seen.elapsed().as_secs()),
+            }
+        })
+        .collect();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_seen));
+    Json(entries)
+}
+
+/// Whitelist `ip` like an admission that passed every stage, e.g. ahead of a tournament.
+async fn whitelist(
+    Path(ip): Path<IpAddr>,
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<StatusCode, AppError> {
+    let ip = client::unit(ip, state.args.ipv6_prefix);
+    pipeline::whitelist(&state, ip, None).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Kick `ip` off the whitelist right away. Pinned entries have to be unpinned first.
+async fn remove_from_whitelist(
+    Path(ip): Path<IpAddr>,
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<StatusCode, AppError> {
+    let unit = client::unit(ip, state.args.ipv6_prefix);
+    if state.pinned.lock().await.contains(&unit) {
+        return Ok(StatusCode::CONFLICT);
+    }
+    if cleaner::evict(&state, ip).await? {
+        tracing::info!("Removed {} from the whitelist", unit);
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn list_pins(State(state): State<Arc<AppState>>) -> Json<HashSet<IpAddr>> {
+    Json(pins::list(&state).await)
+}
+
+async fn pin(
+    Path(ip): Path<IpAddr>,
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<StatusCode, AppError> {
+    pins::pin(&state, ip).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unpin(
+    Path(ip): Path<IpAddr>,
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<StatusCode, AppError> {
+    if pins::unpin(&state, ip).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn list_exempt(
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<Json<Vec<String>>, StatusCode> {
+    state
+        .quota
+        .as_ref()
+        .map(|quota| Json(quota.exempted()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Lift the subnet quota for the subnet of `ip`, e.g. a LAN party behind one NAT range.
+async fn exempt(Path(ip): Path<IpAddr>, State(state): State<Arc<AppState>>) -> StatusCode {
+    let Some(quota) = &state.quota else {
+        return StatusCode::NOT_FOUND;
+    };
+    if quota.exempt(ip) {
+        tracing::info!("Exempted {} from the subnet quota", quota.describe(ip));
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn unexempt(Path(ip): Path<IpAddr>, State(state): State<Arc<AppState>>) -> StatusCode {
+    match &state.quota {
+        Some(quota) if quota.unexempt(ip) => {
+            tracing::info!(
+                "{} is subject to the subnet quota again",
+                quota.describe(ip)
+            );
+            StatusCode::NO_CONTENT
+        }
+        _ => StatusCode::NOT_FOUND,
+    }
+}
+
+#[derive(Serialize)]
+struct RulesetsResponse {
+    active: String,
+    rulesets: Vec<String>,
+}
+
+async fn list_rulesets(State(state): State<Arc<AppState>>) -> Json<RulesetsResponse> {
+    let response = state
+        .with_firewall(|firewall| RulesetsResponse {
+            active: firewall.active_ruleset().to_string(),
+            rulesets: firewall.ruleset_names(),
+        })
+        .await;
+    Json(response)
+}
+
+#[derive(Deserialize)]
+struct SelectRuleset {
+    name: String,
+}
+
+/// Switch the protected ports over to another pre-built ruleset.
+async fn select_ruleset(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SelectRuleset>,
+) -> std::result::Result<StatusCode, AppError> {
+    let name = request.name.clone();
+    let switched = state
+        .with_firewall(move |firewall| {
+            if !firewall.ruleset_names().contains(&name) {
+                return Ok(Some(StatusCode::NOT_FOUND));
+            }
+            if firewall.active_ruleset() == name {
+                return Ok(Some(StatusCode::NO_CONTENT));
+            }
+            firewall
+                .select_ruleset(&name)
+                .map(|()| None)
+                .map_err(|e| anyhow::anyhow!("Failed to switch rulesets: {}", e))
+        })
+        .await?;
+    if let Some(status) = switched {
+        return Ok(status);
+    }
+    let message = format!(
+        "Switched {} to ruleset {}",
+        state.args.protected_ports(),
+        request.name
+    );
+    tracing::warn!("{}", message);
+    state.notifier.notify(notify::Kind::RulesetChanged, message);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn session(
+    Path(session): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> std::result::Result<Json<SessionInfo>, StatusCode> {
+    state
+        .refresher
+        .session(&session)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn revoke_session(
+    Path(session): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> StatusCode {
+    if state.refresher.revoke(&session) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn restore(
+    State(state): State<Arc<AppState>>,
+    Json(snapshot): Json<Snapshot>,
+) -> std::result::Result<Json<RestoreSummary>, AppError> {
+    Ok(Json(snapshot::restore(&state, snapshot).await?))
+}
+
+async fn start_sampling(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SamplingRequest>,
+) -> std::result::Result<StatusCode, AppError> {
+    sampling::start(&state, request).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Start a bounded packet capture, answering with the file it goes to. While one is running,
+/// that one's file is returned instead.
+async fn start_capture(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<CaptureRequest>,
+) -> std::result::Result<(StatusCode, Json<CaptureStarted>), StatusCode> {
+    match capture::start(&state, request).await {
+        Start::Started(path) => Ok((StatusCode::ACCEPTED, Json(CaptureStarted { path }))),
+        Start::Running(path) => Ok((StatusCode::CONFLICT, Json(CaptureStarted { path }))),
+        Start::Disabled => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn stop_capture(State(state): State<Arc<AppState>>) -> StatusCode {
+    capture::stop(&state).await;
+    StatusCode::NO_CONTENT
+}
+
+async fn stop_sampling(State(state): State<Arc<AppState>>) -> StatusCode {
+    sampling::stop(&state).await;
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,97 @@
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+
+use crate::{cidr::Cidr, state::AppState};
+
+/// Let every source in `net` bypass all mortis rules, until it is disallowed again.
+pub async fn allow(state: &AppState, net: Cidr) -> Result<()> {
+    let mut nets = state.allowed_nets.lock().await;
+    // Added again even if it is allowed already, in case the set lost it
+    state
+        .allow_session
+        .lock()
+        .await
+        .add_net(net)
+        .inspect_err(|e| state.metrics.record_add_error(e))?;
+    if nets.insert(net) {
+        tracing::info!("Allowed {}", net);
+    }
+    Ok(())
+}
+
+/// Returns whether `net` was allowed with [`allow`]. Networks of `--allow-host` and
+/// `--cloudflare-allow` aren't affected: the set holds each network once, so it stays while one
+/// of them still allows the same network.
+pub async fn disallow(state: &AppState, net: Cidr) -> Result<bool> {
+    let mut nets = state.allowed_nets.lock().await;
+    if !nets.contains(&net) {
+        return Ok(false);
+    }
+    let hosts = state.allowed_hosts.lock().await;
+    let cloudflare = state.allowed_cloudflare.lock().await;
+    let held = (net.is_host() && hosts.contains(&net.addr())) || cloudflare.contains(&net);
+    if !held {
+        state
+            .allow_session
+            .lock()
+            .await
+            .del_net(net)
+            .inspect_err(|_| state.metrics.record_netlink_error("del"))?;
+    }
+    nets.remove(&net);
+    tracing::info!("Disallowed {}", net);
+    Ok(true)
+}
+
+/// Allow exactly `nets`, swapped into the allow set at once instead of one by one. Addresses of
+/// `--allow-host` and the ranges of `--cloudflare-allow` stay.
+pub async fn replace(state: &AppState, nets: BTreeSet<Cidr>) -> Result<()> {
+    let mut allowed = state.allowed_nets.lock().await;
+    let hosts = state.allowed_hosts.lock().await;
+    let cloudflare = state.allowed_cloudflare.lock().await;
+    let entries: BTreeSet<Cidr> = nets
+        .iter()
+        .copied()
+        .chain(hosts.iter().map(|ip| Cidr::host(*ip)))
+        .chain(cloudflare.iter().copied())
+        .collect();
+    state
+        .allow_session
+        .lock()
+        .await
+        .replace(&entries.into_iter().collect::<Vec<_>>(), None)
+        .inspect_err(|e| state.metrics.record_add_error(e))?;
+    tracing::info!(
+        "Replaced the {} allowed networks with {}",
+        allowed.len(),
+        nets.len()
+    );
+    *allowed = nets;
+    Ok(())
+}
+
+pub async fn list(state: &AppState) -> BTreeSet<Cidr> {
+    state.allowed_nets.lock().await.clone()
+}
+
+/// Put everything allowed back into a recreated allow set: the allowed networks, the addresses
+/// of `--allow-host` and the ranges of `--cloudflare-allow`, like [`replace`] keeps them.
+pub async fn refill(state: &AppState) -> Result<()> {
+    let nets = state.allowed_nets.lock().await;
+    let hosts = state.allowed_hosts.lock().await;
+    let cloudflare = state.allowed_cloudflare.lock().await;
+    let entries: BTreeSet<Cidr> = nets
+        .iter()
+        .copied()
+        .chain(hosts.iter().map(|ip| Cidr::host(*ip)))
+        .chain(cloudflare.iter().copied())
+        .collect();
+    let mut allow = state.allow_session.lock().await;
+    for net in entries {
+        allow
+            .add_net(net)
+            .inspect_err(|e| state.metrics.record_add_error(e))?;
+    }
+    Ok(())
+}
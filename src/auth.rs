@@ -0,0 +1,251 @@
+//! Signed, time-limited whitelist URLs the game server generates for each client,
+//! `/auth/<expiry>/<signature>/...`, so a URL captured off a client stops working once it
+//! expires instead of being a token for good.
+//!
+//! `<expiry>` is a unix timestamp and `<signature>` the hex HMAC-SHA256, keyed with the
+//! contents of `--auth-secret-file`, of either
+//!
+//! - `ip:<address>.<expiry>`, binding the URL to the address the game server sees the client
+//!   connect from, or
+//! - `nonce:<nonce>.<expiry>`, for clients whose HTTP requests may come from another address.
+//!   The signature is then `<nonce>.<hex>` and the URL works only once.
+//!
+//! Clocks of the game server and of mortis may differ by up to `--auth-skew` seconds.
+
+use std::{collections::HashMap, net::IpAddr, path::Path, sync::Mutex, time::Duration};
+
+use anyhow::{Context, Result};
+use hmac::Mac;
+
+use crate::signing::{self, HmacSha256};
+
+/// Nonces outside these lengths are refused before checking their signature.
+const NONCE_LENGTH: std::ops::RangeInclusive<usize> = 8..=64;
+
+pub struct Verifier {
+    secret: Vec<u8>,
+    skew: Duration,
+    /// Longest a URL may be valid for, so a game server can't hand out ones that never expire
+    max_lifetime: Duration,
+    /// Nonces already used and when they expire
+    used: Mutex<HashMap<String, u64>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    Expired,
+    /// The expiry is further away than `--auth-max-lifetime`
+    TooLong,
+    BadSignature,
+    /// The nonce was used before
+    Replayed,
+}
+
+impl Rejection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Rejection::Expired => "expired",
+            Rejection::TooLong => "too_long",
+            Rejection::BadSignature => "bad_signature",
+            Rejection::Replayed => "replayed",
+        }
+    }
+}
+
+impl Verifier {
+    pub fn new(secret_file: &Path, skew: Duration, max_lifetime: Duration) -> Result<Self> {
+        let secret = std::fs::read_to_string(secret_file)
+            .with_context(|| format!("Failed to read {}", secret_file.display()))?;
+        Ok(Self {
+            secret: secret.trim_end().as_bytes().to_vec(),
+            skew,
+            max_lifetime,
+            used: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn mac(&self, kind: &str, subject: &str, expiry: u64) -> HmacSha256 {
+        let mut mac = signing::mac(&self.secret);
+        mac.update(format!("{}:{}.{}", kind, subject, expiry).as_bytes());
+        mac
+    }
+
+    /// Check the `signature` of a URL expiring at `expiry` requested by `client` at `now`.
+    pub fn verify(
+        &self,
+        client: IpAddr,
+        expiry: u64,
+        signature: &str,
+        now: u64,
+    ) -> Result<(), Rejection> {
+        let skew = self.skew.as_secs();
+        if expiry.saturating_add(skew) < now {
+            return Err(Rejection::Expired);
+        }
+        if expiry > now + self.max_lifetime.as_secs() + skew {
+            return Err(Rejection::TooLong);
+        }
+
+        let (mac, nonce, tag) = match signature.split_once('.') {
+            Some((nonce, tag))
+                if NONCE_LENGTH.contains(&nonce.len())
+                    && nonce
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') =>
+            {
+                (self.mac("nonce", nonce, expiry), Some(nonce), tag)
+            }
+            Some(_) => return Err(Rejection::BadSignature),
+            None => (
+                self.mac("ip", &client.to_canonical().to_string(), expiry),
+                None,
+                signature,
+            ),
+        };
+        let tag = signing::unhex(tag).ok_or(Rejection::BadSignature)?;
+        mac.verify_slice(&tag)
+            .map_err(|_| Rejection::BadSignature)?;
+
+        if let Some(nonce) = nonce {
+            let mut used = self.used.lock().unwrap();
+            used.retain(|_, expiry| expiry.saturating_add(skew) >= now);
+            if used.insert(nonce.to_string(), expiry).is_some() {
+                return Err(Rejection::Replayed);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn verifier() -> Verifier {
+        Verifier {
+            secret: b"auth-secret".to_vec(),
+            skew: Duration::from_secs(30),
+            max_lifetime: Duration::from_secs(3600),
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn client() -> IpAddr {
+        "198.51.100.7".parse().unwrap()
+    }
+
+    /// The signature a game server would put in the URL.
+    fn sign(kind: &str, subject: &str, expiry: u64) -> String {
+        let mut mac = signing::mac(b"auth-secret");
+        mac.update(format!("{}:{}.{}", kind, subject, expiry).as_bytes());
+        signing::hex(&mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn accepts_good_urls() {
+        let verifier = verifier();
+        let expiry = NOW + 600;
+        let signature = sign("ip", "198.51.100.7", expiry);
+        assert_eq!(verifier.verify(client(), expiry, &signature, NOW), Ok(()));
+        // Bound to the address rather than single use
+        assert_eq!(verifier.verify(client(), expiry, &signature, NOW), Ok(()));
+        // Mapped addresses are the IPv4 client they map
+        let mapped = "::ffff:198.51.100.7".parse().unwrap();
+        assert_eq!(verifier.verify(mapped, expiry, &signature, NOW), Ok(()));
+
+        let signature = format!("abcdefgh.{}", sign("nonce", "abcdefgh", expiry));
+        let elsewhere = "203.0.113.1".parse().unwrap();
+        assert_eq!(verifier.verify(elsewhere, expiry, &signature, NOW), Ok(()));
+    }
+
+    #[test]
+    fn rejects_expired() {
+        let verifier = verifier();
+        let signature = sign("ip", "198.51.100.7", NOW - 31);
+        assert_eq!(
+            verifier.verify(client(), NOW - 31, &signature, NOW),
+            Err(Rejection::Expired)
+        );
+        // Within the skew
+        let signature = sign("ip", "198.51.100.7", NOW - 30);
+        assert_eq!(verifier.verify(client(), NOW - 30, &signature, NOW), Ok(()));
+    }
+
+    #[test]
+    fn rejects_too_long() {
+        let verifier = verifier();
+        let expiry = NOW + 3600 + 31;
+        let signature = sign("ip", "198.51.100.7", expiry);
+        assert_eq!(
+            verifier.verify(client(), expiry, &signature, NOW),
+            Err(Rejection::TooLong)
+        );
+    }
+
+    #[test]
+    fn rejects_tampered() {
+        let verifier = verifier();
+        let expiry = NOW + 600;
+        let signature = sign("ip", "198.51.100.7", expiry);
+
+        let other = "198.51.100.8".parse().unwrap();
+        assert_eq!(
+            verifier.verify(other, expiry, &signature, NOW),
+            Err(Rejection::BadSignature)
+        );
+        assert_eq!(
+            verifier.verify(client(), expiry + 1, &signature, NOW),
+            Err(Rejection::BadSignature)
+        );
+        let flipped = if signature.starts_with('0') { "1" } else { "0" };
+        let tampered = format!("{}{}", flipped, &signature[1..]);
+        assert_eq!(
+            verifier.verify(client(), expiry, &tampered, NOW),
+            Err(Rejection::BadSignature)
+        );
+        // A nonce signature doesn't pass for another nonce
+        let tag = sign("nonce", "abcdefgh", expiry);
+        assert_eq!(
+            verifier.verify(client(), expiry, &format!("abcdefgi.{}", tag), NOW),
+            Err(Rejection::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let verifier = verifier();
+        let expiry = NOW + 600;
+        let tag = sign("nonce", "abcdefgh", expiry);
+        for garbage in [
+            String::new(),
+            "not-hex".to_string(),
+            "abc".to_string(),
+            format!("short.{}", tag),
+            format!("{}.{}", "a".repeat(65), tag),
+            format!("abc/defgh.{}", tag),
+            format!("abcdefgh.{}.", tag),
+            "abcdefgh.".to_string(),
+        ] {
+            assert_eq!(
+                verifier.verify(client(), expiry, &garbage, NOW),
+                Err(Rejection::BadSignature),
+                "{:?}",
+                garbage
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_replayed_nonce() {
+        let verifier = verifier();
+        let expiry = NOW + 600;
+        let signature = format!("abcdefgh.{}", sign("nonce", "abcdefgh", expiry));
+        assert_eq!(verifier.verify(client(), expiry, &signature, NOW), Ok(()));
+        assert_eq!(
+            verifier.verify(client(), expiry, &signature, NOW + 1),
+            Err(Rejection::Replayed)
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Banned sources. The blacklist set drops them whether they are whitelisted or not, only the
+//! allow set goes first. Bans come from the admin API and from the source port anomaly
+//! detection, see `--sport-ban`, and end after their duration or when pardoned.
+
+use std::{collections::HashSet, net::IpAddr, time::Duration};
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::{cidr::Cidr, client, journal::EventKind, state::AppState};
+
+/// A ban of [`list`].
+#[derive(Serialize)]
+pub struct Ban {
+    pub ip: IpAddr,
+    /// Seconds until the ban ends, `None` for permanent bans
+    pub expires_in: Option<u64>,
+}
+
+/// Drop every packet from the unit of `ip` for `duration`, or until it is pardoned without one.
+/// Banning it again replaces its duration.
+pub async fn ban(state: &AppState, ip: IpAddr, duration: Option<Duration>) -> Result<()> {
+    let ip = client::unit(ip, state.args.ipv6_prefix);
+    let mut bans = state.bans.lock().await;
+    let timeout = duration.map_or(0, |duration| duration.as_secs().clamp(1, u32::MAX as u64));
+    state
+        .blacklist_session
+        .lock()
+        .await
+        .add_ip_for(ip, timeout as u32)
+        .inspect_err(|e| state.metrics.record_add_error(e))?;
+    bans.insert(ip, duration.map(|duration| Instant::now() + duration));
+    state.journal.record(ip, EventKind::Banned);
+    match duration {
+        Some(duration) => tracing::info!("Banned {} for {}s", ip, duration.as_secs()),
+        None => tracing::info!("Banned {}", ip),
+    }
+    Ok(())
+}
+
+/// Lift the ban of `ip` early, returns whether it was banned.
+pub async fn pardon(state: &AppState, ip: IpAddr) -> Result<bool> {
+    let ip = client::unit(ip, state.args.ipv6_prefix);
+    let mut bans = state.bans.lock().await;
+    match bans.remove(&ip) {
+        Some(expiry) if expiry.is_none_or(|expiry| expiry > Instant::now()) => {}
+        // The kernel is done with it already
+        _ => return Ok(false),
+    }
+    state
+        .blacklist_session
+        .lock()
+        .await
+        .del_ip(ip)
+        .inspect_err(|_| state.metrics.record_netlink_error("del"))?;
+    state.journal.record(ip, EventKind::Pardoned);
+    tracing::info!("Pardoned {}", ip);
+    Ok(true)
+}
+
+/// Ban exactly `ips`, each for `duration` like [`ban`], swapped into the blacklist set at once,
+/// e.g. for importing an external blocklist. Bans of other sources are lifted.
+pub async fn replace(state: &AppState, ips: Vec<IpAddr>, duration: Option<Duration>) -> Result<()> {
+    let ips: HashSet<IpAddr> = ips
+        .into_iter()
+        .map(|ip| client::unit(ip, state.args.ipv6_prefix))
+        .collect();
+    let mut bans = state.bans.lock().await;
+    let timeout = duration.map_or(0, |duration| duration.as_secs().clamp(1, u32::MAX as u64));
+    let entries: Vec<Cidr> = ips.iter().map(|ip| Cidr::host(*ip)).collect();
+    state
+        .blacklist_session
+        .lock()
+        .await
+        .replace(&entries, Some(timeout as u32))
+        .inspect_err(|e| state.metrics.record_add_error(e))?;
+
+    let now = Instant::now();
+    for (ip, expiry) in bans.iter() {
+        if !ips.contains(ip) && expiry.is_none_or(|expiry| expiry > now) {
+            state.journal.record(*ip, EventKind::Pardoned);
+        }
+    }
+    bans.retain(|ip, _| ips.contains(ip));
+    for ip in &ips {
+        state.journal.record(*ip, EventKind::Banned);
+        bans.insert(*ip, duration.map(|duration| now + duration));
+    }
+    tracing::info!("Replaced the blacklist with {} bans", ips.len());
+    Ok(())
+}
+
+/// Bans in force, ended ones are dropped along the way.
+pub async fn list(state: &AppState) -> Vec<Ban> {
+    let mut bans = state.bans.lock().await;
+    let now = Instant::now();
+    bans.retain(|_, expiry| expiry.is_none_or(|expiry| expiry > now));
+    bans.iter()
+        .map(|(ip, expiry)| Ban {
+            ip: *ip,
+            expires_in: expiry.map(|expiry| (expiry - now).as_secs()),
+        })
+        .collect()
+}
+
+/// Put the bans in force back into a recreated blacklist set, each with the time it had left.
+pub async fn refill(state: &AppState) -> Result<()> {
+    let bans = state.bans.lock().await;
+    let mut blacklist = state.blacklist_session.lock().await;
+    let now = Instant::now();
+    for (ip, expiry) in bans.iter() {
+        let timeout = match expiry {
+            None => 0,
+            Some(expiry) if *expiry > now => (*expiry - now).as_secs().max(1) as u32,
+            Some(_) => continue,
+        };
+        blacklist
+            .add_ip_for(*ip, timeout)
+            .inspect_err(|e| state.metrics.record_add_error(e))?;
+    }
+    Ok(())
+}
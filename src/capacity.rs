@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use tokio::process::Command;
+
+use crate::{firewall, state::AppState};
+
+const CONNTRACK_COUNT: &str = "/proc/sys/net/netfilter/nf_conntrack_count";
+const CONNTRACK_MAX: &str = "/proc/sys/net/netfilter/nf_conntrack_max";
+const HASHLIMIT_DIR: &str = "/proc/net/ipt_hashlimit";
+
+/// Refresh the kernel capacity gauges, called on every scrape of `/metrics`. Sources that are
+/// unavailable (e.g. conntrack not loaded) are skipped.
+pub async fn collect(state: &AppState) {
+    let names = firewall::Names::new(state.args.instance_name.as_deref());
+    let mut sets = vec![firewall::MORTIS_IPSET, firewall::MORTIS_ALLOW_IPSET];
+    if state.probation_session.is_some() {
+        sets.push(firewall::MORTIS_PROBATION_IPSET);
+    }
+    for set in sets {
+        match ipset_usage(&names.of(set)).await {
+            Ok((entries, maxelem)) => {
+                if set == firewall::MORTIS_IPSET && entries >= maxelem {
+                    // forceadd makes room by dropping random players
+                    tracing::warn!(
+                        "Whitelist set is full with {} entries, raise --whitelist-maxelem",
+                        entries
+                    );
+                }
+                state.metrics.set_ipset_usage(set, entries, maxelem)
+            }
+            Err(e) => tracing::debug!("Failed to read usage of ipset {}: {:#}", set, e),
+        }
+    }
+
+    match (
+        read_number(CONNTRACK_COUNT).await,
+        read_number(CONNTRACK_MAX).await,
+    ) {
+        (Ok(entries), Ok(max)) => state.metrics.set_conntrack_usage(entries, max),
+        (Err(e), _) | (_, Err(e)) => tracing::debug!("Failed to read conntrack usage: {:#}", e),
+    }
+
+    if let Err(e) = hashlimit_usage(state, &names).await {
+        tracing::debug!("Failed to read hashlimit tables: {:#}", e);
+    }
+}
+
+/// Entry count and maxelem of `set`, from the header printed by `ipset list -t`.
+async fn ipset_usage(set: &str) -> Result<(u64, u64)> {
+    let output = Command::new("ipset")
+        .args(["list", "-t", set])
+        .output()
+        .await
+        .context("Failed to run ipset")?;
+
+    if !output.status.success() {
+        bail!(
+            "ipset exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut entries = None;
+    let mut maxelem = None;
+    for line in stdout.lines() {
+        if let Some(count) = line.strip_prefix("Number of entries:") {
+            entries = count.trim().parse().ok();
+        } else if let Some(header) = line.strip_prefix("Header:") {
+            // e.g. `Header: family inet hashsize 1024 maxelem 65536 forceadd`
+            let mut tokens = header.split_ascii_whitespace();
+            while let Some(token) = tokens.next() {
+                if token == "maxelem" {
+                    maxelem = tokens.next().and_then(|v| v.parse().ok());
+                }
+            }
+        }
+    }
+
+    entries.zip(maxelem).context("Unexpected ipset list output")
+}
+
+async fn read_number(path: &str) -> Result<u64> {
+    let data = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path))?;
+    data.trim()
+        .parse()
+        .with_context(|| format!("{} is not a number", path))
+}
+
+/// Each line of `/proc/net/ipt_hashlimit/<name>` is one tracked bucket.
+async fn hashlimit_usage(state: &AppState, names: &firewall::Names) -> Result<()> {
+    let mut tables = tokio::fs::read_dir(HASHLIMIT_DIR).await?;
+    while let Some(table) = tables.next_entry().await? {
+        let name = table.file_name();
+        let Some(name) = name.to_str().filter(|name| names.is_hashlimit(name)) else {
+            continue;
+        };
+        let data = tokio::fs::read_to_string(Path::new(HASHLIMIT_DIR).join(name)).await?;
+        state
+            .metrics
+            .set_hashlimit_entries(name, data.lines().count() as u64);
+    }
+
+    Ok(())
+}
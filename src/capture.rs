@@ -0,0 +1,228 @@
+//! Bounded packet captures of the traffic hitting the mortis chain, written as pcap files to
+//! `--capture-dir` for post-mortem analysis of an attack.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::oneshot,
+    task::JoinHandle,
+};
+
+use crate::{nflog, notify::Kind, snapshot::unix_now, state::AppState};
+
+/// Whole packets, NFLOG truncates to what fits into a netlink message anyway.
+const COPY_RANGE: u32 = 0xffff;
+/// Packets start at the IP header, NFLOG doesn't pass the link layer.
+const LINKTYPE_RAW: u32 = 101;
+
+#[derive(Deserialize)]
+pub struct CaptureRequest {
+    /// Seconds to capture for, `--capture-duration` if unset
+    pub duration: Option<u64>,
+    /// Bytes after which the file is closed, `--capture-max-bytes` if unset
+    pub max_bytes: Option<u64>,
+    /// Why the capture was started, included in the notification
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CaptureStarted {
+    pub path: PathBuf,
+}
+
+pub struct CaptureSession {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+    path: PathBuf,
+}
+
+pub enum Start {
+    Started(PathBuf),
+    /// Only one capture runs at a time, this is the running one's file
+    Running(PathBuf),
+    /// `--capture-dir` is not set
+    Disabled,
+}
+
+/// Start a capture unless one is running already. Meant to be triggered by whatever notices an
+/// attack, an operator included.
+pub async fn start(state: &Arc<AppState>, request: CaptureRequest) -> Start {
+    let Some(dir) = &state.args.capture_dir else {
+        return Start::Disabled;
+    };
+    let mut session = state.capture.lock().await;
+    if let Some(running) = session.as_ref()
+        && !running.handle.is_finished()
+    {
+        return Start::Running(running.path.clone());
+    }
+
+    let path = dir.join(format!("mortis-{}.pcap", unix_now()));
+    let (stop, stopped) = oneshot::channel();
+    let limits = Limits {
+        duration: Duration::from_secs(request.duration.unwrap_or(state.args.capture_duration)),
+        max_bytes: request.max_bytes.unwrap_or(state.args.capture_max_bytes),
+    };
+    let handle = tokio::spawn(run(
+        state.clone(),
+        path.clone(),
+        limits,
+        request.reason,
+        stopped,
+    ));
+    *session = Some(CaptureSession {
+        stop,
+        handle,
+        path: path.clone(),
+    });
+
+    Start::Started(path)
+}
+
+pub async fn stop(state: &AppState) {
+    if let Some(running) = state.capture.lock().await.take() {
+        let _ = running.stop.send(());
+        let _ = running.handle.await;
+    }
+}
+
+struct Limits {
+    duration: Duration,
+    max_bytes: u64,
+}
+
+async fn run(
+    state: Arc<AppState>,
+    path: PathBuf,
+    limits: Limits,
+    reason: Option<String>,
+    stopped: oneshot::Receiver<()>,
+) {
+    let rate = state.args.capture_rate;
+    let group = state.args.capture_nflog_group;
+    let socket = match nflog::NflogSocket::bind(group, COPY_RANGE) {
+        Ok(socket) => socket,
+        Err(e) => {
+            tracing::error!("Failed to bind NFLOG group {}: {}", group, e);
+            return;
+        }
+    };
+    let started = state
+        .with_firewall(move |firewall| {
+            firewall
+                .start_capture(rate, group)
+                .map_err(|e| e.to_string())
+        })
+        .await;
+    if let Err(e) = started {
+        tracing::error!("Failed to insert capture rule: {}", e);
+        return;
+    }
+    tracing::info!(
+        "Capturing up to {} packets/sec into {} for {}s",
+        rate,
+        path.display(),
+        limits.duration.as_secs()
+    );
+
+    let result = capture(&socket, &path, &limits, stopped).await;
+
+    let stopped = state
+        .with_firewall(move |firewall| {
+            firewall
+                .stop_capture(rate, group)
+                .map_err(|e| e.to_string())
+        })
+        .await;
+    if let Err(e) = stopped {
+        tracing::error!("Failed to delete capture rule: {}", e);
+    }
+    let mut message = match result {
+        Ok((packets, bytes)) => format!(
+            "Captured {} packets ({} bytes) into {}",
+            packets,
+            bytes,
+            path.display()
+        ),
+        Err(e) => format!("Capture into {} failed, {:#}", path.display(), e),
+    };
+    if let Some(reason) = reason {
+        message = format!("{}: {}", reason, message);
+    }
+    tracing::info!("{}", message);
+    state.notifier.notify(Kind::CaptureFinished, message);
+}
+
+/// Returns the number of packets and bytes written.
+async fn capture(
+    socket: &nflog::NflogSocket,
+    path: &Path,
+    limits: &Limits,
+    mut stopped: oneshot::Receiver<()>,
+) -> Result<(u64, u64)> {
+    let file = File::create(path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    out.write_all(&global_header()).await?;
+    let mut written = 24;
+    let mut packets = 0;
+
+    let deadline = tokio::time::sleep(limits.duration);
+    tokio::pin!(deadline);
+    let mut buf = vec![0u8; 1 << 20];
+
+    while written < limits.max_bytes {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = &mut stopped => break,
+            received = socket.recv(&mut buf) => {
+                let len = received.context("Failed to receive captured packets")?;
+                for packet in nflog::packets(&buf[..len]) {
+                    let record = record_header(packet.payload.len());
+                    out.write_all(&record).await?;
+                    out.write_all(packet.payload).await?;
+                    written += (record.len() + packet.payload.len()) as u64;
+                    packets += 1;
+                }
+            }
+        }
+    }
+    out.flush().await?;
+
+    Ok((packets, written))
+}
+
+fn global_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    // Timezone offset and timestamp accuracy, both always 0
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&COPY_RANGE.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+/// Timestamped when received, which is close enough to when the packet was logged.
+fn record_header(len: usize) -> Vec<u8> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut header = Vec::with_capacity(16);
+    header.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+    header.extend_from_slice(&now.subsec_micros().to_le_bytes());
+    // Captured and original length, NFLOG doesn't say how much it cut off
+    header.extend_from_slice(&(len as u32).to_le_bytes());
+    header.extend_from_slice(&(len as u32).to_le_bytes());
+    header
+}
@@ -0,0 +1,176 @@
+//! Networks in CIDR notation, e.g. `10.0.0.0/8`, for the allow set.
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// A network with the host bits of its address cleared. A bare address is the network of just
+/// that host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Fails for prefixes longer than the address or of zero, sets can't hold `/0`.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, String> {
+        let addr = addr.to_canonical();
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix == 0 || prefix > bits {
+            return Err(format!("invalid prefix length /{} for {}", prefix, addr));
+        }
+        let addr = match addr {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                IpAddr::V4((u32::from(ip) & mask).into())
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                IpAddr::V6((u128::from(ip) & mask).into())
+            }
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn host(ip: IpAddr) -> Self {
+        let ip = ip.to_canonical();
+        let prefix = if ip.is_ipv4() { 32 } else { 128 };
+        Self { addr: ip, prefix }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        Self::new(ip, self.prefix).is_ok_and(|net| net == *self)
+    }
+
+    /// Whether the network is a single address.
+    pub fn is_host(&self) -> bool {
+        *self == Self::host(self.addr)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("invalid address in {:?}: {}", s, e))?;
+        match prefix {
+            Some(prefix) => {
+                let prefix = prefix
+                    .parse()
+                    .map_err(|e| format!("invalid prefix length in {:?}: {}", s, e))?;
+                Self::new(addr, prefix)
+            }
+            None => Ok(Self::host(addr)),
+        }
+    }
+}
+
+impl serde::Serialize for Cidr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn rejects_zero_prefix() {
+        assert!("0.0.0.0/0".parse::<Cidr>().is_err());
+        assert!("::/0".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn full_prefixes_are_hosts() {
+        let v4: Cidr = "192.0.2.7/32".parse().unwrap();
+        assert_eq!(v4, Cidr::host(ip("192.0.2.7")));
+        assert!(v4.is_host());
+        assert!(v4.contains(ip("192.0.2.7")));
+        assert!(!v4.contains(ip("192.0.2.8")));
+
+        let v6: Cidr = "2001:db8::7/128".parse().unwrap();
+        assert_eq!(v6, "2001:db8::7".parse().unwrap());
+        assert!(v6.is_host());
+        assert!(!v6.contains(ip("2001:db8::8")));
+    }
+
+    #[test]
+    fn clears_host_bits() {
+        let net: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(net.addr(), ip("10.0.0.0"));
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert!(!net.is_host());
+        assert!(net.contains(ip("10.255.255.255")));
+        assert!(!net.contains(ip("11.0.0.0")));
+
+        let net: Cidr = "2001:db8:1:2::1/48".parse().unwrap();
+        assert_eq!(net.to_string(), "2001:db8:1::/48");
+        assert!(net.contains(ip("2001:db8:1:ffff::1")));
+        assert!(!net.contains(ip("2001:db8:2::1")));
+    }
+
+    #[test]
+    fn families_dont_mix() {
+        let v4: Cidr = "10.0.0.0/8".parse().unwrap();
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(!v4.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+        // IPv4-mapped addresses are the IPv4 address they map
+        assert!(v4.contains(ip("::ffff:10.0.0.1")));
+        assert_eq!(
+            "::ffff:10.0.0.0/8".parse::<Cidr>(),
+            Ok(v4),
+            "the prefix applies to the canonical address"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_prefixes() {
+        for s in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/eight",
+            "10.0.0/8",
+            "10.0.0.0/8/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{} parsed", s);
+        }
+    }
+}
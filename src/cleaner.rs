@@ -1,36 +1,151 @@
-use std::{ops::DerefMut, sync::Arc, time::Duration};
+//! Whitelist expiry. The kernel removes a whitelist entry [`ENTRY_TTL`] after it was last added,
+//! so the whitelist map only caches what the ipset holds: entries past their TTL count as gone.
+//! A sweep drops them from the map every few seconds, and so does the map when it would grow
+//! while full.
+//!
+//! An expired entry doesn't end the flows it let in: conntrack keeps them until they idle out,
+//! and their packets never reach the RETURN rule again. With `--conntrack-flush` the sweep
+//! deletes the flows of the entries it drops, as do evictions.
 
-use anyhow::{Ok, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::state::AppState;
+use anyhow::Result;
+use tokio::time::Instant;
 
-pub async fn task(state: Arc<AppState>) {
-    loop {
-        tokio::time::sleep(Duration::from_secs(60)).await;
-        {
-            let _ = clean_ipset(state.clone()).await;
-        }
+use crate::{client, conntrack, journal::EventKind, metrics::Outcome, state::AppState};
+
+/// How long a whitelist entry stays valid after the last successful ping.
+pub const ENTRY_TTL: Duration = Duration::from_secs(300);
+
+/// How often the sweep looks for expired entries.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether the kernel still holds an entry last added at `last_seen`. Pinned entries never
+/// expire.
+pub fn is_live(last_seen: Instant, pinned: bool) -> bool {
+    pinned || last_seen.elapsed() < ENTRY_TTL
+}
+
+/// Seconds the kernel has left to keep an entry last added at `last_seen`, at least 1 so it is
+/// added with a timeout. 0 for pinned entries, which never expire.
+pub fn timeout_left(last_seen: Instant, pinned: bool) -> u32 {
+    match pinned {
+        true => 0,
+        false => ENTRY_TTL
+            .saturating_sub(last_seen.elapsed())
+            .as_secs()
+            .max(1) as u32,
     }
 }
 
-async fn clean_ipset(state: Arc<AppState>) -> Result<()> {
-    let mut whitelist = state.whitelist.lock().await;
-    let mut ipset_session = state.ipset_session.lock().await;
-    let ipset = ipset_session.deref_mut();
+/// Whether `ip` is whitelisted right now.
+pub async fn is_whitelisted(state: &AppState, ip: IpAddr) -> bool {
+    let whitelist = state.whitelist.lock().await;
+    match whitelist.get(&ip) {
+        Some(last_seen) => is_live(*last_seen, state.pinned.lock().await.contains(&ip)),
+        None => false,
+    }
+}
 
-    let mut to_remove = Vec::new();
+/// Drop the entries the kernel let expire from the map when it is full, so it only grows while
+/// everything in it is live. Between sweeps that keeps adding to it cheap. Returns the dropped
+/// entries, for [`flush`] once the locks are released.
+pub fn prune(
+    state: &AppState,
+    whitelist: &mut HashMap<IpAddr, Instant>,
+    pinned: &HashSet<IpAddr>,
+) -> Vec<IpAddr> {
+    if whitelist.len() < whitelist.capacity() {
+        return Vec::new();
+    }
+    expire(state, whitelist, pinned)
+}
 
-    for (ip, instant) in whitelist.iter() {
-        if instant.elapsed().as_secs() > 300 {
-            to_remove.push(*ip);
+fn expire(
+    state: &AppState,
+    whitelist: &mut HashMap<IpAddr, Instant>,
+    pinned: &HashSet<IpAddr>,
+) -> Vec<IpAddr> {
+    let mut expired = Vec::new();
+    whitelist.retain(|ip, last_seen| {
+        let live = is_live(*last_seen, pinned.contains(ip));
+        if !live {
+            state.metrics.record(Outcome::Expired);
+            state.journal.record(*ip, EventKind::Expired);
+            expired.push(*ip);
         }
+        live
+    });
+    *state.last_sweep.lock().unwrap() = Some(Instant::now());
+    expired
+}
+
+/// Drop expired entries every [`SWEEP_INTERVAL`] and delete their flows, so they are journaled
+/// and `--conntrack-flush` cuts a source off within seconds of its expiry instead of when the
+/// map fills up.
+pub async fn task(state: Arc<AppState>) {
+    loop {
+        tokio::time::sleep(SWEEP_INTERVAL).await;
+        let expired = {
+            let mut whitelist = state.whitelist.lock().await;
+            let pinned = state.pinned.lock().await;
+            let expired = expire(&state, &mut whitelist, &pinned);
+            state.metrics.set_whitelist_entries(whitelist.len());
+            expired
+        };
+        flush(&state, &expired).await;
     }
+}
 
-    to_remove.iter().try_for_each(|ip| {
-        whitelist.remove(ip);
-        ipset.del(*ip)?;
-        Ok(())
-    })?;
+/// Delete the flows of sources that just left the whitelist, with `--conntrack-flush`. Must not
+/// be called with the whitelist locked, it runs the conntrack tool for every flow.
+pub async fn flush(state: &AppState, ips: &[IpAddr]) {
+    if !state.args.conntrack_flush {
+        return;
+    }
+    let udp = state.args.protected_ports();
+    let tcp = state.args.protected_tcp_ports();
+    for ip in ips {
+        match conntrack::flush(*ip, state.args.ipv6_prefix, &udp, tcp.as_ref()).await {
+            Ok(0) => {}
+            Ok(deleted) => tracing::debug!("Deleted {} conntrack flows of {}", deleted, ip),
+            Err(e) => tracing::warn!("Failed to delete the conntrack flows of {}: {:#}", ip, e),
+        }
+    }
+}
+
+/// Remove `ip` from the whitelist ahead of its expiry. Pinned entries are kept, returns whether
+/// `ip` was removed.
+pub async fn evict(state: &AppState, ip: IpAddr) -> Result<bool> {
+    let ip = client::unit(ip, state.args.ipv6_prefix);
+    let mut whitelist = state.whitelist.lock().await;
+    if state.pinned.lock().await.contains(&ip) {
+        return Ok(false);
+    }
+    match whitelist.remove(&ip) {
+        Some(last_seen) if is_live(last_seen, false) => {}
+        // The kernel is done with it already
+        _ => return Ok(false),
+    }
+    state
+        .ipset_session
+        .lock()
+        .await
+        .del_ip(ip)
+        .inspect_err(|_| state.metrics.record_netlink_error("del"))?;
+    if let Some(probation) = &state.probation_session {
+        let _ = probation.lock().await.del_ip(ip);
+    }
+    state.metrics.record(Outcome::Evicted);
+    state.journal.record(ip, EventKind::Evicted);
+    state.metrics.set_whitelist_entries(whitelist.len());
+    drop(whitelist);
+    flush(state, &[ip]).await;
 
-    Ok(())
+    Ok(true)
 }
@@ -0,0 +1,86 @@
+#[cfg(target_os = "linux")]
+use std::{
+    mem,
+    os::fd::{AsRawFd, RawFd},
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use axum::{extract::connect_info::Connected, serve::IncomingStream};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::listener::PublicListener;
+
+/// Connection details captured when a client connects, used instead of a bare `SocketAddr`
+/// as the `ConnectInfo` of the public listener.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientInfo {
+    pub addr: SocketAddr,
+    /// Smoothed RTT the kernel measured during the TCP handshake
+    pub rtt: Option<Duration>,
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientInfo {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self::of(stream.io(), *stream.remote_addr())
+    }
+}
+
+/// Captured by the listener already, when it accepted the connection.
+impl Connected<IncomingStream<'_, PublicListener>> for ClientInfo {
+    fn connect_info(stream: IncomingStream<'_, PublicListener>) -> Self {
+        *stream.remote_addr()
+    }
+}
+
+impl ClientInfo {
+    pub fn of(stream: &TcpStream, addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            #[cfg(target_os = "linux")]
+            rtt: tcp_rtt(stream.as_raw_fd()),
+            #[cfg(not(target_os = "linux"))]
+            rtt: None,
+        }
+    }
+
+    /// What the client is tracked as, see [`unit`].
+    pub fn unit(&self, ipv6_prefix: u8) -> IpAddr {
+        unit(self.addr.ip(), ipv6_prefix)
+    }
+}
+
+/// The unit mortis admits, limits and expires: the address for IPv4, its `ipv6_prefix` network
+/// for IPv6. Clients rotate their interface IDs all the time, so tracking single IPv6 addresses
+/// would leak entries and let a misbehaving host shed its record by switching addresses.
+pub fn unit(ip: IpAddr, ipv6_prefix: u8) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - ipv6_prefix as u32).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & mask).into())
+        }
+        ip => ip,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn tcp_rtt(fd: RawFd) -> Option<Duration> {
+    let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 || info.tcpi_rtt == 0 {
+        return None;
+    }
+
+    Some(Duration::from_micros(info.tcpi_rtt.into()))
+}
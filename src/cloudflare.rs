@@ -0,0 +1,122 @@
+//! Cloudflare in front of the HTTP half with `--cloudflare`. The ranges Cloudflare publishes are
+//! fetched every `--cloudflare-interval` and trusted like `--trusted-proxies`, their requests
+//! name the client in `CF-Connecting-IP`. With `--cloudflare-allow` the ranges also go into the
+//! allow set, for protected TCP ports Cloudflare has to reach.
+
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::{cidr::Cidr, state::AppState};
+
+const IPS_URL: &str = "https://api.cloudflare.com/client/v4/ips";
+/// Until the ranges could be fetched once, fetching is retried this often.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+pub const CLIENT_HEADER: &str = "cf-connecting-ip";
+
+#[derive(Deserialize)]
+struct IpsResponse {
+    success: bool,
+    result: Ips,
+}
+
+#[derive(Deserialize)]
+struct Ips {
+    ipv4_cidrs: Vec<Cidr>,
+    ipv6_cidrs: Vec<Cidr>,
+}
+
+async fn fetch(client: &reqwest::Client) -> Result<Vec<Cidr>> {
+    let response: IpsResponse = client
+        .get(IPS_URL)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .context("Failed to reach the Cloudflare API")?
+        .error_for_status()
+        .context("Cloudflare API refused the request")?
+        .json()
+        .await
+        .context("Unexpected response from the Cloudflare API")?;
+    if !response.success || response.result.ipv4_cidrs.is_empty() {
+        bail!("Cloudflare API answered without any ranges");
+    }
+    Ok([response.result.ipv4_cidrs, response.result.ipv6_cidrs].concat())
+}
+
+/// Keep the trusted ranges, and with `--cloudflare-allow` the allow set, in sync with the ones
+/// Cloudflare publishes. The last known ranges stay when fetching fails.
+pub async fn task(state: Arc<AppState>, client: reqwest::Client) {
+    let interval = Duration::from_secs(state.args.cloudflare_interval);
+    loop {
+        let ranges = match fetch(&client).await {
+            Ok(ranges) => ranges,
+            Err(e) => {
+                tracing::warn!("Failed to fetch the Cloudflare ranges: {:#}", e);
+                let fetched = !state.cloudflare_ranges.read().unwrap().is_empty();
+                tokio::time::sleep(if fetched { interval } else { RETRY_INTERVAL }).await;
+                continue;
+            }
+        };
+
+        let changed = {
+            let mut trusted = state.cloudflare_ranges.write().unwrap();
+            let changed = *trusted != ranges;
+            *trusted = ranges.clone();
+            changed
+        };
+        if changed {
+            tracing::info!("Trusting {} Cloudflare ranges", ranges.len());
+        }
+        if state.args.cloudflare_allow {
+            // Without IPv6 support the allow set only holds IPv4 networks
+            let current: BTreeSet<Cidr> = ranges
+                .into_iter()
+                .filter(|net| net.addr().is_ipv4() || !state.args.no_ipv6)
+                .collect();
+            sync_allowed(&state, &current).await;
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Like [`crate::hosts::task`], failed operations are retried next round.
+async fn sync_allowed(state: &AppState, current: &BTreeSet<Cidr>) {
+    let nets = state.allowed_nets.lock().await;
+    let hosts = state.allowed_hosts.lock().await;
+    let mut applied = state.allowed_cloudflare.lock().await;
+    let mut allow = state.allow_session.lock().await;
+
+    // Adding again is harmless, and refills the set if the watchdog had to recreate it
+    for net in current {
+        match allow.add_net(*net) {
+            Ok(_) => {
+                applied.insert(*net);
+            }
+            Err(e) => {
+                state.metrics.record_add_error(&e);
+                tracing::error!("Failed to allow {}: {}", net, e);
+            }
+        }
+    }
+    let gone: Vec<Cidr> = applied.difference(current).copied().collect();
+    for net in gone {
+        // The set holds each network once, another source may still allow it
+        if nets.contains(&net) || (net.is_host() && hosts.contains(&net.addr())) {
+            applied.remove(&net);
+            continue;
+        }
+        match allow.del_net(net) {
+            Ok(_) => {
+                applied.remove(&net);
+            }
+            Err(e) => {
+                state.metrics.record_netlink_error("del");
+                tracing::error!("Failed to remove {} from the allow set: {}", net, e);
+            }
+        }
+    }
+}
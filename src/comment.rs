@@ -0,0 +1,196 @@
+//! What `--entry-comments` writes into the comment of a whitelist entry, e.g.
+//! `mortis;instance=eu1;seen=1760000000;pin=1;ua=Mozilla/5.0%20(X11)`, so `ipset list` and
+//! `nft list set` tell whose entry it is and when it was last admitted. `--adopt` restores pins
+//! from it. Comments are single words, ipset lists them split at whitespace.
+
+use std::fmt;
+
+use crate::{snapshot, state::AppState};
+
+const TAG: &str = "mortis";
+/// Longest comment ipset keeps
+const MAX_LEN: usize = 255;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Comment {
+    pub instance: Option<String>,
+    /// Unix timestamp of the add
+    pub seen: u64,
+    pub pinned: bool,
+    /// Of the request that admitted or refreshed the entry, cut to what fits
+    pub user_agent: Option<String>,
+}
+
+impl Comment {
+    /// The comment of an add to the whitelist set right now.
+    pub fn whitelist(state: &AppState, pinned: bool, user_agent: Option<&str>) -> String {
+        Self {
+            instance: state.args.instance_name.clone(),
+            seen: snapshot::unix_now(),
+            pinned,
+            user_agent: user_agent.map(str::to_string),
+        }
+        .to_string()
+    }
+
+    /// `None` for comments mortis didn't write. Unknown fields are skipped.
+    pub fn parse(comment: &str) -> Option<Self> {
+        let mut fields = comment.trim_matches('"').split(';');
+        if fields.next() != Some(TAG) {
+            return None;
+        }
+        let mut parsed = Self::default();
+        for field in fields {
+            match field.split_once('=') {
+                Some(("instance", value)) => parsed.instance = Some(value.to_string()),
+                Some(("seen", value)) => parsed.seen = value.parse().ok()?,
+                Some(("pin", value)) => parsed.pinned = value == "1",
+                Some(("ua", value)) => parsed.user_agent = Some(decode(value)),
+                _ => {}
+            }
+        }
+        Some(parsed)
+    }
+}
+
+impl fmt::Display for Comment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut comment = format!("{};", TAG);
+        if let Some(instance) = &self.instance {
+            comment += &format!("instance={};", instance);
+        }
+        comment += &format!("seen={};pin={}", self.seen, u8::from(self.pinned));
+        if let Some(user_agent) = &self.user_agent {
+            comment += ";ua=";
+            for c in encode(user_agent) {
+                if comment.len() + c.len() > MAX_LEN {
+                    break;
+                }
+                comment += &c;
+            }
+        }
+        f.write_str(&comment)
+    }
+}
+
+/// Percent-encode everything but a few printable characters, one string per character.
+fn encode(s: &str) -> impl Iterator<Item = String> + '_ {
+    s.chars().map(|c| match c {
+        'A'..='Z' | 'a'..='z' | '0'..='9' | '/' | '.' | '_' | '-' | '(' | ')' | ':' | ',' => {
+            c.to_string()
+        }
+        c => {
+            let mut bytes = [0; 4];
+            c.encode_utf8(&mut bytes)
+                .bytes()
+                .map(|b| format!("%{:02X}", b))
+                .collect()
+        }
+    })
+}
+
+fn decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail
+            .get(..2)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (b, hex) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(user_agent: &str) -> Comment {
+        Comment {
+            instance: Some("eu1".to_string()),
+            seen: 1760000000,
+            pinned: true,
+            user_agent: Some(user_agent.to_string()),
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let written = comment("Mozilla/5.0 (X11; Linux) é;ua=x%41").to_string();
+        assert_eq!(
+            written,
+            "mortis;instance=eu1;seen=1760000000;pin=1;ua=Mozilla/5.0%20(X11%3B%20Linux)%20%C3%A9%3Bua%3Dx%2541"
+        );
+        assert!(!written.contains(char::is_whitespace));
+        assert_eq!(
+            Comment::parse(&written),
+            Some(comment("Mozilla/5.0 (X11; Linux) é;ua=x%41"))
+        );
+
+        let bare = Comment {
+            seen: 1,
+            ..Comment::default()
+        };
+        assert_eq!(bare.to_string(), "mortis;seen=1;pin=0");
+        assert_eq!(Comment::parse(&bare.to_string()), Some(bare));
+        // nft lists comments quoted
+        assert_eq!(
+            Comment::parse("\"mortis;seen=1;pin=1\"").map(|comment| comment.pinned),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn cuts_user_agent_between_characters() {
+        let user_agent = "é".repeat(100);
+        let written = comment(&user_agent).to_string();
+        assert!(written.len() <= MAX_LEN);
+        assert!(MAX_LEN - written.len() < "%C3%A9".len());
+
+        let parsed = Comment::parse(&written).unwrap().user_agent.unwrap();
+        assert!(!parsed.is_empty());
+        assert!(user_agent.starts_with(&parsed));
+        assert!(!parsed.contains(char::REPLACEMENT_CHARACTER));
+    }
+
+    #[test]
+    fn skips_foreign_comments() {
+        for comment in [
+            "",
+            "added by hand",
+            "mortisd;seen=1",
+            "mortis;seen=soon",
+            "\"\"",
+        ] {
+            assert_eq!(Comment::parse(comment), None, "{}", comment);
+        }
+        // Fields of later versions are skipped
+        assert_eq!(
+            Comment::parse("mortis;seen=5;pin=1;tier=2"),
+            Some(Comment {
+                seen: 5,
+                pinned: true,
+                ..Comment::default()
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_only_escapes() {
+        assert_eq!(decode("a%20b"), "a b");
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%+1%2"), "%+1%2");
+        assert_eq!(decode("%zz"), "%zz");
+    }
+}
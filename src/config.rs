@@ -0,0 +1,293 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::{
+    firewall::DEFAULT_RULESET, notify::SinkConfig, pipeline::PipelineConfig, policy::Pattern,
+    ports::Ports, proxy::ProxyRoute, redirect::AllowedTarget, steam::SteamConfig,
+};
+
+/// Hashlimit names carry the ruleset's index as a single digit.
+const MAX_RULESETS: usize = 9;
+const MAX_RULESET_NAME: usize = 19;
+/// Their hashlimit names carry the policy's index as a single digit after the ruleset's.
+const MAX_PORT_POLICIES: usize = 9;
+
+/// Settings that don't fit on the command line, read from `--config`.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Additional rules to put into the mortis chain
+    #[serde(default)]
+    pub extra_rules: Vec<ExtraRule>,
+    /// Where to send operator notifications
+    #[serde(default)]
+    pub notify: Vec<SinkConfig>,
+    /// JSON lines file for notifications no sink accepted, retried periodically
+    pub dead_letter_file: Option<PathBuf>,
+    /// Prefixes of the HTTP listener to forward to other web servers
+    #[serde(default)]
+    pub proxy: Vec<ProxyRoute>,
+    /// Alternative rulesets to switch to at runtime, next to the `default` one made of the
+    /// command line and the top level `extra_rules`
+    #[serde(default)]
+    pub rulesets: BTreeMap<String, Ruleset>,
+    /// Ruleset to start with, `default` if unset
+    pub ruleset: Option<String>,
+    /// Admission policy to try on a share of the clients before rolling it out
+    pub canary: Option<Canary>,
+    /// Admission stages of each kind of request
+    #[serde(default)]
+    pub pipeline: PipelineConfig,
+    /// Limits for groups of protected UDP ports that differ from the ruleset's, e.g. a laxer
+    /// unknown limit for the query port. Every ruleset applies them on top of its own limits
+    #[serde(default)]
+    pub port_policies: Vec<PortPolicy>,
+    /// Secrets accepted in `/t/<token>/...` URLs by name, see [`crate::tokens`]. Once set, web
+    /// requests need one unless `[pipeline] web` says otherwise
+    #[serde(default)]
+    pub tokens: BTreeMap<String, String>,
+    /// Validate Steam session tickets before whitelisting, see [`crate::steam`]
+    pub steam: Option<SteamConfig>,
+    /// Who gets admitted, instead of the User-Agent the `--game` preset looks for
+    pub user_agent: Option<UserAgentConfig>,
+    /// Destinations of the `/<key>` redirects by key, see [`crate::redirect`]
+    #[serde(default)]
+    pub redirects: BTreeMap<String, String>,
+    /// Absolute targets `/<key>` may redirect to without `redirects`, relative paths only when
+    /// empty
+    #[serde(default)]
+    pub redirect_allow: Vec<AllowedTarget>,
+}
+
+/// Conditions on the User-Agent of a request, see [`crate::policy`].
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UserAgentConfig {
+    /// Substrings the User-Agent must all contain
+    #[serde(default)]
+    pub contains: Vec<String>,
+    /// Substrings the User-Agent may not contain
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    /// Regexes the User-Agent must all match, e.g. `"^CitizenFX/"`
+    #[serde(default)]
+    pub matches: Vec<Pattern>,
+}
+
+/// Limits of some of the protected ports, unset ones are those of the active ruleset.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PortPolicy {
+    /// Ports in the `--protect` syntax, e.g. `27015` or `27020:27030`
+    pub ports: Ports,
+    pub whitelist_limit: Option<u32>,
+    pub whitelist_bandwidth: Option<u32>,
+    /// 0 drops every source that isn't whitelisted
+    pub unknown_limit: Option<u32>,
+    pub unknown_subnet_limit: Option<u32>,
+    pub burst: Option<u32>,
+    pub probation_limit: Option<u32>,
+}
+
+/// Policy only part of the clients are checked against, see [`crate::policy`].
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Canary {
+    /// Share of clients to check against this policy instead of the baseline, 0 to 100
+    pub percent: u8,
+    /// Substrings the User-Agent must all contain
+    #[serde(default)]
+    pub user_agent_contains: Vec<String>,
+    /// Substrings the User-Agent may not contain
+    #[serde(default)]
+    pub user_agent_excludes: Vec<String>,
+    /// Regexes the User-Agent must all match
+    #[serde(default)]
+    pub user_agent_matches: Vec<Pattern>,
+}
+
+/// A complete set of mortis rules, unset limits are those of the `default` ruleset.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Ruleset {
+    /// Packets per second a whitelisted source may send to a port
+    pub whitelist_limit: Option<u32>,
+    /// Kilobytes per second a whitelisted source may send to a port, overrides
+    /// `--whitelist-bandwidth`
+    pub whitelist_bandwidth: Option<u32>,
+    /// Packets per second an unknown source may send to a port, 0 drops them all
+    pub unknown_limit: Option<u32>,
+    /// Overrides `--unknown-subnet-limit`, 0 disables it
+    pub unknown_subnet_limit: Option<u32>,
+    /// Packets a source may send at once before its rate counts
+    pub burst: Option<u32>,
+    /// Overrides `--probation-limit`, ignored when probation is disabled
+    pub probation_limit: Option<u32>,
+    /// Used instead of the top level `extra_rules`
+    #[serde(default)]
+    pub extra_rules: Vec<ExtraRule>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ExtraRule {
+    /// Rule specification as passed to `iptables -A mortis`, e.g.
+    /// `-s 198.51.100.7 -p udp --sport 27005 -j RETURN`, or in nft syntax with `--backend
+    /// nftables`, e.g. `ip saddr 198.51.100.7 udp sport 27005 return`
+    pub rule: String,
+    #[serde(default)]
+    pub position: Position,
+    /// Rules usually name addresses of one family, so they only go into the IPv4 chains unless
+    /// they say otherwise
+    #[serde(default)]
+    pub family: RuleFamily,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleFamily {
+    #[default]
+    Ipv4,
+    Ipv6,
+    Both,
+}
+
+/// Where in the mortis chain an extra rule goes.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Position {
+    /// Before every mortis rule, even the allow set
+    Top,
+    /// After the allow set and amplification drops, before any rate limit
+    #[default]
+    BeforeLimits,
+    /// After the rate limits, right before the final RETURN
+    Bottom,
+}
+
+pub fn load(path: &Path) -> Result<Config> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config {}", path.display()))?;
+    let config: Config =
+        toml::from_str(&data).with_context(|| format!("Invalid config {}", path.display()))?;
+
+    for (i, extra) in config.extra_rules.iter().enumerate() {
+        validate_rule(&extra.rule).with_context(|| format!("Invalid extra_rules[{}]", i))?;
+    }
+
+    for (name, ruleset) in &config.rulesets {
+        validate_ruleset(name, ruleset).with_context(|| format!("Invalid ruleset {}", name))?;
+    }
+    if config.rulesets.len() > MAX_RULESETS {
+        bail!("At most {} rulesets may be defined", MAX_RULESETS);
+    }
+    if let Some(name) = &config.ruleset
+        && name != DEFAULT_RULESET
+        && !config.rulesets.contains_key(name)
+    {
+        bail!("ruleset {} is not defined", name);
+    }
+
+    if config.port_policies.len() > MAX_PORT_POLICIES {
+        bail!("At most {} port policies may be defined", MAX_PORT_POLICIES);
+    }
+    if let Some(i) = config.port_policies.iter().position(|p| p.ports.is_empty()) {
+        bail!("port_policies[{}] has no ports", i);
+    }
+
+    if let Some(canary) = &config.canary
+        && canary.percent > 100
+    {
+        bail!("canary.percent must be between 0 and 100");
+    }
+
+    crate::tokens::validate(&config.tokens)
+        .with_context(|| format!("Invalid tokens in {}", path.display()))?;
+
+    crate::redirect::validate(&config.redirects)
+        .with_context(|| format!("Invalid redirects in {}", path.display()))?;
+
+    crate::proxy::validate(&config.proxy)
+        .with_context(|| format!("Invalid proxy in {}", path.display()))?;
+
+    Ok(config)
+}
+
+/// Ruleset names end up in chain names, which iptables limits to 28 characters.
+fn validate_ruleset(name: &str, ruleset: &Ruleset) -> Result<()> {
+    if name == DEFAULT_RULESET {
+        bail!(
+            "{} is the ruleset made of the top level settings",
+            DEFAULT_RULESET
+        );
+    }
+    if name.is_empty()
+        || name.len() > MAX_RULESET_NAME
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        bail!(
+            "name must be 1 to {} lowercase letters, digits, - or _",
+            MAX_RULESET_NAME
+        );
+    }
+    for (i, extra) in ruleset.extra_rules.iter().enumerate() {
+        validate_rule(&extra.rule).with_context(|| format!("Invalid extra_rules[{}]", i))?;
+    }
+    Ok(())
+}
+
+/// Extra rules are rule specifications only, they may not manipulate chains or loop back into
+/// the mortis chain.
+fn validate_rule(rule: &str) -> Result<()> {
+    if rule.contains(['\n', '\r', '"', '\'', ';']) {
+        bail!("rule must be a single line without quotes or semicolons");
+    }
+
+    let tokens: Vec<&str> = rule.split_whitespace().collect();
+    for (i, token) in tokens.iter().enumerate() {
+        if token.starts_with("-t")
+            || matches!(
+                *token,
+                "-A" | "--append"
+                    | "-I"
+                    | "--insert"
+                    | "-D"
+                    | "--delete"
+                    | "-R"
+                    | "--replace"
+                    | "-F"
+                    | "--flush"
+                    | "-N"
+                    | "--new-chain"
+                    | "-X"
+                    | "--delete-chain"
+                    | "-P"
+                    | "--policy"
+                    | "--table"
+            )
+        {
+            bail!("{} is not allowed in a rule specification", token);
+        }
+        if matches!(*token, "-j" | "--jump" | "-g" | "--goto")
+            && tokens.get(i + 1).is_some_and(|t| t.starts_with("mortis"))
+        {
+            bail!("rule may not jump into a mortis chain");
+        }
+    }
+
+    if !tokens
+        .iter()
+        .any(|t| matches!(*t, "-j" | "--jump" | "-g" | "--goto"))
+    {
+        bail!("rule has no target");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,198 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::ports::Ports;
+
+#[derive(Serialize, Debug, Default)]
+pub struct Flow {
+    pub protocol: String,
+    pub timeout: u64,
+    pub src: Option<IpAddr>,
+    pub dst: Option<IpAddr>,
+    pub sport: Option<u16>,
+    pub dport: Option<u16>,
+    /// Counters are only present when `nf_conntrack_acct` is enabled.
+    pub packets: Option<u64>,
+    pub bytes: Option<u64>,
+    pub reply_packets: Option<u64>,
+    pub reply_bytes: Option<u64>,
+    pub flags: Vec<String>,
+}
+
+/// List the active UDP flows originating from `ip` towards the protected ports.
+pub async fn flows(ip: IpAddr, protected_port: &Ports) -> Result<Vec<Flow>> {
+    list(ip, None, "udp", protected_port).await
+}
+
+/// Delete the flows from `ip` to the protected UDP and TCP ports, so its next packets go
+/// through the rules again instead of riding on a flow. An IPv6 `ip` is a unit, the flows of
+/// every address in its `ipv6_prefix` network go. Returns how many were deleted.
+pub async fn flush(ip: IpAddr, ipv6_prefix: u8, udp: &Ports, tcp: Option<&Ports>) -> Result<usize> {
+    let mask = ip.is_ipv6().then(|| {
+        let mask = u128::MAX.checked_shl(128 - ipv6_prefix as u32).unwrap_or(0);
+        IpAddr::V6(Ipv6Addr::from(mask))
+    });
+    let mut deleted = 0;
+    for (protocol, ports) in [("udp", Some(udp)), ("tcp", tcp)] {
+        let Some(ports) = ports else {
+            continue;
+        };
+        for flow in list(ip, mask, protocol, ports).await? {
+            let (Some(src), Some(dst), Some(sport), Some(dport)) =
+                (flow.src, flow.dst, flow.sport, flow.dport)
+            else {
+                continue;
+            };
+            let output = Command::new("conntrack")
+                .args(["-D", "-p", protocol])
+                .args([
+                    "--orig-src",
+                    &src.to_string(),
+                    "--orig-dst",
+                    &dst.to_string(),
+                ])
+                .args(["--sport", &sport.to_string(), "--dport", &dport.to_string()])
+                .output()
+                .await
+                .context("Failed to run conntrack")?;
+            // Fails for flows that timed out in between
+            if output.status.success() {
+                deleted += 1;
+            }
+        }
+    }
+    Ok(deleted)
+}
+
+/// The active `protocol` flows from `ip`, or from its network under `mask`, to `ports`.
+async fn list(
+    ip: IpAddr,
+    mask: Option<IpAddr>,
+    protocol: &str,
+    ports: &Ports,
+) -> Result<Vec<Flow>> {
+    let family = if ip.is_ipv6() { "ipv6" } else { "ipv4" };
+    let mut command = Command::new("conntrack");
+    command
+        .args(["-L", "-f", family, "-p", protocol, "--orig-src"])
+        .arg(ip.to_string());
+    if let Some(mask) = mask {
+        command.arg("--mask-src").arg(mask.to_string());
+    }
+    let output = command.output().await.context("Failed to run conntrack")?;
+
+    if !output.status.success() {
+        bail!(
+            "conntrack exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(parse_flows(&String::from_utf8_lossy(&output.stdout))
+        .into_iter()
+        .filter(|flow| flow.dport.is_some_and(|port| ports.contains(port)))
+        .collect())
+}
+
+/// The flows in `conntrack -L` output. The summary goes to stderr, lines that aren't flows are
+/// skipped.
+fn parse_flows(output: &str) -> Vec<Flow> {
+    output.lines().filter_map(parse_flow).collect()
+}
+
+/// Parse one line of `conntrack -L` output, e.g.
+/// `udp 17 29 src=1.2.3.4 dst=5.6.7.8 sport=27005 dport=27015 packets=3 bytes=120 src=... [ASSURED] mark=0 use=1`.
+/// The first tuple is the original direction, the second one the reply.
+fn parse_flow(line: &str) -> Option<Flow> {
+    let mut tokens = line.split_ascii_whitespace();
+    let mut flow = Flow {
+        protocol: tokens.next()?.to_string(),
+        ..Default::default()
+    };
+    tokens.next()?;
+    flow.timeout = tokens.next()?.parse().ok()?;
+
+    let mut reply = false;
+    for token in tokens {
+        if let Some(flag) = token.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            flow.flags.push(flag.to_string());
+            continue;
+        }
+        let Some((key, value)) = token.split_once('=') else {
+            continue;
+        };
+        match (key, reply) {
+            ("src", false) if flow.src.is_some() => {
+                reply = true;
+            }
+            ("src", false) => flow.src = value.parse().ok(),
+            ("dst", false) => flow.dst = value.parse().ok(),
+            ("sport", false) => flow.sport = value.parse().ok(),
+            ("dport", false) => flow.dport = value.parse().ok(),
+            ("packets", false) => flow.packets = value.parse().ok(),
+            ("bytes", false) => flow.bytes = value.parse().ok(),
+            ("packets", true) => flow.reply_packets = value.parse().ok(),
+            ("bytes", true) => flow.reply_bytes = value.parse().ok(),
+            _ => {}
+        }
+    }
+
+    Some(flow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = "\
+udp      17 29 src=1.2.3.4 dst=5.6.7.8 sport=27005 dport=27015 packets=3 bytes=120 src=5.6.7.8 dst=1.2.3.4 sport=27015 dport=27005 packets=2 bytes=96 [ASSURED] mark=0 use=1
+udp      17 12 src=2001:db8::1 dst=2001:db8::2 sport=40000 dport=27016 [UNREPLIED] src=2001:db8::2 dst=2001:db8::1 sport=27016 dport=40000 mark=0 use=1
+";
+
+    #[test]
+    fn parses_flows() {
+        let flows = parse_flows(OUTPUT);
+        assert_eq!(flows.len(), 2);
+
+        let flow = &flows[0];
+        assert_eq!(flow.protocol, "udp");
+        assert_eq!(flow.timeout, 29);
+        assert_eq!(flow.src, Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(flow.dst, Some("5.6.7.8".parse().unwrap()));
+        assert_eq!(flow.sport, Some(27005));
+        assert_eq!(flow.dport, Some(27015));
+        assert_eq!((flow.packets, flow.bytes), (Some(3), Some(120)));
+        assert_eq!((flow.reply_packets, flow.reply_bytes), (Some(2), Some(96)));
+        assert_eq!(flow.flags, ["ASSURED"]);
+
+        // Without nf_conntrack_acct there are no counters
+        let flow = &flows[1];
+        assert_eq!(flow.src, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(flow.dport, Some(27016));
+        assert_eq!((flow.packets, flow.reply_packets), (None, None));
+        assert_eq!(flow.flags, ["UNREPLIED"]);
+    }
+
+    #[test]
+    fn empty_table() {
+        assert!(parse_flows("").is_empty());
+        assert!(parse_flows("\n").is_empty());
+    }
+
+    #[test]
+    fn skips_unexpected_lines() {
+        let output = format!(
+            "conntrack v1.4.7 (conntrack-tools): 1 flow entries have been shown.\n\
+             udp 17\n\
+             udp 17 soon src=1.2.3.4\n\
+             {OUTPUT}"
+        );
+        let flows = parse_flows(&output);
+        assert_eq!(flows.len(), 2);
+        assert_eq!(flows[0].sport, Some(27005));
+    }
+}
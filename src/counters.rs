@@ -0,0 +1,116 @@
+//! Traffic through the mortis chains, from the kernel counters of the [`Counted`] rules. They
+//! are read every `--counter-interval` seconds and served by `/stats/firewall` and as metrics.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+
+use crate::{
+    firewall::{Counted, Family},
+    state::AppState,
+};
+
+/// Summed counters of the rules of one kind in one chain
+type Readings = HashMap<(Family, String, Counted), (u64, u64)>;
+
+#[derive(Default)]
+pub struct Counters {
+    stats: Mutex<Stats>,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct Stats {
+    /// Seconds between the last two readings the rates are from, 0 before there were two
+    interval: f64,
+    rules: BTreeMap<&'static str, Traffic>,
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Traffic {
+    /// Since mortis started
+    packets: u64,
+    bytes: u64,
+    packets_per_second: f64,
+    bytes_per_second: f64,
+}
+
+impl Counters {
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Add what the rules of each kind matched over the last `interval`.
+    fn record(&self, deltas: &BTreeMap<Counted, (u64, u64)>, interval: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        let secs = interval.as_secs_f64();
+        stats.interval = secs;
+        for (counted, (packets, bytes)) in deltas {
+            let traffic = stats.rules.entry(counted.as_str()).or_default();
+            traffic.packets += packets;
+            traffic.bytes += bytes;
+            traffic.packets_per_second = *packets as f64 / secs;
+            traffic.bytes_per_second = *bytes as f64 / secs;
+        }
+    }
+}
+
+pub async fn task(state: Arc<AppState>) {
+    let interval = Duration::from_secs(state.args.counter_interval);
+    // Whatever the chains counted before mortis started isn't its traffic
+    let mut last = read(&state).await.unwrap_or_default();
+    let mut read_at = Instant::now();
+    loop {
+        tokio::time::sleep(interval).await;
+        let readings = match read(&state).await {
+            Ok(readings) => readings,
+            Err(e) => {
+                tracing::warn!("Failed to read the firewall counters: {:#}", e);
+                continue;
+            }
+        };
+        let elapsed = read_at.elapsed();
+        read_at = Instant::now();
+
+        let mut deltas: BTreeMap<Counted, (u64, u64)> =
+            Counted::ALL.into_iter().map(|c| (c, (0, 0))).collect();
+        for (key, (packets, bytes)) in &readings {
+            let (last_packets, last_bytes) = last.get(key).copied().unwrap_or_default();
+            // A chain built anew, e.g. by a reload, starts counting at 0 again
+            let delta = match *packets >= last_packets {
+                true => (packets - last_packets, bytes.saturating_sub(last_bytes)),
+                false => (*packets, *bytes),
+            };
+            let total = deltas.entry(key.2).or_default();
+            total.0 += delta.0;
+            total.1 += delta.1;
+        }
+        last = readings;
+
+        for (counted, (packets, bytes)) in &deltas {
+            state
+                .metrics
+                .record_firewall_traffic(counted.as_str(), *packets, *bytes, elapsed);
+        }
+        state.counters.record(&deltas, elapsed);
+    }
+}
+
+async fn read(state: &Arc<AppState>) -> Result<Readings> {
+    let counters = state
+        .with_firewall(|firewall| firewall.counters().map_err(|e| anyhow!("{}", e)))
+        .await?;
+    let mut readings = Readings::new();
+    for (family, rule) in counters {
+        let reading = readings
+            .entry((family, rule.chain, rule.counted))
+            .or_default();
+        reading.0 += rule.packets;
+        reading.1 += rule.bytes;
+    }
+    Ok(readings)
+}
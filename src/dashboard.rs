@@ -0,0 +1,11 @@
+//! Web UI of the admin listener at `/admin/dashboard`: the whitelist, traffic through the
+//! rules, live events, and buttons for bans, rulesets and the kill switch. The page is built
+//! into the binary and holds no data, it asks for the admin token and calls the admin API with it.
+
+use axum::response::Html;
+
+const PAGE: &str = include_str!("dashboard.html");
+
+pub async fn page() -> Html<&'static str> {
+    Html(PAGE)
+}
@@ -0,0 +1,113 @@
+//! Caching resolver shared by outbound integrations. Attacks are when webhooks have to get
+//! through and also when the upstream resolver is most likely to struggle, so answers are
+//! cached and the last good one keeps being served for `--dns-stale` when lookups fail.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, anyhow};
+use hickory_resolver::{
+    TokioAsyncResolver,
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    system_conf::read_system_conf,
+};
+
+/// Names cached by hickory, which honours the record TTLs.
+const CACHE_SIZE: usize = 1024;
+
+struct Answer {
+    addrs: Vec<IpAddr>,
+    resolved: Instant,
+}
+
+pub struct Resolver {
+    resolver: TokioAsyncResolver,
+    /// Last good answer of every name looked up, for when the resolver stops answering
+    last: Mutex<HashMap<String, Answer>>,
+    stale: Duration,
+}
+
+impl Resolver {
+    /// Query `servers` directly, or the ones in `/etc/resolv.conf` when there are none.
+    pub fn new(servers: &[IpAddr], stale: Duration) -> Result<Self> {
+        let (config, mut options) = if servers.is_empty() {
+            read_system_conf().context("Failed to read the system resolver config")?
+        } else {
+            let group = NameServerConfigGroup::from_ips_clear(servers, 53, true);
+            (
+                ResolverConfig::from_parts(None, vec![], group),
+                ResolverOpts::default(),
+            )
+        };
+        options.cache_size = CACHE_SIZE;
+        let resolver = TokioAsyncResolver::tokio(config, options);
+
+        Ok(Self {
+            resolver,
+            last: Mutex::new(HashMap::new()),
+            stale,
+        })
+    }
+
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Ok(ip) = host.parse() {
+            return Ok(vec![ip]);
+        }
+
+        let error = match self.resolver.lookup_ip(host).await {
+            Ok(lookup) => {
+                let addrs: Vec<IpAddr> = lookup.iter().collect();
+                if !addrs.is_empty() {
+                    self.last.lock().unwrap().insert(
+                        host.to_string(),
+                        Answer {
+                            addrs: addrs.clone(),
+                            resolved: Instant::now(),
+                        },
+                    );
+                    return Ok(addrs);
+                }
+                anyhow!("{} has no addresses", host)
+            }
+            Err(e) => anyhow!("Failed to resolve {}: {}", host, e),
+        };
+
+        let last = self.last.lock().unwrap();
+        match last.get(host) {
+            Some(answer) if answer.resolved.elapsed() < self.stale => {
+                tracing::warn!(
+                    "{:#}, using the answer from {}s ago",
+                    error,
+                    answer.resolved.elapsed().as_secs()
+                );
+                Ok(answer.addrs.clone())
+            }
+            _ => Err(error),
+        }
+    }
+
+    /// A client builder that resolves through this resolver.
+    pub fn client(self: &Arc<Self>) -> reqwest::ClientBuilder {
+        reqwest::Client::builder().dns_resolver(Arc::new(Shared(self.clone())))
+    }
+}
+
+/// reqwest wants a `'static` future, so it gets a handle instead of the resolver itself.
+struct Shared(Arc<Resolver>);
+
+impl reqwest::dns::Resolve for Shared {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = self.0.clone();
+        Box::pin(async move {
+            let addrs = resolver.lookup(name.as_str()).await?;
+            // reqwest fills in the port of the URL
+            let addrs: reqwest::dns::Addrs =
+                Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
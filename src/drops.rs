@@ -0,0 +1,212 @@
+//! Drop sampling, enabled with `--drop-sample-rate`: the drop chains log a few packets per
+//! second of every reason to an NFLOG group, and this module tallies which sources and ports
+//! are under attack.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{firewall::DROP_PREFIX, nflog, state::AppState};
+
+/// The IPv6 header and the ports after it.
+const COPY_RANGE: u32 = 44;
+const IPPROTO_TCP: u8 = 6;
+/// Sources and ports tracked, packets from further ones are only counted.
+const MAX_TRACKED: usize = 10000;
+const TOP: usize = 20;
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Tally {
+    packets: u64,
+    reasons: HashMap<String, u64>,
+    sources: HashMap<IpAddr, u64>,
+    ports: HashMap<u16, u64>,
+}
+
+#[derive(Default)]
+pub struct DropSampler {
+    tally: Mutex<Tally>,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    /// Sampled packets, not all of the dropped ones
+    packets: u64,
+    reasons: HashMap<String, u64>,
+    /// Sources with the most sampled drops, busiest first
+    top_sources: Vec<SourceReport>,
+    /// Destination ports with the most sampled drops, busiest first
+    top_ports: Vec<PortReport>,
+}
+
+#[derive(Serialize)]
+pub struct SourceReport {
+    ip: IpAddr,
+    packets: u64,
+}
+
+#[derive(Serialize)]
+pub struct PortReport {
+    port: u16,
+    packets: u64,
+}
+
+fn count<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, u64>, key: K) {
+    let tracked = counts.len();
+    match counts.get_mut(&key) {
+        Some(count) => *count += 1,
+        None if tracked < MAX_TRACKED => {
+            counts.insert(key, 1);
+        }
+        None => {}
+    }
+}
+
+/// The keys of `counts` with the highest counts, highest first.
+fn top<K: Copy>(counts: &HashMap<K, u64>) -> Vec<(K, u64)> {
+    let mut top: Vec<(K, u64)> = counts.iter().map(|(key, count)| (*key, *count)).collect();
+    top.sort_unstable_by_key(|(_, count)| std::cmp::Reverse(*count));
+    top.truncate(TOP);
+    top
+}
+
+impl DropSampler {
+    fn record(&self, reason: &str, packet: &[u8]) {
+        let mut tally = self.tally.lock().unwrap();
+        tally.packets += 1;
+        *tally.reasons.entry(reason.to_string()).or_default() += 1;
+        let Some((src, dport)) = parse(packet) else {
+            return;
+        };
+        count(&mut tally.sources, src);
+        if let Some(dport) = dport {
+            count(&mut tally.ports, dport);
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        let tally = self.tally.lock().unwrap();
+        Report {
+            packets: tally.packets,
+            reasons: tally.reasons.clone(),
+            top_sources: top(&tally.sources)
+                .into_iter()
+                .map(|(ip, packets)| SourceReport { ip, packets })
+                .collect(),
+            top_ports: top(&tally.ports)
+                .into_iter()
+                .map(|(port, packets)| PortReport { port, packets })
+                .collect(),
+        }
+    }
+}
+
+/// Read sampled drops from `--drop-nflog-group` for as long as the process runs.
+pub async fn task(state: Arc<AppState>) {
+    let Some(sampler) = &state.drops else {
+        return;
+    };
+    let group = state.args.drop_nflog_group;
+    let socket = match nflog::NflogSocket::bind(group, COPY_RANGE) {
+        Ok(socket) => socket,
+        Err(e) => {
+            tracing::error!("Failed to bind drop sampling NFLOG group {}: {}", group, e);
+            return;
+        }
+    };
+
+    let mut summary = tokio::time::interval(SUMMARY_INTERVAL);
+    summary.tick().await;
+    let mut logged = 0;
+    let mut buf = vec![0u8; 65536];
+
+    loop {
+        tokio::select! {
+            _ = summary.tick() => logged = log_summary(sampler, logged),
+            received = socket.recv(&mut buf) => match received {
+                Ok(len) => {
+                    for packet in nflog::packets(&buf[..len]) {
+                        let Some(reason) = packet
+                            .prefix
+                            .and_then(|prefix| std::str::from_utf8(prefix).ok())
+                            .and_then(|prefix| prefix.strip_prefix(DROP_PREFIX))
+                        else {
+                            continue;
+                        };
+                        sampler.record(reason, packet.payload);
+                    }
+                }
+                Err(e) => tracing::warn!("Failed to receive sampled drops: {}", e),
+            },
+        }
+    }
+}
+
+/// Source address and destination port of a UDP or TCP packet, without the port for other
+/// protocols, fragments past the first and IPv6 packets with extension headers.
+fn parse(packet: &[u8]) -> Option<(IpAddr, Option<u16>)> {
+    let (src, protocol, ports) = match packet.first()? >> 4 {
+        4 => {
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let fragment_offset = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]) & 0x1fff;
+            let header_len = ((packet[0] & 0x0f) as usize) * 4;
+            let ports = (fragment_offset == 0).then(|| packet.get(header_len..header_len + 4));
+            (
+                IpAddr::from(Ipv4Addr::from(src)),
+                packet[9],
+                ports.flatten(),
+            )
+        }
+        6 => {
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            (
+                IpAddr::from(Ipv6Addr::from(src)),
+                *packet.get(6)?,
+                packet.get(40..44),
+            )
+        }
+        _ => return None,
+    };
+    let dport = match protocol {
+        nflog::IPPROTO_UDP | IPPROTO_TCP => {
+            ports.map(|ports| u16::from_be_bytes([ports[2], ports[3]]))
+        }
+        _ => None,
+    };
+    Some((src, dport))
+}
+
+/// Log the top sources and ports if anything was sampled since `logged` packets, returns how
+/// many packets are logged now.
+fn log_summary(sampler: &DropSampler, logged: u64) -> u64 {
+    let report = sampler.report();
+    if report.packets == logged {
+        return logged;
+    }
+    let sources: Vec<String> = report
+        .top_sources
+        .iter()
+        .take(5)
+        .map(|s| format!("{} ({})", s.ip, s.packets))
+        .collect();
+    let ports: Vec<String> = report
+        .top_ports
+        .iter()
+        .take(5)
+        .map(|p| format!("{} ({})", p.port, p.packets))
+        .collect();
+    tracing::info!(
+        target: "mortis::drops",
+        "Sampled {} dropped packets, top sources: {}, top ports: {}",
+        report.packets,
+        sources.join(", "),
+        ports.join(", ")
+    );
+    report.packets
+}
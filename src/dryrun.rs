@@ -0,0 +1,188 @@
+//! `--dry-run`: print the sets and rules mortis would set up for the given arguments, in the
+//! form the backend would hand them to the kernel, and exit without touching anything.
+
+use std::{error::Error, net::IpAddr};
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    Args,
+    cidr::Cidr,
+    config::Config,
+    engine::{self, Plan},
+    firewall::{
+        self, AddressSet, Backend, ChainOptions, Family, Firewall, FirewallBackend, Hook, Listed,
+        Names, RuleCounter, SetOptions,
+    },
+    firewalld, nftables, pf,
+    ports::Ports,
+};
+
+/// A backend that prints what it is asked to do instead of doing it.
+struct DryRun {
+    backend: Backend,
+    firewalld: bool,
+    hook: Hook,
+    /// `--hook-position`
+    position: u32,
+    ipv6_prefix: Option<u8>,
+    /// The nft table, see [`nftables::TABLE`]
+    table: String,
+    /// The pf anchor, see [`pf::ANCHOR`]
+    anchor: String,
+}
+
+impl FirewallBackend for DryRun {
+    fn syntax(&self) -> Backend {
+        self.backend
+    }
+
+    fn families(&self) -> Vec<Family> {
+        match self.ipv6_prefix {
+            Some(_) => vec![Family::V4, Family::V6],
+            None => vec![Family::V4],
+        }
+    }
+
+    fn setup_set(&mut self, options: &SetOptions) -> Result<Box<dyn AddressSet>> {
+        match self.backend {
+            Backend::Iptables => {
+                for command in engine::ipset_commands(options, self.ipv6_prefix) {
+                    println!("{}", command);
+                }
+            }
+            Backend::Nftables => {
+                println!("# nft -f -");
+                print!(
+                    "{}",
+                    nftables::set_script(&self.table, options, self.ipv6_prefix, false)
+                );
+            }
+            Backend::Pf => println!(
+                "pfctl {}",
+                pf::create_args(&self.anchor, &options.name).join(" ")
+            ),
+        }
+        println!();
+        Ok(Box::new(Unapplied))
+    }
+
+    fn apply(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        match (self.backend, self.firewalld) {
+            (Backend::Iptables, true) => {
+                for command in firewalld::commands(family, self.hook, plan) {
+                    println!("{}", command);
+                }
+            }
+            (Backend::Iptables, false) => {
+                let cmd = match family {
+                    Family::V4 => "iptables",
+                    Family::V6 => "ip6tables",
+                };
+                println!("# {}-restore --noflush", cmd);
+                print!(
+                    "{}",
+                    engine::restore_script(engine::table(self.hook), self.position, plan)
+                );
+            }
+            (Backend::Nftables, _) => {
+                println!("# nft -f -");
+                print!("{}", nftables::ruleset_script(family, &self.table, plan));
+            }
+            (Backend::Pf, _) => {
+                println!("# pfctl -a {} -f -", self.anchor);
+                print!("{}", pf::anchor_script(plan));
+            }
+        }
+        println!();
+        Ok(())
+    }
+
+    fn verify(&self, _family: Family) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(Vec::new())
+    }
+
+    fn reinstall(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        self.apply(family, plan)
+    }
+
+    fn counters(&self, _family: Family) -> Result<Vec<RuleCounter>, Box<dyn Error>> {
+        Ok(Vec::new())
+    }
+
+    fn teardown(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn purge(&mut self, _names: &Names) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// A set that was only printed, nothing is ever added to it.
+struct Unapplied;
+
+impl AddressSet for Unapplied {
+    fn add_ip(&mut self, _ip: IpAddr) -> Result<()> {
+        Ok(())
+    }
+
+    fn add_ip_for(&mut self, _ip: IpAddr, _timeout: u32) -> Result<()> {
+        Ok(())
+    }
+
+    fn del_ip(&mut self, _ip: IpAddr) -> Result<()> {
+        Ok(())
+    }
+
+    fn ensure(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn replace(&mut self, _nets: &[Cidr], _timeout: Option<u32>) -> Result<()> {
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<Vec<Listed>> {
+        Ok(Vec::new())
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Print the sets and then the chains of every family, as the run with `args` would create them.
+/// The XDP program, `--synproxy` sysctls and runtime additions like `--allow-net` entries aren't
+/// part of it.
+pub fn print(args: &Args, config: &Config, protected: &Ports) -> Result<()> {
+    let options = ChainOptions::new(args, config, args.probation_period > 0);
+    let names = &options.names;
+    let mut backend: Box<dyn FirewallBackend> = Box::new(DryRun {
+        backend: args.backend,
+        firewalld: args.firewalld,
+        hook: args.hook,
+        position: args.hook_position,
+        ipv6_prefix: (!args.no_ipv6).then_some(args.ipv6_prefix),
+        table: names.of(nftables::TABLE),
+        anchor: names.of(pf::ANCHOR),
+    });
+
+    firewall::setup_ipset(backend.as_mut(), names, args)?;
+    firewall::setup_allow_ipset(backend.as_mut(), names)?;
+    firewall::setup_blacklist_ipset(backend.as_mut(), names)?;
+    if args.probation_period > 0 {
+        firewall::setup_probation_ipset(backend.as_mut(), names, args.probation_period)?;
+    }
+    if args.grace_period > 0 {
+        firewall::setup_grace_ipset(backend.as_mut(), names, args.grace_period)?;
+    }
+    Firewall::setup(
+        backend,
+        protected,
+        args.protected_tcp_ports().as_ref(),
+        &options,
+    )
+    .map_err(|e| anyhow!("Failed to render the rules: {}", e))?;
+    Ok(())
+}
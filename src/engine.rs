@@ -0,0 +1,1149 @@
+//! Declarative firewall state. Everything mortis wants in its table is described as a
+//! [`Plan`], and [`Engine::apply`] diffs it against the last applied plan, making only the
+//! changes in between in one atomic `iptables-restore` run. Setup, reloads, runtime switches and uninstalling are all just plans.
+//! An engine per family together with ipsets for the sets make up the iptables
+//! [`FirewallBackend`].
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    error::Error,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use anyhow::{Result, anyhow};
+
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+use crate::iptables::restore;
+use crate::{
+    cidr::Cidr,
+    firewall::{
+        AddressSet, Backend, Counted, Family, FirewallBackend, Hook, Listed, MORTIS_SETS, Names,
+        RuleCounter, SetFull, SetOptions, ipset_name,
+    },
+    ipset::{
+        Session,
+        types::{AddOption, EnvOption, HashIp, HashNet, NetDataType, SetType},
+    },
+    iptables::{self, IPTables},
+};
+
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    /// Chains mortis owns with their rules, a chain only jumps to chains listed before it
+    pub chains: Vec<(String, Vec<String>)>,
+    /// Rules mortis puts at the top of built-in chains, as (chain, rule), in the order they are
+    /// to be evaluated. A hook that doesn't go to one of the chains may be in another table,
+    /// see [`hook_table`]
+    pub hooks: Vec<(String, String)>,
+}
+
+impl Plan {
+    pub fn chain(&self, name: &str) -> Option<&[String]> {
+        self.chains
+            .iter()
+            .find(|(chain, _)| chain == name)
+            .map(|(_, rules)| rules.as_slice())
+    }
+}
+
+/// The two sets of iptables programs, programming the same tables through different kernel
+/// interfaces. Rules added with one are invisible to the other, and those of the legacy one
+/// are never evaluated once the nft one holds rules.
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IptablesVariant {
+    /// The variant holding more rules, or plain `iptables` if neither holds any
+    Auto,
+    /// `iptables-legacy`, the x_tables interface
+    Legacy,
+    /// `iptables-nft`, x_tables rules on top of nf_tables
+    Nft,
+}
+
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+fn open(ipv6: bool, variant: IptablesVariant) -> Result<IPTables, Box<dyn Error>> {
+    let base = if ipv6 { "ip6tables" } else { "iptables" };
+    let (legacy, nft) = if ipv6 {
+        ("ip6tables-legacy", "ip6tables-nft")
+    } else {
+        ("iptables-legacy", "iptables-nft")
+    };
+    let cmd = match variant {
+        IptablesVariant::Legacy => legacy,
+        IptablesVariant::Nft => nft,
+        IptablesVariant::Auto => match (rule_count(legacy), rule_count(nft)) {
+            (Some(l), Some(n)) if l != n => {
+                let pick = if l > n { legacy } else { nft };
+                if l.min(n) > 0 {
+                    tracing::warn!(
+                        "Both {} and {} hold rules, using {}, pass --iptables-variant to override",
+                        legacy,
+                        nft,
+                        pick
+                    );
+                }
+                pick
+            }
+            // Only one variant installed, or nothing to tell them apart
+            _ => base,
+        },
+    };
+    let mut ipt = iptables::new(ipv6).map_err(|e| format!("Failed to run {}: {}", base, e))?;
+    // Both variants take the same arguments, only the binary differs
+    if cmd != base {
+        tracing::info!("Using {}", cmd);
+        Command::new(cmd)
+            .arg("--version")
+            .output()
+            .map_err(|e| format!("Failed to run {}: {}", cmd, e))?;
+        ipt.cmd = cmd;
+    }
+    Ok(ipt)
+}
+
+/// The mock has a single variant.
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+fn open(ipv6: bool, _variant: IptablesVariant) -> Result<IPTables, Box<dyn Error>> {
+    iptables::new(ipv6)
+}
+
+/// Rules `cmd` reports across all tables, `None` if it can't be run.
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+fn rule_count(cmd: &str) -> Option<usize> {
+    let output = Command::new(format!("{}-save", cmd)).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| line.starts_with("-A "))
+            .count(),
+    )
+}
+
+/// Applies plans to the chains of one family, remembering what it applied so the next plan only
+/// rewrites the chains that changed.
+pub struct Engine {
+    ipt: IPTables,
+    /// `filter` or `raw`, see [`Hook`]
+    table: &'static str,
+    /// Where the hooks go in the built-in chains, 1 for the top, see `--hook-position`
+    position: u32,
+    applied: Plan,
+}
+
+impl Engine {
+    /// `ipv6` drives ip6tables instead of iptables.
+    fn new(
+        ipv6: bool,
+        table: &'static str,
+        variant: IptablesVariant,
+        position: u32,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            ipt: open(ipv6, variant)?,
+            table,
+            position,
+            applied: Plan::default(),
+        })
+    }
+
+    /// Make the kernel match `desired` in a single `iptables-restore` transaction, so either
+    /// the whole plan is in place afterwards or nothing changed. Only chains whose rules differ
+    /// from the last applied plan are rewritten, chains and hooks left behind by a previous run
+    /// that didn't get to clean up are taken over. After a failure the engine still holds the
+    /// last applied plan, so applying again retries the whole change.
+    fn apply(&mut self, desired: &Plan) -> Result<(), Box<dyn Error>> {
+        let ipt = &self.ipt;
+        let table = self.table;
+        let mut declarations = String::new();
+        // Per table, hooks may be in another one than the chains
+        let mut rules: BTreeMap<&str, String> = BTreeMap::new();
+        let first = self.applied == Plan::default();
+        if first {
+            // Replaced by the desired hooks in the same transaction
+            for (chain, rule) in stale_hooks(ipt, desired)? {
+                *rules.entry(hook_table(&chain)).or_default() +=
+                    &format!("-D {} {}\n", chain, rule);
+            }
+            // The others are found by their rule
+            for (chain, rule) in &desired.hooks {
+                if !goes_to(rule, desired) && ipt.exists(hook_table(chain), chain, rule)? {
+                    *rules.entry(hook_table(chain)).or_default() +=
+                        &format!("-D {} {}\n", chain, rule);
+                }
+            }
+        }
+
+        // Declaring a chain creates it, or flushes it if it exists
+        for (chain, desired_rules) in &desired.chains {
+            if self.applied.chain(chain) == Some(desired_rules.as_slice()) {
+                continue;
+            }
+            declarations += &format!(":{} - [0:0]\n", chain);
+            for rule in desired_rules {
+                *rules.entry(table).or_default() += &format!("-A {} {}\n", chain, rule);
+            }
+        }
+        // Inserted last first, so they end up in plan order above anything already at the
+        // position
+        for hook in desired.hooks.iter().rev() {
+            let (chain, rule) = hook;
+            let hook_table = hook_table(chain);
+            if first
+                || !self.applied.hooks.contains(hook) && !ipt.exists(hook_table, chain, rule)?
+            {
+                *rules.entry(hook_table).or_default() +=
+                    &format!("-I {} {} {}\n", chain, self.position, rule);
+            }
+        }
+        for hook in &self.applied.hooks {
+            let (chain, rule) = hook;
+            let hook_table = hook_table(chain);
+            if !desired.hooks.contains(hook) && ipt.exists(hook_table, chain, rule)? {
+                *rules.entry(hook_table).or_default() += &format!("-D {} {}\n", chain, rule);
+            }
+        }
+        // Flushed by their declaration before anything else goes, nothing left refers to them
+        for (chain, _) in &self.applied.chains {
+            if desired.chain(chain).is_none() {
+                declarations += &format!(":{} - [0:0]\n", chain);
+                *rules.entry(table).or_default() += &format!("-X {}\n", chain);
+            }
+        }
+
+        if !declarations.is_empty() {
+            rules.entry(table).or_default();
+        }
+        if !rules.is_empty() {
+            restore(ipt, &script(table, &declarations, &rules))?;
+        }
+        self.applied = desired.clone();
+        Ok(())
+    }
+
+    /// What the last applied plan has that the kernel doesn't, e.g. after someone flushed the
+    /// table. Each entry names a chain, or a rule as `chain: rule`.
+    fn missing(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let ipt = &self.ipt;
+        let table = self.table;
+        let mut missing = Vec::new();
+        for (chain, rules) in &self.applied.chains {
+            if !ipt.chain_exists(table, chain)? {
+                missing.push(chain.clone());
+                continue;
+            }
+            for rule in rules {
+                if !ipt.exists(table, chain, rule)? {
+                    missing.push(format!("{}: {}", chain, rule));
+                }
+            }
+        }
+        for (chain, rule) in &self.applied.hooks {
+            if !ipt.exists(hook_table(chain), chain, rule)? {
+                missing.push(format!("{}: {}", chain, rule));
+            }
+        }
+        Ok(missing)
+    }
+
+    fn counters(&self) -> Result<Vec<RuleCounter>, Box<dyn Error>> {
+        Ok(parse_counters(&list_counted(&self.ipt, self.table)?))
+    }
+
+    /// Remove the chains of `names` from both tables a hook can be in, with the rules of other
+    /// chains going to them, one `iptables-restore` transaction per table. Unlike taking over in
+    /// [`Engine::apply`] this finds chains whatever the previous run called them.
+    fn purge(&mut self, names: &Names) -> Result<(), Box<dyn Error>> {
+        for table in ["filter", "raw"] {
+            let listed = self.ipt.list_table(table)?;
+            let mut owned = BTreeSet::new();
+            let mut jumps = Vec::new();
+            for line in &listed {
+                let mut tokens = line.split_ascii_whitespace();
+                match (tokens.next(), tokens.next()) {
+                    (Some("-N" | "-A"), Some(chain)) if names.owns(chain) => {
+                        owned.insert(chain);
+                    }
+                    (Some("-A"), Some(_)) => jumps.push(&line[3..]),
+                    _ => {}
+                }
+            }
+            let jumps: Vec<&str> = jumps
+                .into_iter()
+                .filter(|rule| target(rule).is_some_and(|target| owned.contains(target)))
+                .collect();
+            if owned.is_empty() && jumps.is_empty() {
+                continue;
+            }
+
+            // Flushed by their declaration, nothing left refers to them once the jumps are gone
+            let mut script = format!("*{}\n", table);
+            for chain in &owned {
+                script += &format!(":{} - [0:0]\n", chain);
+            }
+            for rule in &jumps {
+                script += &format!("-D {}\n", rule);
+            }
+            for chain in &owned {
+                script += &format!("-X {}\n", chain);
+            }
+            script += "COMMIT\n";
+            restore(&self.ipt, &script)?;
+            tracing::info!(
+                "Removed {} stale chains and {} rules going to them from the {} table",
+                owned.len(),
+                jumps.len(),
+                table
+            );
+        }
+        self.applied = Plan::default();
+        Ok(())
+    }
+}
+
+/// `iptables-restore` input with the chain `declarations` of `table` and the `rules` of each
+/// table.
+fn script(table: &str, declarations: &str, rules: &BTreeMap<&str, String>) -> String {
+    let mut script = String::new();
+    for (name, rules) in rules {
+        let declarations = if *name == table { declarations } else { "" };
+        script += &format!("*{}\n{}{}COMMIT\n", name, declarations, rules);
+    }
+    script
+}
+
+/// The `iptables-restore --noflush` input setting up `plan` with its chains in `table` and its
+/// hooks at `position`, what [`Engine::apply`] runs when nothing is there yet.
+pub fn restore_script(table: &'static str, position: u32, plan: &Plan) -> String {
+    let mut declarations = String::new();
+    let mut rules: BTreeMap<&str, String> = BTreeMap::new();
+    rules.entry(table).or_default();
+    for (chain, chain_rules) in &plan.chains {
+        declarations += &format!(":{} - [0:0]\n", chain);
+        for rule in chain_rules {
+            *rules.entry(table).or_default() += &format!("-A {} {}\n", chain, rule);
+        }
+    }
+    for (chain, rule) in plan.hooks.iter().rev() {
+        *rules.entry(hook_table(chain)).or_default() +=
+            &format!("-I {} {} {}\n", chain, position, rule);
+    }
+    script(table, &declarations, &rules)
+}
+
+/// `iptables -S -v` of `table`, the rules with their counters.
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+fn list_counted(ipt: &IPTables, table: &str) -> Result<String, Box<dyn Error>> {
+    let output = ipt.execute(table, "-S -v")?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} -S exited with {}: {}",
+            ipt.cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The mock counts nothing, its rules are listed without counters.
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+fn list_counted(ipt: &IPTables, table: &str) -> Result<String, Box<dyn Error>> {
+    Ok(ipt.list_table(table)?.join("\n"))
+}
+
+/// The [`Counted`] rules of `iptables -S -v` output, e.g. `-A mortis-a0 -m set --match-set
+/// mortis-whitelist src -m comment --comment "mortis-stat:whitelisted" -c 12 840 -j RETURN`.
+/// firewalld passes the same output through.
+pub fn parse_counters(output: &str) -> Vec<RuleCounter> {
+    let mut counters = Vec::new();
+    for line in output.lines() {
+        let mut tokens = line.split_ascii_whitespace();
+        let (Some("-A"), Some(chain)) = (tokens.next(), tokens.next()) else {
+            continue;
+        };
+        let mut counted = None;
+        let (mut packets, mut bytes) = (0, 0);
+        while let Some(token) = tokens.next() {
+            match token {
+                "--comment" => counted = tokens.next().and_then(Counted::from_comment),
+                "-c" => {
+                    packets = tokens.next().and_then(|t| t.parse().ok()).unwrap_or(0);
+                    bytes = tokens.next().and_then(|t| t.parse().ok()).unwrap_or(0);
+                }
+                _ => {}
+            }
+        }
+        if let Some(counted) = counted {
+            counters.push(RuleCounter {
+                chain: chain.to_string(),
+                counted,
+                packets,
+                bytes,
+            });
+        }
+    }
+    counters
+}
+
+/// The table the built-in `chain` of a hook is in: PREROUTING only in raw, INPUT, FORWARD and
+/// Docker's DOCKER-USER only in filter. Either way the one [`table`] picks for the hook of the
+/// same name.
+pub fn hook_table(chain: &str) -> &'static str {
+    match chain {
+        "PREROUTING" => "raw",
+        _ => "filter",
+    }
+}
+
+/// The chain or target `rule` jumps or goes to.
+fn target(rule: &str) -> Option<&str> {
+    let mut tokens = rule.split_ascii_whitespace();
+    tokens.find(|t| *t == "-j" || *t == "-g").and(tokens.next())
+}
+
+/// Whether `rule` jumps or goes to one of the chains of `plan`.
+fn goes_to(rule: &str, plan: &Plan) -> bool {
+    target(rule).is_some_and(|target| plan.chain(target).is_some())
+}
+
+/// Rules of the built-in chains `desired` hooks into that go to one of its chains, as (chain,
+/// rule). Before the first plan is applied these can only be left over from a previous run.
+fn stale_hooks(ipt: &IPTables, desired: &Plan) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let hooked: HashSet<&str> = desired.hooks.iter().map(|(c, _)| c.as_str()).collect();
+    let mut stale = Vec::new();
+    for chain in hooked {
+        let prefix = format!("-A {} ", chain);
+        for rule in ipt.list(hook_table(chain), chain)? {
+            let Some(rule) = rule.strip_prefix(&prefix) else {
+                continue;
+            };
+            if goes_to(rule, desired) {
+                stale.push((chain.to_string(), rule.to_string()));
+            }
+        }
+    }
+    Ok(stale)
+}
+
+/// The iptables [`FirewallBackend`]: an [`Engine`] per family, ip6tables for IPv6, and an ipset
+/// per family for every set.
+pub struct Iptables {
+    v4: Engine,
+    v6: Option<Engine>,
+    /// `--ipv6-prefix` of IPv6 units, `None` when IPv6 is disabled
+    ipv6_prefix: Option<u8>,
+    /// Reuse existing ipsets, see [`crate::firewall::backend`]
+    adopt: bool,
+}
+
+impl Iptables {
+    /// The chains go into the table of `hook`, the hooks at `position` in their chains.
+    pub fn new(
+        ipv6_prefix: Option<u8>,
+        hook: Hook,
+        adopt: bool,
+        variant: IptablesVariant,
+        position: u32,
+    ) -> Result<Self, Box<dyn Error>> {
+        let table = table(hook);
+        Ok(Self {
+            v4: Engine::new(false, table, variant, position)?,
+            v6: ipv6_prefix
+                .map(|_| Engine::new(true, table, variant, position))
+                .transpose()?,
+            ipv6_prefix,
+            adopt,
+        })
+    }
+
+    fn engine(&mut self, family: Family) -> Result<&mut Engine, Box<dyn Error>> {
+        match family {
+            Family::V4 => Ok(&mut self.v4),
+            Family::V6 => self.v6.as_mut().ok_or_else(|| "IPv6 is disabled".into()),
+        }
+    }
+}
+
+impl FirewallBackend for Iptables {
+    fn syntax(&self) -> Backend {
+        Backend::Iptables
+    }
+
+    fn families(&self) -> Vec<Family> {
+        match self.v6 {
+            Some(_) => vec![Family::V4, Family::V6],
+            None => vec![Family::V4],
+        }
+    }
+
+    fn setup_set(&mut self, options: &SetOptions) -> Result<Box<dyn AddressSet>> {
+        ipsets(options, self.ipv6_prefix, self.adopt)
+    }
+
+    fn apply(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        self.engine(family)?.apply(plan)
+    }
+
+    fn verify(&self, family: Family) -> Result<Vec<String>, Box<dyn Error>> {
+        match family {
+            Family::V4 => self.v4.missing(),
+            Family::V6 => self.v6.as_ref().map_or(Ok(Vec::new()), Engine::missing),
+        }
+    }
+
+    fn reinstall(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        let engine = self.engine(family)?;
+        let applied = std::mem::take(&mut engine.applied);
+        engine.apply(plan).inspect_err(|_| engine.applied = applied)
+    }
+
+    fn counters(&self, family: Family) -> Result<Vec<RuleCounter>, Box<dyn Error>> {
+        match family {
+            Family::V4 => self.v4.counters(),
+            Family::V6 => self.v6.as_ref().map_or(Ok(Vec::new()), Engine::counters),
+        }
+    }
+
+    fn teardown(&mut self) -> Result<(), Box<dyn Error>> {
+        self.v4.apply(&Plan::default())?;
+        if let Some(v6) = &mut self.v6 {
+            v6.apply(&Plan::default())?;
+        }
+        Ok(())
+    }
+
+    fn purge(&mut self, names: &Names) -> Result<(), Box<dyn Error>> {
+        self.v4.purge(names)?;
+        if let Some(v6) = &mut self.v6 {
+            v6.purge(names)?;
+        }
+        destroy_ipsets(names, self.v6.is_some())?;
+        Ok(())
+    }
+}
+
+/// The table the chains of `hook` go into.
+pub fn table(hook: Hook) -> &'static str {
+    match hook {
+        Hook::Input | Hook::Forward | Hook::DockerUser => "filter",
+        Hook::Raw => "raw",
+    }
+}
+
+/// Destroy the ipsets of `names` a previous run left behind, with the staging sets of
+/// [`swap_in`]. IPv6 ones only with `ipv6`, the rules of a family left alone may still use them.
+pub fn destroy_ipsets(names: &Names, ipv6: bool) -> Result<()> {
+    let families: &[Family] = match ipv6 {
+        true => &[Family::V4, Family::V6],
+        false => &[Family::V4],
+    };
+    for base in MORTIS_SETS {
+        for family in families {
+            let name = ipset_name(&names.of(base), *family);
+            for name in [format!("{}-new", name), name] {
+                match Session::<HashIp>::new(name.clone()).destroy() {
+                    Ok(_) => tracing::info!("Destroyed stale ipset {}", name),
+                    Err(e) if e.to_string().contains("does not exist") => {}
+                    Err(e) => return Err(anyhow!("Failed to destroy ipset {}: {}", name, e)),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The `ipset create` commands for the sets [`ipsets`] creates through libipset.
+pub fn ipset_commands(options: &SetOptions, ipv6_prefix: Option<u8>) -> Vec<String> {
+    let kind = match options.nets {
+        true => "hash:net",
+        false => "hash:ip",
+    };
+    let mut sets = vec![(options.name.clone(), "inet", None)];
+    if let Some(prefix) = ipv6_prefix {
+        let netmask = (!options.nets && options.units && prefix < 128).then_some(prefix);
+        sets.push((ipset_name(&options.name, Family::V6), "inet6", netmask));
+    }
+    sets.into_iter()
+        .map(|(name, family, netmask)| {
+            let mut command = format!("ipset create {} {} family {}", name, kind, family);
+            if let Some(netmask) = netmask {
+                command += &format!(" netmask {}", netmask);
+            }
+            if let Some(timeout) = options.timeout {
+                command += &format!(" timeout {}", timeout);
+            }
+            if options.forceadd {
+                command += " forceadd";
+            }
+            if let Some(maxelem) = options.maxelem {
+                command += &format!(" maxelem {}", maxelem);
+            }
+            if let Some(hashsize) = options.hashsize {
+                command += &format!(" hashsize {}", hashsize);
+            }
+            if options.comments && !options.nets {
+                command += " comment";
+            }
+            command
+        })
+        .collect()
+}
+
+/// An ipset per family for `options`, IPv6 ones only with an `ipv6_prefix`. With `adopt` existing
+/// sets are kept, see [`crate::firewall::backend`].
+pub fn ipsets(
+    options: &SetOptions,
+    ipv6_prefix: Option<u8>,
+    adopt: bool,
+) -> Result<Box<dyn AddressSet>> {
+    if options.nets {
+        return Ok(Box::new(NetIpsets::create(
+            options,
+            ipv6_prefix.is_some(),
+            adopt,
+        )?));
+    }
+    let v4 = create_ipset(options.name.clone(), options, None, adopt)?;
+    let v6 = match ipv6_prefix {
+        Some(prefix) => Some(create_ipset(
+            ipset_name(&options.name, Family::V6),
+            options,
+            // IPv6 entries are units, the kernel masks every packet to the same prefix
+            Some(if options.units { prefix } else { 128 }),
+            adopt,
+        )?),
+        None => None,
+    };
+
+    Ok(Box::new(Ipsets {
+        v4,
+        v6,
+        options: options.clone(),
+        ipv6_netmask: ipv6_prefix.map(|prefix| if options.units { prefix } else { 128 }),
+    }))
+}
+
+/// `netmask` makes it an IPv6 set. With `adopt` an existing set of the same type and options is
+/// kept as it is.
+fn create_ipset(
+    name: String,
+    options: &SetOptions,
+    netmask: Option<u8>,
+    adopt: bool,
+) -> Result<Session<HashIp>> {
+    let mut session: Session<HashIp> = Session::<HashIp>::new(name);
+    if adopt {
+        session.set_option(EnvOption::Exist);
+    }
+    let created = session.create(|builder| {
+        let mut builder = builder.with_ipv6(netmask.is_some())?;
+        if let Some(netmask) = netmask.filter(|netmask| *netmask < 128) {
+            builder = builder.with_netmask(netmask)?;
+        }
+        if let Some(timeout) = options.timeout {
+            builder = builder.with_timeout(timeout)?;
+        }
+        if options.forceadd {
+            builder = builder.with_forceadd()?;
+        }
+        if let Some(maxelem) = options.maxelem {
+            builder = builder.with_max_elem(maxelem)?;
+        }
+        if let Some(hashsize) = options.hashsize {
+            builder = builder.with_hash_size(hashsize)?;
+        }
+        if options.comments {
+            builder = builder.with_comment()?;
+        }
+        builder.build()
+    });
+    session.unset_option(EnvOption::Exist);
+    created?;
+    Ok(session)
+}
+
+/// ipset refuses to add an entry twice unless told to, in which case it replaces the entry and
+/// with it the timeout. Deleting stays strict.
+fn add(session: &mut Session<HashIp>, ip: IpAddr, options: &[AddOption]) -> Result<()> {
+    session.set_option(EnvOption::Exist);
+    let added = session.add(ip, options);
+    session.unset_option(EnvOption::Exist);
+    added?;
+    Ok(())
+}
+
+/// Fill a set named after `live` with `fill` and swap it with the live one, the old entries go
+/// with it when it is destroyed afterwards.
+fn swap_in<T: SetType>(
+    live: &mut Session<T>,
+    name: &str,
+    create: impl FnOnce(String) -> Result<Session<T>>,
+    fill: impl FnOnce(&mut Session<T>) -> Result<()>,
+) -> Result<()> {
+    let staging = format!("{}-new", name);
+    // Left behind by a replace that failed halfway
+    let _ = Session::<T>::new(staging.clone()).destroy();
+    let mut new = create(staging.clone())?;
+    let swapped = fill(&mut new).and_then(|_| swap(live, name, &mut new, &staging));
+    new.destroy()?;
+    swapped
+}
+
+/// ipset swaps the contents of the two sets by name, rules matching `live` see all the new entries
+/// at once.
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+fn swap<T: SetType>(
+    _live: &mut Session<T>,
+    live_name: &str,
+    _staging: &mut Session<T>,
+    staging_name: &str,
+) -> Result<()> {
+    // The ipset crate has no binding for it
+    let output = Command::new("ipset")
+        .args(["swap", staging_name, live_name])
+        .output()
+        .map_err(|e| anyhow!("Failed to run ipset: {}", e))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "ipset swap exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+fn swap<T: SetType>(
+    live: &mut Session<T>,
+    _live_name: &str,
+    staging: &mut Session<T>,
+    _staging_name: &str,
+) -> Result<()> {
+    crate::ipset::swap(live, staging)?;
+    Ok(())
+}
+
+/// `nets` split by family, failing for IPv6 ones without an IPv6 set. Networks that aren't a
+/// single address only go into sets of networks.
+fn by_family(nets: &[Cidr], ipv6: bool, networks: bool) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for net in nets {
+        if !networks && !net.is_host() {
+            anyhow::bail!("{} is a network, the set only holds addresses", net);
+        }
+        match Family::of(net.addr()) {
+            Family::V4 => v4.push(*net),
+            Family::V6 if ipv6 => v6.push(*net),
+            Family::V6 => anyhow::bail!("Can't add IPv6 network {}, IPv6 is disabled", net),
+        }
+    }
+    Ok((v4, v6))
+}
+
+/// An entry `session.list()` returned, ipset lists comments in quotes.
+fn listed(ip: IpAddr, options: &[AddOption]) -> Listed {
+    let mut entry = Listed {
+        ip,
+        timeout: 0,
+        comment: None,
+    };
+    for option in options {
+        match option {
+            AddOption::Timeout(timeout) => entry.timeout = *timeout,
+            AddOption::Comment(comment) => {
+                entry.comment = Some(comment.trim_matches('"').to_string())
+            }
+            // The mock has no other options
+            #[allow(unreachable_patterns)]
+            _ => {}
+        }
+    }
+    entry
+}
+
+/// `e` of an add to `set`, as [`SetFull`] if the kernel refused it for a full set.
+fn full(e: anyhow::Error, set: &str) -> anyhow::Error {
+    match e.to_string().contains("is full") {
+        true => SetFull(set.to_string()).into(),
+        false => e,
+    }
+}
+
+/// An ipset per family, every address goes to the one of its family.
+struct Ipsets {
+    v4: Session<HashIp>,
+    v6: Option<Session<HashIp>>,
+    /// To create them again, see [`AddressSet::ensure`]
+    options: SetOptions,
+    ipv6_netmask: Option<u8>,
+}
+
+impl Ipsets {
+    fn session(&mut self, ip: IpAddr) -> Result<(&mut Session<HashIp>, IpAddr)> {
+        let ip = ip.to_canonical();
+        match Family::of(ip) {
+            Family::V4 => Ok((&mut self.v4, ip)),
+            Family::V6 => match &mut self.v6 {
+                Some(v6) => Ok((v6, ip)),
+                None => Err(anyhow!("Can't add IPv6 address {}, IPv6 is disabled", ip)),
+            },
+        }
+    }
+}
+
+impl AddressSet for Ipsets {
+    fn add_ip(&mut self, ip: IpAddr) -> Result<()> {
+        let (session, ip) = self.session(ip)?;
+        add(session, ip, &[]).map_err(|e| full(e, &self.options.name))
+    }
+
+    fn add_ip_for(&mut self, ip: IpAddr, timeout: u32) -> Result<()> {
+        let (session, ip) = self.session(ip)?;
+        add(session, ip, &[AddOption::Timeout(timeout)]).map_err(|e| full(e, &self.options.name))
+    }
+
+    fn del_ip(&mut self, ip: IpAddr) -> Result<()> {
+        let (session, ip) = self.session(ip)?;
+        session.del(ip)?;
+        Ok(())
+    }
+
+    fn add_commented(&mut self, ip: IpAddr, timeout: Option<u32>, comment: &str) -> Result<()> {
+        let mut options: Vec<AddOption> = timeout.map(AddOption::Timeout).into_iter().collect();
+        if self.options.comments {
+            options.push(AddOption::Comment(comment.to_string()));
+        }
+        let (session, ip) = self.session(ip)?;
+        add(session, ip, &options).map_err(|e| full(e, &self.options.name))
+    }
+
+    fn ensure(&mut self) -> Result<bool> {
+        // Testing fails for a set that doesn't exist
+        let mut recreated = false;
+        if self.v4.test(IpAddr::from(Ipv4Addr::UNSPECIFIED)).is_err() {
+            self.v4 = create_ipset(self.options.name.clone(), &self.options, None, true)?;
+            recreated = true;
+        }
+        if let Some(v6) = &mut self.v6
+            && v6.test(IpAddr::from(Ipv6Addr::UNSPECIFIED)).is_err()
+        {
+            *v6 = create_ipset(
+                ipset_name(&self.options.name, Family::V6),
+                &self.options,
+                self.ipv6_netmask,
+                true,
+            )?;
+            recreated = true;
+        }
+        Ok(recreated)
+    }
+
+    fn replace(&mut self, nets: &[Cidr], timeout: Option<u32>) -> Result<()> {
+        let (v4, v6) = by_family(nets, self.v6.is_some(), false)?;
+        let options = &self.options;
+        let fill = |ips: Vec<Cidr>| {
+            move |session: &mut Session<HashIp>| {
+                for ip in ips {
+                    let add_options: Vec<AddOption> =
+                        timeout.map(AddOption::Timeout).into_iter().collect();
+                    add(session, ip.addr(), &add_options).map_err(|e| full(e, &options.name))?;
+                }
+                Ok(())
+            }
+        };
+        swap_in(
+            &mut self.v4,
+            &options.name,
+            |name| create_ipset(name, options, None, false),
+            fill(v4),
+        )?;
+        if let Some(session) = &mut self.v6 {
+            swap_in(
+                session,
+                &ipset_name(&options.name, Family::V6),
+                |name| create_ipset(name, options, self.ipv6_netmask, false),
+                fill(v6),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<Vec<Listed>> {
+        let mut entries = Vec::new();
+        for session in std::iter::once(&mut self.v4).chain(&mut self.v6) {
+            for (ip, options) in session.list()? {
+                entries.push(listed(ip.to_ip_addr(), &options));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        for session in std::iter::once(&mut self.v4).chain(&mut self.v6) {
+            session.flush()?;
+            session.destroy()?;
+        }
+        Ok(())
+    }
+}
+
+/// The hash:net counterpart of [`create_ipset`].
+fn create_net_ipset(
+    name: String,
+    options: &SetOptions,
+    ipv6: bool,
+    adopt: bool,
+) -> Result<Session<HashNet>> {
+    let mut session: Session<HashNet> = Session::<HashNet>::new(name);
+    if adopt {
+        session.set_option(EnvOption::Exist);
+    }
+    let created = session.create(|builder| {
+        let mut builder = builder.with_ipv6(ipv6)?;
+        if let Some(timeout) = options.timeout {
+            builder = builder.with_timeout(timeout)?;
+        }
+        if options.forceadd {
+            builder = builder.with_forceadd()?;
+        }
+        if let Some(maxelem) = options.maxelem {
+            builder = builder.with_max_elem(maxelem)?;
+        }
+        if let Some(hashsize) = options.hashsize {
+            builder = builder.with_hash_size(hashsize)?;
+        }
+        builder.build()
+    });
+    session.unset_option(EnvOption::Exist);
+    created?;
+    Ok(session)
+}
+
+/// A hash:net ipset per family, for sets of [`SetOptions::nets`].
+struct NetIpsets {
+    v4: Session<HashNet>,
+    v6: Option<Session<HashNet>>,
+    options: SetOptions,
+}
+
+impl NetIpsets {
+    fn create(options: &SetOptions, ipv6: bool, adopt: bool) -> Result<Self> {
+        Ok(Self {
+            v4: create_net_ipset(options.name.clone(), options, false, adopt)?,
+            v6: match ipv6 {
+                true => Some(create_net_ipset(
+                    ipset_name(&options.name, Family::V6),
+                    options,
+                    true,
+                    adopt,
+                )?),
+                false => None,
+            },
+            options: options.clone(),
+        })
+    }
+
+    fn session(&mut self, net: Cidr) -> Result<(&mut Session<HashNet>, NetDataType)> {
+        let data = NetDataType::new(net.addr(), net.prefix());
+        match Family::of(net.addr()) {
+            Family::V4 => Ok((&mut self.v4, data)),
+            Family::V6 => match &mut self.v6 {
+                Some(v6) => Ok((v6, data)),
+                None => Err(anyhow!("Can't add IPv6 network {}, IPv6 is disabled", net)),
+            },
+        }
+    }
+
+    fn add(&mut self, net: Cidr, options: &[AddOption]) -> Result<()> {
+        let (session, data) = self.session(net)?;
+        session.set_option(EnvOption::Exist);
+        let added = session.add(data, options);
+        session.unset_option(EnvOption::Exist);
+        added.map_err(|e| full(e.into(), &self.options.name))?;
+        Ok(())
+    }
+}
+
+impl AddressSet for NetIpsets {
+    fn add_ip(&mut self, ip: IpAddr) -> Result<()> {
+        self.add(Cidr::host(ip), &[])
+    }
+
+    fn add_ip_for(&mut self, ip: IpAddr, timeout: u32) -> Result<()> {
+        self.add(Cidr::host(ip), &[AddOption::Timeout(timeout)])
+    }
+
+    fn del_ip(&mut self, ip: IpAddr) -> Result<()> {
+        self.del_net(Cidr::host(ip))
+    }
+
+    fn add_net(&mut self, net: Cidr) -> Result<()> {
+        self.add(net, &[])
+    }
+
+    fn del_net(&mut self, net: Cidr) -> Result<()> {
+        let (session, data) = self.session(net)?;
+        session.del(data)?;
+        Ok(())
+    }
+
+    fn ensure(&mut self) -> Result<bool> {
+        let gone = self
+            .v4
+            .test(NetDataType::new(IpAddr::from(Ipv4Addr::UNSPECIFIED), 32))
+            .is_err()
+            || self.v6.as_mut().is_some_and(|v6| {
+                v6.test(NetDataType::new(IpAddr::from(Ipv6Addr::UNSPECIFIED), 128))
+                    .is_err()
+            });
+        if gone {
+            *self = Self::create(&self.options, self.v6.is_some(), true)?;
+        }
+        Ok(gone)
+    }
+
+    fn replace(&mut self, nets: &[Cidr], timeout: Option<u32>) -> Result<()> {
+        let (v4, v6) = by_family(nets, self.v6.is_some(), true)?;
+        let options = &self.options;
+        let fill = |nets: Vec<Cidr>| {
+            move |session: &mut Session<HashNet>| {
+                let add_options: Vec<AddOption> =
+                    timeout.map(AddOption::Timeout).into_iter().collect();
+                session.set_option(EnvOption::Exist);
+                let added = nets.into_iter().try_for_each(|net| {
+                    session
+                        .add(NetDataType::new(net.addr(), net.prefix()), &add_options)
+                        .map(|_| ())
+                });
+                session.unset_option(EnvOption::Exist);
+                added.map_err(|e| full(e.into(), &options.name))
+            }
+        };
+        swap_in(
+            &mut self.v4,
+            &options.name,
+            |name| create_net_ipset(name, options, false, false),
+            fill(v4),
+        )?;
+        if let Some(session) = &mut self.v6 {
+            swap_in(
+                session,
+                &ipset_name(&options.name, Family::V6),
+                |name| create_net_ipset(name, options, true, false),
+                fill(v6),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Networks are listed by their first address.
+    fn list(&mut self) -> Result<Vec<Listed>> {
+        let mut entries = Vec::new();
+        for session in std::iter::once(&mut self.v4).chain(&mut self.v6) {
+            for (net, options) in session.list()? {
+                entries.push(listed(net.ip(), &options));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        for session in std::iter::once(&mut self.v4).chain(&mut self.v6) {
+            session.flush()?;
+            session.destroy()?;
+        }
+        Ok(())
+    }
+}
+
+/// Apply the `iptables-restore` input `script` as one transaction, leaving the chains it doesn't
+/// mention alone.
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+fn restore(ipt: &IPTables, script: &str) -> Result<(), Box<dyn Error>> {
+    let cmd = format!("{}-restore", ipt.cmd);
+    let mut child = Command::new(&cmd)
+        .args(["--noflush", "--wait"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", cmd, e))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(script.as_bytes())?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} exited with {}: {}",
+            cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_counters() {
+        let output = "\
+-P INPUT ACCEPT -c 0 0
+-N mortis-a0
+-A mortis-a0 -p udp -m udp --sport 53 -m comment --comment \"mortis-stat:amplification\" -c 7 3500 -j DROP
+-A mortis-a0 -m set --match-set mortis-whitelist src -m comment --comment \"mortis-stat:whitelisted\" -c 12 840 -j RETURN
+-A mortis-a0 -m hashlimit --hashlimit-above 5/sec -m comment --comment \"mortis-stat:unknown\" -c 0 0 -j DROP
+-A mortis-a0 -c 30 2100 -j RETURN
+";
+        let counters = parse_counters(output);
+        let counted: Vec<_> = counters
+            .iter()
+            .map(|c| (c.chain.as_str(), c.counted, c.packets, c.bytes))
+            .collect();
+        assert_eq!(
+            counted,
+            [
+                ("mortis-a0", Counted::Amplification, 7, 3500),
+                ("mortis-a0", Counted::Whitelisted, 12, 840),
+                ("mortis-a0", Counted::Unknown, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_table() {
+        assert!(parse_counters("").is_empty());
+        assert!(parse_counters("-P INPUT ACCEPT -c 0 0\n-P OUTPUT ACCEPT -c 0 0\n").is_empty());
+    }
+
+    #[test]
+    fn skips_unexpected_lines() {
+        let output = "\
+# Warning: iptables-legacy tables present, use iptables-legacy to see them
+-A
+-A mortis-a0 -m comment --comment \"someone-else:whitelisted\" -c 1 1 -j RETURN
+-A mortis-a0 -m comment --comment \"mortis-stat:bogus\" -c 1 1 -j RETURN
+-A mortis-a0 -m comment --comment \"mortis-stat:whitelisted\" -c many 1 -j RETURN
+";
+        let counters = parse_counters(output);
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].counted, Counted::Whitelisted);
+        assert_eq!((counters[0].packets, counters[0].bytes), (0, 1));
+    }
+}
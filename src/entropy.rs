@@ -0,0 +1,107 @@
+//! Source port anomaly detection. A game client sends from a single source port, a whitelisted
+//! address showing up with many of them is spoofed or shared by a compromised host, so it is
+//! evicted from the whitelist, and banned with `--sport-ban`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{blacklist, cleaner, nflog, state::AppState};
+
+/// The IP header plus the UDP source port.
+const COPY_RANGE: u32 = 64;
+/// Source ports are counted per window, evictions only happen within one.
+const WINDOW: Duration = Duration::from_secs(60);
+/// Sources tracked per window, samples of further sources are ignored.
+const MAX_SOURCES: usize = 10000;
+
+pub async fn task(state: Arc<AppState>) {
+    let group = state.args.sport_nflog_group;
+    let rate = state.args.sport_sample_rate;
+    let socket = match nflog::NflogSocket::bind(group, COPY_RANGE) {
+        Ok(socket) => socket,
+        Err(e) => {
+            tracing::error!("Failed to bind NFLOG group {}: {}", group, e);
+            return;
+        }
+    };
+    let started = state
+        .with_firewall(move |firewall| {
+            firewall
+                .start_sport_sampling(rate, group)
+                .map_err(|e| e.to_string())
+        })
+        .await;
+    if let Err(e) = started {
+        tracing::error!("Failed to insert source port sampling rule: {}", e);
+        return;
+    }
+
+    let mut window = tokio::time::interval(WINDOW);
+    let mut ports: HashMap<IpAddr, HashSet<u16>> = HashMap::new();
+    let mut buf = vec![0u8; 65536];
+
+    loop {
+        tokio::select! {
+            _ = window.tick() => ports.clear(),
+            received = socket.recv(&mut buf) => {
+                let len = match received {
+                    Ok(len) => len,
+                    Err(e) => {
+                        tracing::warn!("Failed to receive source port samples: {}", e);
+                        continue;
+                    }
+                };
+                let mut offenders = Vec::new();
+                for packet in nflog::packets(&buf[..len]) {
+                    let Some((src, sport)) = source(packet.payload) else {
+                        continue;
+                    };
+                    let tracked = ports.len();
+                    let seen = match ports.get_mut(&src) {
+                        Some(seen) => seen,
+                        None if tracked < MAX_SOURCES => ports.entry(src).or_default(),
+                        None => continue,
+                    };
+                    if seen.insert(sport) && seen.len() == state.args.sport_limit + 1 {
+                        offenders.push(src);
+                    }
+                }
+
+                for ip in offenders {
+                    if state.args.sport_ban > 0 {
+                        let duration = Duration::from_secs(state.args.sport_ban);
+                        if let Err(e) = blacklist::ban(&state, ip, Some(duration)).await {
+                            tracing::error!("Failed to ban {}: {}", ip, e);
+                        }
+                    }
+                    match cleaner::evict(&state, ip).await {
+                        Ok(true) => tracing::warn!(
+                            "Evicted {}, seen with more than {} source ports within {}s",
+                            ip,
+                            state.args.sport_limit,
+                            WINDOW.as_secs()
+                        ),
+                        Ok(false) => {}
+                        Err(e) => tracing::error!("Failed to evict {}: {}", ip, e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Source address and port of an IPv4/UDP packet.
+fn source(packet: &[u8]) -> Option<(IpAddr, u16)> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 || packet[9] != nflog::IPPROTO_UDP {
+        return None;
+    }
+    let header_len = ((packet[0] & 0x0f) as usize) * 4;
+    let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let udp = packet.get(header_len..header_len + 2)?;
+
+    Some((src.into(), u16::from_be_bytes([udp[0], udp[1]])))
+}
@@ -0,0 +1,54 @@
+use std::{fmt::Write, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use clap::ValueEnum;
+
+use crate::{cleaner, snapshot::write_atomic, state::AppState};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    /// `1.2.3.4 1;` lines, for an nginx `geo` block include
+    Nginx,
+    /// One address per line
+    Plain,
+}
+
+/// Keep `path` in sync with the whitelist so a reverse proxy can gate on the same state.
+pub async fn task(state: Arc<AppState>, path: PathBuf) {
+    let interval = Duration::from_secs(state.args.export_interval);
+    let mut last_written = None;
+
+    loop {
+        let rendered = render(&state).await;
+        if last_written.as_ref() != Some(&rendered) {
+            match write_atomic(&path, rendered.as_bytes()).await {
+                Ok(()) => last_written = Some(rendered),
+                Err(e) => tracing::error!("Failed to export whitelist: {:#}", e),
+            }
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn render(state: &AppState) -> String {
+    let whitelist = state.whitelist.lock().await;
+    let pinned = state.pinned.lock().await;
+    let mut ips: Vec<IpAddr> = whitelist
+        .iter()
+        .filter(|(ip, last_seen)| cleaner::is_live(**last_seen, pinned.contains(ip)))
+        .map(|(ip, _)| *ip)
+        .collect();
+    drop(pinned);
+    drop(whitelist);
+    ips.sort_unstable();
+
+    let mut out = String::new();
+    for ip in ips {
+        let _ = match state.args.export_format {
+            ExportFormat::Nginx => writeln!(out, "{} 1;", ip),
+            ExportFormat::Plain => writeln!(out, "{}", ip),
+        };
+    }
+
+    out
+}
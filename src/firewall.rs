@@ -1,85 +1,1889 @@
-use std::error::Error;
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
 
-use anyhow::Result;
-use ipset::{types::HashIp, Session};
-use iptables::IPTables;
+use crate::{
+    Args,
+    cidr::Cidr,
+    cleaner::ENTRY_TTL,
+    config::{Config, ExtraRule, Position, RuleFamily},
+    engine::{Iptables, Plan},
+    firewalld, nftables, pf,
+    ports::Ports,
+};
+use anyhow::{Result, anyhow};
 
 const IPTABLES_CHAIN: &str = "mortis";
-const MORTIS_IPSET: &str = "mortis-whitelist";
-
-pub fn setup_ipset() -> Result<Session<HashIp>> {
-    let mut session: Session<HashIp> = Session::<HashIp>::new(MORTIS_IPSET.to_string());
-    session.create(|builder| {
-        builder
-            .with_ipv6(false)?
-            // .with_timeout(300)?
-            .with_forceadd()?
-            .build()
-    })?;
-
-    Ok(session)
-}
-
-pub fn clean_ipset(ipset_session: &mut Session<HashIp>) -> Result<()> {
-    ipset_session.flush()?;
-    ipset_session.destroy()?;
-    Ok(())
-}
-
-pub fn setup_iptables(protected_port: &str) -> Result<IPTables, Box<dyn Error>> {
-    let ipt = iptables::new(false)?;
-    ipt.new_chain("filter", IPTABLES_CHAIN)?;
-
-    ipt.append(
-        "filter",
-        IPTABLES_CHAIN,
-        "-p udp --match multiport --sports 123,53,161,3702,19 -j DROP",
-    )?;
-    ipt.append(
-        "filter",
-        IPTABLES_CHAIN,
-        format!(
-            "--match set --match-set {} src --match hashlimit --hashlimit-above 150/sec --hashlimit-burst 10 --hashlimit-mode srcip,dstport --hashlimit-name mortis-white -j DROP",
-            MORTIS_IPSET.to_string()
-        )
-        .as_str(),
-    )?;
-    ipt.append(
-        "filter",
-        IPTABLES_CHAIN,
-        format!(
-            "--match set --match-set {} src -j RETURN",
-            MORTIS_IPSET.to_string()
+/// Longest chain name iptables accepts
+const IPTABLES_CHAIN_MAX: usize = 28;
+pub const MORTIS_IPSET: &str = "mortis-whitelist";
+pub const MORTIS_ALLOW_IPSET: &str = "mortis-allow";
+pub const MORTIS_PROBATION_IPSET: &str = "mortis-probation";
+pub const MORTIS_GRACE_IPSET: &str = "mortis-grace";
+pub const MORTIS_BLACKLIST_IPSET: &str = "mortis-blacklist";
+/// Every set mortis may create, for removing them whatever the previous run had enabled
+pub const MORTIS_SETS: [&str; 5] = [
+    MORTIS_IPSET,
+    MORTIS_ALLOW_IPSET,
+    MORTIS_PROBATION_IPSET,
+    MORTIS_GRACE_IPSET,
+    MORTIS_BLACKLIST_IPSET,
+];
+const PROBATION_CHAIN: &str = "mortis-probation";
+/// In monitor-only mode, packets that would be dropped are sent into one of these chains
+/// instead. They log the packet and return to INPUT as if it had passed mortis.
+const MONITOR_AMPLIFICATION_CHAIN: &str = "mortis-mon-amp";
+const MONITOR_WHITELIST_CHAIN: &str = "mortis-mon-white";
+const MONITOR_UNKNOWN_CHAIN: &str = "mortis-mon-unknown";
+const MONITOR_PAYLOAD_CHAIN: &str = "mortis-mon-payload";
+const MONITOR_BLACKLIST_CHAIN: &str = "mortis-mon-black";
+const MONITOR_CHAINS: [(&str, &str); 5] = [
+    (MONITOR_AMPLIFICATION_CHAIN, "amplification"),
+    (MONITOR_WHITELIST_CHAIN, "whitelist_limit"),
+    (MONITOR_UNKNOWN_CHAIN, "unknown_limit"),
+    (MONITOR_PAYLOAD_CHAIN, "payload"),
+    (MONITOR_BLACKLIST_CHAIN, "blacklist"),
+];
+pub const MONITOR_PREFIX: &str = "mortis-monitor:";
+/// With drop sampling or mirroring, dropped packets go into the chain next to the one
+/// monitor-only mode would send them to. They log or mirror a few of them and drop them all.
+const DROP_CHAINS: [(&str, &str); 5] = [
+    (MONITOR_AMPLIFICATION_CHAIN, "mortis-drop-amp"),
+    (MONITOR_WHITELIST_CHAIN, "mortis-drop-white"),
+    (MONITOR_UNKNOWN_CHAIN, "mortis-drop-unknown"),
+    (MONITOR_PAYLOAD_CHAIN, "mortis-drop-payload"),
+    (MONITOR_BLACKLIST_CHAIN, "mortis-drop-black"),
+];
+pub const DROP_PREFIX: &str = "mortis-drop:";
+/// Starts the comment of every [`Counted`] rule
+const COUNTED_PREFIX: &str = "mortis-stat:";
+/// Bumped whenever the layout of the mortis chains changes, reported by `mortis status`.
+pub const RULE_SCHEMA_VERSION: u32 = 10;
+
+/// Ruleset built from the command line and the top level `extra_rules`.
+pub const DEFAULT_RULESET: &str = "default";
+/// Packets per second a whitelisted source may send to a port unless a ruleset says otherwise
+pub const DEFAULT_WHITELIST_LIMIT: u32 = 150;
+/// Packets per second an unknown source may send to a port unless a ruleset says otherwise
+pub const DEFAULT_UNKNOWN_LIMIT: u32 = 5;
+/// Packets a source may send at once before its rate counts
+pub const DEFAULT_BURST: u32 = 10;
+/// Packets per second a source on probation may send to a port, above what a Source client
+/// sends on a 66-tick server with voice
+pub const DEFAULT_PROBATION_LIMIT: u32 = 100;
+
+/// What the rate limits count packets by, like `--hashlimit-mode`.
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HashlimitMode {
+    /// Every source gets a rate per protected port
+    SrcipDstport,
+    /// Every source gets one rate for all protected ports
+    Srcip,
+}
+
+/// What programs the kernel, the rules of every chain are written in its syntax.
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+    /// iptables and ip6tables chains in the table of the [`Hook`], ipsets for the sets
+    Iptables,
+    /// Native nftables tables holding both, see [`crate::nftables`]
+    Nftables,
+    /// pf tables and an anchor of rules on FreeBSD, see [`crate::pf`]. Only part of the mortis
+    /// rules have a pf counterpart, see [`pf_plan`]
+    Pf,
+}
+
+/// Syntax the rules of the iptables and nftables plans are written in, pf plans are built by
+/// [`pf_plan`].
+#[derive(Clone, Copy)]
+enum Dialect {
+    Iptables,
+    Nftables,
+}
+
+/// Where the protected ports are hooked into.
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Hook {
+    /// The INPUT chain of the filter table, after conntrack has seen the packet
+    Input,
+    /// The PREROUTING chain of the raw table, floods are dropped before conntrack creates an
+    /// entry for every packet
+    Raw,
+    /// The FORWARD chain of the filter table, for servers in containers or VMs the host routes
+    /// to. The protected ports are those after DNAT, the ones the server listens on
+    Forward,
+    /// Docker's DOCKER-USER chain, which FORWARD jumps to ahead of the rules of Docker itself.
+    /// nftables has no such chain, its forward hook sees the same packets
+    DockerUser,
+}
+
+/// Every family gets its own chains and sets, laid out the same way.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+}
+
+/// Name of the ipset holding the `family` addresses of `set`. Every ipset is limited to one
+/// family, the IPv6 ones get a suffix.
+pub fn ipset_name(set: &str, family: Family) -> String {
+    match family {
+        Family::V4 => set.to_string(),
+        Family::V6 => format!("{}6", set),
+    }
+}
+
+/// Names of the kernel objects of one mortis instance. Every chain, set, hashlimit and nft table
+/// name gets the `--instance-name` appended, so several instances can share a host.
+#[derive(Clone, Default, Debug)]
+pub struct Names {
+    suffix: String,
+}
+
+impl Names {
+    pub fn new(instance: Option<&str>) -> Self {
+        Self {
+            suffix: instance
+                .map(|instance| format!("-{}", instance))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// What the object called `base` in a lone instance is called in this one.
+    pub fn of(&self, base: &str) -> String {
+        format!("{}{}", base, self.suffix)
+    }
+
+    /// Whether the chain `name` could be one of this instance's. Without an `--instance-name`
+    /// those of named instances look the same.
+    pub fn owns(&self, name: &str) -> bool {
+        name.starts_with("mortis") && name.ends_with(self.suffix.as_str())
+    }
+
+    /// Whether `name` is one of the hashlimits of this instance, e.g. `mortis-a0-white`.
+    pub fn is_hashlimit(&self, name: &str) -> bool {
+        let Some(base) = name.strip_suffix(self.suffix.as_str()) else {
+            return false;
+        };
+        let parts: Vec<&str> = base.split('-').collect();
+        matches!(
+            parts.as_slice(),
+            [
+                "mortis",
+                _,
+                "new" | "white" | "bw" | "grace" | "unk" | "sub"
+            ]
         )
-        .as_str(),
-    )?;
-    ipt.append("filter", IPTABLES_CHAIN,  "--match hashlimit --hashlimit-above 5/sec --hashlimit-burst 10 --hashlimit-mode srcip,dstport --hashlimit-name mortis -j DROP")?;
-    ipt.append("filter", IPTABLES_CHAIN, "-j RETURN")?;
-    ipt.insert(
-        "filter",
-        "INPUT",
-        format!(
-            "-p udp --match multiport --dports {} -j {}",
-            protected_port, IPTABLES_CHAIN,
+    }
+}
+
+/// Rule fragments for the chains of one family.
+#[derive(Clone, Copy)]
+struct Syntax<'a> {
+    dialect: Dialect,
+    family: Family,
+    hook: Hook,
+    names: &'a Names,
+}
+
+impl Syntax<'_> {
+    /// Kernel name of the chain called `base` in a lone instance.
+    fn chain(self, base: &str) -> String {
+        self.names.of(base)
+    }
+
+    /// Chain the protected ports are hooked into, nftables names the base chain by its hook.
+    fn input(self) -> &'static str {
+        match (self.dialect, self.hook) {
+            (Dialect::Iptables, Hook::Input) => "INPUT",
+            (Dialect::Iptables, Hook::Raw) => "PREROUTING",
+            (Dialect::Iptables, Hook::Forward) => "FORWARD",
+            (Dialect::Iptables, Hook::DockerUser) => "DOCKER-USER",
+            (Dialect::Nftables, Hook::Input) => "input",
+            (Dialect::Nftables, Hook::Raw) => "prerouting",
+            (Dialect::Nftables, Hook::Forward | Hook::DockerUser) => "forward",
+        }
+    }
+
+    /// The filter chain conntrack has seen the packets of the hook by: the hooked chain itself,
+    /// or INPUT for raw.
+    fn conntracked(self) -> &'static str {
+        match (self.dialect, self.hook) {
+            (Dialect::Iptables, Hook::Raw) => "INPUT",
+            (Dialect::Nftables, Hook::Raw) => "input",
+            _ => self.input(),
+        }
+    }
+
+    /// Source address as nft calls it.
+    fn saddr(self) -> &'static str {
+        match self.family {
+            Family::V4 => "ip saddr",
+            Family::V6 => "ip6 saddr",
+        }
+    }
+
+    fn in_set(self, set: &str) -> String {
+        let set = self.names.of(set);
+        match self.dialect {
+            Dialect::Iptables => format!(
+                "--match set --match-set {} src",
+                ipset_name(&set, self.family)
+            ),
+            Dialect::Nftables => format!("{} @{}", self.saddr(), set),
+        }
+    }
+
+    /// Matches packets over `limit` per second from one source, after a burst of `burst`.
+    /// Sources are grouped by `--hashlimit-srcmask` for IPv4 and by their unit for IPv6, `name`
+    /// keeps the rates in the kernel.
+    fn above(self, options: &ChainOptions, limit: u32, burst: u32, name: &str) -> String {
+        self.over(
+            options,
+            Rate::Packets { limit, burst },
+            self.srcmask(options),
+            name,
         )
-        .as_str(),
-        1,
-    )?;
+    }
+
+    /// Like [`Syntax::above`] for traffic over `kbytes` kilobytes per second, a second of it
+    /// may come at once.
+    fn above_bandwidth(self, options: &ChainOptions, kbytes: u32, name: &str) -> String {
+        self.over(options, Rate::Kbytes(kbytes), self.srcmask(options), name)
+    }
+
+    /// Like [`Syntax::above`] for all sources of a `--subnet-srcmask` network together.
+    fn above_subnet(self, options: &ChainOptions, limit: u32, burst: u32, name: &str) -> String {
+        let srcmask = match self.family {
+            Family::V4 => options.subnet_srcmask,
+            Family::V6 => options.subnet_srcmask6,
+        };
+        self.over(options, Rate::Packets { limit, burst }, srcmask, name)
+    }
+
+    fn srcmask(self, options: &ChainOptions) -> u8 {
+        match self.family {
+            Family::V4 => options.srcmask,
+            Family::V6 => options.ipv6_prefix,
+        }
+    }
+
+    fn over(self, options: &ChainOptions, rate: Rate, srcmask: u8, name: &str) -> String {
+        let name = self.names.of(name);
+        match self.dialect {
+            Dialect::Iptables => format!(
+                "--match hashlimit --hashlimit-above {} --hashlimit-burst {} --hashlimit-mode {} --hashlimit-srcmask {} --hashlimit-name {}",
+                match rate {
+                    Rate::Packets { limit, .. } => format!("{}/sec", limit),
+                    Rate::Kbytes(kbytes) => format!("{}kb/s", kbytes),
+                },
+                match rate {
+                    Rate::Packets { burst, .. } => burst.to_string(),
+                    Rate::Kbytes(kbytes) => format!("{}kb", kbytes),
+                },
+                match options.hashlimit_mode {
+                    HashlimitMode::SrcipDstport => "srcip,dstport",
+                    HashlimitMode::Srcip => "srcip",
+                },
+                srcmask,
+                name
+            ),
+            Dialect::Nftables => {
+                let source = match self.family {
+                    Family::V4 if srcmask < 32 => format!(
+                        "{} & {}",
+                        self.saddr(),
+                        Ipv4Addr::from(u32::MAX << (32 - srcmask))
+                    ),
+                    Family::V6 if srcmask < 128 => format!(
+                        "{} & {}",
+                        self.saddr(),
+                        Ipv6Addr::from(u128::MAX << (128 - srcmask))
+                    ),
+                    _ => self.saddr().to_string(),
+                };
+                let key = match options.hashlimit_mode {
+                    HashlimitMode::SrcipDstport => format!("{} . th dport", source),
+                    HashlimitMode::Srcip => source,
+                };
+                let limit = match rate {
+                    Rate::Packets { limit, burst } => {
+                        format!("{}/second burst {} packets", limit, burst)
+                    }
+                    Rate::Kbytes(kbytes) => {
+                        format!("{} kbytes/second burst {} kbytes", kbytes, kbytes)
+                    }
+                };
+                format!(
+                    "meter {} {{ {} timeout 10s limit rate over {} }}",
+                    name, key, limit
+                )
+            }
+        }
+    }
+
+    /// Matches up to `rate` packets per second in total.
+    fn rate(self, rate: u32) -> String {
+        match self.dialect {
+            Dialect::Iptables => {
+                format!("--match limit --limit {}/sec --limit-burst {}", rate, rate)
+            }
+            Dialect::Nftables => format!("limit rate {}/second burst {} packets", rate, rate),
+        }
+    }
+
+    /// Restarts the timeout of the source in `set`.
+    fn refresh(self, set: &str) -> String {
+        let set = self.names.of(set);
+        match self.dialect {
+            Dialect::Iptables => format!(
+                "-j SET --add-set {} src --exist",
+                ipset_name(&set, self.family)
+            ),
+            Dialect::Nftables => format!("update @{} {{ {} }}", set, self.saddr()),
+        }
+    }
+
+    fn nflog(self, group: u16, prefix: &str) -> String {
+        match self.dialect {
+            Dialect::Iptables => {
+                format!("-j NFLOG --nflog-group {} --nflog-prefix {}", group, prefix)
+            }
+            Dialect::Nftables => format!("log group {} prefix \"{}\"", group, prefix),
+        }
+    }
+
+    /// Sends a copy of the packet to `gateway`, which has to be on a directly connected network.
+    /// The packet itself goes on.
+    fn tee(self, gateway: IpAddr) -> String {
+        match self.dialect {
+            Dialect::Iptables => format!("-j TEE --gateway {}", gateway),
+            Dialect::Nftables => format!("dup to {}", gateway),
+        }
+    }
+
+    /// `chain` is the name in a lone instance, like for every mortis chain.
+    fn jump(self, chain: &str) -> String {
+        match self.dialect {
+            Dialect::Iptables => format!("-j {}", self.chain(chain)),
+            Dialect::Nftables => format!("jump {}", self.chain(chain)),
+        }
+    }
+
+    fn goto(self, chain: &str) -> String {
+        match self.dialect {
+            Dialect::Iptables => format!("-g {}", self.chain(chain)),
+            Dialect::Nftables => format!("goto {}", self.chain(chain)),
+        }
+    }
+
+    fn drop(self) -> &'static str {
+        match self.dialect {
+            Dialect::Iptables => "-j DROP",
+            Dialect::Nftables => "drop",
+        }
+    }
+
+    fn ret(self) -> &'static str {
+        match self.dialect {
+            Dialect::Iptables => "-j RETURN",
+            Dialect::Nftables => "return",
+        }
+    }
+
+    /// A rule of `matches` and `target` counted as `counted`. nft only counts rules with a
+    /// counter, and wants the comment last.
+    fn counted(self, matches: &str, target: &str, counted: Counted) -> String {
+        let rule = match self.dialect {
+            Dialect::Iptables => format!(
+                "{} --match comment --comment {} {}",
+                matches,
+                counted.comment(),
+                target
+            ),
+            Dialect::Nftables => format!(
+                "{} counter {} comment \"{}\"",
+                matches,
+                target,
+                counted.comment()
+            ),
+        };
+        rule.trim_start().to_string()
+    }
+}
+
+/// What the packets of a rule whose counters are read are, see [`Firewall::counters`]. The rules
+/// carry it in their comment.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub enum Counted {
+    /// Dropped for coming from an amplification port
+    Amplification,
+    /// Whitelisted sources dropped over their limit
+    WhitelistLimit,
+    /// Whitelisted sources let through
+    Whitelisted,
+    /// Unknown sources dropped over their limit
+    Unknown,
+}
+
+impl Counted {
+    pub const ALL: [Counted; 4] = [
+        Counted::Amplification,
+        Counted::WhitelistLimit,
+        Counted::Whitelisted,
+        Counted::Unknown,
+    ];
 
-    Ok(ipt)
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Counted::Amplification => "amplification",
+            Counted::WhitelistLimit => "whitelist_limit",
+            Counted::Whitelisted => "whitelisted",
+            Counted::Unknown => "unknown",
+        }
+    }
+
+    fn comment(self) -> String {
+        format!("{}{}", COUNTED_PREFIX, self.as_str())
+    }
+
+    /// What a rule with `comment` counts, `None` for rules that don't. iptables lists comments
+    /// in quotes.
+    pub fn from_comment(comment: &str) -> Option<Self> {
+        let name = comment.trim_matches('"').strip_prefix(COUNTED_PREFIX)?;
+        Self::ALL
+            .into_iter()
+            .find(|counted| counted.as_str() == name)
+    }
+}
+
+/// Packets and bytes a [`Counted`] rule matched since its chain was created.
+pub struct RuleCounter {
+    pub chain: String,
+    pub counted: Counted,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// What a rate limit counts, see [`Syntax::above`].
+#[derive(Clone, Copy)]
+enum Rate {
+    Packets { limit: u32, burst: u32 },
+    Kbytes(u32),
+}
+
+/// Everything that shapes the contents of the mortis chains.
+#[derive(Clone)]
+pub struct ChainOptions {
+    /// Every ruleset gets a pre-built chain, the first is [`DEFAULT_RULESET`]
+    pub rulesets: Vec<RulesetOptions>,
+    /// Ruleset the mortis chain goes to at startup
+    pub initial: String,
+    /// Packets per second allowed from sources in the grace set, `None` when it's disabled
+    pub grace_limit: Option<u32>,
+    /// NFLOG group to report would-be drops to instead of dropping, see `--monitor-only`
+    pub monitor_group: Option<u16>,
+    /// Dropped packets logged, see `--drop-sample-rate`
+    pub drop_sampling: Option<DropSampling>,
+    /// Dropped packets copied to an analysis host, see `--mirror-to`
+    pub mirror: Option<Mirror>,
+    /// UDP source ports dropped from everyone outside the allow set, `None` to keep them
+    pub amplification_ports: Option<Ports>,
+    /// UDP ports that only take Source engine connectionless packets, see `--a2s-only-ports`
+    pub a2s_ports: Option<Ports>,
+    /// UDP packets to the protected ports conntrack can't make sense of are dropped
+    pub drop_invalid: bool,
+    /// Fragments are dropped before they reach the protected ports, see [`fragment_hook`]
+    pub drop_fragments: bool,
+    pub hashlimit_mode: HashlimitMode,
+    /// Prefix length IPv4 sources are rate limited by
+    pub srcmask: u8,
+    /// Prefix length IPv6 sources are rate limited by, the `--ipv6-prefix` of units
+    pub ipv6_prefix: u8,
+    /// Prefix lengths of the networks of the subnet limit, IPv4 and IPv6
+    pub subnet_srcmask: u8,
+    pub subnet_srcmask6: u8,
+    pub hook: Hook,
+    pub names: Names,
+    /// Handshakes to the protected TCP ports are answered by SYNPROXY, see `--synproxy`
+    pub synproxy: Option<Synproxy>,
+}
+
+/// How many dropped packets go to which NFLOG group.
+#[derive(Clone, Copy)]
+pub struct DropSampling {
+    /// Packets per second, for every reason on its own
+    pub rate: u32,
+    pub group: u16,
+}
+
+/// Where and how many dropped packets are copied to.
+#[derive(Clone)]
+pub struct Mirror {
+    /// Packets per second, for every reason on its own
+    pub rate: u32,
+    /// At most one of each family, the chains of a family without one don't mirror
+    pub gateways: Vec<IpAddr>,
+}
+
+impl Mirror {
+    fn gateway(&self, family: Family) -> Option<IpAddr> {
+        self.gateways
+            .iter()
+            .copied()
+            .find(|gateway| Family::of(*gateway) == family)
+    }
+}
+
+/// TCP options SYNPROXY announces to clients in place of the server.
+#[derive(Clone, Copy)]
+pub struct Synproxy {
+    /// For IPv4, IPv6 headers take 20 bytes more
+    pub mss: u16,
+    pub wscale: u8,
+}
+
+#[derive(Clone)]
+pub struct RulesetOptions {
+    pub name: String,
+    pub whitelist_limit: u32,
+    /// Kilobytes per second a whitelisted source may send to a port, `None` for no cap
+    pub whitelist_bandwidth: Option<u32>,
+    /// 0 drops every source that isn't whitelisted
+    pub unknown_limit: u32,
+    /// Packets per second of the unknown sources of one network together, 0 for no limit
+    pub unknown_subnet_limit: u32,
+    pub burst: u32,
+    pub probation_limit: Option<u32>,
+    pub extra_rules: Vec<ExtraRule>,
+    /// Ports with limits of their own, the first policy listing a port wins
+    pub port_policies: Vec<PortPolicyOptions>,
+}
+
+/// Limits of a group of protected UDP ports that differ from those of their ruleset, which
+/// otherwise applies to them as it is.
+#[derive(Clone)]
+pub struct PortPolicyOptions {
+    pub ports: Ports,
+    pub whitelist_limit: u32,
+    pub whitelist_bandwidth: Option<u32>,
+    pub unknown_limit: u32,
+    pub unknown_subnet_limit: u32,
+    pub burst: u32,
+    pub probation_limit: Option<u32>,
+}
+
+impl RulesetOptions {
+    /// The policies of `config` on top of this ruleset, unset limits are its own.
+    fn with_policies(mut self, config: &Config) -> Self {
+        self.port_policies = config
+            .port_policies
+            .iter()
+            .map(|policy| PortPolicyOptions {
+                ports: policy.ports.clone(),
+                whitelist_limit: policy.whitelist_limit.unwrap_or(self.whitelist_limit),
+                whitelist_bandwidth: policy.whitelist_bandwidth.or(self.whitelist_bandwidth),
+                unknown_limit: policy.unknown_limit.unwrap_or(self.unknown_limit),
+                unknown_subnet_limit: policy
+                    .unknown_subnet_limit
+                    .unwrap_or(self.unknown_subnet_limit),
+                burst: policy.burst.unwrap_or(self.burst),
+                probation_limit: self
+                    .probation_limit
+                    .map(|limit| policy.probation_limit.unwrap_or(limit)),
+            })
+            .collect();
+        self
+    }
+
+    /// The ruleset as it applies to the ports of `policy`.
+    fn for_policy(&self, policy: &PortPolicyOptions) -> Self {
+        Self {
+            whitelist_limit: policy.whitelist_limit,
+            whitelist_bandwidth: policy.whitelist_bandwidth,
+            unknown_limit: policy.unknown_limit,
+            unknown_subnet_limit: policy.unknown_subnet_limit,
+            burst: policy.burst,
+            probation_limit: policy.probation_limit,
+            port_policies: Vec::new(),
+            ..self.clone()
+        }
+    }
+}
+
+impl ChainOptions {
+    /// `probation` is whether the probation set exists.
+    pub fn new(args: &Args, config: &Config, probation: bool) -> Self {
+        let probation_limit = probation.then_some(args.probation_limit);
+        let mut rulesets = vec![
+            RulesetOptions {
+                name: DEFAULT_RULESET.to_string(),
+                whitelist_limit: args.whitelist_limit,
+                whitelist_bandwidth: args.whitelist_bandwidth,
+                unknown_limit: args.unknown_limit,
+                unknown_subnet_limit: args.unknown_subnet_limit,
+                burst: args.hashlimit_burst,
+                probation_limit,
+                extra_rules: config.extra_rules.clone(),
+                port_policies: Vec::new(),
+            }
+            .with_policies(config),
+        ];
+        rulesets.extend(config.rulesets.iter().map(|(name, ruleset)| {
+            RulesetOptions {
+                name: name.clone(),
+                whitelist_limit: ruleset.whitelist_limit.unwrap_or(args.whitelist_limit),
+                whitelist_bandwidth: ruleset.whitelist_bandwidth.or(args.whitelist_bandwidth),
+                unknown_limit: ruleset.unknown_limit.unwrap_or(args.unknown_limit),
+                unknown_subnet_limit: ruleset
+                    .unknown_subnet_limit
+                    .unwrap_or(args.unknown_subnet_limit),
+                burst: ruleset.burst.unwrap_or(args.hashlimit_burst),
+                probation_limit: probation_limit
+                    .map(|limit| ruleset.probation_limit.unwrap_or(limit)),
+                extra_rules: ruleset.extra_rules.clone(),
+                port_policies: Vec::new(),
+            }
+            .with_policies(config)
+        }));
+
+        Self {
+            rulesets,
+            initial: config
+                .ruleset
+                .clone()
+                .unwrap_or_else(|| DEFAULT_RULESET.to_string()),
+            grace_limit: (args.grace_period > 0).then_some(args.grace_limit),
+            monitor_group: args.monitor_only.then_some(args.monitor_nflog_group),
+            drop_sampling: (args.drop_sample_rate > 0).then_some(DropSampling {
+                rate: args.drop_sample_rate,
+                group: args.drop_nflog_group,
+            }),
+            mirror: (!args.mirror_to.is_empty()).then(|| Mirror {
+                rate: args.mirror_rate,
+                gateways: args.mirror_to.iter().map(|ip| ip.to_canonical()).collect(),
+            }),
+            amplification_ports: Some(args.amplification_ports.clone())
+                .filter(|ports| !ports.is_empty()),
+            a2s_ports: Some(args.a2s_only_ports.clone()).filter(|ports| !ports.is_empty()),
+            drop_invalid: args.drop_invalid,
+            drop_fragments: args.drop_fragments,
+            hashlimit_mode: args.hashlimit_mode,
+            srcmask: args.hashlimit_srcmask,
+            ipv6_prefix: args.ipv6_prefix,
+            subnet_srcmask: args.subnet_srcmask,
+            subnet_srcmask6: args.subnet_srcmask6,
+            hook: args.hook,
+            names: Names::new(args.instance_name.as_deref()),
+            synproxy: args.synproxy.then_some(Synproxy {
+                mss: args.synproxy_mss,
+                wscale: args.synproxy_wscale,
+            }),
+        }
+    }
 }
 
-pub fn clean_iptables(ipt: &IPTables, protected_port: &str) -> Result<(), Box<dyn Error>> {
-    ipt.delete(
-        "filter",
-        "INPUT",
+/// Target for packets mortis rejects, `chain` is where monitor-only mode sends them.
+fn drop_target(syntax: Syntax, options: &ChainOptions, chain: &str) -> String {
+    match options.monitor_group {
+        Some(_) => syntax.goto(chain),
+        None if has_drop_chains(options) => syntax.goto(drop_chain(chain)),
+        None => syntax.drop().to_string(),
+    }
+}
+
+/// Whether dropped packets go through the [`DROP_CHAINS`] first.
+fn has_drop_chains(options: &ChainOptions) -> bool {
+    options.drop_sampling.is_some() || options.mirror.is_some()
+}
+
+/// The drop chain next to the monitor chain `chain`.
+fn drop_chain(chain: &str) -> &'static str {
+    DROP_CHAINS
+        .iter()
+        .find(|(monitor, _)| *monitor == chain)
+        .map(|(_, drop)| *drop)
+        .expect("every monitor chain has a drop chain")
+}
+
+/// Ruleset chains come in two slots, a reload builds the idle one while the live one keeps
+/// filtering.
+#[derive(Clone, Copy)]
+enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    fn id(self) -> char {
+        match self {
+            Slot::A => 'a',
+            Slot::B => 'b',
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn chain(self, ruleset: &str) -> String {
+        format!("mortis-{}-{}", self.id(), ruleset)
+    }
+}
+
+/// Kernel side of the firewall. [`Firewall`] decides what the chains hold, the backend creates
+/// the sets and gets the chains into the kernel.
+pub trait FirewallBackend: Send {
+    /// Syntax the rules of the plans have to be written in.
+    fn syntax(&self) -> Backend;
+
+    /// Families chains and sets are set up for, IPv4 always comes first.
+    fn families(&self) -> Vec<Family>;
+
+    fn setup_set(&mut self, options: &SetOptions) -> Result<Box<dyn AddressSet>>;
+
+    /// Make the `family` chains match `plan`. A failed apply may leave part of the plan
+    /// applied, applying again picks up where it stopped.
+    fn apply(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>>;
+
+    /// What the last applied `family` plan has that the kernel doesn't, e.g. after someone
+    /// flushed the ruleset. Each entry names a chain, or a rule as `chain: rule`.
+    fn verify(&self, family: Family) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Apply `plan` again as if for the first time, for when [`FirewallBackend::verify`] found
+    /// parts of it gone from the kernel.
+    fn reinstall(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>>;
+
+    /// Counters of the [`Counted`] rules in the `family` chains.
+    fn counters(&self, family: Family) -> Result<Vec<RuleCounter>, Box<dyn Error>>;
+
+    /// Remove every chain and rule mortis added.
+    fn teardown(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Remove whatever chains, rules going to them, and sets of `names` a previous run left in
+    /// the kernel, for `--flush-on-start`. Only called before any set is set up.
+    fn purge(&mut self, names: &Names) -> Result<(), Box<dyn Error>>;
+}
+
+/// Run `f`, which waits for a child process, without holding up the other tasks of the runtime
+/// worker it is called on. Sets of the nftables and pf backends are changed by running `nft`
+/// and `pfctl`, from request handlers as well.
+pub fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) if runtime.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+/// A set of source addresses the mortis rules match, one kernel set per family. Each set is
+/// locked on its own, so adding to one doesn't wait for a reload or for the others.
+pub trait AddressSet: Send {
+    /// Goes into the set of the family of `ip`. Adding an entry again restarts its timeout.
+    fn add_ip(&mut self, ip: IpAddr) -> Result<()>;
+
+    /// Like [`AddressSet::add_ip`] with a timeout of its own, 0 keeps the entry until it is
+    /// removed. Only for sets created with a timeout.
+    fn add_ip_for(&mut self, ip: IpAddr, timeout: u32) -> Result<()>;
+
+    /// Fails for addresses that aren't in the set.
+    fn del_ip(&mut self, ip: IpAddr) -> Result<()>;
+
+    /// Like [`AddressSet::add_ip`] for a whole network, only sets created with
+    /// [`SetOptions::nets`] take more than single addresses.
+    fn add_net(&mut self, net: Cidr) -> Result<()> {
+        match net.is_host() {
+            true => self.add_ip(net.addr()),
+            false => Err(anyhow!(
+                "{} is a network, the set only holds addresses",
+                net
+            )),
+        }
+    }
+
+    fn del_net(&mut self, net: Cidr) -> Result<()> {
+        match net.is_host() {
+            true => self.del_ip(net.addr()),
+            false => Err(anyhow!(
+                "{} is a network, the set only holds addresses",
+                net
+            )),
+        }
+    }
+
+    /// Create the kernel set again if it is gone, e.g. destroyed by another tool. Returns
+    /// whether it was, its entries are lost then.
+    fn ensure(&mut self) -> Result<bool>;
+
+    /// Like [`AddressSet::add_ip_for`], or [`AddressSet::add_ip`] without a `timeout`, with a
+    /// comment on the entry in sets created with [`SetOptions::comments`]. Other sets drop it.
+    fn add_commented(&mut self, ip: IpAddr, timeout: Option<u32>, _comment: &str) -> Result<()> {
+        match timeout {
+            Some(timeout) => self.add_ip_for(ip, timeout),
+            None => self.add_ip(ip),
+        }
+    }
+
+    /// Replace all entries with `nets` at once, packets see either the old entries or the new
+    /// ones and never a partially filled set. `timeout` is that of [`AddressSet::add_commented`].
+    fn replace(&mut self, nets: &[Cidr], timeout: Option<u32>) -> Result<()>;
+
+    /// Entries in the kernel. IPv6 entries of unit sets are the first address of their network.
+    fn list(&mut self) -> Result<Vec<Listed>>;
+
+    fn teardown(&mut self) -> Result<()>;
+}
+
+/// An entry of [`AddressSet::list`].
+pub struct Listed {
+    pub ip: IpAddr,
+    /// Seconds until the kernel removes it, 0 for permanent entries
+    pub timeout: u32,
+    pub comment: Option<String>,
+}
+
+/// The kernel refused an add because the named set holds its most entries already.
+#[derive(Debug)]
+pub struct SetFull(pub String);
+
+impl fmt::Display for SetFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "set {} is full", self.0)
+    }
+}
+
+impl Error for SetFull {}
+
+#[derive(Clone)]
+pub struct SetOptions {
+    /// Name of the set in the kernel, see [`Names`]
+    pub name: String,
+    /// Seconds after which the kernel removes an entry, restarted when it is added again
+    pub timeout: Option<u32>,
+    /// Replace a random entry when the set is full instead of failing
+    pub forceadd: bool,
+    /// Entries are client units, see [`crate::client::unit`], so an IPv6 entry covers its
+    /// whole `--ipv6-prefix` network
+    pub units: bool,
+    /// Entries can be networks of any size, see [`AddressSet::add_net`]
+    pub nets: bool,
+    /// Most entries per family, the kernel default when `None`
+    pub maxelem: Option<u32>,
+    /// Initial hash size of an ipset, nftables sizes its sets itself
+    pub hashsize: Option<u32>,
+    /// Entries carry a comment, see [`AddressSet::add_commented`]
+    pub comments: bool,
+}
+
+/// The backend picked with `--backend`, IPv6 is only filtered without `--no-ipv6`. With
+/// `--adopt` the sets a previous run left behind are reused with their entries, instead of
+/// failing to create them (iptables) or emptying them (nftables). `--firewalld` hands the iptables
+/// chains to firewalld, see [`crate::firewalld`]; recent firewalld versions leave the nftables
+/// tables of others alone.
+pub fn backend(args: &Args, names: &Names) -> Result<Box<dyn FirewallBackend>, Box<dyn Error>> {
+    let ipv6_prefix = (!args.no_ipv6).then_some(args.ipv6_prefix);
+    Ok(match args.backend {
+        Backend::Iptables if args.firewalld => Box::new(firewalld::Firewalld::new(
+            ipv6_prefix,
+            args.hook,
+            args.adopt,
+        )?),
+        Backend::Nftables | Backend::Pf if args.firewalld => {
+            return Err("--firewalld works with the iptables backend only".into());
+        }
+        Backend::Pf if args.monitor_only || args.synproxy => {
+            return Err("--monitor-only and --synproxy don't work with the pf backend".into());
+        }
+        Backend::Iptables => Box::new(Iptables::new(
+            ipv6_prefix,
+            args.hook,
+            args.adopt,
+            args.iptables_variant,
+            args.hook_position,
+        )?),
+        Backend::Nftables => Box::new(nftables::Nftables::new(
+            ipv6_prefix,
+            names.of(nftables::TABLE),
+            args.adopt,
+        )),
+        Backend::Pf => Box::new(pf::Pf::new(ipv6_prefix, names.of(pf::ANCHOR), args.adopt)),
+    })
+}
+
+/// Whitelisted sources, the kernel expires them [`ENTRY_TTL`] after they were last added.
+pub fn setup_ipset(
+    backend: &mut dyn FirewallBackend,
+    names: &Names,
+    args: &Args,
+) -> Result<Box<dyn AddressSet>> {
+    backend.setup_set(&SetOptions {
+        name: names.of(MORTIS_IPSET),
+        timeout: Some(ENTRY_TTL.as_secs() as u32),
+        forceadd: true,
+        units: true,
+        nets: false,
+        maxelem: args.whitelist_maxelem,
+        hashsize: args.whitelist_hashsize,
+        comments: args.entry_comments,
+    })
+}
+
+/// Permanent set of sources that bypass all mortis rules, e.g. resolved `--allow-host`s and
+/// `--allow-net` networks.
+pub fn setup_allow_ipset(
+    backend: &mut dyn FirewallBackend,
+    names: &Names,
+) -> Result<Box<dyn AddressSet>> {
+    backend.setup_set(&SetOptions {
+        name: names.of(MORTIS_ALLOW_IPSET),
+        timeout: None,
+        forceadd: false,
+        units: false,
+        nets: true,
+        maxelem: None,
+        hashsize: None,
+        comments: false,
+    })
+}
+
+/// Banned sources, dropped whatever else they are but allowed, see [`crate::blacklist`]. Every
+/// entry is added with a timeout of its own, 0 for permanent bans.
+pub fn setup_blacklist_ipset(
+    backend: &mut dyn FirewallBackend,
+    names: &Names,
+) -> Result<Box<dyn AddressSet>> {
+    backend.setup_set(&SetOptions {
+        name: names.of(MORTIS_BLACKLIST_IPSET),
+        timeout: Some(ENTRY_TTL.as_secs() as u32),
+        forceadd: false,
+        units: true,
+        nets: false,
+        maxelem: None,
+        hashsize: None,
+        comments: false,
+    })
+}
+
+/// Newly admitted sources, the kernel drops them from the set once `period` seconds pass
+/// without them going over the probation limit.
+pub fn setup_probation_ipset(
+    backend: &mut dyn FirewallBackend,
+    names: &Names,
+    period: u32,
+) -> Result<Box<dyn AddressSet>> {
+    backend.setup_set(&SetOptions {
+        name: names.of(MORTIS_PROBATION_IPSET),
+        timeout: Some(period),
+        forceadd: true,
+        units: true,
+        nets: false,
+        maxelem: None,
+        hashsize: None,
+        comments: false,
+    })
+}
+
+/// Sources whose HTTP request is still being validated, so their first packets aren't held to
+/// the unknown limit. Entries time out after `period` seconds.
+pub fn setup_grace_ipset(
+    backend: &mut dyn FirewallBackend,
+    names: &Names,
+    period: u32,
+) -> Result<Box<dyn AddressSet>> {
+    backend.setup_set(&SetOptions {
+        name: names.of(MORTIS_GRACE_IPSET),
+        timeout: Some(period),
+        forceadd: true,
+        units: true,
+        nets: false,
+        maxelem: None,
+        hashsize: None,
+        comments: false,
+    })
+}
+
+/// Non-terminating rules at the top of the mortis chain.
+#[derive(Clone, PartialEq)]
+enum Tap {
+    Sampling { rate: u32, group: u16 },
+    Capture { rate: u32, group: u16 },
+    SportSampling { rate: u32, group: u16 },
+}
+
+impl Tap {
+    /// Only UDP is tapped, TCP connection attempts to `--protect-tcp` ports pass the mortis
+    /// chain as well.
+    fn rule(&self, syntax: Syntax) -> String {
+        let udp = match syntax.dialect {
+            Dialect::Iptables => "-p udp",
+            Dialect::Nftables => "meta l4proto udp",
+        };
+        match *self {
+            Tap::Sampling { rate, group } => {
+                format!(
+                    "{} {} {}",
+                    udp,
+                    syntax.rate(rate),
+                    syntax.nflog(group, "mortis-sample")
+                )
+            }
+            Tap::Capture { rate, group } => {
+                format!(
+                    "{} {} {}",
+                    udp,
+                    syntax.rate(rate),
+                    syntax.nflog(group, "mortis-capture")
+                )
+            }
+            Tap::SportSampling { rate, group } => format!(
+                "{} {} {} {}",
+                udp,
+                syntax.in_set(MORTIS_IPSET),
+                syntax.rate(rate),
+                syntax.nflog(group, "mortis-sport")
+            ),
+        }
+    }
+}
+
+/// What mortis wants in the kernel besides the sets, turned into a [`Plan`] per family on
+/// every change.
+#[derive(Clone)]
+struct Desired {
+    options: ChainOptions,
+    /// Slot the ruleset chains are built in
+    slot: Slot,
+    active: String,
+    /// Whether INPUT jumps into the mortis chain, i.e. the kill switch is off
+    armed: bool,
+    /// E.g. packet sampling, the latest first
+    taps: Vec<Tap>,
+}
+
+/// The mortis chains and how they are wired up. Every change goes through [`Firewall::update`],
+/// which applies the resulting plans and rolls back to the previous ones if that fails.
+pub struct Firewall {
+    kernel: Box<dyn FirewallBackend>,
+    protected_port: Ports,
+    /// TCP ports whose new connections go through the same rules, see `--protect-tcp`
+    protected_tcp_port: Option<Ports>,
+    desired: Desired,
+}
+
+impl Firewall {
+    pub fn setup(
+        kernel: Box<dyn FirewallBackend>,
+        protected_port: &Ports,
+        protected_tcp_port: Option<&Ports>,
+        options: &ChainOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut firewall = Self {
+            kernel,
+            protected_port: protected_port.clone(),
+            protected_tcp_port: protected_tcp_port.cloned(),
+            desired: Desired {
+                options: options.clone(),
+                slot: Slot::A,
+                active: options.initial.clone(),
+                armed: true,
+                taps: Vec::new(),
+            },
+        };
+        // A family applied before another one failed would otherwise stay behind
+        if let Err(e) = firewall.apply() {
+            if let Err(e) = firewall.kernel.teardown() {
+                tracing::error!("Failed to roll back firewall setup: {}", e);
+            }
+            return Err(e);
+        }
+
+        Ok(firewall)
+    }
+
+    fn plan(&self, family: Family) -> Plan {
+        let desired = &self.desired;
+        let dialect = match self.kernel.syntax() {
+            Backend::Iptables => Dialect::Iptables,
+            Backend::Nftables => Dialect::Nftables,
+            Backend::Pf => {
+                return pf_plan(
+                    family,
+                    &self.protected_port,
+                    self.protected_tcp_port.as_ref(),
+                    desired,
+                );
+            }
+        };
+        let syntax = Syntax {
+            dialect,
+            family,
+            hook: desired.options.hook,
+            names: &desired.options.names,
+        };
+        let mut chains = support_chains(syntax, &desired.options);
+        chains.extend(ruleset_chains(syntax, desired.slot, &desired.options));
+        let mut dispatch: Vec<String> = desired.taps.iter().map(|tap| tap.rule(syntax)).collect();
+        // Going to the ruleset chain makes its end return straight to the hooked chain
+        dispatch.push(syntax.goto(&desired.slot.chain(&desired.active)));
+        chains.push((syntax.chain(IPTABLES_CHAIN), dispatch));
+
+        let mut hooks = Vec::new();
+        if desired.armed {
+            if desired.options.drop_fragments {
+                hooks.push(fragment_hook(syntax));
+            }
+            if desired.options.drop_invalid {
+                hooks.extend(invalid_hooks(syntax, &self.protected_port));
+            }
+            let mut rules = jump_rules(syntax, &self.protected_port);
+            if let Some(port) = &self.protected_tcp_port {
+                rules.extend(tcp_jump_rules(syntax, port));
+            }
+            hooks.extend(
+                rules
+                    .into_iter()
+                    .map(|rule| (syntax.input().to_string(), rule)),
+            );
+            // After the jumps, so the SYNs are rate limited before SYNPROXY answers them
+            if let (Some(synproxy), Some(port)) =
+                (desired.options.synproxy, &self.protected_tcp_port)
+            {
+                hooks.extend(synproxy_hooks(syntax, port, synproxy));
+            }
+        }
+
+        Plan { chains, hooks }
+    }
+
+    fn apply(&mut self) -> Result<(), Box<dyn Error>> {
+        for family in self.kernel.families() {
+            let plan = self.plan(family);
+            if matches!(self.kernel.syntax(), Backend::Iptables)
+                && let Some((chain, _)) = plan
+                    .chains
+                    .iter()
+                    .find(|(chain, _)| chain.len() > IPTABLES_CHAIN_MAX)
+            {
+                return Err(format!(
+                    "Chain {} is longer than the {} characters iptables allows, use a shorter ruleset or instance name",
+                    chain, IPTABLES_CHAIN_MAX
+                )
+                .into());
+            }
+            self.kernel.apply(family, &plan)?;
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, change: impl FnOnce(&mut Desired)) -> Result<(), Box<dyn Error>> {
+        let previous = self.desired.clone();
+        change(&mut self.desired);
+
+        if let Err(e) = self.apply() {
+            self.desired = previous;
+            // Undo whatever part of the change made it into the kernel
+            if let Err(e) = self.apply() {
+                tracing::error!("Failed to roll back firewall change: {}", e);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Swap in freshly built ruleset chains without ever leaving the protected ports
+    /// unprotected: the replacements are built in the idle slot next to the live chains, and
+    /// the mortis chain only goes to them once they are complete. The active ruleset stays
+    /// selected if the new options still define it.
+    pub fn reload(&mut self, options: &ChainOptions) -> Result<(), Box<dyn Error>> {
+        self.update(|desired| {
+            if !options.rulesets.iter().any(|r| r.name == desired.active) {
+                desired.active = options.initial.clone();
+            }
+            desired.options = options.clone();
+            desired.slot = desired.slot.other();
+        })
+    }
+
+    pub fn ruleset_names(&self) -> Vec<String> {
+        self.desired
+            .options
+            .rulesets
+            .iter()
+            .map(|ruleset| ruleset.name.clone())
+            .collect()
+    }
+
+    pub fn active_ruleset(&self) -> &str {
+        &self.desired.active
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.desired.armed
+    }
+
+    /// See [`FirewallBackend::verify`], entries of the IPv6 chains start with `ipv6`.
+    pub fn missing(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut missing = Vec::new();
+        for family in self.kernel.families() {
+            let entries = self.kernel.verify(family)?;
+            missing.extend(entries.into_iter().map(|entry| match family {
+                Family::V4 => entry,
+                Family::V6 => format!("ipv6 {}", entry),
+            }));
+        }
+        Ok(missing)
+    }
+
+    /// Counters of the [`Counted`] rules of every family, see [`FirewallBackend::counters`].
+    pub fn counters(&self) -> Result<Vec<(Family, RuleCounter)>, Box<dyn Error>> {
+        let mut counters = Vec::new();
+        for family in self.kernel.families() {
+            let rules = self.kernel.counters(family)?;
+            counters.extend(rules.into_iter().map(|rule| (family, rule)));
+        }
+        Ok(counters)
+    }
+
+    /// Put back whatever [`Firewall::missing`] reports, returns what that was.
+    pub fn repair(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let missing = self.missing()?;
+        if !missing.is_empty() {
+            for family in self.kernel.families() {
+                let plan = self.plan(family);
+                self.kernel.reinstall(family, &plan)?;
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Point the mortis chain at another pre-built ruleset.
+    pub fn select_ruleset(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        if !self.desired.options.rulesets.iter().any(|r| r.name == name) {
+            return Err(format!("Unknown ruleset {}", name).into());
+        }
+        self.update(|desired| desired.active = name.to_string())
+    }
+
+    /// Remove the INPUT jump, so protected ports receive unfiltered traffic while the chains
+    /// and ipsets stay in place for [`Firewall::arm`].
+    pub fn disarm(&mut self) -> Result<(), Box<dyn Error>> {
+        self.update(|desired| desired.armed = false)
+    }
+
+    pub fn arm(&mut self) -> Result<(), Box<dyn Error>> {
+        self.update(|desired| desired.armed = true)
+    }
+
+    /// NFLOG is non-terminating, so sampled packets still go through the rest of the chain.
+    pub fn start_sampling(&mut self, rate: u32, group: u16) -> Result<(), Box<dyn Error>> {
+        self.add_tap(Tap::Sampling { rate, group })
+    }
+
+    pub fn stop_sampling(&mut self, rate: u32, group: u16) -> Result<(), Box<dyn Error>> {
+        self.remove_tap(Tap::Sampling { rate, group })
+    }
+
+    /// Copies whole packets for [`crate::capture`].
+    pub fn start_capture(&mut self, rate: u32, group: u16) -> Result<(), Box<dyn Error>> {
+        self.add_tap(Tap::Capture { rate, group })
+    }
+
+    pub fn stop_capture(&mut self, rate: u32, group: u16) -> Result<(), Box<dyn Error>> {
+        self.remove_tap(Tap::Capture { rate, group })
+    }
+
+    /// Samples whitelisted traffic for [`crate::entropy`] until shutdown.
+    pub fn start_sport_sampling(&mut self, rate: u32, group: u16) -> Result<(), Box<dyn Error>> {
+        self.add_tap(Tap::SportSampling { rate, group })
+    }
+
+    fn add_tap(&mut self, tap: Tap) -> Result<(), Box<dyn Error>> {
+        self.update(|desired| desired.taps.insert(0, tap))
+    }
+
+    fn remove_tap(&mut self, tap: Tap) -> Result<(), Box<dyn Error>> {
+        self.update(|desired| desired.taps.retain(|t| *t != tap))
+    }
+
+    /// Remove every chain and rule mortis added.
+    pub fn clean(&mut self) -> Result<(), Box<dyn Error>> {
+        self.kernel.teardown()
+    }
+}
+
+/// Matches for `protocol` packets with their `direction` port (`dport` or `sport`) in
+/// `ports`. iptables needs a rule per 15 ports, nft takes the whole list at once.
+fn port_matches(syntax: Syntax, protocol: &str, direction: &str, ports: &Ports) -> Vec<String> {
+    match syntax.dialect {
+        Dialect::Iptables => ports
+            .multiport()
+            .into_iter()
+            .map(|list| {
+                format!(
+                    "-p {} --match multiport --{}s {}",
+                    protocol, direction, list
+                )
+            })
+            .collect(),
+        Dialect::Nftables => vec![format!("{} {} {{ {} }}", protocol, direction, ports.nft())],
+    }
+}
+
+fn jump_rules(syntax: Syntax, protected_port: &Ports) -> Vec<String> {
+    port_matches(syntax, "udp", "dport", protected_port)
+        .into_iter()
+        .map(|ports| format!("{} {}", ports, syntax.jump(IPTABLES_CHAIN)))
+        .collect()
+}
+
+/// Only connection attempts are rate limited, established connections sending more than the
+/// game traffic limits must not be cut off. The rulesets hold every source to its limit in SYNs
+/// per second.
+fn tcp_jump_rules(syntax: Syntax, protected_port: &Ports) -> Vec<String> {
+    let syn = match syntax.dialect {
+        Dialect::Iptables => "--syn",
+        Dialect::Nftables => "tcp flags & (syn | ack) == syn",
+    };
+    port_matches(syntax, "tcp", "dport", protected_port)
+        .into_iter()
+        .map(|ports| format!("{} {} {}", ports, syn, syntax.jump(IPTABLES_CHAIN)))
+        .collect()
+}
+
+/// Drops fragments in the hooked chain, as (chain, rule). Only the first fragment of a datagram
+/// carries the UDP header, the others pass the port matches of the jumps and so every rate
+/// limit; the stack reassembles them into the datagram mortis never saw. Their port can't be
+/// told, so they are dropped whatever it is. That doesn't cost any Source engine traffic, which
+/// stays below the MTU. It is the IPv4 fragments but the first, which is enough to keep the
+/// datagram from being reassembled, and every IPv6 fragment. Packets conntrack reassembled
+/// before the hook aren't fragments anymore and go through the jumps like any other.
+fn fragment_hook(syntax: Syntax) -> (String, String) {
+    let rule = match (syntax.dialect, syntax.family) {
+        (Dialect::Iptables, Family::V4) => "-f -j DROP",
+        (Dialect::Iptables, Family::V6) => "--match frag -j DROP",
+        (Dialect::Nftables, Family::V4) => "ip frag-off & 0x1fff != 0 drop",
+        (Dialect::Nftables, Family::V6) => "exthdr frag exists drop",
+    };
+    (syntax.input().to_string(), rule.to_string())
+}
+
+/// Drops UDP packets to the protected ports that conntrack found INVALID, e.g. with a bad
+/// length or checksum, as (chain, rule). conntrack has only seen the packets by the filter
+/// table, the raw table would find every packet INVALID, so they go into INPUT with the raw hook.
+fn invalid_hooks(syntax: Syntax, protected_port: &Ports) -> Vec<(String, String)> {
+    let input = syntax.conntracked();
+    let invalid = match syntax.dialect {
+        Dialect::Iptables => "--match conntrack --ctstate INVALID -j DROP",
+        Dialect::Nftables => "ct state invalid drop",
+    };
+    port_matches(syntax, "udp", "dport", protected_port)
+        .into_iter()
+        .map(|ports| (input.to_string(), format!("{} {}", ports, invalid)))
+        .collect()
+}
+
+/// SYNPROXY for the protected TCP ports, as (chain, rule). SYNs skip conntrack, so a flood
+/// doesn't fill the table, and get answered with a cookie; only the ACK of a completed handshake,
+/// INVALID to conntrack, opens the connection to the server. Whatever else is INVALID is dropped.
+/// The notrack rule is in the raw table and the others in the filter chain of the hook, INPUT
+/// for the raw one.
+fn synproxy_hooks(
+    syntax: Syntax,
+    protected_port: &Ports,
+    synproxy: Synproxy,
+) -> Vec<(String, String)> {
+    let mss = match syntax.family {
+        Family::V4 => synproxy.mss,
+        Family::V6 => synproxy.mss.saturating_sub(20),
+    };
+    let prerouting = match syntax.dialect {
+        Dialect::Iptables => "PREROUTING",
+        Dialect::Nftables => "prerouting",
+    };
+    let input = syntax.conntracked();
+    let mut hooks = Vec::new();
+    for ports in port_matches(syntax, "tcp", "dport", protected_port) {
+        let (notrack, proxy, invalid) = match syntax.dialect {
+            Dialect::Iptables => (
+                format!("{} --syn -j CT --notrack", ports),
+                format!(
+                    "{} --match conntrack --ctstate INVALID,UNTRACKED -j SYNPROXY --sack-perm --timestamp --wscale {} --mss {}",
+                    ports, synproxy.wscale, mss
+                ),
+                format!("{} --match conntrack --ctstate INVALID -j DROP", ports),
+            ),
+            Dialect::Nftables => (
+                format!("{} tcp flags & (syn | ack) == syn notrack", ports),
+                format!(
+                    "{} ct state invalid,untracked synproxy mss {} wscale {} timestamp sack-perm",
+                    ports, mss, synproxy.wscale
+                ),
+                format!("{} ct state invalid drop", ports),
+            ),
+        };
+        hooks.push((prerouting.to_string(), notrack));
+        hooks.push((input.to_string(), proxy));
+        hooks.push((input.to_string(), invalid));
+    }
+    hooks
+}
+
+/// Monitor, drop and probation chains the rulesets jump to, only created for the options that
+/// use them.
+fn support_chains(syntax: Syntax, options: &ChainOptions) -> Vec<(String, Vec<String>)> {
+    let mut chains = Vec::new();
+    if let Some(group) = options.monitor_group {
+        for (chain, reason) in MONITOR_CHAINS {
+            chains.push((
+                syntax.chain(chain),
+                vec![monitor_rule(syntax, group, reason)],
+            ));
+        }
+    } else if has_drop_chains(options) {
+        for (chain, reason) in MONITOR_CHAINS {
+            let mut rules = drop_taps(syntax, options, reason);
+            rules.push(syntax.drop().to_string());
+            chains.push((syntax.chain(drop_chain(chain)), rules));
+        }
+    }
+    if options.rulesets.iter().any(|r| r.probation_limit.is_some()) {
+        chains.push((
+            syntax.chain(PROBATION_CHAIN),
+            probation_chain(syntax, options),
+        ));
+    }
+    chains
+}
+
+/// The chain of every ruleset in `slot`, each after the chains of its port policies.
+fn ruleset_chains(
+    syntax: Syntax,
+    slot: Slot,
+    options: &ChainOptions,
+) -> Vec<(String, Vec<String>)> {
+    let mut chains = Vec::new();
+    for (i, ruleset) in options.rulesets.iter().enumerate() {
+        // The kernel keeps the rate of the first rule using a hashlimit name, so every
+        // ruleset, policy and slot gets its own
+        let hashlimit = format!("mortis-{}{}", slot.id(), i);
+        let mut policies = Vec::new();
+        for (j, policy) in ruleset.port_policies.iter().enumerate() {
+            let chain = format!("mortis-{}{}-port{}", slot.id(), i, j + 1);
+            chains.push((
+                syntax.chain(&chain),
+                limit_rules(
+                    syntax,
+                    &ruleset.for_policy(policy),
+                    &format!("{}{}", hashlimit, j + 1),
+                    options,
+                ),
+            ));
+            policies.push((&policy.ports, chain));
+        }
+        chains.push((
+            syntax.chain(&slot.chain(&ruleset.name)),
+            ruleset_rules(syntax, ruleset, &policies, &hashlimit, options),
+        ));
+    }
+    chains
+}
+
+/// `policies` are the ports with chains of their own, with the name of the chain in a lone
+/// instance. Their packets go there once the allow set and the drops of every port are through.
+fn ruleset_rules(
+    syntax: Syntax,
+    ruleset: &RulesetOptions,
+    policies: &[(&Ports, String)],
+    hashlimit: &str,
+    options: &ChainOptions,
+) -> Vec<String> {
+    let extra_rules = &ruleset.extra_rules;
+    let mut rules = extra_rules_at(syntax.family, extra_rules, Position::Top);
+
+    rules.push(format!(
+        "{} {}",
+        syntax.in_set(MORTIS_ALLOW_IPSET),
+        syntax.ret()
+    ));
+    // Whitelisted or not, only the allow set goes first
+    rules.push(format!(
+        "{} {}",
+        syntax.in_set(MORTIS_BLACKLIST_IPSET),
+        drop_target(syntax, options, MONITOR_BLACKLIST_CHAIN)
+    ));
+    if let Some(ports) = &options.amplification_ports {
+        for amplification in port_matches(syntax, "udp", "sport", ports) {
+            rules.push(syntax.counted(
+                &amplification,
+                &drop_target(syntax, options, MONITOR_AMPLIFICATION_CHAIN),
+                Counted::Amplification,
+            ));
+        }
+    }
+    if let Some(ports) = &options.a2s_ports {
+        for a2s in port_matches(syntax, "udp", "dport", ports) {
+            for payload in not_connectionless(syntax) {
+                rules.push(format!(
+                    "{} {} {}",
+                    a2s,
+                    payload,
+                    drop_target(syntax, options, MONITOR_PAYLOAD_CHAIN)
+                ));
+            }
+        }
+    }
+    rules.extend(extra_rules_at(
+        syntax.family,
+        extra_rules,
+        Position::BeforeLimits,
+    ));
+    for (ports, chain) in policies {
+        for ports in port_matches(syntax, "udp", "dport", ports) {
+            rules.push(format!("{} {}", ports, syntax.goto(chain)));
+        }
+    }
+    rules.extend(limit_rules(syntax, ruleset, hashlimit, options));
+    rules
+}
+
+/// The rate limits of `ruleset` and its bottom extra rules, where the ruleset chains and those
+/// of its port policies end.
+fn limit_rules(
+    syntax: Syntax,
+    ruleset: &RulesetOptions,
+    hashlimit: &str,
+    options: &ChainOptions,
+) -> Vec<String> {
+    let extra_rules = &ruleset.extra_rules;
+    let mut rules = Vec::new();
+    if let Some(limit) = ruleset.probation_limit {
+        // Going to the probation chain makes its end return straight to INPUT in monitor-only mode
+        let jump = match options.monitor_group {
+            Some(_) => syntax.goto(PROBATION_CHAIN),
+            None => syntax.jump(PROBATION_CHAIN),
+        };
+        rules.push(format!(
+            "{} {} {}",
+            syntax.in_set(MORTIS_PROBATION_IPSET),
+            syntax.above(options, limit, ruleset.burst, &format!("{}-new", hashlimit)),
+            jump
+        ));
+    }
+    let whitelist_target = drop_target(syntax, options, MONITOR_WHITELIST_CHAIN);
+    rules.push(syntax.counted(
+        &format!(
+            "{} {}",
+            syntax.in_set(MORTIS_IPSET),
+            syntax.above(
+                options,
+                ruleset.whitelist_limit,
+                ruleset.burst,
+                &format!("{}-white", hashlimit)
+            )
+        ),
+        &whitelist_target,
+        Counted::WhitelistLimit,
+    ));
+    // Large packets don't add up to the packet rate, an attacker who got whitelisted could
+    // flood the server with them
+    if let Some(kbytes) = ruleset.whitelist_bandwidth {
+        rules.push(syntax.counted(
+            &format!(
+                "{} {}",
+                syntax.in_set(MORTIS_IPSET),
+                syntax.above_bandwidth(options, kbytes, &format!("{}-bw", hashlimit))
+            ),
+            &whitelist_target,
+            Counted::WhitelistLimit,
+        ));
+    }
+    rules.push(syntax.counted(
+        &syntax.in_set(MORTIS_IPSET),
+        syntax.ret(),
+        Counted::Whitelisted,
+    ));
+    let unknown_target = drop_target(syntax, options, MONITOR_UNKNOWN_CHAIN);
+    if let Some(limit) = options.grace_limit {
+        rules.push(format!(
+            "{} {} {}",
+            syntax.in_set(MORTIS_GRACE_IPSET),
+            syntax.above(
+                options,
+                limit,
+                ruleset.burst,
+                &format!("{}-grace", hashlimit)
+            ),
+            unknown_target
+        ));
+        rules.push(format!(
+            "{} {}",
+            syntax.in_set(MORTIS_GRACE_IPSET),
+            syntax.ret()
+        ));
+    }
+    // Sources within the per-source limit may still add up to a flood from their network
+    if ruleset.unknown_limit > 0 && ruleset.unknown_subnet_limit > 0 {
+        rules.push(syntax.counted(
+            &syntax.above_subnet(
+                options,
+                ruleset.unknown_subnet_limit,
+                ruleset.burst,
+                &format!("{}-sub", hashlimit),
+            ),
+            &unknown_target,
+            Counted::Unknown,
+        ));
+    }
+    let unknown = match ruleset.unknown_limit {
+        0 => String::new(),
+        limit => syntax.above(options, limit, ruleset.burst, &format!("{}-unk", hashlimit)),
+    };
+    rules.push(syntax.counted(&unknown, &unknown_target, Counted::Unknown));
+    rules.extend(extra_rules_at(syntax.family, extra_rules, Position::Bottom));
+    rules.push(syntax.ret().to_string());
+
+    rules
+}
+
+/// Extra rules live in the ruleset chains, so deleting them on shutdown removes them as well.
+fn extra_rules_at(family: Family, extra_rules: &[ExtraRule], position: Position) -> Vec<String> {
+    extra_rules
+        .iter()
+        .filter(|r| r.position == position)
+        .filter(|r| match (r.family, family) {
+            (RuleFamily::Both, _) => true,
+            (RuleFamily::Ipv4, family) => family == Family::V4,
+            (RuleFamily::Ipv6, family) => family == Family::V6,
+        })
+        .map(|r| r.rule.clone())
+        .collect()
+}
+
+/// Sources going over the probation limit restart their probation period before being dropped.
+fn probation_chain(syntax: Syntax, options: &ChainOptions) -> Vec<String> {
+    let mut rules = vec![syntax.refresh(MORTIS_PROBATION_IPSET)];
+    match options.monitor_group {
+        Some(group) => rules.push(monitor_rule(syntax, group, "probation_limit")),
+        None => {
+            rules.extend(drop_taps(syntax, options, "probation_limit"));
+            rules.push(syntax.drop().to_string());
+        }
+    }
+    rules
+}
+
+/// Matches for UDP packets whose payload doesn't start with the 0xFFFFFFFF header of Source
+/// engine connectionless packets, A2S queries and replies, challenges and connects. Any one of
+/// them matching is enough. u32 reads IPv6 packets at a fixed offset, those with extension
+/// headers match too; nft would let short packets pass the header check. The expressions hold
+/// no spaces, so they need no quoting in any backend.
+fn not_connectionless(syntax: Syntax) -> Vec<String> {
+    match (syntax.dialect, syntax.family) {
+        (Dialect::Iptables, Family::V4) => {
+            vec!["--match u32 ! --u32 0>>22&0x3C@8=0xFFFFFFFF".to_string()]
+        }
+        (Dialect::Iptables, Family::V6) => {
+            vec!["--match u32 ! --u32 48=0xFFFFFFFF".to_string()]
+        }
+        (Dialect::Nftables, _) => vec![
+            "udp length < 12".to_string(),
+            "@th,64,32 != 0xffffffff".to_string(),
+        ],
+    }
+}
+
+fn monitor_rule(syntax: Syntax, group: u16, reason: &str) -> String {
+    syntax.nflog(group, &format!("{}{}", MONITOR_PREFIX, reason))
+}
+
+/// Non-terminating rules ahead of the drop of packets dropped for `reason`.
+fn drop_taps(syntax: Syntax, options: &ChainOptions, reason: &str) -> Vec<String> {
+    let mut rules = Vec::new();
+    if let Some(sampling) = options.drop_sampling {
+        rules.push(drop_sample_rule(syntax, sampling, reason));
+    }
+    if let Some(mirror) = &options.mirror
+        && let Some(gateway) = mirror.gateway(syntax.family)
+    {
+        rules.push(format!(
+            "{} {}",
+            syntax.rate(mirror.rate),
+            syntax.tee(gateway)
+        ));
+    }
+    rules
+}
+
+fn drop_sample_rule(syntax: Syntax, sampling: DropSampling, reason: &str) -> String {
+    format!(
+        "{} {}",
+        syntax.rate(sampling.rate),
+        syntax.nflog(sampling.group, &format!("{}{}", DROP_PREFIX, reason))
+    )
+}
+
+/// The pf anchor of `family` as a plan of one chain, named like the mortis chain, and no hooks:
+/// pf.conf has to reference the anchor where the protected ports are filtered. pf stops at the
+/// first `quick` rule that matches, packets the anchor doesn't decide on are up to the rest of
+/// pf.conf. pf keeps no rates per source, so whitelisted sources aren't limited and the grace
+/// and unknown limits are for all their sources together, through `max-pkt-rate`. The allow set,
+/// blacklist, amplification drops and extra rules, in pf syntax, work as with the other backends;
+/// A2S payload checks, bandwidth, subnet and probation limits, taps and mirroring have no
+/// counterpart.
+fn pf_plan(
+    family: Family,
+    protected_port: &Ports,
+    protected_tcp_port: Option<&Ports>,
+    desired: &Desired,
+) -> Plan {
+    let options = &desired.options;
+    let chain = options.names.of(IPTABLES_CHAIN);
+    if !desired.armed {
+        return Plan {
+            chains: vec![(chain, Vec::new())],
+            hooks: Vec::new(),
+        };
+    }
+    let ruleset = options
+        .rulesets
+        .iter()
+        .find(|r| r.name == desired.active)
+        .unwrap_or(&options.rulesets[0]);
+    let af = match family {
+        Family::V4 => "inet",
+        Family::V6 => "inet6",
+    };
+    let from = |set: &str| format!("from <{}>", options.names.of(set));
+    let label = |counted: Counted| format!("label \"{}\"", counted.comment());
+    // Connection attempts keep state, so the rest of an admitted connection passes
+    let mut protocols = vec![("udp", protected_port, "no state")];
+    if let Some(ports) = protected_tcp_port {
+        protocols.push(("tcp", ports, "flags S/SA keep state"));
+    }
+    let rule = |action: &str, protocol: &str, from: &str, ports: &Ports, filter: &str| {
         format!(
-            "-p udp --match multiport --dports {} -j {}",
-            protected_port, IPTABLES_CHAIN
+            "{} in quick {} proto {} {} to any port {{ {} }} {}",
+            action,
+            af,
+            protocol,
+            from,
+            ports.pf(),
+            filter
         )
-        .as_str(),
-    )?;
-    ipt.flush_chain("filter", IPTABLES_CHAIN)?;
-    ipt.delete_chain("filter", IPTABLES_CHAIN)?;
-    Ok(())
+        .trim_end()
+        .to_string()
+    };
+    let limited = |limit: u32, state: &str| format!("{} max-pkt-rate {}/1", state, limit);
+
+    let extra_rules = &ruleset.extra_rules;
+    let mut rules = extra_rules_at(family, extra_rules, Position::Top);
+    for (protocol, ports, state) in &protocols {
+        rules.push(rule(
+            "pass",
+            protocol,
+            &from(MORTIS_ALLOW_IPSET),
+            ports,
+            state,
+        ));
+    }
+    for (protocol, ports, _) in &protocols {
+        rules.push(rule(
+            "block drop",
+            protocol,
+            &from(MORTIS_BLACKLIST_IPSET),
+            ports,
+            "",
+        ));
+    }
+    if let Some(amplification) = &options.amplification_ports {
+        rules.push(rule(
+            "block drop",
+            "udp",
+            &format!("from any port {{ {} }}", amplification.pf()),
+            protected_port,
+            &label(Counted::Amplification),
+        ));
+    }
+    rules.extend(extra_rules_at(family, extra_rules, Position::BeforeLimits));
+    for (protocol, ports, state) in &protocols {
+        rules.push(rule(
+            "pass",
+            protocol,
+            &from(MORTIS_IPSET),
+            ports,
+            &format!("{} {}", state, label(Counted::Whitelisted)),
+        ));
+    }
+    if let Some(limit) = options.grace_limit {
+        for (protocol, ports, state) in &protocols {
+            rules.push(rule(
+                "pass",
+                protocol,
+                &from(MORTIS_GRACE_IPSET),
+                ports,
+                &limited(limit, state),
+            ));
+        }
+    }
+    // A rule over its rate stops matching, the drop right after it keeps the packets from
+    // reaching the limit of the ruleset
+    for policy in &ruleset.port_policies {
+        if policy.unknown_limit > 0 {
+            rules.push(rule(
+                "pass",
+                "udp",
+                "from any",
+                &policy.ports,
+                &limited(policy.unknown_limit, "no state"),
+            ));
+        }
+        rules.push(rule(
+            "block drop",
+            "udp",
+            "from any",
+            &policy.ports,
+            &label(Counted::Unknown),
+        ));
+    }
+    if ruleset.unknown_limit > 0 {
+        for (protocol, ports, state) in &protocols {
+            rules.push(rule(
+                "pass",
+                protocol,
+                "from any",
+                ports,
+                &limited(ruleset.unknown_limit, state),
+            ));
+        }
+    }
+    rules.extend(extra_rules_at(family, extra_rules, Position::Bottom));
+    for (protocol, ports, _) in &protocols {
+        rules.push(rule(
+            "block drop",
+            protocol,
+            "from any",
+            ports,
+            &label(Counted::Unknown),
+        ));
+    }
+
+    Plan {
+        chains: vec![(chain, rules)],
+        hooks: Vec::new(),
+    }
 }
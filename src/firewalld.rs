@@ -0,0 +1,417 @@
+//! firewalld integration, `--firewalld` with the iptables backend. firewalld owns the ruleset
+//! and drops every rule it didn't add itself when it reloads, so the chains go in through its
+//! direct interface with `firewall-cmd`, its D-Bus client, instead of `iptables-restore`. Rich
+//! rules can't express the chains, and there is no D-Bus library to talk to firewalld directly.
+//! The sets are plain ipsets as with the iptables backend, firewalld leaves sets it doesn't
+//! manage alone. Its own ipsets would take a `firewall-cmd` run for every admission. The rules are runtime only: permanent ones would outlive mortis and break the next
+//! start of firewalld on the sets that are gone by then. A reload still removes them, the
+//! watchdog finds them missing and adds them again.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    process::Command,
+};
+
+use anyhow::Result;
+
+use crate::{
+    engine::{self, Plan},
+    firewall::{
+        AddressSet, Backend, Family, FirewallBackend, Hook, Names, RuleCounter, SetOptions,
+    },
+};
+
+/// Priority of the first rule of every other rewrite of a chain, see [`Direct::apply`].
+const BAND: usize = 1000;
+
+fn firewall_cmd(args: &[&str]) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("firewall-cmd")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run firewall-cmd: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "firewall-cmd {} exited with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The direct chains and rules of one family. firewalld orders the rules of a chain by their
+/// priority, adding one it has already or removing one it hasn't only warns.
+struct Direct {
+    /// `ipv4` or `ipv6`
+    ipv: &'static str,
+    table: &'static str,
+    applied: Plan,
+    /// Priority of the first rule of each applied chain, 0 or [`BAND`]
+    offsets: BTreeMap<String, usize>,
+}
+
+impl Direct {
+    fn new(ipv: &'static str, table: &'static str) -> Self {
+        Self {
+            ipv,
+            table,
+            applied: Plan::default(),
+            offsets: BTreeMap::new(),
+        }
+    }
+
+    /// Run `firewall-cmd --direct action` on `chain`, with the priority and arguments of a rule
+    /// for the rule actions.
+    fn direct(
+        &self,
+        action: &str,
+        chain: &str,
+        rule: Option<(usize, &str)>,
+    ) -> Result<(), Box<dyn Error>> {
+        let priority;
+        let mut args = vec!["--direct", action, self.ipv, self.table_of(chain), chain];
+        if let Some((p, rule)) = rule {
+            priority = p.to_string();
+            args.push(&priority);
+            args.extend(rule.split_ascii_whitespace());
+        }
+        firewall_cmd(&args).map(drop)
+    }
+
+    /// The table of `chain`, built-in chains are in that of their hook, see
+    /// [`engine::hook_table`].
+    fn table_of(&self, chain: &str) -> &'static str {
+        match chain {
+            "PREROUTING" | "INPUT" => engine::hook_table(chain),
+            _ => self.table,
+        }
+    }
+
+    /// A rule as `firewall-cmd --direct --get-all-rules` lists it.
+    fn line(&self, chain: &str, priority: usize, rule: &str) -> String {
+        let rule: Vec<&str> = rule.split_ascii_whitespace().collect();
+        format!(
+            "{} {} {} {} {}",
+            self.ipv,
+            self.table_of(chain),
+            chain,
+            priority,
+            rule.join(" ")
+        )
+    }
+
+    /// The `firewall-cmd` commands adding `plan` when nothing is there yet.
+    fn commands(&self, plan: &Plan) -> Vec<String> {
+        let mut commands = Vec::new();
+        for (chain, rules) in &plan.chains {
+            commands.push(format!(
+                "firewall-cmd --direct --add-chain {} {} {}",
+                self.ipv,
+                self.table_of(chain),
+                chain
+            ));
+            for (i, rule) in rules.iter().enumerate() {
+                commands.push(format!(
+                    "firewall-cmd --direct --add-rule {}",
+                    self.line(chain, i, rule)
+                ));
+            }
+        }
+        for (chain, rule) in &plan.hooks {
+            commands.push(format!(
+                "firewall-cmd --direct --add-rule {}",
+                self.line(chain, 0, rule)
+            ));
+        }
+        commands
+    }
+
+    /// Make firewalld's runtime configuration match `desired`. firewalld can't replace the rules
+    /// of a chain at once, so a changed chain gets its new rules in the other priority band
+    /// before the old ones are removed: the old rules keep deciding until they are gone.
+    fn apply(&mut self, desired: &Plan) -> Result<(), Box<dyn Error>> {
+        if self.applied == Plan::default() {
+            self.take_over(desired)?;
+        }
+
+        for (chain, rules) in &desired.chains {
+            let old = self.applied.chain(chain).map(<[String]>::to_vec);
+            if old.as_deref() == Some(rules.as_slice()) {
+                continue;
+            }
+            let old_offset = self.offsets.get(chain).copied().unwrap_or(0);
+            let offset = match old {
+                Some(_) if old_offset == 0 => BAND,
+                _ => 0,
+            };
+            if old.is_none() {
+                self.direct("--add-chain", chain, None)?;
+            }
+            for (i, rule) in rules.iter().enumerate() {
+                self.direct("--add-rule", chain, Some((offset + i, rule)))?;
+            }
+            for (i, rule) in old.iter().flatten().enumerate() {
+                self.direct("--remove-rule", chain, Some((old_offset + i, rule)))?;
+            }
+
+            // A failed apply starts over from the chains done so far
+            self.offsets.insert(chain.clone(), offset);
+            match self.applied.chains.iter_mut().find(|(c, _)| c == chain) {
+                Some((_, applied)) => *applied = rules.clone(),
+                None => self.applied.chains.push((chain.clone(), rules.clone())),
+            }
+        }
+
+        for hook in &desired.hooks {
+            let (chain, rule) = hook;
+            if !self.applied.hooks.contains(hook) {
+                self.direct("--add-rule", chain, Some((0, rule)))?;
+            }
+        }
+        for hook in &self.applied.hooks {
+            let (chain, rule) = hook;
+            if !desired.hooks.contains(hook) {
+                self.direct("--remove-rule", chain, Some((0, rule)))?;
+            }
+        }
+        // Emptied first, they may go to each other
+        let removed: Vec<&String> = self
+            .applied
+            .chains
+            .iter()
+            .map(|(chain, _)| chain)
+            .filter(|chain| desired.chain(chain).is_none())
+            .collect();
+        for chain in &removed {
+            self.direct("--remove-rules", chain, None)?;
+        }
+        for chain in &removed {
+            self.direct("--remove-chain", chain, None)?;
+        }
+
+        self.offsets
+            .retain(|chain, _| desired.chain(chain).is_some());
+        self.applied = desired.clone();
+        Ok(())
+    }
+
+    /// Remove what a previous run left in firewalld's runtime configuration: the rules of the
+    /// chains of `desired`, and those of built-in chains that go to them. The chains themselves
+    /// are kept.
+    fn take_over(&self, desired: &Plan) -> Result<(), Box<dyn Error>> {
+        let chains: HashSet<&str> = desired.chains.iter().map(|(c, _)| c.as_str()).collect();
+        for line in firewall_cmd(&["--direct", "--get-all-rules"])?.lines() {
+            let mut fields = line.split_ascii_whitespace();
+            let (Some(ipv), Some(table), Some(chain), Some(priority)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if ipv != self.ipv || table != self.table {
+                continue;
+            }
+            let rule: Vec<&str> = fields.collect();
+            let target = rule
+                .iter()
+                .position(|t| *t == "-j" || *t == "-g")
+                .and_then(|i| rule.get(i + 1));
+            if chains.contains(chain) || target.is_some_and(|target| chains.contains(target)) {
+                self.direct(
+                    "--remove-rule",
+                    chain,
+                    Some((priority.parse()?, &rule.join(" "))),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the direct chains of `names` in any table with their rules, and the rules of other
+    /// chains going to them.
+    fn purge(&mut self, names: &Names) -> Result<(), Box<dyn Error>> {
+        for line in firewall_cmd(&["--direct", "--get-all-rules"])?.lines() {
+            let mut fields = line.split_ascii_whitespace();
+            let (Some(ipv), Some(table), Some(chain), Some(priority)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if ipv != self.ipv {
+                continue;
+            }
+            let rule: Vec<&str> = fields.collect();
+            let target = rule
+                .iter()
+                .position(|t| *t == "-j" || *t == "-g")
+                .and_then(|i| rule.get(i + 1));
+            if names.owns(chain) || target.is_some_and(|target| names.owns(target)) {
+                let mut args = vec!["--direct", "--remove-rule", ipv, table, chain, priority];
+                args.extend(&rule);
+                firewall_cmd(&args)?;
+            }
+        }
+        for line in firewall_cmd(&["--direct", "--get-all-chains"])?.lines() {
+            if let [ipv, table, chain] = line.split_ascii_whitespace().collect::<Vec<_>>()[..]
+                && ipv == self.ipv
+                && names.owns(chain)
+            {
+                firewall_cmd(&["--direct", "--remove-chain", ipv, table, chain])?;
+                tracing::info!("Removed stale firewalld chain {} {} {}", ipv, table, chain);
+            }
+        }
+        self.applied = Plan::default();
+        self.offsets.clear();
+        Ok(())
+    }
+
+    /// What the last applied plan has that firewalld doesn't, e.g. after a reload. firewalld
+    /// doesn't notice rules removed behind its back, neither does this.
+    fn missing(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let chains = firewall_cmd(&["--direct", "--get-all-chains"])?;
+        let chains: HashSet<&str> = chains.lines().map(str::trim).collect();
+        let rules = firewall_cmd(&["--direct", "--get-all-rules"])?;
+        let rules: HashSet<String> = rules
+            .lines()
+            .map(|line| line.split_ascii_whitespace().collect::<Vec<_>>().join(" "))
+            .collect();
+
+        let mut missing = Vec::new();
+        for (chain, chain_rules) in &self.applied.chains {
+            if !chains.contains(format!("{} {} {}", self.ipv, self.table, chain).as_str()) {
+                missing.push(chain.clone());
+                continue;
+            }
+            let offset = self.offsets.get(chain).copied().unwrap_or(0);
+            for (i, rule) in chain_rules.iter().enumerate() {
+                if !rules.contains(&self.line(chain, offset + i, rule)) {
+                    missing.push(format!("{}: {}", chain, rule));
+                }
+            }
+        }
+        for (chain, rule) in &self.applied.hooks {
+            if !rules.contains(&self.line(chain, 0, rule)) {
+                missing.push(format!("{}: {}", chain, rule));
+            }
+        }
+        Ok(missing)
+    }
+}
+
+/// The `firewall-cmd` commands adding `plan` to the chains of `family` in the table of `hook`.
+pub fn commands(family: Family, hook: Hook, plan: &Plan) -> Vec<String> {
+    let ipv = match family {
+        Family::V4 => "ipv4",
+        Family::V6 => "ipv6",
+    };
+    Direct::new(ipv, engine::table(hook)).commands(plan)
+}
+
+/// The firewalld [`FirewallBackend`], the iptables one with the rules going through firewalld.
+pub struct Firewalld {
+    v4: Direct,
+    v6: Option<Direct>,
+    /// `--ipv6-prefix` of IPv6 units, `None` when IPv6 is disabled
+    ipv6_prefix: Option<u8>,
+    /// Reuse existing ipsets, see [`crate::firewall::backend`]
+    adopt: bool,
+}
+
+impl Firewalld {
+    /// Fails unless firewalld is running. The chains go into the table of `hook`.
+    pub fn new(ipv6_prefix: Option<u8>, hook: Hook, adopt: bool) -> Result<Self, Box<dyn Error>> {
+        firewall_cmd(&["--state"]).map_err(|e| format!("firewalld isn't running: {}", e))?;
+        let table = engine::table(hook);
+        Ok(Self {
+            v4: Direct::new("ipv4", table),
+            v6: ipv6_prefix.map(|_| Direct::new("ipv6", table)),
+            ipv6_prefix,
+            adopt,
+        })
+    }
+
+    fn direct(&mut self, family: Family) -> Result<&mut Direct, Box<dyn Error>> {
+        match family {
+            Family::V4 => Ok(&mut self.v4),
+            Family::V6 => self.v6.as_mut().ok_or_else(|| "IPv6 is disabled".into()),
+        }
+    }
+}
+
+impl FirewallBackend for Firewalld {
+    fn syntax(&self) -> Backend {
+        Backend::Iptables
+    }
+
+    fn families(&self) -> Vec<Family> {
+        match self.v6 {
+            Some(_) => vec![Family::V4, Family::V6],
+            None => vec![Family::V4],
+        }
+    }
+
+    fn setup_set(&mut self, options: &SetOptions) -> Result<Box<dyn AddressSet>> {
+        engine::ipsets(options, self.ipv6_prefix, self.adopt)
+    }
+
+    fn apply(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        self.direct(family)?.apply(plan)
+    }
+
+    fn verify(&self, family: Family) -> Result<Vec<String>, Box<dyn Error>> {
+        match family {
+            Family::V4 => self.v4.missing(),
+            Family::V6 => self.v6.as_ref().map_or(Ok(Vec::new()), Direct::missing),
+        }
+    }
+
+    fn reinstall(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        let direct = self.direct(family)?;
+        let applied = std::mem::take(&mut direct.applied);
+        let offsets = std::mem::take(&mut direct.offsets);
+        direct.apply(plan).inspect_err(|_| {
+            direct.applied = applied;
+            direct.offsets = offsets;
+        })
+    }
+
+    /// firewalld runs iptables for the listing, so it is the variant holding its rules.
+    fn counters(&self, family: Family) -> Result<Vec<RuleCounter>, Box<dyn Error>> {
+        let direct = match family {
+            Family::V4 => &self.v4,
+            Family::V6 => match &self.v6 {
+                Some(v6) => v6,
+                None => return Ok(Vec::new()),
+            },
+        };
+        let output = firewall_cmd(&[
+            "--direct",
+            "--passthrough",
+            direct.ipv,
+            "-t",
+            direct.table,
+            "-S",
+            "-v",
+        ])?;
+        Ok(engine::parse_counters(&output))
+    }
+
+    fn teardown(&mut self) -> Result<(), Box<dyn Error>> {
+        self.v4.apply(&Plan::default())?;
+        if let Some(v6) = &mut self.v6 {
+            v6.apply(&Plan::default())?;
+        }
+        Ok(())
+    }
+
+    fn purge(&mut self, names: &Names) -> Result<(), Box<dyn Error>> {
+        self.v4.purge(names)?;
+        if let Some(v6) = &mut self.v6 {
+            v6.purge(names)?;
+        }
+        engine::destroy_ipsets(names, self.v6.is_some())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+//! The client behind a reverse proxy such as nginx, from the `Forwarded`, `X-Forwarded-For` or
+//! `X-Real-IP` header of requests whose peer is one of the `--trusted-proxies`. Requests from
+//! anyone else keep their peer as the client, their headers could name any address. Requests
+//! from the `--cloudflare` ranges name the client in `CF-Connecting-IP` instead.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, header},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{cidr::Cidr, client::ClientInfo, cloudflare, state::AppState};
+
+fn trusted(proxies: &[Cidr], ip: IpAddr) -> bool {
+    proxies.iter().any(|net| net.contains(ip))
+}
+
+/// The address of a node in a forwarding header: a bare address, or one with a port such as
+/// `192.0.2.1:4711` and `"[2001:db8::1]:4711"`, quoted as `Forwarded` needs for IPv6. `None` for
+/// anything else, including the `unknown` and obfuscated nodes of `Forwarded`.
+fn node(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+    if let Some(bracketed) = value.strip_prefix('[') {
+        let (ip, port) = bracketed.split_once(']')?;
+        if !port.is_empty() {
+            port.strip_prefix(':')?.parse::<u16>().ok()?;
+        }
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    if let Ok(ip) = value.parse() {
+        return Some(ip);
+    }
+    let (ip, port) = value.split_once(':')?;
+    port.parse::<u16>().ok()?;
+    ip.parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+}
+
+/// The nodes the proxies forwarded the request for, oldest first, e.g. from `Forwarded:
+/// for=192.0.2.1, for="[2001:db8::1]:4711";proto=https`. `None` without the header.
+fn forwarded_for(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let elements: Vec<Option<IpAddr>> = headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    name.trim().eq_ignore_ascii_case("for").then_some(value)
+                })
+                .and_then(node)
+        })
+        .collect();
+    (!elements.is_empty()).then_some(elements)
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let entries: Vec<Option<IpAddr>> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(node)
+        .collect();
+    (!entries.is_empty()).then_some(entries)
+}
+
+/// The address the proxies forwarded the request for. Proxies append the peer they got the
+/// request from, so the client is the last entry that isn't a proxy, and entries left of it are
+/// whatever the client sent. When every entry is a proxy the first one is the client. An entry
+/// that can't be read before the client is found leaves the peer as the client.
+fn client(headers: &HeaderMap, proxies: &[Cidr]) -> Option<IpAddr> {
+    let Some(hops) = forwarded_for(headers).or_else(|| x_forwarded_for(headers)) else {
+        return node(headers.get("x-real-ip")?.to_str().ok()?);
+    };
+    let mut client = None;
+    for hop in hops.into_iter().rev() {
+        let ip = hop?;
+        client = Some(ip);
+        if !trusted(proxies, ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// The client a request from `peer` was forwarded for, `None` to keep the peer.
+fn forwarded_client(
+    peer: IpAddr,
+    headers: &HeaderMap,
+    proxies: &[Cidr],
+    cloudflare_ranges: &[Cidr],
+) -> Option<IpAddr> {
+    if trusted(cloudflare_ranges, peer) {
+        return node(headers.get(cloudflare::CLIENT_HEADER)?.to_str().ok()?);
+    }
+    trusted(proxies, peer)
+        .then(|| client(headers, proxies))
+        .flatten()
+}
+
+/// Replace the [`ClientInfo`] of requests from trusted proxies with the client they name.
+pub async fn real_client(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let forwarded = request
+        .extensions()
+        .get::<ConnectInfo<ClientInfo>>()
+        .and_then(|ConnectInfo(peer)| {
+            forwarded_client(
+                peer.addr.ip(),
+                request.headers(),
+                &state.args.trusted_proxies,
+                &state.cloudflare_ranges.read().unwrap(),
+            )
+        });
+    if let Some(ip) = forwarded
+        && let Some(ConnectInfo(client)) = request
+            .extensions_mut()
+            .get_mut::<ConnectInfo<ClientInfo>>()
+    {
+        // The RTT measured is the one to the proxy
+        *client = ClientInfo {
+            addr: (ip, 0).into(),
+            rtt: None,
+        };
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn proxies() -> Vec<Cidr> {
+        vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "2001:db8:ffff::/48".parse().unwrap(),
+        ]
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    /// The client of a request from the trusted proxy 10.0.0.1.
+    fn resolve(pairs: &[(&'static str, &'static str)]) -> Option<IpAddr> {
+        forwarded_client(ip("10.0.0.1"), &headers(pairs), &proxies(), &[])
+    }
+
+    #[test]
+    fn ignores_headers_of_untrusted_peers() {
+        let headers = headers(&[
+            ("x-forwarded-for", "192.0.2.1"),
+            ("x-real-ip", "192.0.2.1"),
+            ("forwarded", "for=192.0.2.1"),
+            ("cf-connecting-ip", "192.0.2.1"),
+        ]);
+        assert_eq!(
+            forwarded_client(ip("198.51.100.1"), &headers, &proxies(), &[]),
+            None
+        );
+    }
+
+    #[test]
+    fn walks_trusted_hops() {
+        assert_eq!(
+            resolve(&[("x-forwarded-for", "192.0.2.1, 10.1.1.1, 10.2.2.2")]),
+            Some(ip("192.0.2.1"))
+        );
+        // Entries left of the first untrusted one are the client's to make up
+        assert_eq!(
+            resolve(&[("x-forwarded-for", "203.0.113.9, 192.0.2.1, 10.1.1.1")]),
+            Some(ip("192.0.2.1"))
+        );
+        // Split over several headers
+        assert_eq!(
+            resolve(&[
+                ("x-forwarded-for", "203.0.113.9, 192.0.2.1"),
+                ("x-forwarded-for", "10.1.1.1"),
+            ]),
+            Some(ip("192.0.2.1"))
+        );
+        assert_eq!(
+            resolve(&[("forwarded", "for=192.0.2.1;proto=https, for=10.1.1.1")]),
+            Some(ip("192.0.2.1"))
+        );
+        assert_eq!(
+            resolve(&[("x-real-ip", "192.0.2.1")]),
+            Some(ip("192.0.2.1"))
+        );
+        assert_eq!(resolve(&[]), None);
+    }
+
+    #[test]
+    fn prefers_forwarded() {
+        assert_eq!(
+            resolve(&[
+                ("x-forwarded-for", "203.0.113.9"),
+                ("forwarded", "for=192.0.2.1"),
+                ("x-real-ip", "198.51.100.9"),
+            ]),
+            Some(ip("192.0.2.1"))
+        );
+    }
+
+    #[test]
+    fn stops_at_malformed_entries() {
+        assert_eq!(
+            resolve(&[("x-forwarded-for", "192.0.2.1, not-an-address, 10.1.1.1")]),
+            None
+        );
+        assert_eq!(
+            resolve(&[("x-forwarded-for", "192.0.2.1,, 10.1.1.1")]),
+            None
+        );
+        assert_eq!(resolve(&[("forwarded", "for=unknown, for=10.1.1.1")]), None);
+        assert_eq!(resolve(&[("forwarded", "for=_hidden")]), None);
+        assert_eq!(resolve(&[("forwarded", "proto=https")]), None);
+        assert_eq!(resolve(&[("x-real-ip", "192.0.2.1, 192.0.2.2")]), None);
+        // Past the client nothing is read
+        assert_eq!(
+            resolve(&[("x-forwarded-for", "not-an-address, 192.0.2.1, 10.1.1.1")]),
+            Some(ip("192.0.2.1"))
+        );
+    }
+
+    #[test]
+    fn reads_nodes_with_ports() {
+        assert_eq!(
+            resolve(&[("forwarded", r#"for="[2001:db8::17]:4711";proto=https"#)]),
+            Some(ip("2001:db8::17"))
+        );
+        assert_eq!(
+            resolve(&[(
+                "forwarded",
+                r#"For="[2001:db8::17]", for="[2001:db8:ffff::1]:443""#
+            )]),
+            Some(ip("2001:db8::17"))
+        );
+        assert_eq!(
+            resolve(&[("forwarded", "for=192.0.2.1:4711")]),
+            Some(ip("192.0.2.1"))
+        );
+        assert_eq!(
+            resolve(&[("x-forwarded-for", "192.0.2.1:4711, [2001:db8::17]:80")]),
+            Some(ip("2001:db8::17"))
+        );
+        assert_eq!(
+            resolve(&[("forwarded", r#"for="[2001:db8::17]:port""#)]),
+            None
+        );
+        assert_eq!(resolve(&[("forwarded", r#"for="[192.0.2.1]""#)]), None);
+    }
+
+    #[test]
+    fn takes_first_hop_when_all_are_trusted() {
+        assert_eq!(
+            resolve(&[("x-forwarded-for", "10.3.3.3, 10.1.1.1")]),
+            Some(ip("10.3.3.3"))
+        );
+        assert_eq!(
+            resolve(&[("forwarded", r#"for="[2001:db8:ffff::2]", for=10.1.1.1"#)]),
+            Some(ip("2001:db8:ffff::2"))
+        );
+    }
+
+    #[test]
+    fn reads_cloudflare_header() {
+        let cloudflare: Vec<Cidr> = vec!["173.245.48.0/20".parse().unwrap()];
+        let headers = headers(&[
+            ("cf-connecting-ip", "192.0.2.1"),
+            ("x-forwarded-for", "203.0.113.9"),
+        ]);
+        assert_eq!(
+            forwarded_client(ip("173.245.48.1"), &headers, &proxies(), &cloudflare),
+            Some(ip("192.0.2.1"))
+        );
+        assert_eq!(
+            forwarded_client(ip("10.0.0.1"), &headers, &proxies(), &cloudflare),
+            Some(ip("203.0.113.9"))
+        );
+    }
+}
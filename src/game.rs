@@ -0,0 +1,101 @@
+//! Presets for the games mortis protects, `--game`. A preset picks who gets admitted, by the
+//! User-Agent of the HTTP client the game ships, and the ports and rates for its traffic. Flags
+//! given on the command line and a `[user_agent]` section in the config take precedence.
+
+use clap::{ArgMatches, ValueEnum, parser::ValueSource};
+
+use crate::{Args, config::UserAgentConfig, firewall, policy::Pattern};
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Game {
+    /// Garry's Mod, the game port 27015
+    Gmod,
+    /// FiveM, UDP and TCP 30120. Clients send more than Source games do
+    Fivem,
+    /// Other Source engine games, whose HTTP client is Steam's, the game port 27015
+    Source,
+    /// Admit every User-Agent and set no ports, for games without a preset
+    None,
+}
+
+/// What a game's traffic looks like.
+struct Preset {
+    protect: &'static str,
+    protect_tcp: &'static str,
+    whitelist_limit: u32,
+    unknown_limit: u32,
+    burst: u32,
+    probation_limit: u32,
+}
+
+impl Game {
+    fn preset(self) -> Preset {
+        let source = Preset {
+            protect: "27015",
+            protect_tcp: "",
+            whitelist_limit: firewall::DEFAULT_WHITELIST_LIMIT,
+            unknown_limit: firewall::DEFAULT_UNKNOWN_LIMIT,
+            burst: firewall::DEFAULT_BURST,
+            probation_limit: firewall::DEFAULT_PROBATION_LIMIT,
+        };
+        match self {
+            Game::Gmod | Game::Source => source,
+            Game::Fivem => Preset {
+                protect: "30120",
+                protect_tcp: "30120",
+                whitelist_limit: 300,
+                unknown_limit: 10,
+                burst: 20,
+                probation_limit: 200,
+            },
+            Game::None => Preset {
+                protect: "",
+                ..source
+            },
+        }
+    }
+
+    /// What the User-Agent of the game's HTTP client is recognized by.
+    pub fn user_agent(self) -> UserAgentConfig {
+        let (contains, matches): (&[&str], &[&str]) = match self {
+            Game::Gmod => (&["GMod"], &[]),
+            Game::Fivem => (&[], &["^CitizenFX/"]),
+            Game::Source => (&[], &["^Valve/Steam HTTP Client"]),
+            Game::None => (&[], &[]),
+        };
+        UserAgentConfig {
+            contains: contains.iter().map(|s| s.to_string()).collect(),
+            excludes: Vec::new(),
+            matches: matches
+                .iter()
+                .map(|s| Pattern::new(s).expect("presets are valid regexes"))
+                .collect(),
+        }
+    }
+}
+
+/// Fill in the ports and rates of `--game` that weren't given on the command line.
+pub fn apply(args: &mut Args, matches: &ArgMatches) {
+    let preset = args.game.preset();
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let ports = |ports: &str| ports.parse().expect("presets are valid port lists");
+
+    if !given("protect") && !preset.protect.is_empty() {
+        args.protect = vec![ports(preset.protect)];
+    }
+    if !given("protect_tcp") && !preset.protect_tcp.is_empty() {
+        args.protect_tcp = vec![ports(preset.protect_tcp)];
+    }
+    if !given("whitelist_limit") {
+        args.whitelist_limit = preset.whitelist_limit;
+    }
+    if !given("unknown_limit") {
+        args.unknown_limit = preset.unknown_limit;
+    }
+    if !given("hashlimit_burst") {
+        args.hashlimit_burst = preset.burst;
+    }
+    if !given("probation_limit") {
+        args.probation_limit = preset.probation_limit;
+    }
+}
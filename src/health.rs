@@ -0,0 +1,49 @@
+//! Probes for load balancers and systemd, `/healthz` and `/readyz`. Neither changes anything,
+//! unlike the watchdog they only report what is missing instead of putting it back.
+
+use std::sync::Arc;
+
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+
+use crate::state::AppState;
+
+#[derive(Serialize)]
+pub struct Readiness {
+    ready: bool,
+    /// Chains, rules and sets that went missing, or why they couldn't be checked
+    problems: Vec<String>,
+}
+
+/// The process is up and serving requests.
+pub async fn healthz() -> StatusCode {
+    StatusCode::NO_CONTENT
+}
+
+/// The chains, rules and whitelist set mortis relies on are in place, 503 otherwise.
+pub async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Readiness>) {
+    let mut problems = state
+        .with_firewall(|firewall| match firewall.missing() {
+            Ok(missing) => missing
+                .into_iter()
+                .map(|artifact| format!("missing {}", artifact))
+                .collect(),
+            Err(e) => vec![format!("Failed to list iptables rules: {}", e)],
+        })
+        .await;
+    if let Err(e) = state.ipset_session.lock().await.list() {
+        problems.push(format!("Failed to list the whitelist set: {}", e));
+    }
+
+    let status = match problems.is_empty() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (
+        status,
+        Json(Readiness {
+            ready: problems.is_empty(),
+            problems,
+        }),
+    )
+}
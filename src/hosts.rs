@@ -0,0 +1,87 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{cidr::Cidr, state::AppState};
+
+/// Periodically resolve `--allow-host` names and keep the allow set in sync with their
+/// current addresses.
+pub async fn task(state: Arc<AppState>) {
+    let interval = Duration::from_secs(state.args.allow_host_interval);
+    let mut resolved: HashMap<String, HashSet<IpAddr>> = HashMap::new();
+
+    loop {
+        for host in &state.args.allow_host {
+            match resolve(&state, host).await {
+                Ok(addrs) => {
+                    let old = resolved.insert(host.clone(), addrs.clone());
+                    if old.as_ref() != Some(&addrs) {
+                        tracing::info!("Allow host {} now resolves to {:?}", host, addrs);
+                    }
+                }
+                // Keep the last known addresses, a flaky resolver shouldn't lock out a relay
+                Err(e) => tracing::warn!("Failed to resolve allow host {}: {}", host, e),
+            }
+        }
+
+        let current: HashSet<IpAddr> = resolved.values().flatten().copied().collect();
+        let nets = state.allowed_nets.lock().await;
+        // Addresses currently in the kernel set, failed operations are retried next round
+        let mut applied = state.allowed_hosts.lock().await;
+        let cloudflare = state.allowed_cloudflare.lock().await;
+        let mut allow = state.allow_session.lock().await;
+
+        // Adding again is harmless, and refills the set if the watchdog had to recreate it
+        let to_add: Vec<IpAddr> = current.iter().copied().collect();
+        let to_remove: Vec<IpAddr> = applied.difference(&current).copied().collect();
+
+        for ip in to_add {
+            match allow.add_ip(ip) {
+                Ok(_) => {
+                    applied.insert(ip);
+                }
+                Err(e) => {
+                    state.metrics.record_add_error(&e);
+                    tracing::error!("Failed to allow {}: {}", ip, e);
+                }
+            }
+        }
+        for ip in to_remove {
+            // The set holds each network once, another source may still allow the address
+            let host = Cidr::host(ip);
+            if nets.contains(&host) || cloudflare.contains(&host) {
+                applied.remove(&ip);
+                continue;
+            }
+            match allow.del_ip(ip) {
+                Ok(_) => {
+                    applied.remove(&ip);
+                }
+                Err(e) => {
+                    state.metrics.record_netlink_error("del");
+                    tracing::error!("Failed to remove {} from the allow set: {}", ip, e);
+                }
+            }
+        }
+
+        drop(allow);
+        drop(cloudflare);
+        drop(applied);
+        drop(nets);
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn resolve(state: &AppState, host: &str) -> anyhow::Result<HashSet<IpAddr>> {
+    Ok(state
+        .resolver
+        .lookup(host)
+        .await?
+        .into_iter()
+        // Without IPv6 support the allow set only holds IPv4 addresses
+        .filter(|ip| ip.is_ipv4() || !state.args.no_ipv6)
+        .collect())
+}
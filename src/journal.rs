@@ -0,0 +1,196 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{broadcast, mpsc},
+};
+
+use crate::snapshot::unix_now;
+
+/// Updates a slow subscriber may fall behind by before it misses some, see
+/// [`Journal::subscribe`].
+const LIVE_BACKLOG: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Admitted,
+    Refreshed,
+    RejectedUa,
+    RejectedQuota,
+    /// Without a valid token, see [`crate::tokens`]
+    RejectedToken,
+    /// Without a Steam ticket Steam accepted, see [`crate::steam`]
+    RejectedSteam,
+    Expired,
+    /// Removed for misbehaving, e.g. see [`crate::entropy`]
+    Evicted,
+    Pinned,
+    Unpinned,
+    Restored,
+    /// See [`crate::blacklist`]
+    Banned,
+    Pardoned,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Event {
+    /// Unix timestamp of the event
+    pub at: u64,
+    pub ip: IpAddr,
+    pub kind: EventKind,
+    /// Of the request behind an admission decision, for `mortis-rs replay`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+/// The watchdog put back rules or a set that went missing.
+#[derive(Clone, Serialize)]
+pub struct Repair {
+    pub at: u64,
+    /// `rules` or `set`
+    pub kind: &'static str,
+    /// The rules reinstalled or the set recreated
+    pub entries: Vec<String>,
+}
+
+/// What live subscribers get, see [`Journal::subscribe`].
+#[derive(Clone)]
+pub enum Update {
+    Event(Event),
+    Repair(Repair),
+}
+
+/// Bounded in-memory log of whitelist events, the oldest ones are dropped first.
+pub struct Journal {
+    capacity: usize,
+    events: Mutex<VecDeque<Event>>,
+    /// Where events go to be appended to `--journal-file`
+    file: Option<mpsc::UnboundedSender<Event>>,
+    live: broadcast::Sender<Update>,
+}
+
+impl Journal {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            file: None,
+            live: broadcast::channel(LIVE_BACKLOG).0,
+        }
+    }
+
+    /// Every event and repair from now on, for `/admin/events`. Repairs aren't kept, they
+    /// aren't about a client.
+    pub fn subscribe(&self) -> broadcast::Receiver<Update> {
+        self.live.subscribe()
+    }
+
+    pub fn record_repair(&self, kind: &'static str, entries: Vec<String>) {
+        let _ = self.live.send(Update::Repair(Repair {
+            at: unix_now(),
+            kind,
+            entries,
+        }));
+    }
+
+    /// Also send every event to the returned receiver, for [`write_task`].
+    pub fn persist(&mut self) -> mpsc::UnboundedReceiver<Event> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.file = Some(sender);
+        receiver
+    }
+
+    pub fn record(&self, ip: IpAddr, kind: EventKind) {
+        self.record_request(ip, kind, None);
+    }
+
+    /// Record an admission decision along with what it was based on.
+    pub fn record_request(&self, ip: IpAddr, kind: EventKind, user_agent: Option<&str>) {
+        let event = Event {
+            at: unix_now(),
+            ip,
+            kind,
+            user_agent: user_agent.map(str::to_string),
+        };
+        if let Some(file) = &self.file {
+            let _ = file.send(event.clone());
+        }
+        if self.live.receiver_count() > 0 {
+            let _ = self.live.send(Update::Event(event.clone()));
+        }
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        if self.capacity > 0 {
+            events.push_back(event);
+        }
+    }
+
+    /// Events of `ip`, oldest first.
+    pub fn history(&self, ip: IpAddr) -> Vec<Event> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.ip == ip)
+            .cloned()
+            .collect()
+    }
+
+    /// When each address was whitelisted since it last expired or was removed. Addresses whose
+    /// admission the journal dropped already are missing.
+    pub fn admitted_at(&self) -> HashMap<IpAddr, u64> {
+        let mut admitted = HashMap::new();
+        for event in self.events.lock().unwrap().iter() {
+            match event.kind {
+                EventKind::Admitted | EventKind::Pinned | EventKind::Restored => {
+                    admitted.entry(event.ip).or_insert(event.at);
+                }
+                EventKind::Expired | EventKind::Evicted | EventKind::Banned => {
+                    admitted.remove(&event.ip);
+                }
+                _ => {}
+            }
+        }
+        admitted
+    }
+}
+
+/// Append events to `path` as JSON lines until the process exits.
+pub async fn write_task(path: &Path, mut events: mpsc::UnboundedReceiver<Event>) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open journal file {}", path.display()))?;
+
+    while let Some(event) = events.recv().await {
+        let mut line = serde_json::to_vec(&event)?;
+        line.push(b'\n');
+        file.write_all(&line)
+            .await
+            .with_context(|| format!("Failed to write to journal file {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Read a file written by [`write_task`], skipping lines that don't parse, e.g. a torn last one.
+pub fn read(path: &Path) -> Result<Vec<Event>> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read journal {}", path.display()))?;
+    Ok(data
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
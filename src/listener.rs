@@ -0,0 +1,142 @@
+//! The public listener when connections need work before HTTP can be spoken on them: reading
+//! the `--proxy-protocol` header and the TLS handshake, see [`crate::tls`]. Each connection does
+//! its part in its own task, so a client that stalls in it doesn't hold up the others. Also used
+//! to count connections against `--max-connections`, see [`crate::concurrency`].
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::{Semaphore, mpsc},
+};
+
+use crate::{
+    cidr::Cidr, client::ClientInfo, concurrency::Slotted, proxy_protocol, state::AppState,
+    tls::Certificates,
+};
+
+/// Longest a client may take to get through the PROXY header and the handshake.
+const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections ready for HTTP waiting for the server to pick them up.
+const ACCEPTED_BACKLOG: usize = 128;
+
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Io for T {}
+
+#[derive(Clone)]
+struct Preamble {
+    /// Peers whose PROXY header is read, `None` without `--proxy-protocol`
+    proxy_protocol: Option<Arc<[Cidr]>>,
+    tls: Option<Arc<Certificates>>,
+}
+
+pub struct PublicListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(Box<dyn Io>, ClientInfo)>,
+}
+
+impl PublicListener {
+    pub fn new(listener: TcpListener, state: Arc<AppState>) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, accepted) = mpsc::channel(ACCEPTED_BACKLOG);
+        tokio::spawn(accept(listener, state, sender));
+        Ok(Self {
+            local_addr,
+            accepted,
+        })
+    }
+}
+
+async fn accept(
+    listener: TcpListener,
+    state: Arc<AppState>,
+    sender: mpsc::Sender<(Box<dyn Io>, ClientInfo)>,
+) {
+    let preamble = Preamble {
+        proxy_protocol: state
+            .args
+            .proxy_protocol
+            .then(|| state.args.proxy_protocol_from.as_slice().into()),
+        tls: state.tls.clone(),
+    };
+    let connections = (state.args.max_connections > 0)
+        .then(|| Arc::new(Semaphore::new(state.args.max_connections)));
+    while !sender.is_closed() {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Like axum's own listener, e.g. when out of file descriptors
+                tracing::debug!("Failed to accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        // Taken before the preamble, clients stalling in it are what the limit is for
+        let slot = match &connections {
+            Some(connections) => match connections.clone().try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => {
+                    state.metrics.record_http_shed("connections");
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let preamble = preamble.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(PREAMBLE_TIMEOUT, prepare(stream, addr, &preamble)).await {
+                Ok(Ok((stream, client))) => {
+                    let stream: Box<dyn Io> = match slot {
+                        Some(slot) => Box::new(Slotted::new(stream, slot)),
+                        None => stream,
+                    };
+                    let _ = sender.send((stream, client)).await;
+                }
+                Ok(Err(e)) => tracing::debug!("Dropped the connection from {}: {}", addr, e),
+                Err(_) => tracing::debug!("Connection from {} timed out before HTTP", addr),
+            }
+        });
+    }
+}
+
+async fn prepare(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    preamble: &Preamble,
+) -> io::Result<(Box<dyn Io>, ClientInfo)> {
+    let mut client = ClientInfo::of(&stream, addr);
+    if let Some(balancers) = &preamble.proxy_protocol
+        && let Some(addr) = proxy_protocol::client(&mut stream, addr.ip(), balancers).await?
+    {
+        // The RTT measured is the one to the balancer
+        client = ClientInfo { addr, rtt: None };
+    }
+    let stream: Box<dyn Io> = match &preamble.tls {
+        Some(tls) => Box::new(tls.acceptor().accept(stream).await?),
+        None => Box::new(stream),
+    };
+    Ok((stream, client))
+}
+
+impl axum::serve::Listener for PublicListener {
+    type Io = Box<dyn Io>;
+    type Addr = ClientInfo;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(accepted) => accepted,
+            // The accepting task lives as long as the listener
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(ClientInfo {
+            addr: self.local_addr,
+            rtt: None,
+        })
+    }
+}
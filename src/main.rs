@@ -1,62 +1,784 @@
+mod acme;
+mod admin;
+mod allow;
+mod auth;
+mod blacklist;
+mod capacity;
+mod capture;
+mod cidr;
 mod cleaner;
+mod client;
+mod cloudflare;
+mod comment;
+mod concurrency;
+mod config;
+mod conntrack;
+mod counters;
+mod dashboard;
+mod dns;
+mod drops;
+mod dryrun;
+mod engine;
+mod entropy;
+mod export;
 mod firewall;
+mod firewalld;
+mod forwarded;
+mod game;
+mod health;
+mod hosts;
+mod journal;
+mod listener;
+mod metrics;
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+mod mock;
+mod monitor;
+mod nflog;
+mod nftables;
+mod notify;
+mod openapi;
+mod overload;
+mod pending;
+mod pf;
+mod pins;
+mod pipeline;
+mod policy;
+mod ports;
+mod proxy;
+mod proxy_protocol;
+mod quota;
+mod ratelimit;
+mod reconcile;
+mod redirect;
+mod refresh;
+mod region;
+#[cfg(unix)]
+mod reload;
+mod replay;
+mod sampling;
+mod selftest;
+mod shutdown;
+mod signing;
+mod snapshot;
 mod state;
+mod status;
+mod steam;
+mod synproxy;
+mod tls;
+mod tokens;
+mod watchdog;
+#[cfg(all(feature = "xdp", target_os = "linux"))]
+mod xdp;
 use anyhow::{Context, Result};
 
 use axum::{
     Router,
     extract::{ConnectInfo, Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Redirect, Response},
-    routing::any,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{any, get, post},
 };
 use axum_extra::{TypedHeader, headers};
+use client::ClientInfo;
+use pipeline::{Credential, Profile};
 use state::AppState;
 
-use std::{net::SocketAddr, ops::DerefMut, sync::Arc, time::Duration};
+#[cfg(any(feature = "mock", not(target_os = "linux")))]
+use mock::{ipset, iptables};
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+use {::ipset, ::iptables};
 
-use clap::Parser;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 
 use tokio::{signal, sync::Mutex, time::Instant};
 use tower_http::{timeout::TimeoutLayer, trace::TraceLayer};
+use tracing_subscriber::EnvFilter;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Option<Args>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Restore whitelist entries from a snapshot into a running instance
+    Restore {
+        /// Snapshot file written to --snapshot-dir
+        #[arg(long)]
+        snapshot: PathBuf,
+
+        /// Base URL of the running instance's admin API
+        #[arg(long, default_value = "http://127.0.0.1:3031")]
+        admin_url: String,
+
+        /// Like --admin-token-file of the running instance
+        #[arg(long)]
+        admin_token_file: Option<PathBuf>,
+    },
+
+    /// Simulate a client end-to-end against a protected server and report pass/fail
+    Selftest(selftest::SelftestArgs),
+
+    /// Report how a config would have decided the admissions recorded in a journal file
+    Replay(replay::ReplayArgs),
+
+    /// Print the health of a running instance, exiting non-zero if its firewall is broken
+    Status {
+        /// Base URL of the running instance's admin API
+        #[arg(long, default_value = "http://127.0.0.1:3031")]
+        admin_url: String,
+
+        /// Like --admin-token-file of the running instance
+        #[arg(long)]
+        admin_token_file: Option<PathBuf>,
+
+        #[arg(long, value_enum, default_value_t = status::StatusFormat::Text)]
+        format: status::StatusFormat,
+    },
+
+    /// Check the signature of a webhook body read from stdin, exiting non-zero if invalid
+    VerifyWebhook {
+        /// File holding the sink's secret
+        #[arg(long)]
+        secret_file: PathBuf,
+
+        /// Value of the X-Mortis-Timestamp header
+        #[arg(long)]
+        timestamp: u64,
+
+        /// Value of the X-Mortis-Signature header
+        #[arg(long)]
+        signature: String,
+
+        /// Seconds the timestamp may be off from now
+        #[arg(long, default_value_t = 300)]
+        tolerance: u64,
+    },
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
     /// Port to listen on
     #[arg(short, long, default_value_t = 3030)]
     listen: u16,
 
-    /// UDP Port to protect (like iptables multiport)
+    /// Expect a PROXY protocol header, v1 or v2, ahead of every connection from
+    /// --proxy-protocol-from and admit the client it names
+    #[arg(long, requires = "proxy_protocol_from")]
+    proxy_protocol: bool,
+
+    /// Networks of the load balancers sending PROXY protocol headers, e.g. 10.0.0.0/8. Headers
+    /// of other peers could name any client, they are the client themselves and their
+    /// connections are read as HTTP right away
+    #[arg(long, value_delimiter = ',', requires = "proxy_protocol")]
+    proxy_protocol_from: Vec<cidr::Cidr>,
+
+    /// Networks of reverse proxies whose Forwarded, X-Forwarded-For or X-Real-IP header names
+    /// the client, e.g. 127.0.0.1,10.0.0.0/8. Other peers are the client themselves
+    #[arg(long, value_delimiter = ',')]
+    trusted_proxies: Vec<cidr::Cidr>,
+
+    /// Trust the ranges Cloudflare publishes as proxies naming the client in CF-Connecting-IP,
+    /// fetched from the Cloudflare API every --cloudflare-interval
+    #[arg(long)]
+    cloudflare: bool,
+
+    /// Seconds between fetching the Cloudflare ranges
+    #[arg(long, default_value_t = 86400)]
+    cloudflare_interval: u64,
+
+    /// Also let the Cloudflare ranges bypass mortis through the allow set, for protected TCP
+    /// ports proxied through Cloudflare
+    #[arg(long, requires = "cloudflare")]
+    cloudflare_allow: bool,
+
+    /// PEM certificate chain to serve HTTPS with instead of HTTP, read again on SIGHUP
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS with a certificate for this domain from Let's Encrypt, obtained and renewed
+    /// automatically
+    #[arg(long, conflicts_with = "tls_cert")]
+    acme_domain: Option<String>,
+
+    /// Contact address for the ACME account, where the CA sends expiry warnings
+    #[arg(long, requires = "acme_domain")]
+    acme_email: Option<String>,
+
+    /// Directory the ACME account, certificate and key are kept in
+    #[arg(long, default_value = "/var/lib/mortis-rs/acme")]
+    acme_dir: PathBuf,
+
+    /// Use the Let's Encrypt staging CA, whose certificates aren't trusted, for trying things out
+    #[arg(long, requires = "acme_domain")]
+    acme_staging: bool,
+
+    /// Address to answer ACME HTTP-01 challenges on, the CA connects to port 80 of the domain
+    #[arg(long, default_value = "[::]:80")]
+    acme_http_listen: SocketAddr,
+
+    /// UDP ports to protect, like iptables multiport, e.g. 27015,27020:27030 (repeatable).
+    /// Defaults to the ports of --game when that is given
+    #[arg(short, long, required_unless_present = "game")]
+    protect: Vec<ports::Ports>,
+
+    /// Game the server runs, sets the User-Agent clients are admitted by and the defaults of
+    /// --protect, --protect-tcp, --whitelist-limit, --unknown-limit, --hashlimit-burst and
+    /// --probation-limit
+    #[arg(long, value_enum, default_value_t = game::Game::Gmod)]
+    game: game::Game,
+
+    /// UDP source ports of reflection attacks, like --protect. Packets from them are dropped
+    /// unless the source is in the allow set, an empty list keeps them
+    #[arg(long, default_value = "19,53,123,161,3702")]
+    amplification_ports: ports::Ports,
+
+    /// UDP ports that only take Source engine connectionless packets (A2S queries, challenges,
+    /// connects), e.g. a separate query port, like --protect. Packets without the 0xFFFFFFFF
+    /// header are dropped from every source outside the allow set, whitelisted ones too. Game
+    /// traffic doesn't carry it, keep the game port out
+    #[arg(long, default_value = "")]
+    a2s_only_ports: ports::Ports,
+
+    /// TCP ports to protect, like --protect. Only new connections are limited, every source
+    /// may open as many per second as the ruleset lets it send UDP packets (repeatable)
+    #[arg(long)]
+    protect_tcp: Vec<ports::Ports>,
+
+    /// Answer handshakes to the --protect-tcp ports with SYNPROXY, so SYN floods from spoofed
+    /// sources reach neither the server nor the conntrack table. Turns off conntrack's loose
+    /// pickup of TCP flows and turns on TCP timestamps while mortis runs
+    #[arg(long, requires = "protect_tcp", conflicts_with_all = ["monitor_only", "firewalld"])]
+    synproxy: bool,
+
+    /// MSS SYNPROXY announces to IPv4 clients, IPv6 ones get 20 less. Should match what the
+    /// server announces, e.g. the interface MTU less 40
+    #[arg(long, default_value_t = 1460)]
+    synproxy_mss: u16,
+
+    /// Window scale SYNPROXY announces to clients, should match what the server uses
+    #[arg(long, default_value_t = 7, value_parser = clap::value_parser!(u8).range(0..=14))]
+    synproxy_wscale: u8,
+
+    /// What programs the firewall, nftables doesn't need the ipset and iptables tools and pf is
+    /// for FreeBSD
+    #[arg(long, value_enum, default_value_t = firewall::Backend::Iptables)]
+    backend: firewall::Backend,
+
+    /// Seconds between checks that the chains, hooks and sets are still in the kernel, missing
+    /// ones are put back (0 disables the watchdog)
+    #[arg(long, default_value_t = 30)]
+    watchdog_interval: u64,
+
+    /// Seconds between comparisons of the whitelist set with the entries mortis knows of, live
+    /// ones missing from it are added back and unknown ones removed (0 disables them)
+    #[arg(long, default_value_t = 300)]
+    reconcile_interval: u64,
+
+    /// Seconds between readings of the counters of the mortis rules for `/stats/firewall` and
+    /// the metrics (0 disables them)
+    #[arg(long, default_value_t = 10)]
+    counter_interval: u64,
+
+    /// Reuse the sets a previous run left behind, e.g. after a crash, and carry on with the
+    /// whitelist entries in them
+    #[arg(long)]
+    adopt: bool,
+
+    /// Leave the chains and sets in place on exit, so the server stays protected until the next
+    /// run takes them over with --adopt. SIGUSR2 toggles it while running
+    #[arg(long)]
+    no_clean_on_exit: bool,
+
+    /// Remove the chains, rules going to them and sets a previous run left behind before setting
+    /// up, e.g. after a crash. Without --instance-name the chains of named instances go as well
+    #[arg(long, conflicts_with = "adopt")]
+    flush_on_start: bool,
+
+    /// Print the sets and rules mortis would create for these arguments and exit, without
+    /// touching the firewall
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Drop UDP packets to the protected ports that conntrack finds INVALID
+    #[arg(long, conflicts_with = "monitor_only")]
+    drop_invalid: bool,
+
+    /// Drop IP fragments, they would get past the port matches and rate limits. Fragments of
+    /// any port are dropped, their ports can't be told
+    #[arg(long, conflicts_with = "monitor_only")]
+    drop_fragments: bool,
+
+    /// Program the iptables chains through firewalld's direct interface, for hosts where
+    /// firewalld owns the ruleset and drops other rules whenever it reloads. This runs
+    /// firewall-cmd rather than speaking D-Bus, and uses direct rules rather than rich rules.
+    /// The sets stay plain ipsets, not firewalld-managed ones, which would take a firewall-cmd
+    /// run per admission
+    #[arg(long)]
+    firewalld: bool,
+
+    /// Which iptables programs the backend runs, auto picks the one the existing rules are in
+    #[arg(long, value_enum, default_value_t = engine::IptablesVariant::Auto)]
+    iptables_variant: engine::IptablesVariant,
+
+    /// Where the protected ports are filtered, raw drops floods before conntrack tracks them
+    #[arg(long, value_enum, default_value_t = firewall::Hook::Input)]
+    hook: firewall::Hook,
+
+    /// Position of the mortis rules in the built-in chains they go into, 1 puts them on top. The
+    /// chains need as many rules ahead of it. Only the iptables backend has one, nftables hooks
+    /// are base chains of their own and firewalld orders its rules itself
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    hook_position: u32,
+
+    /// Suffix for the chains, sets and hashlimits of this instance, so several can run on one
+    /// host (up to 8 lowercase letters and digits)
+    #[arg(long, value_parser = parse_instance_name)]
+    instance_name: Option<String>,
+
+    /// Interface to attach the XDP prefilter to, which drops floods before the firewall sees
+    /// them (disabled when unset)
+    #[cfg(all(feature = "xdp", target_os = "linux"))]
+    #[arg(long, requires = "xdp_object", conflicts_with = "monitor_only")]
+    xdp_interface: Option<String>,
+
+    /// The compiled bpf/mortis.bpf.c
+    #[cfg(all(feature = "xdp", target_os = "linux"))]
+    #[arg(long)]
+    xdp_object: Option<PathBuf>,
+
+    /// TOML file with additional settings, e.g. extra_rules. Reloaded on SIGHUP
     #[arg(short, long)]
-    protect: String,
+    config: Option<PathBuf>,
+
+    /// Address for the admin API to listen on (disabled when unset)
+    #[arg(long)]
+    admin_listen: Option<SocketAddr>,
+
+    /// Unix socket for the admin API to listen on, next to or instead of --admin-listen. Only
+    /// its owner may connect. Unix only
+    #[arg(long)]
+    admin_socket: Option<PathBuf>,
+
+    /// File holding the bearer token every admin API request has to carry
+    #[arg(long)]
+    admin_token_file: Option<PathBuf>,
+
+    /// Serve a Swagger UI of the admin API at /admin/docs, which loads its scripts from
+    /// unpkg.com. /openapi.json is served either way
+    #[arg(long)]
+    admin_docs: bool,
+
+    /// Directory to periodically write whitelist snapshots to (disabled when unset)
+    #[arg(long)]
+    snapshot_dir: Option<PathBuf>,
+
+    /// Seconds between whitelist snapshots
+    #[arg(long, default_value_t = 300)]
+    snapshot_interval: u64,
+
+    /// Number of snapshots to keep in the snapshot directory
+    #[arg(long, default_value_t = 12)]
+    snapshot_retention: usize,
+
+    /// File to mirror the whitelist into for a reverse proxy (disabled when unset)
+    #[arg(long)]
+    export_file: Option<PathBuf>,
+
+    /// Format of the export file
+    #[arg(long, value_enum, default_value_t = export::ExportFormat::Nginx)]
+    export_format: export::ExportFormat,
+
+    /// Seconds between export file updates
+    #[arg(long, default_value_t = 5)]
+    export_interval: u64,
+
+    /// Hostname whose addresses bypass mortis entirely (repeatable)
+    #[arg(long)]
+    allow_host: Vec<String>,
+
+    /// Network that bypasses mortis entirely, e.g. 10.0.0.0/8 or a single address (repeatable)
+    #[arg(long)]
+    allow_net: Vec<cidr::Cidr>,
+
+    /// Seconds between re-resolving --allow-host names
+    #[arg(long, default_value_t = 300)]
+    allow_host_interval: u64,
+
+    /// DNS server outbound integrations resolve names with, instead of the ones in
+    /// /etc/resolv.conf (repeatable)
+    #[arg(long)]
+    dns_server: Vec<IpAddr>,
+
+    /// Seconds the last answer for a name keeps being used while its lookups fail
+    #[arg(long, default_value_t = 3600)]
+    dns_stale: u64,
+
+    /// Packets per second a whitelisted source may send to a port, for the default ruleset and
+    /// rulesets that don't set their own
+    #[arg(long, default_value_t = firewall::DEFAULT_WHITELIST_LIMIT)]
+    whitelist_limit: u32,
+
+    /// Kilobytes per second a whitelisted source may send to a port, on top of
+    /// --whitelist-limit and like it. Unset lets large packets through at the packet rate
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    whitelist_bandwidth: Option<u32>,
+
+    /// Packets per second an unknown source may send to a port, like --whitelist-limit (0 drops
+    /// every source that isn't whitelisted)
+    #[arg(long, default_value_t = firewall::DEFAULT_UNKNOWN_LIMIT)]
+    unknown_limit: u32,
+
+    /// Packets per second the unknown sources of one --subnet-srcmask network may send to a port
+    /// together, on top of --unknown-limit for each, against floods from addresses spread over
+    /// a network (0 disables it)
+    #[arg(long, default_value_t = 0)]
+    unknown_subnet_limit: u32,
+
+    /// Prefix length IPv4 sources share --unknown-subnet-limit by
+    #[arg(long, default_value_t = 24, value_parser = clap::value_parser!(u8).range(1..=32))]
+    subnet_srcmask: u8,
+
+    /// Prefix length IPv6 sources share --unknown-subnet-limit by
+    #[arg(long, default_value_t = 48, value_parser = clap::value_parser!(u8).range(1..=128))]
+    subnet_srcmask6: u8,
+
+    /// Packets a source may send at once before the limits apply, like --whitelist-limit
+    #[arg(long, default_value_t = firewall::DEFAULT_BURST)]
+    hashlimit_burst: u32,
+
+    /// What the limits count packets by
+    #[arg(long, value_enum, default_value_t = firewall::HashlimitMode::SrcipDstport)]
+    hashlimit_mode: firewall::HashlimitMode,
+
+    /// Prefix length IPv4 sources share a rate by, IPv6 sources share one per --ipv6-prefix
+    #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u8).range(1..=32))]
+    hashlimit_srcmask: u8,
+
+    /// Seconds newly whitelisted sources stay under the tighter probation limit, reset whenever
+    /// they exceed it (0 disables probation)
+    #[arg(long, default_value_t = 0)]
+    probation_period: u32,
+
+    /// Packets per second allowed from sources on probation
+    #[arg(long, default_value_t = firewall::DEFAULT_PROBATION_LIMIT)]
+    probation_limit: u32,
+
+    /// Seconds a client making an HTTP request may send at --grace-limit while the request is
+    /// validated (0 disables the grace set)
+    #[arg(long, default_value_t = 0)]
+    grace_period: u32,
+
+    /// Packets per second allowed from sources in the grace set
+    #[arg(long, default_value_t = 30)]
+    grace_limit: u32,
+
+    /// Most entries of each whitelist set, when expecting more unique players than the kernel
+    /// default of 65536. A full ipset replaces random entries, a full nftables set refuses them
+    #[arg(long)]
+    whitelist_maxelem: Option<u32>,
+
+    /// Initial hash size of the whitelist ipsets, the kernel grows it as entries come in
+    #[arg(long)]
+    whitelist_hashsize: Option<u32>,
+
+    /// Note the instance, time and user agent of every whitelist add in the comment of the entry
+    #[arg(long)]
+    entry_comments: bool,
+
+    /// Address that stays whitelisted and never expires (repeatable)
+    #[arg(long)]
+    pin: Vec<IpAddr>,
+
+    /// Number of whitelist events kept in memory for /admin/history
+    #[arg(long, default_value_t = 10000)]
+    journal_capacity: usize,
+
+    /// File to append every whitelist event to as JSON lines, e.g. for `mortis-rs replay`
+    #[arg(long)]
+    journal_file: Option<PathBuf>,
+
+    /// Region name reported to clients by /region
+    #[arg(long)]
+    region: Option<String>,
+
+    /// Addresses a single SDK session may whitelist at the same time
+    #[arg(long, default_value_t = 3)]
+    session_max_ips: usize,
+
+    /// Milliseconds firewall operations may take on average before admissions are applied
+    /// asynchronously (0 disables the fallback)
+    #[arg(long, default_value_t = 100)]
+    latency_budget_ms: u64,
+
+    /// File holding the key refresh URLs are signed with, random per process when unset
+    #[arg(long)]
+    refresh_secret_file: Option<PathBuf>,
+
+    /// File holding the key the game server signs `/auth/...` URLs with, see the auth module.
+    /// Once set, web requests need a signed URL or a token unless `[pipeline] web` says otherwise
+    #[arg(long)]
+    auth_secret_file: Option<PathBuf>,
+
+    /// Seconds the clock of the game server may be off when checking signed URLs
+    #[arg(long, default_value_t = 30)]
+    auth_skew: u64,
+
+    /// Seconds into the future a signed URL may expire at most
+    #[arg(long, default_value_t = 600)]
+    auth_max_lifetime: u64,
+
+    /// Log and count what mortis would drop instead of dropping it, to validate thresholds
+    /// against real traffic
+    #[arg(long)]
+    monitor_only: bool,
+
+    /// NFLOG group used for reporting in monitor-only mode
+    #[arg(long, default_value_t = 101)]
+    monitor_nflog_group: u16,
+
+    /// Dropped packets per second logged to --drop-nflog-group for every drop reason, to find
+    /// the sources and ports under attack (0 disables the sampling)
+    #[arg(long, default_value_t = 0, conflicts_with = "monitor_only")]
+    drop_sample_rate: u32,
+
+    /// NFLOG group used for sampling dropped packets
+    #[arg(long, default_value_t = 104)]
+    drop_nflog_group: u16,
+
+    /// Host on a directly connected network that copies of dropped packets are sent to, e.g. an
+    /// IDS, with TEE or nft dup. Repeat it for an IPv6 host, at most one of each family
+    #[arg(long, conflicts_with = "monitor_only")]
+    mirror_to: Vec<IpAddr>,
+
+    /// Dropped packets per second mirrored to --mirror-to for every drop reason
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    mirror_rate: u32,
+
+    /// Directory to write packet captures to (captures are disabled when unset)
+    #[arg(long)]
+    capture_dir: Option<PathBuf>,
+
+    /// Seconds a packet capture runs unless it is started with another duration
+    #[arg(long, default_value_t = 60)]
+    capture_duration: u64,
+
+    /// Bytes after which a packet capture stops unless it is started with another limit
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    capture_max_bytes: u64,
+
+    /// Packets per second a capture copies at most
+    #[arg(long, default_value_t = 5000)]
+    capture_rate: u32,
+
+    /// NFLOG group used for packet captures
+    #[arg(long, default_value_t = 103)]
+    capture_nflog_group: u16,
+
+    /// NFLOG group used for admin-triggered packet sampling
+    #[arg(long, default_value_t = 100)]
+    sampling_nflog_group: u16,
+
+    /// Packets per second sampled from whitelisted sources to watch their source ports (0
+    /// disables the check). Players sharing an address behind a NAT count as one source
+    #[arg(long, default_value_t = 0)]
+    sport_sample_rate: u32,
+
+    /// Distinct source ports a whitelisted source may be sampled with per minute before it is
+    /// removed from the whitelist
+    #[arg(long, default_value_t = 64)]
+    sport_limit: usize,
+
+    /// Seconds to ban sources for that go over --sport-limit, instead of only removing them
+    /// from the whitelist (0 only removes them)
+    #[arg(long, default_value_t = 0)]
+    sport_ban: u64,
+
+    /// NFLOG group used for source port sampling
+    #[arg(long, default_value_t = 102)]
+    sport_nflog_group: u16,
+
+    /// Prefix length IPv6 sources are admitted, limited and expired by, instead of single
+    /// addresses
+    #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u8).range(1..=128))]
+    ipv6_prefix: u8,
+
+    /// Only filter IPv4: no ip6tables chains or IPv6 sets, and the listener only binds 0.0.0.0
+    #[arg(long)]
+    no_ipv6: bool,
+
+    /// CPU usage in percent that, sustained, degrades service tier by tier: first the admin
+    /// API, then /metrics, then new admissions (0 ignores CPU usage)
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    overload_cpu: u8,
+
+    /// Admissions queued on the slow path that count as overload, like --overload-cpu
+    /// (0 ignores the queue)
+    #[arg(long, default_value_t = 0)]
+    overload_queue: usize,
+
+    /// New addresses per hour one subnet may get whitelisted (0 disables the quota)
+    #[arg(long, default_value_t = 0)]
+    subnet_quota: u32,
+
+    /// IPv4 prefix length the subnet quota groups addresses by
+    #[arg(long, default_value_t = 24, value_parser = clap::value_parser!(u8).range(0..=32))]
+    subnet_quota_prefix: u8,
+
+    /// IPv6 prefix length the subnet quota groups addresses by, at most --ipv6-prefix
+    #[arg(long, default_value_t = 48, value_parser = clap::value_parser!(u8).range(0..=128))]
+    subnet_quota_prefix6: u8,
+
+    /// Requests per second one client may make to the public listener, more get a 429
+    /// (0 disables the limit)
+    #[arg(long, default_value_t = 0)]
+    rate_limit: u32,
+
+    /// Requests one client may make at once before --rate-limit applies
+    #[arg(long, default_value_t = 20)]
+    rate_limit_burst: u32,
+
+    /// Connections the public listener keeps open at once, more are closed as soon as they are
+    /// accepted (0 for no limit)
+    #[arg(long, default_value_t = 0)]
+    max_connections: usize,
+
+    /// Requests the public listener handles at once, more get a 503 (0 for no limit)
+    #[arg(long, default_value_t = 0)]
+    max_requests: usize,
+
+    /// Delete the conntrack flows of a source to the protected ports as soon as it leaves the
+    /// whitelist, needs the conntrack tool
+    #[arg(long)]
+    conntrack_flush: bool,
+}
+
+impl Args {
+    /// Every port of the repeated --protect flags.
+    fn protected_ports(&self) -> ports::Ports {
+        ports::Ports::merge(&self.protect)
+    }
+
+    /// Every port of the repeated --protect-tcp flags, `None` when there are none.
+    fn protected_tcp_ports(&self) -> Option<ports::Ports> {
+        Some(ports::Ports::merge(&self.protect_tcp)).filter(|ports| !ports.is_empty())
+    }
+}
+
+/// Kept short so the longest chain name stays within the 28 characters iptables allows.
+fn parse_instance_name(name: &str) -> std::result::Result<String, String> {
+    if name.is_empty()
+        || name.len() > 8
+        || !name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+    {
+        return Err("expected 1 to 8 lowercase letters and digits".to_string());
+    }
+    Ok(name.to_string())
+}
+
+/// Push back the expiry of a client that is whitelisted already, for games that keep an HTTP
+/// request going during long matches. Clients that aren't get a 404 and have to be admitted
+/// through `/` again. Pinning them through the admin API takes the expiry away altogether.
+async fn keepalive(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
+) -> std::result::Result<StatusCode, AppError> {
+    let ip = client.unit(state.args.ipv6_prefix);
+    if !cleaner::is_whitelisted(&state, ip).await {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+    pipeline::whitelist(&state, ip, None).await?;
+
+    Ok(StatusCode::NO_CONTENT)
 }
 
 async fn handler(
     key: Option<Path<String>>,
     State(state): State<Arc<AppState>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
     TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
+    request_headers: HeaderMap,
 ) -> std::result::Result<Response, AppError> {
-    if !user_agent.as_str().contains("GMod") {
-        return Ok(StatusCode::FORBIDDEN.into_response());
-    }
+    let key = key.map(|Path(key)| key);
+    admit(&state, client, &user_agent, &request_headers, None, key).await
+}
 
-    let ip = addr.ip();
+/// Like [`handler`] for the `/t/<token>/...` URLs, see [`tokens`].
+async fn token_handler(
+    Path(params): Path<HashMap<String, String>>,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
+    TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
+    request_headers: HeaderMap,
+) -> std::result::Result<Response, AppError> {
+    let token = params.get("token").map(|token| Credential::Token(token));
+    let key = params.get("key").cloned();
+    admit(&state, client, &user_agent, &request_headers, token, key).await
+}
 
-    let mut whitelist = state.whitelist.lock().await;
+/// Like [`handler`] for the signed `/auth/<expiry>/<signature>/...` URLs, see [`auth`].
+async fn auth_handler(
+    Path(params): Path<HashMap<String, String>>,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
+    TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
+    request_headers: HeaderMap,
+) -> std::result::Result<Response, AppError> {
+    let expiry = params.get("expiry").and_then(|expiry| expiry.parse().ok());
+    let signed = expiry
+        .zip(params.get("signature"))
+        .map(|(expiry, signature)| Credential::Signed {
+            client: client.addr.ip(),
+            expiry,
+            signature,
+        });
+    let key = params.get("key").cloned();
+    admit(&state, client, &user_agent, &request_headers, signed, key).await
+}
 
-    if whitelist.contains_key(&ip) == false {
-        let mut ipset = state.ipset_session.lock().await;
-        ipset.add(ip, &[])?;
+async fn admit(
+    state: &AppState,
+    client: ClientInfo,
+    user_agent: &headers::UserAgent,
+    request_headers: &HeaderMap,
+    credential: Option<Credential<'_>>,
+    key: Option<String>,
+) -> std::result::Result<Response, AppError> {
+    let ip = client.unit(state.args.ipv6_prefix);
+    if let Some(status) = pipeline::admit(
+        state,
+        Profile::Web,
+        ip,
+        Some(user_agent.as_str()),
+        credential,
+        steam::ticket(request_headers),
+    )
+    .await?
+    {
+        return Ok(status.into_response());
     }
 
-    whitelist.insert(ip, Instant::now());
-
-    drop(whitelist);
-
-    if let Some(path) = key {
-        return Ok(Redirect::temporary(&path).into_response());
+    if let Some(key) = key {
+        return Ok(redirect::respond(state, &key));
     }
 
     Ok(StatusCode::OK.into_response())
@@ -102,13 +824,8 @@ async fn shutdown_signal(state: Arc<AppState>) {
     let terminate = std::future::pending::<()>();
 
     let clean = || async {
-        let protected_port = state.args.protect.clone();
-        let ipt = &state.iptables;
-        let mut binding = state.ipset_session.lock().await;
-        let ipset_session = binding.deref_mut();
-
-        firewall::clean_iptables(ipt, &protected_port).unwrap();
-        firewall::clean_ipset(ipset_session).unwrap();
+        let report = shutdown::clean(&state).await;
+        shutdown::finish(&state, report).await;
     };
 
     tokio::select! {
@@ -123,45 +840,483 @@ async fn shutdown_signal(state: Arc<AppState>) {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .init();
+
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(args) = &mut cli.args {
+        game::apply(args, &matches);
+    }
+
+    match cli.command {
+        Some(Command::Restore {
+            snapshot,
+            admin_url,
+            admin_token_file,
+        }) => {
+            let token = admin_token_file.as_deref().map(admin::token).transpose()?;
+            snapshot::restore_remote(&admin_url, token.as_deref(), &snapshot).await
+        }
+        Some(Command::Selftest(args)) => selftest::run(args).await,
+        Some(Command::Replay(args)) => replay::run(args),
+        Some(Command::Status {
+            admin_url,
+            admin_token_file,
+            format,
+        }) => {
+            let token = admin_token_file.as_deref().map(admin::token).transpose()?;
+            status::print_remote(&admin_url, token.as_deref(), format).await
+        }
+        Some(Command::VerifyWebhook {
+            secret_file,
+            timestamp,
+            signature,
+            tolerance,
+        }) => verify_webhook(&secret_file, timestamp, &signature, tolerance),
+        None => {
+            run(cli
+                .args
+                .expect("clap requires the run arguments without a subcommand"))
+            .await
+        }
+    }
+}
+
+/// Bind the unix socket at `path`, replacing the one a previous run left behind.
+#[cfg(unix)]
+fn bind_socket(path: &std::path::Path) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove the stale socket {}", path.display()))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind admin API to {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict access to {}", path.display()))?;
+    Ok(listener)
+}
+
+fn verify_webhook(
+    secret_file: &std::path::Path,
+    timestamp: u64,
+    signature: &str,
+    tolerance: u64,
+) -> Result<()> {
+    let secret = std::fs::read_to_string(secret_file)
+        .with_context(|| format!("Failed to read {}", secret_file.display()))?;
+    let mut body = Vec::new();
+    std::io::Read::read_to_end(&mut std::io::stdin(), &mut body)?;
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", &args.listen))
+    if !signing::verify_webhook(
+        secret.trim_end().as_bytes(),
+        timestamp,
+        &body,
+        signature,
+        snapshot::unix_now(),
+        Duration::from_secs(tolerance),
+    ) {
+        anyhow::bail!("signature is invalid or the timestamp is too old");
+    }
+    println!("signature OK");
+
+    Ok(())
+}
+
+async fn run(args: Args) -> Result<()> {
+    let protected = args.protected_ports();
+    if protected.is_empty() {
+        anyhow::bail!("--protect needs at least one port");
+    }
+    let mirror_families: HashSet<_> = args
+        .mirror_to
+        .iter()
+        .map(|ip| ip.to_canonical().is_ipv4())
+        .collect();
+    if mirror_families.len() < args.mirror_to.len() {
+        anyhow::bail!("--mirror-to takes at most one IPv4 and one IPv6 host");
+    }
+
+    let config = match &args.config {
+        Some(path) => config::load(path)?,
+        None => config::Config::default(),
+    };
+    if args.dry_run {
+        return dryrun::print(&args, &config, &protected);
+    }
+
+    // Binding :: accepts IPv4 as well, as mapped addresses
+    let host = if args.no_ipv6 { "0.0.0.0" } else { "[::]" };
+    let listener = tokio::net::TcpListener::bind(format!("{}:{}", host, &args.listen))
         .await
         .with_context(|| format!("Failed to bind to port {}", &args.listen))?;
 
-    let ipset_session =
-        firewall::setup_ipset().map_err(|e| anyhow::anyhow!("Failed to setup ipset: {}", e))?;
-    let iptables = firewall::setup_iptables(&args.protect)
-        .map_err(|e| anyhow::anyhow!("Failed to setup iptables: {}", e))?;
+    let acme = match &args.acme_domain {
+        Some(domain) => {
+            let acme = acme::Acme::new(
+                domain.clone(),
+                args.acme_email.as_deref(),
+                &args.acme_dir,
+                args.acme_staging,
+                args.acme_http_listen,
+            )
+            .await?;
+            if acme.due() {
+                match acme.issue().await {
+                    Ok(()) => {}
+                    // Renewals are tried again later, the current certificate is still valid
+                    Err(e) if acme.cert_path().exists() => {
+                        tracing::warn!("Failed to renew the certificate of {}: {:#}", domain, e)
+                    }
+                    Err(e) => return Err(e.context("Failed to obtain a certificate")),
+                }
+            }
+            Some(acme)
+        }
+        None => None,
+    };
+    let tls = match (&args.tls_cert, &args.tls_key, &acme) {
+        (Some(cert), Some(key), _) => Some(Arc::new(tls::Certificates::load(cert, key)?)),
+        (_, _, Some(acme)) => Some(Arc::new(tls::Certificates::load(
+            &acme.cert_path(),
+            &acme.key_path(),
+        )?)),
+        _ => None,
+    };
+
+    let admin_listener = match args.admin_listen {
+        Some(addr) => Some(
+            tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to bind admin API to {}", addr))?,
+        ),
+        None => None,
+    };
+    #[cfg(unix)]
+    let admin_socket = match &args.admin_socket {
+        Some(path) => Some(bind_socket(path)?),
+        None => None,
+    };
+    #[cfg(not(unix))]
+    if args.admin_socket.is_some() {
+        anyhow::bail!("--admin-socket needs unix sockets, use --admin-listen on this platform");
+    }
+    let admin_token = args
+        .admin_token_file
+        .as_deref()
+        .map(admin::token)
+        .transpose()?;
+    if admin_token.is_none()
+        && let Some(addr) = args.admin_listen
+        && !addr.ip().is_loopback()
+    {
+        tracing::warn!(
+            "The admin API listens on {} without --admin-token-file, anyone who can reach it may use it",
+            addr
+        );
+    }
+
+    if let Some(dir) = &args.snapshot_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create snapshot directory {}", dir.display()))?;
+    }
+    if let Some(dir) = &args.capture_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create capture directory {}", dir.display()))?;
+    }
+
+    let synproxy = args
+        .synproxy
+        .then(synproxy::Tuning::apply)
+        .transpose()
+        .context("Failed to tune conntrack for SYNPROXY")?;
+    let chain_options = firewall::ChainOptions::new(&args, &config, args.probation_period > 0);
+    let names = &chain_options.names;
+    let mut backend = firewall::backend(&args, names)
+        .map_err(|e| anyhow::anyhow!("Failed to setup the firewall backend: {}", e))?;
+    if args.flush_on_start {
+        backend
+            .purge(names)
+            .map_err(|e| anyhow::anyhow!("Failed to remove what a previous run left: {}", e))?;
+    }
+    #[cfg(all(feature = "xdp", target_os = "linux"))]
+    if let (Some(interface), Some(object)) = (&args.xdp_interface, &args.xdp_object) {
+        backend = Box::new(
+            xdp::Xdp::attach(backend, object, interface, &protected, &chain_options)
+                .context("Failed to setup XDP")?,
+        );
+    }
+    let ipset_session = firewall::setup_ipset(backend.as_mut(), names, &args)
+        .map_err(|e| anyhow::anyhow!("Failed to setup ipset: {}", e))?;
+    let allow_session = firewall::setup_allow_ipset(backend.as_mut(), names)
+        .map_err(|e| anyhow::anyhow!("Failed to setup allow ipset: {}", e))?;
+    let blacklist_session = firewall::setup_blacklist_ipset(backend.as_mut(), names)
+        .map_err(|e| anyhow::anyhow!("Failed to setup blacklist ipset: {}", e))?;
+    let probation_session = match args.probation_period {
+        0 => None,
+        period => Some(
+            firewall::setup_probation_ipset(backend.as_mut(), names, period)
+                .map_err(|e| anyhow::anyhow!("Failed to setup probation ipset: {}", e))?,
+        ),
+    };
+    let grace_session = match args.grace_period {
+        0 => None,
+        period => Some(
+            firewall::setup_grace_ipset(backend.as_mut(), names, period)
+                .map_err(|e| anyhow::anyhow!("Failed to setup grace ipset: {}", e))?,
+        ),
+    };
+    let firewall = firewall::Firewall::setup(
+        backend,
+        &protected,
+        args.protected_tcp_ports().as_ref(),
+        &chain_options,
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to setup iptables: {}", e))?;
+
+    let metrics = metrics::Metrics::new(&protected.to_string())?;
+    let mut journal = journal::Journal::new(args.journal_capacity);
+    let journal_events = args.journal_file.is_some().then(|| journal.persist());
+    let budget =
+        (args.latency_budget_ms > 0).then(|| Duration::from_millis(args.latency_budget_ms));
+    let (slow_path, pending_worker) = pending::SlowPath::new(budget);
+    let resolver = Arc::new(dns::Resolver::new(
+        &args.dns_server,
+        Duration::from_secs(args.dns_stale),
+    )?);
+    let client = resolver
+        .client()
+        .build()
+        .context("Failed to build the HTTP client")?;
+    let admission = policy::Admission::new(&config, args.game);
+    let redirects = redirect::Redirects::new(&config);
+    let (notifier, notify_bus) =
+        notify::Notifier::new(config.notify, config.dead_letter_file, client.clone());
+    let refresher =
+        refresh::Refresher::new(args.refresh_secret_file.as_deref(), args.session_max_ips)?;
+    let auth = args
+        .auth_secret_file
+        .as_deref()
+        .map(|path| {
+            auth::Verifier::new(
+                path,
+                Duration::from_secs(args.auth_skew),
+                Duration::from_secs(args.auth_max_lifetime),
+            )
+        })
+        .transpose()?;
 
     let state = Arc::new(AppState {
-        iptables,
+        firewall: std::sync::Mutex::new(firewall),
         ipset_session: Mutex::new(ipset_session),
+        allow_session: Mutex::new(allow_session),
+        blacklist_session: Mutex::new(blacklist_session),
+        probation_session: probation_session.map(Mutex::new),
+        grace_session: grace_session.map(Mutex::new),
         whitelist: Mutex::new(std::collections::HashMap::new()),
+        pinned: Mutex::new(std::collections::HashSet::new()),
+        allowed_nets: Mutex::new(std::collections::BTreeSet::new()),
+        allowed_hosts: Mutex::new(std::collections::HashSet::new()),
+        allowed_cloudflare: Mutex::new(std::collections::BTreeSet::new()),
+        cloudflare_ranges: std::sync::RwLock::new(Vec::new()),
+        bans: Mutex::new(std::collections::HashMap::new()),
+        sampling: Mutex::new(None),
+        capture: Mutex::new(None),
+        metrics,
+        journal,
+        refresher,
+        slow_path,
+        notifier,
+        resolver,
+        monitor: args.monitor_only.then(monitor::Monitor::default),
+        drops: (args.drop_sample_rate > 0).then(drops::DropSampler::default),
+        counters: counters::Counters::default(),
+        admission: std::sync::RwLock::new(admission),
+        pipelines: std::sync::RwLock::new(pipeline::Pipelines::new(
+            &config.pipeline,
+            !config.tokens.is_empty() || args.auth_secret_file.is_some(),
+            config.steam.is_some(),
+        )),
+        tokens: std::sync::RwLock::new(tokens::Tokens::new(&config.tokens)),
+        redirects: std::sync::RwLock::new(redirects),
+        auth,
+        steam: config
+            .steam
+            .map(|steam| steam::Steam::new(steam, client.clone())),
+        started: Instant::now(),
+        last_sweep: std::sync::Mutex::new(None),
+        degradation: overload::Degradation::default(),
+        quota: (args.subnet_quota > 0).then(|| {
+            quota::SubnetQuota::new(
+                args.subnet_quota,
+                args.subnet_quota_prefix,
+                args.subnet_quota_prefix6,
+                args.ipv6_prefix,
+            )
+        }),
+        requests: (args.max_requests > 0).then(|| tokio::sync::Semaphore::new(args.max_requests)),
+        rate_limiter: (args.rate_limit > 0)
+            .then(|| ratelimit::RateLimiter::new(args.rate_limit, args.rate_limit_burst)),
+        synproxy,
+        keep_on_exit: std::sync::atomic::AtomicBool::new(args.no_clean_on_exit),
+        tls,
         args,
     });
+
+    if state.args.adopt {
+        let summary = snapshot::adopt(&state)
+            .await
+            .context("Failed to adopt the existing whitelist")?;
+        if summary.restored > 0 {
+            tracing::info!("Adopted {} whitelist entries", summary.restored);
+        }
+    }
+
+    for net in state.args.allow_net.clone() {
+        allow::allow(&state, net)
+            .await
+            .with_context(|| format!("Failed to allow {}", net))?;
+    }
+
+    for ip in state.args.pin.clone() {
+        pins::pin(&state, ip)
+            .await
+            .with_context(|| format!("Failed to pin {}", ip))?;
+    }
+
     let app = Router::new()
+        .route("/region", get(region::region))
+        .route("/sdk/session", get(refresh::start))
+        .route("/sdk/refresh/{token}", get(refresh::refresh))
+        .route("/keepalive", post(keepalive))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
         .route("/", any(handler))
         .route("/{*key}", any(handler))
+        .route("/t/{token}", any(token_handler))
+        .route("/t/{token}/{*key}", any(token_handler))
+        .route("/auth/{expiry}/{signature}", any(auth_handler))
+        .route("/auth/{expiry}/{signature}/{*key}", any(auth_handler));
+    let app = proxy::routes(app, config.proxy, client.clone())
         .layer((
             TraceLayer::new_for_http(),
+            axum::middleware::from_fn_with_state(state.clone(), forwarded::real_client),
+            axum::middleware::from_fn_with_state(
+                (state.clone(), "public"),
+                metrics::track_requests,
+            ),
+            axum::middleware::from_fn_with_state(state.clone(), ratelimit::limit),
+            axum::middleware::from_fn_with_state(state.clone(), concurrency::limit),
             // Graceful shutdown will wait for outstanding requests to complete. Add a timeout so
             // requests don't hang forever.
             TimeoutLayer::new(Duration::from_secs(10)),
         ))
         .with_state(state.clone());
 
+    #[cfg(unix)]
+    {
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            reload::task(state_clone).await;
+        });
+        tokio::spawn(shutdown::toggle_task(state.clone()));
+    }
+
+    tokio::spawn(notify::task(notify_bus));
+
+    if let (Some(acme), Some(certificates)) = (acme, state.tls.clone()) {
+        tokio::spawn(acme::renew_task(acme, certificates));
+    }
+
+    if let (Some(path), Some(events)) = (state.args.journal_file.clone(), journal_events) {
+        tokio::spawn(async move {
+            if let Err(e) = journal::write_task(&path, events).await {
+                tracing::error!("Journal file stopped: {:#}", e);
+            }
+        });
+    }
+
+    if state.args.overload_cpu > 0 || state.args.overload_queue > 0 {
+        tokio::spawn(overload::task(state.clone()));
+    }
+
+    tokio::spawn(cleaner::task(state.clone()));
+    if state.args.watchdog_interval > 0 {
+        tokio::spawn(watchdog::task(state.clone()));
+    }
+    if state.args.reconcile_interval > 0 {
+        tokio::spawn(reconcile::task(state.clone()));
+    }
+    if state.args.counter_interval > 0 {
+        tokio::spawn(counters::task(state.clone()));
+    }
+
+    if state.args.sport_sample_rate > 0 {
+        tokio::spawn(entropy::task(state.clone()));
+    }
+
+    if state.monitor.is_some() {
+        tracing::warn!("Running in monitor-only mode, nothing is being dropped");
+        tokio::spawn(monitor::task(state.clone(), state.args.monitor_nflog_group));
+    }
+    if state.drops.is_some() {
+        tokio::spawn(drops::task(state.clone()));
+    }
+
     let state_clone = state.clone();
     tokio::spawn(async move {
-        cleaner::task(state_clone).await;
+        pending::task(state_clone, pending_worker).await;
     });
 
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .with_graceful_shutdown(shutdown_signal(state))
-    .await?;
+    if state.args.cloudflare {
+        tokio::spawn(cloudflare::task(state.clone(), client));
+    }
+
+    if !state.args.allow_host.is_empty() {
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            hosts::task(state_clone).await;
+        });
+    }
+
+    if let Some(path) = state.args.export_file.clone() {
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            export::task(state_clone, path).await;
+        });
+    }
+
+    if let Some(dir) = state.args.snapshot_dir.clone() {
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            snapshot::task(state_clone, dir).await;
+        });
+    }
+
+    let admin_app = admin::router(state.clone(), admin_token);
+    if let Some(admin_listener) = admin_listener {
+        let admin_app = admin_app.clone();
+        tokio::spawn(async move { axum::serve(admin_listener, admin_app).await });
+    }
+    #[cfg(unix)]
+    if let Some(admin_socket) = admin_socket {
+        tokio::spawn(async move { axum::serve(admin_socket, admin_app).await });
+    }
+
+    let app = app.into_make_service_with_connect_info::<ClientInfo>();
+    if state.args.proxy_protocol || state.tls.is_some() || state.args.max_connections > 0 {
+        let listener = listener::PublicListener::new(listener, state.clone())?;
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal(state))
+            .await?;
+    } else {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal(state))
+            .await?;
+    }
 
     Ok(())
 }
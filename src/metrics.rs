@@ -0,0 +1,582 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{
+    GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+    core::Collector,
+};
+
+use crate::{firewall::SetFull, policy::Track, state::AppState};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Outcome {
+    Admitted,
+    Refreshed,
+    RejectedUa,
+    /// The source's subnet ran out of quota, see [`crate::quota`]
+    RejectedQuota,
+    /// Came without a valid token, see [`crate::tokens`]
+    RejectedToken,
+    /// Came without a Steam ticket Steam accepted, see [`crate::steam`]
+    RejectedSteam,
+    /// Refused while overloaded, see [`crate::overload`]
+    Shed,
+    /// Refused over the per-client rate limit, see [`crate::ratelimit`]
+    RateLimited,
+    Expired,
+    Evicted,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Admitted => "admitted",
+            Outcome::Refreshed => "refreshed",
+            Outcome::RejectedUa => "rejected_ua",
+            Outcome::RejectedQuota => "rejected_quota",
+            Outcome::RejectedToken => "rejected_token",
+            Outcome::RejectedSteam => "rejected_steam",
+            Outcome::Shed => "shed",
+            Outcome::RateLimited => "rate_limited",
+            Outcome::Expired => "expired",
+            Outcome::Evicted => "evicted",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    /// Protect group this instance enforces, attached to every sample as the `group` label.
+    group: String,
+    events: IntCounterVec,
+    whitelist_entries: IntGaugeVec,
+    ipset_entries: IntGaugeVec,
+    ipset_maxelem: IntGaugeVec,
+    ipset_fill_ratio: GaugeVec,
+    conntrack_entries: IntGaugeVec,
+    conntrack_max: IntGaugeVec,
+    conntrack_fill_ratio: GaugeVec,
+    hashlimit_entries: IntGaugeVec,
+    netlink_errors: IntCounterVec,
+    set_full: IntCounterVec,
+    admission_retries: IntCounterVec,
+    watchdog_repairs: IntCounterVec,
+    reconcile_drift: IntCounterVec,
+    would_drop: IntCounterVec,
+    firewall_packets: IntCounterVec,
+    firewall_bytes: IntCounterVec,
+    firewall_packet_rate: GaugeVec,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    http_shed: IntCounterVec,
+    policy_decisions: IntCounterVec,
+    token_requests: IntCounterVec,
+    signed_requests: IntCounterVec,
+    steam_checks: IntCounterVec,
+    degradation_tier: IntGaugeVec,
+}
+
+fn register<C: Collector + Clone + 'static>(registry: &Registry, collector: C) -> Result<C> {
+    registry.register(Box::new(collector.clone()))?;
+    Ok(collector)
+}
+
+impl Metrics {
+    pub fn new(group: &str) -> Result<Self> {
+        let registry = Registry::new();
+
+        let events = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_whitelist_events_total",
+                    "Whitelist decisions by protect group and outcome",
+                ),
+                &["group", "outcome"],
+            )?,
+        )?;
+        let whitelist_entries = register(
+            &registry,
+            IntGaugeVec::new(
+                Opts::new(
+                    "mortis_whitelist_entries",
+                    "Number of currently whitelisted sources",
+                ),
+                &["group"],
+            )?,
+        )?;
+
+        let ipset_entries = register(
+            &registry,
+            IntGaugeVec::new(
+                Opts::new(
+                    "mortis_ipset_entries",
+                    "Number of entries in a mortis ipset",
+                ),
+                &["group", "set"],
+            )?,
+        )?;
+        let ipset_maxelem = register(
+            &registry,
+            IntGaugeVec::new(
+                Opts::new(
+                    "mortis_ipset_maxelem",
+                    "Maximum number of entries a mortis ipset can hold",
+                ),
+                &["group", "set"],
+            )?,
+        )?;
+        let ipset_fill_ratio = register(
+            &registry,
+            GaugeVec::new(
+                Opts::new(
+                    "mortis_ipset_fill_ratio",
+                    "Entries of a mortis ipset relative to its maxelem",
+                ),
+                &["group", "set"],
+            )?,
+        )?;
+
+        let conntrack_entries = register(
+            &registry,
+            IntGaugeVec::new(
+                Opts::new("mortis_conntrack_entries", "Entries in the conntrack table"),
+                &["group"],
+            )?,
+        )?;
+        let conntrack_max = register(
+            &registry,
+            IntGaugeVec::new(
+                Opts::new("mortis_conntrack_max", "Size limit of the conntrack table"),
+                &["group"],
+            )?,
+        )?;
+        let conntrack_fill_ratio = register(
+            &registry,
+            GaugeVec::new(
+                Opts::new(
+                    "mortis_conntrack_fill_ratio",
+                    "Conntrack entries relative to nf_conntrack_max",
+                ),
+                &["group"],
+            )?,
+        )?;
+
+        let hashlimit_entries = register(
+            &registry,
+            IntGaugeVec::new(
+                Opts::new(
+                    "mortis_hashlimit_entries",
+                    "Number of tracked buckets in a mortis hashlimit table",
+                ),
+                &["group", "name"],
+            )?,
+        )?;
+
+        let netlink_errors = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_netlink_errors_total",
+                    "Failed ipset operations by operation",
+                ),
+                &["group", "operation"],
+            )?,
+        )?;
+
+        let set_full = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_ipset_full_total",
+                    "Adds the kernel refused because the set was full, by set",
+                ),
+                &["group", "set"],
+            )?,
+        )?;
+
+        let admission_retries = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_admission_retries_total",
+                    "Admissions whose ipset operations failed, by whether they were retried, recovered on a retry or abandoned",
+                ),
+                &["group", "outcome"],
+            )?,
+        )?;
+
+        let reconcile_drift = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_reconcile_drift_total",
+                    "Whitelist entries the reconciler found missing from the kernel set or stray in it, by kind",
+                ),
+                &["group", "kind"],
+            )?,
+        )?;
+
+        let watchdog_repairs = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_watchdog_repairs_total",
+                    "Times the watchdog found rules or a set missing from the kernel, by kind",
+                ),
+                &["group", "kind"],
+            )?,
+        )?;
+
+        let would_drop = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_would_drop_packets_total",
+                    "Packets monitor-only mode let through that would have been dropped, by reason",
+                ),
+                &["group", "reason"],
+            )?,
+        )?;
+
+        let firewall_packets = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_firewall_packets_total",
+                    "Packets the mortis rules dropped or let through, by rule",
+                ),
+                &["group", "rule"],
+            )?,
+        )?;
+        let firewall_bytes = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_firewall_bytes_total",
+                    "Bytes the mortis rules dropped or let through, by rule",
+                ),
+                &["group", "rule"],
+            )?,
+        )?;
+        let firewall_packet_rate = register(
+            &registry,
+            GaugeVec::new(
+                Opts::new(
+                    "mortis_firewall_packets_per_second",
+                    "Packets per second the mortis rules matched between the last two readings, by rule",
+                ),
+                &["group", "rule"],
+            )?,
+        )?;
+
+        let http_requests = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_http_requests_total",
+                    "HTTP requests by listener, route, method and status",
+                ),
+                &["group", "listener", "route", "method", "status"],
+            )?,
+        )?;
+        let http_request_duration = register(
+            &registry,
+            HistogramVec::new(
+                HistogramOpts::new(
+                    "mortis_http_request_duration_seconds",
+                    "Time to respond to HTTP requests by listener, route and method",
+                ),
+                &["group", "listener", "route", "method"],
+            )?,
+        )?;
+        let http_shed = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_http_shed_total",
+                    "Connections and requests the public listener refused over --max-connections and --max-requests, by limit",
+                ),
+                &["group", "limit"],
+            )?,
+        )?;
+
+        let policy_decisions = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_policy_decisions_total",
+                    "Admission policy decisions by policy, baseline or canary, and decision",
+                ),
+                &["group", "policy", "decision"],
+            )?,
+        )?;
+
+        let token_requests = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_token_requests_total",
+                    "Requests that presented a valid token, by token name, to tell when a rotated out token is no longer used",
+                ),
+                &["group", "token"],
+            )?,
+        )?;
+
+        let signed_requests = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_signed_requests_total",
+                    "Requests to signed /auth URLs, by whether they were accepted or why not",
+                ),
+                &["group", "result"],
+            )?,
+        )?;
+
+        let steam_checks = register(
+            &registry,
+            IntCounterVec::new(
+                Opts::new(
+                    "mortis_steam_checks_total",
+                    "Steam tickets checked, by verdict, cached when Steam wasn't asked and error when it couldn't be",
+                ),
+                &["group", "result"],
+            )?,
+        )?;
+
+        let degradation_tier = register(
+            &registry,
+            IntGaugeVec::new(
+                Opts::new(
+                    "mortis_degradation_tier",
+                    "Current overload degradation tier, 0 when nothing is shed",
+                ),
+                &["group"],
+            )?,
+        )?;
+
+        Ok(Self {
+            registry,
+            group: group.to_string(),
+            events,
+            whitelist_entries,
+            ipset_entries,
+            ipset_maxelem,
+            ipset_fill_ratio,
+            conntrack_entries,
+            conntrack_max,
+            conntrack_fill_ratio,
+            hashlimit_entries,
+            netlink_errors,
+            set_full,
+            admission_retries,
+            watchdog_repairs,
+            reconcile_drift,
+            would_drop,
+            firewall_packets,
+            firewall_bytes,
+            firewall_packet_rate,
+            http_requests,
+            http_request_duration,
+            http_shed,
+            policy_decisions,
+            token_requests,
+            signed_requests,
+            steam_checks,
+            degradation_tier,
+        })
+    }
+
+    pub fn record(&self, outcome: Outcome) {
+        self.events
+            .with_label_values(&[&self.group, outcome.as_str()])
+            .inc();
+    }
+
+    pub fn record_token(&self, name: &str) {
+        self.token_requests
+            .with_label_values(&[&self.group, name])
+            .inc();
+    }
+
+    pub fn record_signed(&self, result: &str) {
+        self.signed_requests
+            .with_label_values(&[&self.group, result])
+            .inc();
+    }
+
+    pub fn record_steam(&self, result: &str) {
+        self.steam_checks
+            .with_label_values(&[&self.group, result])
+            .inc();
+    }
+
+    pub fn record_policy(&self, track: Track, allowed: bool) {
+        let decision = if allowed { "allowed" } else { "rejected" };
+        self.policy_decisions
+            .with_label_values(&[&self.group, track.as_str(), decision])
+            .inc();
+    }
+
+    pub fn set_degradation_tier(&self, tier: u8) {
+        self.degradation_tier
+            .with_label_values(&[&self.group])
+            .set(tier as i64);
+    }
+
+    pub fn set_whitelist_entries(&self, count: usize) {
+        self.whitelist_entries
+            .with_label_values(&[&self.group])
+            .set(count as i64);
+    }
+
+    pub fn set_ipset_usage(&self, set: &str, entries: u64, maxelem: u64) {
+        let labels = [self.group.as_str(), set];
+        self.ipset_entries
+            .with_label_values(&labels)
+            .set(entries as i64);
+        self.ipset_maxelem
+            .with_label_values(&labels)
+            .set(maxelem as i64);
+        if maxelem > 0 {
+            self.ipset_fill_ratio
+                .with_label_values(&labels)
+                .set(entries as f64 / maxelem as f64);
+        }
+    }
+
+    pub fn set_conntrack_usage(&self, entries: u64, max: u64) {
+        let labels = [self.group.as_str()];
+        self.conntrack_entries
+            .with_label_values(&labels)
+            .set(entries as i64);
+        self.conntrack_max
+            .with_label_values(&labels)
+            .set(max as i64);
+        if max > 0 {
+            self.conntrack_fill_ratio
+                .with_label_values(&labels)
+                .set(entries as f64 / max as f64);
+        }
+    }
+
+    pub fn set_hashlimit_entries(&self, name: &str, entries: u64) {
+        self.hashlimit_entries
+            .with_label_values(&[&self.group, name])
+            .set(entries as i64);
+    }
+
+    pub fn record_netlink_error(&self, operation: &str) {
+        self.netlink_errors
+            .with_label_values(&[&self.group, operation])
+            .inc();
+    }
+
+    /// A failed add, also counted by set when the set was full.
+    pub fn record_add_error(&self, e: &anyhow::Error) {
+        self.record_netlink_error("add");
+        if let Some(SetFull(set)) = e.downcast_ref() {
+            self.set_full
+                .with_label_values(&[self.group.as_str(), set])
+                .inc();
+        }
+    }
+
+    pub fn record_admission_retry(&self, outcome: &str) {
+        self.admission_retries
+            .with_label_values(&[&self.group, outcome])
+            .inc();
+    }
+
+    pub fn record_watchdog_repair(&self, kind: &str) {
+        self.watchdog_repairs
+            .with_label_values(&[&self.group, kind])
+            .inc();
+    }
+
+    pub fn record_reconcile_drift(&self, kind: &str, entries: usize) {
+        self.reconcile_drift
+            .with_label_values(&[&self.group, kind])
+            .inc_by(entries as u64);
+    }
+
+    pub fn record_would_drop(&self, reason: &str) {
+        self.would_drop
+            .with_label_values(&[&self.group, reason])
+            .inc();
+    }
+
+    /// What a kind of counted rule matched over `interval`, see [`crate::counters`].
+    pub fn record_firewall_traffic(
+        &self,
+        rule: &str,
+        packets: u64,
+        bytes: u64,
+        interval: Duration,
+    ) {
+        let labels = [self.group.as_str(), rule];
+        self.firewall_packets
+            .with_label_values(&labels)
+            .inc_by(packets);
+        self.firewall_bytes.with_label_values(&labels).inc_by(bytes);
+        self.firewall_packet_rate
+            .with_label_values(&labels)
+            .set(packets as f64 / interval.as_secs_f64());
+    }
+
+    fn record_request(&self, listener: &str, route: &str, method: &str, status: u16, secs: f64) {
+        self.http_requests
+            .with_label_values(&[&self.group, listener, route, method, &status.to_string()])
+            .inc();
+        self.http_request_duration
+            .with_label_values(&[&self.group, listener, route, method])
+            .observe(secs);
+    }
+
+    /// A connection or request refused over its `limit`, see [`crate::concurrency`].
+    pub fn record_http_shed(&self, limit: &str) {
+        self.http_shed
+            .with_label_values(&[&self.group, limit])
+            .inc();
+    }
+
+    pub fn render(&self) -> Result<String> {
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+}
+
+/// Middleware counting and timing every request on `listener`. Requests are labeled with the
+/// route they matched rather than their path, so probing random URLs can't add label values.
+pub async fn track_requests(
+    State((state, listener)): State<(Arc<AppState>, &'static str)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let method = request.method().clone();
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    state.metrics.record_request(
+        listener,
+        &route,
+        method.as_str(),
+        response.status().as_u16(),
+        started.elapsed().as_secs_f64(),
+    );
+
+    response
+}
@@ -0,0 +1,447 @@
+//! In-memory stand-ins for the `ipset` and `iptables` crates, used on targets without netfilter
+//! or with the `mock` feature. Everything above the firewall layer builds and runs against them,
+//! so handlers and the admin API can be developed on macOS or Windows. Nothing gets filtered.
+
+pub mod ipset {
+    use std::{collections::HashSet, marker::PhantomData};
+
+    use self::types::{Error, Key, SetType};
+
+    pub mod types {
+        use std::fmt;
+
+        pub struct HashIp;
+
+        pub struct HashNet;
+
+        /// Entries never expire in the mock and options aren't kept, so timeouts and comments are
+        /// ignored
+        #[derive(Debug)]
+        pub enum AddOption {
+            Timeout(#[allow(dead_code)] u32),
+            Comment(#[allow(dead_code)] String),
+        }
+
+        /// Adding an entry twice always succeeds in the mock
+        #[derive(Debug)]
+        pub enum EnvOption {
+            Exist,
+        }
+
+        pub struct IpDataType(pub(super) std::net::IpAddr);
+
+        impl IpDataType {
+            pub fn to_ip_addr(&self) -> std::net::IpAddr {
+                self.0
+            }
+        }
+
+        impl From<std::net::IpAddr> for IpDataType {
+            fn from(ip: std::net::IpAddr) -> Self {
+                Self(ip)
+            }
+        }
+
+        pub struct NetDataType(std::net::IpAddr, u8);
+
+        impl NetDataType {
+            pub fn new(ip: impl Into<IpDataType>, cidr: u8) -> Self {
+                Self(ip.into().0, cidr)
+            }
+
+            pub fn ip(&self) -> std::net::IpAddr {
+                self.0
+            }
+
+            pub fn cidr(&self) -> u8 {
+                self.1
+            }
+        }
+
+        /// What an entry is stored as, an address with its prefix length
+        pub type Key = (std::net::IpAddr, u8);
+
+        pub trait SetType {
+            type DataType: Into<Key> + From<Key>;
+        }
+
+        impl SetType for HashIp {
+            type DataType = IpDataType;
+        }
+
+        impl SetType for HashNet {
+            type DataType = NetDataType;
+        }
+
+        impl From<IpDataType> for Key {
+            fn from(ip: IpDataType) -> Self {
+                let prefix = if ip.0.is_ipv4() { 32 } else { 128 };
+                (ip.0, prefix)
+            }
+        }
+
+        impl From<Key> for IpDataType {
+            fn from((ip, _): Key) -> Self {
+                Self(ip)
+            }
+        }
+
+        impl From<NetDataType> for Key {
+            fn from(net: NetDataType) -> Self {
+                (net.0, net.1)
+            }
+        }
+
+        impl From<Key> for NetDataType {
+            fn from((ip, cidr): Key) -> Self {
+                Self(ip, cidr)
+            }
+        }
+
+        #[derive(Debug)]
+        pub struct Error(pub(super) String);
+
+        impl fmt::Display for Error {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl std::error::Error for Error {}
+    }
+
+    /// An element with its options, as `Session::list` returns it
+    type Entry<T> = (<T as SetType>::DataType, Vec<types::AddOption>);
+
+    pub struct Session<T> {
+        name: String,
+        created: bool,
+        entries: HashSet<Key>,
+        kind: PhantomData<T>,
+    }
+
+    pub struct CreateBuilder<T>(PhantomData<T>);
+
+    impl<T> CreateBuilder<T> {
+        pub fn with_ipv6(self, _ipv6: bool) -> Result<Self, Error> {
+            Ok(self)
+        }
+
+        pub fn with_timeout(self, _timeout: u32) -> Result<Self, Error> {
+            Ok(self)
+        }
+
+        pub fn with_forceadd(self) -> Result<Self, Error> {
+            Ok(self)
+        }
+
+        pub fn with_netmask(self, _netmask: u8) -> Result<Self, Error> {
+            Ok(self)
+        }
+
+        pub fn with_max_elem(self, _max: u32) -> Result<Self, Error> {
+            Ok(self)
+        }
+
+        pub fn with_hash_size(self, _size: u32) -> Result<Self, Error> {
+            Ok(self)
+        }
+
+        pub fn with_comment(self) -> Result<Self, Error> {
+            Ok(self)
+        }
+
+        pub fn build(self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl<T: SetType> Session<T> {
+        pub fn new(name: String) -> Self {
+            Self {
+                name,
+                created: false,
+                entries: HashSet::new(),
+                kind: PhantomData,
+            }
+        }
+
+        pub fn create<F>(&mut self, f: F) -> Result<bool, Error>
+        where
+            F: Fn(CreateBuilder<T>) -> Result<(), Error>,
+        {
+            f(CreateBuilder(PhantomData))?;
+            self.created = true;
+            Ok(true)
+        }
+
+        pub fn set_option(&self, _option: types::EnvOption) {}
+
+        pub fn unset_option(&self, _option: types::EnvOption) {}
+
+        fn check(&self) -> Result<(), Error> {
+            if self.created {
+                Ok(())
+            } else {
+                Err(Error(format!(
+                    "The set with the given name does not exist: {}",
+                    self.name
+                )))
+            }
+        }
+
+        pub fn add(
+            &mut self,
+            data: impl Into<T::DataType>,
+            _options: &[types::AddOption],
+        ) -> Result<bool, Error> {
+            self.check()?;
+            self.entries.insert(data.into().into());
+            Ok(true)
+        }
+
+        /// Fails for missing elements like the kernel does.
+        pub fn del(&mut self, data: impl Into<T::DataType>) -> Result<bool, Error> {
+            self.check()?;
+            let (ip, prefix) = data.into().into();
+            if !self.entries.remove(&(ip, prefix)) {
+                return Err(Error(format!(
+                    "Element {}/{} is not in set {}",
+                    ip, prefix, self.name
+                )));
+            }
+            Ok(true)
+        }
+
+        pub fn flush(&mut self) -> Result<bool, Error> {
+            self.check()?;
+            self.entries.clear();
+            Ok(true)
+        }
+
+        /// Exact matches only, the mock doesn't look into networks.
+        pub fn test(&mut self, data: impl Into<T::DataType>) -> Result<bool, Error> {
+            self.check()?;
+            Ok(self.entries.contains(&data.into().into()))
+        }
+
+        pub fn list(&mut self) -> Result<Vec<Entry<T>>, Error> {
+            self.check()?;
+            Ok(self
+                .entries
+                .iter()
+                .map(|key| (T::DataType::from(*key), Vec::new()))
+                .collect())
+        }
+
+        pub fn destroy(&mut self) -> Result<bool, Error> {
+            self.check()?;
+            self.created = false;
+            Ok(true)
+        }
+    }
+
+    /// `ipset swap`, which the crate has no binding for. Both sets have to exist.
+    pub fn swap<T: SetType>(a: &mut Session<T>, b: &mut Session<T>) -> Result<(), Error> {
+        a.check()?;
+        b.check()?;
+        std::mem::swap(&mut a.entries, &mut b.entries);
+        Ok(())
+    }
+}
+
+pub mod iptables {
+    use std::{collections::BTreeMap, error::Error, sync::Mutex};
+
+    const BUILTIN_CHAINS: [(&str, &str); 6] = [
+        ("filter", "INPUT"),
+        ("filter", "FORWARD"),
+        ("filter", "OUTPUT"),
+        // Docker's, as if it were running
+        ("filter", "DOCKER-USER"),
+        ("raw", "PREROUTING"),
+        ("raw", "OUTPUT"),
+    ];
+
+    /// Rules are kept as given, without the normalizing iptables does.
+    pub struct IPTables {
+        chains: Mutex<BTreeMap<(String, String), Vec<String>>>,
+    }
+
+    pub fn new(_is_ipv6: bool) -> Result<IPTables, Box<dyn Error>> {
+        let chains = BUILTIN_CHAINS
+            .iter()
+            .map(|(table, chain)| (key(table, chain), Vec::new()))
+            .collect();
+        Ok(IPTables {
+            chains: Mutex::new(chains),
+        })
+    }
+
+    /// Like `iptables-restore --noflush`, either every line of `script` applies or none does.
+    pub fn restore(ipt: &IPTables, script: &str) -> Result<(), Box<dyn Error>> {
+        let staged = IPTables {
+            chains: Mutex::new(ipt.chains.lock().unwrap().clone()),
+        };
+        let mut table = None;
+        for line in script.lines() {
+            if let Some(name) = line.strip_prefix('*') {
+                table = Some(name);
+                continue;
+            }
+            if line == "COMMIT" {
+                continue;
+            }
+            let table = table.ok_or("No table selected")?;
+            if let Some(declaration) = line.strip_prefix(':') {
+                let chain = declaration.split(' ').next().unwrap_or_default();
+                if staged.chain_exists(table, chain)? {
+                    staged.flush_chain(table, chain)?;
+                } else {
+                    staged.new_chain(table, chain)?;
+                }
+                continue;
+            }
+
+            let mut parts = line.splitn(3, ' ');
+            let (command, chain, rule) = (
+                parts.next().unwrap_or_default(),
+                parts.next().unwrap_or_default(),
+                parts.next().unwrap_or_default(),
+            );
+            match command {
+                "-A" => staged.append(table, chain, rule)?,
+                "-D" => staged.delete(table, chain, rule)?,
+                "-X" => staged.delete_chain(table, chain)?,
+                "-I" => {
+                    let (position, rule) = rule.split_once(' ').ok_or("Bad insert")?;
+                    staged.insert(table, chain, rule, position.parse()?)?;
+                }
+                _ => return Err(format!("Unsupported line: {}", line).into()),
+            }
+        }
+
+        *ipt.chains.lock().unwrap() = staged.chains.into_inner().unwrap();
+        Ok(())
+    }
+
+    fn key(table: &str, chain: &str) -> (String, String) {
+        (table.to_string(), chain.to_string())
+    }
+
+    impl IPTables {
+        fn with_chain<R>(
+            &self,
+            table: &str,
+            chain: &str,
+            f: impl FnOnce(&mut Vec<String>) -> Result<R, Box<dyn Error>>,
+        ) -> Result<R, Box<dyn Error>> {
+            let mut chains = self.chains.lock().unwrap();
+            let rules = chains
+                .get_mut(&key(table, chain))
+                .ok_or_else(|| format!("No chain/target/match by that name: {}", chain))?;
+            f(rules)
+        }
+
+        pub fn new_chain(&self, table: &str, chain: &str) -> Result<(), Box<dyn Error>> {
+            let mut chains = self.chains.lock().unwrap();
+            if chains.contains_key(&key(table, chain)) {
+                return Err(format!("Chain already exists: {}", chain).into());
+            }
+            chains.insert(key(table, chain), Vec::new());
+            Ok(())
+        }
+
+        pub fn chain_exists(&self, table: &str, chain: &str) -> Result<bool, Box<dyn Error>> {
+            Ok(self.chains.lock().unwrap().contains_key(&key(table, chain)))
+        }
+
+        pub fn flush_chain(&self, table: &str, chain: &str) -> Result<(), Box<dyn Error>> {
+            self.with_chain(table, chain, |rules| {
+                rules.clear();
+                Ok(())
+            })
+        }
+
+        pub fn delete_chain(&self, table: &str, chain: &str) -> Result<(), Box<dyn Error>> {
+            let mut chains = self.chains.lock().unwrap();
+            match chains.get(&key(table, chain)) {
+                Some(rules) if rules.is_empty() => {
+                    chains.remove(&key(table, chain));
+                    Ok(())
+                }
+                Some(_) => Err(format!("Directory not empty: {}", chain).into()),
+                None => Err(format!("No chain/target/match by that name: {}", chain).into()),
+            }
+        }
+
+        /// In the format of `iptables -S`, without the policy line of built-in chains.
+        pub fn list(&self, table: &str, chain: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            self.with_chain(table, chain, |rules| {
+                Ok(rules
+                    .iter()
+                    .map(|rule| format!("-A {} {}", chain, rule))
+                    .collect())
+            })
+        }
+
+        /// Like [`IPTables::list`] for every chain of `table`, with a `-N` line declaring each
+        /// chain that isn't built in.
+        pub fn list_table(&self, table: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            let chains = self.chains.lock().unwrap();
+            Ok(chains
+                .iter()
+                .filter(|((name, _), _)| name == table)
+                .flat_map(|((_, chain), rules)| {
+                    let builtin = BUILTIN_CHAINS.contains(&(table, chain.as_str()));
+                    let declaration = (!builtin).then(|| format!("-N {}", chain));
+                    declaration.into_iter().chain(
+                        rules
+                            .iter()
+                            .map(move |rule| format!("-A {} {}", chain, rule)),
+                    )
+                })
+                .collect())
+        }
+
+        pub fn exists(&self, table: &str, chain: &str, rule: &str) -> Result<bool, Box<dyn Error>> {
+            self.with_chain(table, chain, |rules| Ok(rules.iter().any(|r| r == rule)))
+        }
+
+        pub fn append(&self, table: &str, chain: &str, rule: &str) -> Result<(), Box<dyn Error>> {
+            self.with_chain(table, chain, |rules| {
+                rules.push(rule.to_string());
+                Ok(())
+            })
+        }
+
+        /// `position` starts at 1, like for iptables.
+        pub fn insert(
+            &self,
+            table: &str,
+            chain: &str,
+            rule: &str,
+            position: i32,
+        ) -> Result<(), Box<dyn Error>> {
+            self.with_chain(table, chain, |rules| {
+                let index = (position - 1) as usize;
+                if position < 1 || index > rules.len() {
+                    return Err("Index of insertion too big".into());
+                }
+                rules.insert(index, rule.to_string());
+                Ok(())
+            })
+        }
+
+        pub fn delete(&self, table: &str, chain: &str, rule: &str) -> Result<(), Box<dyn Error>> {
+            self.with_chain(table, chain, |rules| {
+                let index = rules
+                    .iter()
+                    .position(|r| r == rule)
+                    .ok_or("Bad rule (does a matching rule exist in that chain?)")?;
+                rules.remove(index);
+                Ok(())
+            })
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Monitor-only mode: mortis rules log instead of dropping, and this module tallies which
+//! sources would have been blocked and why.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{firewall::MONITOR_PREFIX, nflog, state::AppState};
+
+/// Only the IP header is needed to attribute a packet.
+const COPY_RANGE: u32 = 40;
+/// Sources tracked per reason, packets from further sources are only counted.
+const MAX_SOURCES: usize = 10000;
+const TOP_SOURCES: usize = 20;
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct ReasonStats {
+    packets: u64,
+    sources: HashMap<IpAddr, u64>,
+}
+
+#[derive(Default)]
+pub struct Monitor {
+    reasons: Mutex<HashMap<String, ReasonStats>>,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    reasons: HashMap<String, ReasonReport>,
+}
+
+#[derive(Serialize)]
+pub struct ReasonReport {
+    packets: u64,
+    /// Sources with the most would-be drops, busiest first
+    top_sources: Vec<SourceReport>,
+}
+
+#[derive(Serialize)]
+pub struct SourceReport {
+    ip: IpAddr,
+    packets: u64,
+}
+
+impl Monitor {
+    fn record(&self, reason: &str, src: Option<IpAddr>) {
+        let mut reasons = self.reasons.lock().unwrap();
+        let stats = reasons.entry(reason.to_string()).or_default();
+        stats.packets += 1;
+        if let Some(src) = src {
+            let tracked = stats.sources.len();
+            match stats.sources.get_mut(&src) {
+                Some(count) => *count += 1,
+                None if tracked < MAX_SOURCES => {
+                    stats.sources.insert(src, 1);
+                }
+                None => {}
+            }
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        let reasons = self.reasons.lock().unwrap();
+        Report {
+            reasons: reasons
+                .iter()
+                .map(|(reason, stats)| {
+                    let mut top_sources: Vec<SourceReport> = stats
+                        .sources
+                        .iter()
+                        .map(|(ip, packets)| SourceReport {
+                            ip: *ip,
+                            packets: *packets,
+                        })
+                        .collect();
+                    top_sources.sort_unstable_by_key(|s| std::cmp::Reverse(s.packets));
+                    top_sources.truncate(TOP_SOURCES);
+
+                    (
+                        reason.clone(),
+                        ReasonReport {
+                            packets: stats.packets,
+                            top_sources,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Read would-be drops from the monitor NFLOG group for as long as the process runs.
+pub async fn task(state: Arc<AppState>, group: u16) {
+    let Some(monitor) = &state.monitor else {
+        return;
+    };
+    let socket = match nflog::NflogSocket::bind(group, COPY_RANGE) {
+        Ok(socket) => socket,
+        Err(e) => {
+            tracing::error!("Failed to bind monitor NFLOG group {}: {}", group, e);
+            return;
+        }
+    };
+
+    let mut summary = tokio::time::interval(SUMMARY_INTERVAL);
+    summary.tick().await;
+    let mut buf = vec![0u8; 65536];
+
+    loop {
+        tokio::select! {
+            _ = summary.tick() => log_summary(monitor),
+            received = socket.recv(&mut buf) => match received {
+                Ok(len) => {
+                    for packet in nflog::packets(&buf[..len]) {
+                        let Some(reason) = packet
+                            .prefix
+                            .and_then(|prefix| std::str::from_utf8(prefix).ok())
+                            .and_then(|prefix| prefix.strip_prefix(MONITOR_PREFIX))
+                        else {
+                            continue;
+                        };
+                        monitor.record(reason, source(packet.payload));
+                        state.metrics.record_would_drop(reason);
+                    }
+                }
+                Err(e) => tracing::warn!("Failed to receive monitored packets: {}", e),
+            },
+        }
+    }
+}
+
+fn source(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => {
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            Some(Ipv4Addr::from(src).into())
+        }
+        6 => {
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            Some(Ipv6Addr::from(src).into())
+        }
+        _ => None,
+    }
+}
+
+fn log_summary(monitor: &Monitor) {
+    for (reason, stats) in monitor.report().reasons {
+        let top: Vec<String> = stats
+            .top_sources
+            .iter()
+            .take(5)
+            .map(|s| format!("{} ({})", s.ip, s.packets))
+            .collect();
+        tracing::info!(
+            target: "mortis::monitor",
+            "Would have dropped {} packets for {}, top sources: {}",
+            stats.packets,
+            reason,
+            top.join(", ")
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Minimal nfnetlink_log client for reading packets sent to an iptables `NFLOG` group.
+//!
+//! Elsewhere than on Linux, binding fails and callers carry on without the packets.
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use std::io;
+#[cfg(target_os = "linux")]
+use std::{
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+#[cfg(target_os = "linux")]
+use tokio::io::unix::AsyncFd;
+
+/// Protocol number of UDP in the IPv4 header
+pub const IPPROTO_UDP: u8 = 17;
+
+const NFNL_SUBSYS_ULOG: u16 = 4;
+const NFULNL_MSG_PACKET: u16 = 0;
+const NFULNL_MSG_CONFIG: u16 = 1;
+
+const NFULA_CFG_CMD: u16 = 1;
+const NFULA_CFG_MODE: u16 = 2;
+const NFULNL_CFG_CMD_BIND: u8 = 1;
+const NFULNL_CFG_CMD_UNBIND: u8 = 2;
+const NFULNL_COPY_PACKET: u8 = 2;
+
+const NFULA_PAYLOAD: u16 = 9;
+const NFULA_PREFIX: u16 = 10;
+
+const NLMSG_HDRLEN: usize = 16;
+const NFGENMSG_LEN: usize = 4;
+const NLA_HDRLEN: usize = 4;
+/// Strips the nested and byte order flags off an attribute type
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+#[cfg(target_os = "linux")]
+pub struct NflogSocket {
+    fd: AsyncFd<OwnedFd>,
+    group: u16,
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct NflogSocket;
+
+#[derive(Default)]
+pub struct Packet<'a> {
+    pub prefix: Option<&'a [u8]>,
+    /// Network layer packet, truncated to the copy range
+    pub payload: &'a [u8],
+}
+
+#[cfg(not(target_os = "linux"))]
+impl NflogSocket {
+    pub fn bind(_group: u16, _copy_range: u32) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "NFLOG is only available on Linux",
+        ))
+    }
+
+    pub async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        std::future::pending().await
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl NflogSocket {
+    /// Bind to `group`, copying at most `copy_range` bytes of every packet.
+    pub fn bind(group: u16, copy_range: u32) -> io::Result<Self> {
+        let raw = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_NETFILTER,
+            )
+        };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let socket = Self {
+            fd: AsyncFd::new(fd)?,
+            group,
+        };
+        socket.send_config(&attr(NFULA_CFG_CMD, &[NFULNL_CFG_CMD_BIND]))?;
+
+        let mut mode = copy_range.to_be_bytes().to_vec();
+        mode.extend([NFULNL_COPY_PACKET, 0]);
+        socket.send_config(&attr(NFULA_CFG_MODE, &mode))?;
+
+        Ok(socket)
+    }
+
+    fn send_config(&self, attrs: &[u8]) -> io::Result<()> {
+        let len = NLMSG_HDRLEN + NFGENMSG_LEN + attrs.len();
+        let mut msg = Vec::with_capacity(len);
+        msg.extend((len as u32).to_ne_bytes());
+        msg.extend(((NFNL_SUBSYS_ULOG << 8) | NFULNL_MSG_CONFIG).to_ne_bytes());
+        msg.extend((libc::NLM_F_REQUEST as u16).to_ne_bytes());
+        msg.extend(0u32.to_ne_bytes());
+        msg.extend(0u32.to_ne_bytes());
+        // nfgenmsg: family, version, resource id (the group)
+        msg.extend([libc::AF_UNSPEC as u8, 0]);
+        msg.extend(self.group.to_be_bytes());
+        msg.extend(attrs);
+
+        let ret = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                msg.as_ptr() as *const libc::c_void,
+                msg.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Receive one netlink datagram, use [`packets`] to split it up.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                let ret = unsafe {
+                    libc::recv(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if ret < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(ret as usize)
+                }
+            });
+
+            if let Ok(result) = result {
+                return result;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for NflogSocket {
+    fn drop(&mut self) {
+        let _ = self.send_config(&attr(NFULA_CFG_CMD, &[NFULNL_CFG_CMD_UNBIND]));
+    }
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn attr(kind: u16, data: &[u8]) -> Vec<u8> {
+    let len = NLA_HDRLEN + data.len();
+    let mut out = Vec::with_capacity(align(len));
+    out.extend((len as u16).to_ne_bytes());
+    out.extend(kind.to_ne_bytes());
+    out.extend(data);
+    out.resize(align(len), 0);
+    out
+}
+
+/// Split a netlink datagram into the logged packets it carries.
+pub fn packets(mut buf: &[u8]) -> Vec<Packet<'_>> {
+    let mut packets = Vec::new();
+
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let kind = u16::from_ne_bytes([buf[4], buf[5]]);
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            break;
+        }
+
+        if kind == (NFNL_SUBSYS_ULOG << 8) | NFULNL_MSG_PACKET && len >= NLMSG_HDRLEN + NFGENMSG_LEN
+        {
+            let mut attrs = &buf[NLMSG_HDRLEN + NFGENMSG_LEN..len];
+            let mut packet = Packet::default();
+
+            while attrs.len() >= NLA_HDRLEN {
+                let attr_len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+                let attr_kind = u16::from_ne_bytes([attrs[2], attrs[3]]) & NLA_TYPE_MASK;
+                if attr_len < NLA_HDRLEN || attr_len > attrs.len() {
+                    break;
+                }
+
+                let data = &attrs[NLA_HDRLEN..attr_len];
+                match attr_kind {
+                    NFULA_PAYLOAD => packet.payload = data,
+                    // The prefix is NUL terminated
+                    NFULA_PREFIX => packet.prefix = data.split(|b| *b == 0).next(),
+                    _ => {}
+                }
+
+                attrs = &attrs[align(attr_len).min(attrs.len())..];
+            }
+
+            packets.push(packet);
+        }
+
+        buf = &buf[align(len).min(buf.len())..];
+    }
+
+    packets
+}
@@ -0,0 +1,636 @@
+//! Native nftables backend, selected with `--backend nftables`. Everything mortis adds lives in
+//! its own `mortis` table per family, `ip mortis` and `ip6 mortis` (suffixed with
+//! `--instance-name`): the sets, the chains of a
+//! [`Plan`] and a base chain per hook. Plans are applied as a single `nft -f` transaction, so
+//! unlike with iptables there is no diffing, a changed chain is flushed and refilled at once
+//! without ever being half applied.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    io::Write,
+    net::IpAddr,
+    process::{Command, Stdio},
+};
+
+use anyhow::anyhow;
+
+use crate::{
+    cidr::Cidr,
+    engine::Plan,
+    firewall::{
+        self, AddressSet, Backend, Counted, Family, FirewallBackend, Listed, Names, RuleCounter,
+        SetFull, SetOptions,
+    },
+};
+
+/// Name of the tables in a lone instance, see [`crate::firewall::Names`]
+pub const TABLE: &str = "mortis";
+
+/// nft family of the mortis table holding `family`.
+fn table_family(family: Family) -> &'static str {
+    match family {
+        Family::V4 => "ip",
+        Family::V6 => "ip6",
+    }
+}
+
+/// Run `script` as one transaction, nothing changes if any part of it fails.
+fn run(script: &str) -> Result<(), Box<dyn Error>> {
+    let output = firewall::blocking(|| -> Result<_, Box<dyn Error>> {
+        let mut child = Command::new("nft")
+            .args(["-f", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run nft: {}", e))?;
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(script.as_bytes())?;
+        Ok(child.wait_with_output()?)
+    })?;
+    if !output.status.success() {
+        return Err(format!(
+            "nft exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// A set in the mortis table of each family, the counterpart of an ipset.
+struct Set {
+    table: String,
+    name: String,
+    /// Default timeout of elements in seconds. Elements carry their own, so permanent ones can
+    /// go into a set with timeouts as well
+    timeout: Option<u32>,
+    /// Prefix of IPv6 elements, `None` when IPv6 is disabled
+    ipv6_prefix: Option<u8>,
+    /// Elements can be networks, see [`SetOptions::nets`]
+    nets: bool,
+    maxelem: Option<u32>,
+    /// Elements carry a comment, see [`SetOptions::comments`]
+    comments: bool,
+}
+
+/// The nft script adding the set of `options` to the `table` of each family, see
+/// [`Set::create`].
+pub fn set_script(
+    table: &str,
+    options: &SetOptions,
+    ipv6_prefix: Option<u8>,
+    adopt: bool,
+) -> String {
+    let flush = |family: &str| match adopt {
+        true => String::new(),
+        false => format!("flush set {} {} {}\n", family, table, options.name),
+    };
+    let ipv4_flags = match (options.nets, options.timeout) {
+        (true, Some(_)) => " flags interval,timeout;",
+        (true, None) => " flags interval;",
+        (false, Some(_)) => " flags timeout;",
+        (false, None) => "",
+    };
+    let size = match options.maxelem {
+        Some(maxelem) => format!(" size {};", maxelem),
+        None => String::new(),
+    };
+    let mut script = format!(
+        "add table ip {table}\nadd set ip {table} {name} {{ type ipv4_addr;{ipv4_flags}{size} }}\n{flush}",
+        name = options.name,
+        flush = flush("ip"),
+    );
+    let ipv6_prefix = ipv6_prefix.map(|prefix| if options.units { prefix } else { 128 });
+    if let Some(prefix) = ipv6_prefix {
+        let ipv6_flags = match (prefix < 128 || options.nets, options.timeout) {
+            (true, Some(_)) => " flags interval,timeout;",
+            (true, None) => " flags interval;",
+            (false, Some(_)) => " flags timeout;",
+            (false, None) => "",
+        };
+        script += &format!(
+            "add table ip6 {table}\nadd set ip6 {table} {name} {{ type ipv6_addr;{ipv6_flags}{size} }}\n{flush}",
+            name = options.name,
+            flush = flush("ip6"),
+        );
+    }
+    script
+}
+
+impl Set {
+    /// Entries are removed by the kernel `timeout` seconds after they were last added. A set
+    /// left behind by a previous run is emptied, unless it is adopted. Unit sets hold IPv6
+    /// networks of `ipv6_prefix`.
+    fn create(
+        table: &str,
+        options: &SetOptions,
+        ipv6_prefix: Option<u8>,
+        adopt: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        run(&set_script(table, options, ipv6_prefix, adopt))?;
+
+        let ipv6_prefix = ipv6_prefix.map(|prefix| if options.units { prefix } else { 128 });
+        Ok(Self {
+            table: table.to_string(),
+            name: options.name.clone(),
+            timeout: options.timeout,
+            ipv6_prefix,
+            nets: options.nets,
+            maxelem: options.maxelem,
+            comments: options.comments,
+        })
+    }
+
+    /// `ip` as an element of the set of its family.
+    fn element(&self, ip: IpAddr) -> anyhow::Result<(&'static str, String)> {
+        let ip = ip.to_canonical();
+        match (Family::of(ip), self.ipv6_prefix) {
+            (Family::V4, _) => Ok(("ip", ip.to_string())),
+            (Family::V6, Some(128)) => Ok(("ip6", ip.to_string())),
+            (Family::V6, Some(prefix)) => Ok(("ip6", format!("{}/{}", ip, prefix))),
+            (Family::V6, None) => Err(anyhow!("Can't add IPv6 address {}, IPv6 is disabled", ip)),
+        }
+    }
+
+    /// `net` as an element of the set of its family, networks that aren't a single address
+    /// only go into sets of networks.
+    fn net_element(&self, net: Cidr) -> anyhow::Result<(&'static str, String)> {
+        if net.is_host() {
+            return self.element(net.addr());
+        }
+        if !self.nets {
+            anyhow::bail!(
+                "{} is a network, set {} only holds addresses",
+                net,
+                self.name
+            );
+        }
+        match (Family::of(net.addr()), self.ipv6_prefix) {
+            (Family::V6, None) => Err(anyhow!("Can't add IPv6 network {}, IPv6 is disabled", net)),
+            (family, _) => Ok((table_family(family), net.to_string())),
+        }
+    }
+
+    /// Adding an element that is in the set already leaves it alone, so it is replaced in the
+    /// same transaction to restart its timeout. The first add keeps the delete from failing.
+    fn add(
+        &self,
+        (family, element): (&str, String),
+        timeout: Option<u32>,
+        comment: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let mut replacement = match timeout {
+            Some(timeout) => format!("{} timeout {}s", element, timeout),
+            None => element.clone(),
+        };
+        if let Some(comment) = comment.filter(|_| self.comments) {
+            replacement += &format!(" comment \"{}\"", comment);
+        }
+        run(&format!(
+            "add element {family} {table} {name} {{ {element} }}\ndelete element {family} {table} {name} {{ {element} }}\nadd element {family} {table} {name} {{ {replacement} }}\n",
+            table = self.table,
+            name = self.name,
+        ))
+        .map_err(|e| match e.to_string().contains("No space left on device") {
+            true => SetFull(self.name.clone()).into(),
+            false => anyhow!("{}", e),
+        })
+    }
+}
+
+impl AddressSet for Set {
+    fn add_ip(&mut self, ip: IpAddr) -> anyhow::Result<()> {
+        self.add(self.element(ip)?, self.timeout, None)
+    }
+
+    fn add_ip_for(&mut self, ip: IpAddr, timeout: u32) -> anyhow::Result<()> {
+        self.add(self.element(ip)?, (timeout > 0).then_some(timeout), None)
+    }
+
+    /// Fails for missing elements, like ipset does.
+    fn del_ip(&mut self, ip: IpAddr) -> anyhow::Result<()> {
+        self.del_net(Cidr::host(ip))
+    }
+
+    fn add_commented(
+        &mut self,
+        ip: IpAddr,
+        timeout: Option<u32>,
+        comment: &str,
+    ) -> anyhow::Result<()> {
+        let timeout = match timeout {
+            Some(timeout) => (timeout > 0).then_some(timeout),
+            None => self.timeout,
+        };
+        self.add(self.element(ip)?, timeout, Some(comment))
+    }
+
+    fn add_net(&mut self, net: Cidr) -> anyhow::Result<()> {
+        self.add(self.net_element(net)?, self.timeout, None)
+    }
+
+    fn del_net(&mut self, net: Cidr) -> anyhow::Result<()> {
+        let (family, element) = self.net_element(net)?;
+        run(&format!(
+            "delete element {} {} {} {{ {} }}\n",
+            family, self.table, self.name, element
+        ))
+        .map_err(|e| anyhow!("{}", e))
+    }
+
+    /// Listing a set tersely, without its elements, fails when it is gone.
+    fn ensure(&mut self) -> anyhow::Result<bool> {
+        let families = std::iter::once("ip").chain(self.ipv6_prefix.map(|_| "ip6"));
+        let mut gone = false;
+        for family in families {
+            gone |= !firewall::blocking(|| {
+                Command::new("nft")
+                    .args(["-t", "list", "set", family, &self.table, &self.name])
+                    .output()
+            })?
+            .status
+            .success();
+        }
+        if gone {
+            *self = Set::create(
+                &self.table,
+                &SetOptions {
+                    name: self.name.clone(),
+                    timeout: self.timeout,
+                    forceadd: false,
+                    // The prefix of the set is that of units already
+                    units: true,
+                    nets: self.nets,
+                    maxelem: self.maxelem,
+                    hashsize: None,
+                    comments: self.comments,
+                },
+                self.ipv6_prefix,
+                true,
+            )
+            .map_err(|e| anyhow!("{}", e))?;
+        }
+        Ok(gone)
+    }
+
+    /// Flushing and filling the sets in one transaction is atomic already.
+    fn replace(&mut self, nets: &[Cidr], timeout: Option<u32>) -> anyhow::Result<()> {
+        let timeout = match timeout {
+            Some(timeout) => (timeout > 0).then_some(timeout),
+            None => self.timeout,
+        };
+        let mut script = String::new();
+        for family in std::iter::once("ip").chain(self.ipv6_prefix.map(|_| "ip6")) {
+            script += &format!("flush set {} {} {}\n", family, self.table, self.name);
+        }
+        for net in nets {
+            let (family, element) = self.net_element(*net)?;
+            let element = match timeout {
+                Some(timeout) => format!("{} timeout {}s", element, timeout),
+                None => element,
+            };
+            script += &format!(
+                "add element {} {} {} {{ {} }}\n",
+                family, self.table, self.name, element
+            );
+        }
+        run(&script).map_err(
+            |e| match e.to_string().contains("No space left on device") {
+                true => SetFull(self.name.clone()).into(),
+                false => anyhow!("{}", e),
+            },
+        )
+    }
+
+    fn list(&mut self) -> anyhow::Result<Vec<Listed>> {
+        let mut entries = Vec::new();
+        let families = std::iter::once("ip").chain(self.ipv6_prefix.map(|_| "ip6"));
+        for family in families {
+            let output = firewall::blocking(|| {
+                Command::new("nft")
+                    .args(["-j", "list", "set", family, &self.table, &self.name])
+                    .output()
+            })?;
+            if !output.status.success() {
+                anyhow::bail!(
+                    "nft exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            let ruleset: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+            for object in ruleset["nftables"].as_array().into_iter().flatten() {
+                for element in object["set"]["elem"].as_array().into_iter().flatten() {
+                    entries.extend(parse_element(element));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Nothing to do, the set went with the mortis tables in [`Nftables::teardown`] already.
+    fn teardown(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Applies plans whose rules are in nft syntax to the mortis table of one family. Hooks name
+/// the base chain they go into by its hook, e.g. `input`.
+struct Table {
+    family: &'static str,
+    name: String,
+    applied: Plan,
+}
+
+impl Table {
+    fn new(family: Family, name: &str) -> Self {
+        Self {
+            family: table_family(family),
+            name: name.to_string(),
+            applied: Plan::default(),
+        }
+    }
+
+    fn apply(&mut self, desired: &Plan) -> Result<(), Box<dyn Error>> {
+        run(&self.script(desired))?;
+        self.applied = desired.clone();
+        Ok(())
+    }
+
+    /// The nft script taking the table from the last applied plan to `desired`.
+    fn script(&self, desired: &Plan) -> String {
+        let family = self.family;
+        let table = &self.name;
+        let mut script = format!("add table {} {}\n", family, table);
+        for (chain, _) in &desired.chains {
+            script += &format!("add chain {} {} {}\n", family, table, chain);
+        }
+        for (chain, rules) in &desired.chains {
+            script += &format!("flush chain {} {} {}\n", family, table, chain);
+            for rule in rules {
+                script += &format!("add rule {} {} {} {}\n", family, table, chain, rule);
+            }
+        }
+
+        let mut hooks: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (hook, rule) in &desired.hooks {
+            hooks.entry(hook).or_default().push(rule);
+        }
+        for (hook, rules) in &hooks {
+            // Ahead of conntrack, like the raw table
+            let priority = if *hook == "prerouting" {
+                "raw"
+            } else {
+                "filter"
+            };
+            script += &format!(
+                "add chain {family} {table} {hook} {{ type filter hook {hook} priority {priority}; policy accept; }}\nflush chain {family} {table} {hook}\n",
+            );
+            for rule in rules {
+                script += &format!("add rule {} {} {} {}\n", family, table, hook, rule);
+            }
+        }
+
+        // Base chains go first, so nothing jumps into the chains by the time they are deleted
+        let unhooked: BTreeSet<&str> = self
+            .applied
+            .hooks
+            .iter()
+            .map(|(hook, _)| hook.as_str())
+            .filter(|hook| !hooks.contains_key(hook))
+            .collect();
+        let removed: Vec<&str> = self
+            .applied
+            .chains
+            .iter()
+            .map(|(chain, _)| chain.as_str())
+            .filter(|chain| !desired.chains.iter().any(|(name, _)| name == chain))
+            .collect();
+        for chain in unhooked.iter().chain(&removed) {
+            script += &format!("flush chain {} {} {}\n", family, table, chain);
+        }
+        for chain in unhooked.iter().chain(&removed) {
+            script += &format!("delete chain {} {} {}\n", family, table, chain);
+        }
+        script
+    }
+
+    /// Chains of the last applied plan that are gone or hold fewer rules than they should. nft
+    /// lists rules in its own normalized form, so they are only counted.
+    fn missing(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let listed = list(self.family, &self.name)?;
+        let mut missing = Vec::new();
+        let mut expected: BTreeMap<&str, usize> = self
+            .applied
+            .chains
+            .iter()
+            .map(|(chain, rules)| (chain.as_str(), rules.len()))
+            .collect();
+        for (hook, _) in &self.applied.hooks {
+            *expected.entry(hook).or_default() += 1;
+        }
+
+        for (chain, count) in expected {
+            match listed.get(chain) {
+                None => missing.push(chain.to_string()),
+                Some(listed) if *listed < count => {
+                    missing.push(format!("{}: {} of {} rules", chain, listed, count))
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(missing)
+    }
+
+    /// The counter of every [`Counted`] rule, found by its comment.
+    fn counters(&self) -> Result<Vec<RuleCounter>, Box<dyn Error>> {
+        let output = Command::new("nft")
+            .args(["-j", "list", "table", self.family, &self.name])
+            .output()
+            .map_err(|e| format!("Failed to run nft: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "nft exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+
+        let ruleset: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        let mut counters = Vec::new();
+        for object in ruleset["nftables"].as_array().into_iter().flatten() {
+            let rule = &object["rule"];
+            let (Some(chain), Some(counted)) = (
+                rule["chain"].as_str(),
+                rule["comment"].as_str().and_then(Counted::from_comment),
+            ) else {
+                continue;
+            };
+            let counter = rule["expr"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .find_map(|expr| expr.get("counter"));
+            if let Some(counter) = counter {
+                counters.push(RuleCounter {
+                    chain: chain.to_string(),
+                    counted,
+                    packets: counter["packets"].as_u64().unwrap_or(0),
+                    bytes: counter["bytes"].as_u64().unwrap_or(0),
+                });
+            }
+        }
+        Ok(counters)
+    }
+
+    /// Delete the table, taking its sets and anything a previous run left with it.
+    fn delete(&mut self) -> Result<(), Box<dyn Error>> {
+        run(&format!(
+            "add table {family} {table}\ndelete table {family} {table}\n",
+            family = self.family,
+            table = self.name,
+        ))?;
+        self.applied = Plan::default();
+        Ok(())
+    }
+}
+
+/// The nft script setting up the `family` table `table` as `plan` says, from scratch.
+pub fn ruleset_script(family: Family, table: &str, plan: &Plan) -> String {
+    Table::new(family, table).script(plan)
+}
+
+pub struct Nftables {
+    table: String,
+    /// Keep the elements of existing sets, see [`crate::firewall::backend`]
+    adopt: bool,
+    v4: Table,
+    v6: Option<Table>,
+    ipv6_prefix: Option<u8>,
+}
+
+impl Nftables {
+    /// `ipv6_prefix` is the `--ipv6-prefix` of IPv6 units, `None` to leave IPv6 alone. Both
+    /// families get a table called `table`.
+    pub fn new(ipv6_prefix: Option<u8>, table: String, adopt: bool) -> Self {
+        Self {
+            adopt,
+            v4: Table::new(Family::V4, &table),
+            v6: ipv6_prefix.map(|_| Table::new(Family::V6, &table)),
+            table,
+            ipv6_prefix,
+        }
+    }
+}
+
+impl FirewallBackend for Nftables {
+    fn syntax(&self) -> Backend {
+        Backend::Nftables
+    }
+
+    fn families(&self) -> Vec<Family> {
+        match self.v6 {
+            Some(_) => vec![Family::V4, Family::V6],
+            None => vec![Family::V4],
+        }
+    }
+
+    /// nftables sets have no size limit unless given a `maxelem`, there is nothing to force.
+    fn setup_set(&mut self, options: &SetOptions) -> anyhow::Result<Box<dyn AddressSet>> {
+        let set = Set::create(&self.table, options, self.ipv6_prefix, self.adopt)
+            .map_err(|e| anyhow!("{}", e))?;
+        Ok(Box::new(set))
+    }
+
+    fn apply(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        match (family, &mut self.v6) {
+            (Family::V4, _) => self.v4.apply(plan),
+            (Family::V6, Some(v6)) => v6.apply(plan),
+            (Family::V6, None) => Err("IPv6 is disabled".into()),
+        }
+    }
+
+    fn verify(&self, family: Family) -> Result<Vec<String>, Box<dyn Error>> {
+        match (family, &self.v6) {
+            (Family::V4, _) => self.v4.missing(),
+            (Family::V6, Some(v6)) => v6.missing(),
+            (Family::V6, None) => Ok(Vec::new()),
+        }
+    }
+
+    /// Every apply rewrites the whole plan already.
+    fn reinstall(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        self.apply(family, plan)
+    }
+
+    fn counters(&self, family: Family) -> Result<Vec<RuleCounter>, Box<dyn Error>> {
+        match family {
+            Family::V4 => self.v4.counters(),
+            Family::V6 => self.v6.as_ref().map_or(Ok(Vec::new()), Table::counters),
+        }
+    }
+
+    fn teardown(&mut self) -> Result<(), Box<dyn Error>> {
+        self.v4.delete()?;
+        if let Some(v6) = &mut self.v6 {
+            v6.delete()?;
+        }
+        Ok(())
+    }
+
+    /// The sets are in the tables as well, deleting them is all there is to do.
+    fn purge(&mut self, _names: &Names) -> Result<(), Box<dyn Error>> {
+        self.teardown()
+    }
+}
+
+/// An element of `nft -j list set`, a bare address or prefix, or an object with its comment
+/// and the seconds until it `expires`.
+fn parse_element(element: &serde_json::Value) -> Option<Listed> {
+    let (value, expires, comment) = match element.get("elem") {
+        Some(elem) => (
+            &elem["val"],
+            elem["expires"].as_u64().unwrap_or(0),
+            elem["comment"].as_str(),
+        ),
+        None => (element, 0, None),
+    };
+    let address = match value.get("prefix") {
+        Some(prefix) => &prefix["addr"],
+        None => value,
+    };
+    Some(Listed {
+        ip: address.as_str()?.parse().ok()?,
+        timeout: u32::try_from(expires).unwrap_or(u32::MAX),
+        comment: comment.map(str::to_string),
+    })
+}
+
+/// Rules per chain of the mortis table `table` of `family`, empty when the table is gone.
+fn list(family: &str, table: &str) -> Result<BTreeMap<String, usize>, Box<dyn Error>> {
+    let output = Command::new("nft")
+        .args(["-j", "list", "table", family, table])
+        .output()
+        .map_err(|e| format!("Failed to run nft: {}", e))?;
+    if !output.status.success() {
+        return Ok(BTreeMap::new());
+    }
+
+    let ruleset: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let mut chains = BTreeMap::new();
+    for object in ruleset["nftables"].as_array().into_iter().flatten() {
+        if let Some(name) = object["chain"]["name"].as_str() {
+            chains.entry(name.to_string()).or_insert(0);
+        } else if let Some(chain) = object["rule"]["chain"].as_str() {
+            *chains.entry(chain.to_string()).or_insert(0) += 1;
+        }
+    }
+    Ok(chains)
+}
@@ -0,0 +1,326 @@
+//! Operator notifications: events are fanned out to the sinks configured under `[[notify]]`,
+//! retried on failure and parked in a dead-letter file when a sink stays unreachable.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{
+        Mutex,
+        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    },
+};
+
+use crate::{
+    signing,
+    snapshot::{unix_now, write_atomic},
+};
+
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// How often parked notifications are tried again.
+const DEAD_LETTER_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    KillswitchEngaged,
+    KillswitchReleased,
+    Reloaded,
+    ReloadFailed,
+    RulesetChanged,
+    Shutdown,
+    /// A packet capture finished, the message says where it was written
+    CaptureFinished,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Notification {
+    pub kind: Kind,
+    pub message: String,
+    /// Unix timestamp the event happened at
+    pub at: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SinkConfig {
+    #[serde(flatten)]
+    pub target: Target,
+    /// Only deliver these kinds of events, all of them when empty
+    #[serde(default)]
+    pub events: Vec<Kind>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Target {
+    /// POSTs the notification as JSON, signed with `secret` when set (see
+    /// [`signing::sign_webhook`])
+    Webhook {
+        url: String,
+        secret: Option<String>,
+    },
+    Discord {
+        webhook_url: String,
+    },
+    Telegram {
+        bot_token: String,
+        chat_id: String,
+    },
+}
+
+#[derive(Deserialize, Serialize)]
+struct DeadLetter {
+    /// Index of the sink in the config
+    sink: usize,
+    notification: Notification,
+}
+
+pub struct Notifier {
+    sender: UnboundedSender<Notification>,
+    /// Deliveries not finished yet, counting a notification once until it's fanned out
+    in_flight: Arc<AtomicUsize>,
+}
+
+pub struct Bus {
+    receiver: UnboundedReceiver<Notification>,
+    delivery: Delivery,
+    in_flight: Arc<AtomicUsize>,
+}
+
+struct Delivery {
+    sinks: Vec<SinkConfig>,
+    dead_letter_file: Option<PathBuf>,
+    client: reqwest::Client,
+    /// Serializes access to the dead-letter file
+    dead_letters: Mutex<()>,
+}
+
+impl Notifier {
+    pub fn new(
+        sinks: Vec<SinkConfig>,
+        dead_letter_file: Option<PathBuf>,
+        client: reqwest::Client,
+    ) -> (Self, Bus) {
+        let (sender, receiver) = unbounded_channel();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let bus = Bus {
+            receiver,
+            in_flight: in_flight.clone(),
+            delivery: Delivery {
+                sinks,
+                dead_letter_file,
+                client,
+                dead_letters: Mutex::new(()),
+            },
+        };
+
+        (Self { sender, in_flight }, bus)
+    }
+
+    pub fn notify(&self, kind: Kind, message: String) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let sent = self.sender.send(Notification {
+            kind,
+            message,
+            at: unix_now(),
+        });
+        if sent.is_err() {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Wait up to `timeout` for every notification to be delivered or dead-lettered. Returns
+    /// whether all of them were.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while self.in_flight.load(Ordering::Relaxed) > 0 {
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        true
+    }
+}
+
+/// Deliver notifications until the process exits.
+pub async fn task(bus: Bus) {
+    let Bus {
+        mut receiver,
+        delivery,
+        in_flight,
+    } = bus;
+    let delivery = Arc::new(delivery);
+
+    if delivery.dead_letter_file.is_some() {
+        let delivery = delivery.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(DEAD_LETTER_INTERVAL).await;
+                if let Err(e) = delivery.retry_dead_letters().await {
+                    tracing::error!("Failed to retry dead-lettered notifications: {:#}", e);
+                }
+            }
+        });
+    }
+
+    while let Some(notification) = receiver.recv().await {
+        for (sink, config) in delivery.sinks.iter().enumerate() {
+            if !config.events.is_empty() && !config.events.contains(&notification.kind) {
+                continue;
+            }
+
+            let delivery = delivery.clone();
+            let notification = notification.clone();
+            let in_flight = in_flight.clone();
+            in_flight.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(async move {
+                delivery.deliver_with_retries(sink, notification).await;
+                in_flight.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Delivery {
+    async fn deliver_with_retries(&self, sink: usize, notification: Notification) {
+        let mut delay = RETRY_DELAY;
+        for attempt in 1..=ATTEMPTS {
+            match self.deliver(sink, &notification).await {
+                Ok(()) => return,
+                Err(e) => tracing::warn!(
+                    "Notification sink {} failed (attempt {}/{}): {:#}",
+                    sink,
+                    attempt,
+                    ATTEMPTS,
+                    e
+                ),
+            }
+            if attempt < ATTEMPTS {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+
+        if let Err(e) = self.park(DeadLetter { sink, notification }).await {
+            tracing::error!("Failed to dead-letter notification: {:#}", e);
+        }
+    }
+
+    async fn deliver(&self, sink: usize, notification: &Notification) -> Result<()> {
+        let request = match &self.sinks[sink].target {
+            Target::Webhook { url, secret } => {
+                let body = serde_json::to_vec(notification)?;
+                let mut request = self
+                    .client
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json");
+                if let Some(secret) = secret {
+                    let timestamp = unix_now();
+                    request = request
+                        .header(signing::TIMESTAMP_HEADER, timestamp.to_string())
+                        .header(
+                            signing::SIGNATURE_HEADER,
+                            signing::sign_webhook(secret.as_bytes(), timestamp, &body),
+                        );
+                }
+                request.body(body)
+            }
+            Target::Discord { webhook_url } => self
+                .client
+                .post(webhook_url)
+                .json(&serde_json::json!({ "content": notification.message })),
+            Target::Telegram { bot_token, chat_id } => self
+                .client
+                .post(format!(
+                    "https://api.telegram.org/bot{}/sendMessage",
+                    bot_token
+                ))
+                .json(&serde_json::json!({ "chat_id": chat_id, "text": notification.message })),
+        };
+
+        request
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn park(&self, letter: DeadLetter) -> Result<()> {
+        let Some(path) = &self.dead_letter_file else {
+            tracing::error!(
+                "Dropping {:?} notification for sink {}, no dead_letter_file configured",
+                letter.notification.kind,
+                letter.sink
+            );
+            return Ok(());
+        };
+
+        let mut line = serde_json::to_vec(&letter)?;
+        line.push(b'\n');
+
+        let _guard = self.dead_letters.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(&line).await?;
+        file.sync_all().await?;
+
+        Ok(())
+    }
+
+    /// Try every parked notification once more, keeping the ones that still fail.
+    async fn retry_dead_letters(&self) -> Result<()> {
+        let Some(path) = &self.dead_letter_file else {
+            return Ok(());
+        };
+
+        let _guard = self.dead_letters.lock().await;
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut remaining = Vec::new();
+        for line in data.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            let letter: DeadLetter = match serde_json::from_slice(line) {
+                Ok(letter) => letter,
+                Err(e) => {
+                    tracing::warn!("Discarding unreadable dead letter: {}", e);
+                    continue;
+                }
+            };
+            // The sink list may have shrunk since the letter was parked
+            if letter.sink >= self.sinks.len() {
+                continue;
+            }
+            if let Err(e) = self.deliver(letter.sink, &letter.notification).await {
+                tracing::debug!(
+                    "Dead letter for sink {} still failing: {:#}",
+                    letter.sink,
+                    e
+                );
+                remaining.extend(serde_json::to_vec(&letter)?);
+                remaining.push(b'\n');
+            }
+        }
+
+        write_atomic(path, &remaining).await
+    }
+}
@@ -0,0 +1,34 @@
+//! OpenAPI description of the admin API at `/openapi.json`, for generating clients, and with
+//! `--admin-docs` a Swagger UI at `/admin/docs`. The description is kept by hand in
+//! `openapi.json` next to this file, routes added to [`crate::admin::router`] go there too.
+
+use axum::{Json, response::Html};
+use serde_json::Value;
+
+const SPEC: &str = include_str!("openapi.json");
+
+/// Swagger UI from unpkg, pointed at `/openapi.json`.
+const DOCS: &str = r##"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>mortis-rs admin API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+pub async fn spec() -> Json<Value> {
+    let mut spec: Value = serde_json::from_str(SPEC).expect("openapi.json is valid JSON");
+    spec["info"]["version"] = env!("CARGO_PKG_VERSION").into();
+    Json(spec)
+}
+
+pub async fn docs() -> Html<&'static str> {
+    Html(DOCS)
+}
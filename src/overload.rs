@@ -0,0 +1,189 @@
+//! Degradation tiers for sustained overload. Each tier sheds one more kind of work, from the
+//! least to the most important, so the whitelist keeps being maintained when the box is
+//! melting: refreshes of existing entries and expiry are never shed.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU8, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::state::AppState;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Samples in a row over a threshold before going one tier up
+const ESCALATE_AFTER: u32 = 5;
+/// Samples in a row under every threshold before going one tier down
+const RECOVER_AFTER: u32 = 30;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tier {
+    Normal,
+    /// The admin API answers 503, except for the kill switch
+    ShedAdmin,
+    /// `/metrics` answers 503 as well, taking load off dashboards
+    ShedMetrics,
+    /// New admissions answer 503 as well
+    ShedAdmissions,
+}
+
+impl Tier {
+    const ALL: [Tier; 4] = [
+        Tier::Normal,
+        Tier::ShedAdmin,
+        Tier::ShedMetrics,
+        Tier::ShedAdmissions,
+    ];
+
+    fn up(self) -> Self {
+        Self::ALL[(self as usize + 1).min(Self::ALL.len() - 1)]
+    }
+
+    fn down(self) -> Self {
+        Self::ALL[(self as usize).saturating_sub(1)]
+    }
+}
+
+#[derive(Default)]
+pub struct Degradation {
+    tier: AtomicU8,
+}
+
+impl Degradation {
+    pub fn tier(&self) -> Tier {
+        Tier::ALL[self.tier.load(Ordering::Relaxed) as usize]
+    }
+
+    /// Whether work that is shed from `tier` on should be refused.
+    pub fn sheds(&self, tier: Tier) -> bool {
+        self.tier() >= tier
+    }
+
+    fn set(&self, tier: Tier) {
+        self.tier.store(tier as u8, Ordering::Relaxed);
+    }
+}
+
+/// Refuse requests once the current tier sheds `tier`.
+pub async fn shed(
+    State((state, tier)): State<(Arc<AppState>, Tier)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.degradation.sheds(tier) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    next.run(request).await
+}
+
+/// Move between tiers as CPU usage and the slow path queue stay over or under their thresholds.
+pub async fn task(state: Arc<AppState>) {
+    let cpu_limit = (state.args.overload_cpu > 0).then(|| state.args.overload_cpu as f64 / 100.0);
+    let queue_limit = (state.args.overload_queue > 0).then_some(state.args.overload_queue);
+    let mut cpu = CpuUsage::default();
+    let mut over = 0;
+    let mut under = 0;
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let busy = cpu.sample();
+        let queued = state.slow_path.queued();
+        let cpu_over = busy
+            .zip(cpu_limit)
+            .is_some_and(|(busy, limit)| busy >= limit);
+        let queue_over = queue_limit.is_some_and(|limit| queued >= limit);
+        if cpu_over || queue_over {
+            over += 1;
+            under = 0;
+        } else {
+            under += 1;
+            over = 0;
+        }
+
+        let current = state.degradation.tier();
+        let next = if over >= ESCALATE_AFTER {
+            over = 0;
+            current.up()
+        } else if under >= RECOVER_AFTER {
+            under = 0;
+            current.down()
+        } else {
+            current
+        };
+        if next == current {
+            continue;
+        }
+
+        state.degradation.set(next);
+        state.metrics.set_degradation_tier(next as u8);
+        let cpu = busy.map_or("unknown".to_string(), |busy| {
+            format!("{:.0}%", busy * 100.0)
+        });
+        if next > current {
+            tracing::warn!(
+                "Overloaded (CPU {}, {} queued), degrading from {:?} to {:?}",
+                cpu,
+                queued,
+                current,
+                next
+            );
+        } else {
+            tracing::info!(
+                "Load went down (CPU {}, {} queued), recovering from {:?} to {:?}",
+                cpu,
+                queued,
+                current,
+                next
+            );
+        }
+    }
+}
+
+/// Busy share of all CPUs between samples, from `/proc/stat`.
+#[derive(Default)]
+struct CpuUsage {
+    last: Option<(u64, u64)>,
+}
+
+impl CpuUsage {
+    /// `None` for the first sample and where `/proc/stat` isn't available.
+    fn sample(&mut self) -> Option<f64> {
+        let (busy, total) = read_cpu_times()?;
+        let usage = self.last.and_then(|(last_busy, last_total)| {
+            let elapsed = total.checked_sub(last_total).filter(|t| *t > 0)?;
+            Some(busy.saturating_sub(last_busy) as f64 / elapsed as f64)
+        });
+        self.last = Some((busy, total));
+        usage
+    }
+}
+
+/// Busy and total jiffies, idle and iowait being the only idle ones.
+fn read_cpu_times() -> Option<(u64, u64)> {
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    let times: Vec<u64> = stat
+        .lines()
+        .next()?
+        .strip_prefix("cpu ")?
+        .split_whitespace()
+        // Later fields count guest time a second time
+        .take(8)
+        .filter_map(|field| field.parse().ok())
+        .collect();
+    let total: u64 = times.iter().sum();
+    let idle = times.get(3)? + times.get(4).unwrap_or(&0);
+    Some((total - idle, total))
+}
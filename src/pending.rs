@@ -0,0 +1,220 @@
+//! Fallback for when the kernel gets slow: admissions are answered right away and the ipset
+//! work is queued for a background worker instead of holding up the HTTP response. Admissions
+//! whose ipset operations failed on the synchronous path are retried through the same worker.
+
+use std::{
+    net::IpAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    time::Instant,
+};
+
+use crate::{cleaner, comment::Comment, state::AppState};
+
+/// Failed adds are retried this many times before the entry is dropped from the whitelist, so
+/// the client's next ping admits it from scratch.
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for every further one
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+struct Pending {
+    ip: IpAddr,
+    attempt: u32,
+    /// Not applied before then
+    due: Instant,
+}
+
+pub struct SlowPath {
+    /// `None` disables the fallback
+    budget: Option<Duration>,
+    /// Moving average of firewall operation latency, in microseconds
+    average_us: AtomicU64,
+    engaged: AtomicBool,
+    queued: AtomicUsize,
+    queue: UnboundedSender<Pending>,
+}
+
+pub struct Worker(UnboundedReceiver<Pending>);
+
+impl SlowPath {
+    pub fn new(budget: Option<Duration>) -> (Self, Worker) {
+        let (queue, receiver) = unbounded_channel();
+        let slow_path = Self {
+            budget,
+            average_us: AtomicU64::new(0),
+            engaged: AtomicBool::new(false),
+            queued: AtomicUsize::new(0),
+            queue,
+        };
+
+        (slow_path, Worker(receiver))
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged.load(Ordering::Relaxed)
+    }
+
+    /// Record how long a firewall operation took, engaging the slow path once the average goes
+    /// over budget.
+    pub fn observe(&self, latency: Duration) {
+        let Some(budget) = self.budget else {
+            return;
+        };
+
+        let sample = latency.as_micros() as u64;
+        let previous = self.average_us.load(Ordering::Relaxed);
+        let average = previous - previous / 8 + sample / 8;
+        self.average_us.store(average, Ordering::Relaxed);
+
+        if average > budget.as_micros() as u64 && !self.engaged.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                "Firewall operations take {}ms on average, over the {}ms budget; applying admissions asynchronously",
+                average / 1000,
+                budget.as_millis()
+            );
+        }
+    }
+
+    /// Admissions waiting for the worker.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn enqueue(&self, ip: IpAddr) {
+        self.push(Pending {
+            ip,
+            attempt: 1,
+            due: Instant::now(),
+        });
+    }
+
+    /// Queue another try of an admission whose ipset operations just failed on the synchronous
+    /// path. The client was told it is admitted, it is dropped from the whitelist only once the
+    /// retries run out.
+    pub fn retry(&self, ip: IpAddr) {
+        self.schedule(ip, 1);
+    }
+
+    /// Retry `ip` after `failed` attempts, backing off exponentially.
+    fn schedule(&self, ip: IpAddr, failed: u32) {
+        self.push(Pending {
+            ip,
+            attempt: failed + 1,
+            due: Instant::now() + RETRY_DELAY * 2u32.pow(failed - 1),
+        });
+    }
+
+    fn push(&self, pending: Pending) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.send(pending);
+    }
+
+    fn send(&self, pending: Pending) {
+        // The worker lives as long as the process, a failed send means we are shutting down
+        let _ = self.queue.send(pending);
+    }
+
+    /// Leave the slow path once the queue is drained and the kernel is comfortably within budget.
+    fn maybe_disengage(&self) {
+        let Some(budget) = self.budget else {
+            return;
+        };
+
+        let average = self.average_us.load(Ordering::Relaxed);
+        if self.queued.load(Ordering::Relaxed) == 0
+            && average < budget.as_micros() as u64 / 2
+            && self.engaged.swap(false, Ordering::Relaxed)
+        {
+            tracing::info!(
+                "Firewall latency back to {}ms, applying admissions synchronously again",
+                average / 1000
+            );
+        }
+    }
+}
+
+/// Apply queued admissions in the background.
+pub async fn task(state: Arc<AppState>, worker: Worker) {
+    let mut receiver = worker.0;
+
+    while let Some(pending) = receiver.recv().await {
+        // Retries wait out their backoff aside, still counted as queued
+        if pending.due > Instant::now() {
+            let state = state.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep_until(pending.due).await;
+                state.slow_path.send(pending);
+            });
+            continue;
+        }
+
+        let started = Instant::now();
+        let result = apply(&state, pending.ip).await;
+        state.slow_path.observe(started.elapsed());
+        state.slow_path.queued.fetch_sub(1, Ordering::Relaxed);
+
+        match result {
+            Ok(()) if pending.attempt > 1 => state.metrics.record_admission_retry("recovered"),
+            Ok(()) => {}
+            Err(e) => {
+                state.metrics.record_add_error(&e);
+                if pending.attempt < MAX_ATTEMPTS {
+                    tracing::warn!("Failed to apply queued admission of {}: {}", pending.ip, e);
+                    state.metrics.record_admission_retry("retried");
+                    state.slow_path.schedule(pending.ip, pending.attempt);
+                } else {
+                    tracing::error!("Giving up on queued admission of {}: {}", pending.ip, e);
+                    state.metrics.record_admission_retry("abandoned");
+                    forget(&state, pending.ip).await;
+                }
+            }
+        }
+
+        state.slow_path.maybe_disengage();
+    }
+}
+
+/// The ipset lock is taken before the whitelist one here, so never both at once.
+async fn apply(state: &AppState, ip: IpAddr) -> Result<()> {
+    // Pinned while queued, adding it would give it a timeout again
+    if state.pinned.lock().await.contains(&ip) {
+        return Ok(());
+    }
+    state.ipset_session.lock().await.add_commented(
+        ip,
+        None,
+        &Comment::whitelist(state, false, None),
+    )?;
+    if let Some(probation) = &state.probation_session {
+        probation.lock().await.add_ip(ip)?;
+    }
+
+    // The entry may have expired or been removed while it was queued
+    let whitelist = state.whitelist.lock().await;
+    if whitelist
+        .get(&ip)
+        .is_none_or(|last_seen| !cleaner::is_live(*last_seen, false))
+    {
+        state.ipset_session.lock().await.del_ip(ip)?;
+    }
+
+    Ok(())
+}
+
+/// Reconcile an admission that never made it into the kernel by dropping it from the whitelist.
+async fn forget(state: &AppState, ip: IpAddr) {
+    let mut whitelist = state.whitelist.lock().await;
+    if state.pinned.lock().await.contains(&ip) {
+        return;
+    }
+    whitelist.remove(&ip);
+    state.metrics.set_whitelist_entries(whitelist.len());
+}
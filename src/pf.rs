@@ -0,0 +1,438 @@
+//! pf backend for FreeBSD, selected with `--backend pf`. Everything mortis adds lives in its own
+//! `mortis` anchor (suffixed with `--instance-name`): the sets as tables of the anchor and the
+//! rules of both families, loaded at once with `pfctl -a mortis -f -`. mortis can't add rules to
+//! the main ruleset, pf.conf has to hold `anchor "mortis"` where the protected ports should be
+//! filtered, ahead of whatever passes them. The rules are those of [`crate::firewall`]'s pf
+//! plans, a subset of those of the other backends.
+//!
+//! pf tables have no timeouts, mortis keeps the expiry of every entry itself and removes those
+//! that are due whenever it touches the table, which at the latest is every
+//! `--reconcile-interval` for the whitelist.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::Write,
+    net::IpAddr,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    cidr::Cidr,
+    engine::Plan,
+    firewall::{
+        self, AddressSet, Backend, Counted, Family, FirewallBackend, Listed, Names, RuleCounter,
+        SetOptions,
+    },
+};
+
+/// Name of the anchor in a lone instance, see [`crate::firewall::Names`]
+pub const ANCHOR: &str = "mortis";
+
+/// Run pfctl with `args` and `input` on its stdin, returns what it printed to stdout and to
+/// stderr, where it reports how many addresses a table command changed.
+fn pfctl(args: &[&str], input: Option<&str>) -> Result<(String, String), Box<dyn Error>> {
+    let output = firewall::blocking(|| -> Result<_, Box<dyn Error>> {
+        let mut child = Command::new("pfctl")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run pfctl: {}", e))?;
+        // Dropped right away without input, so pfctl never waits for it
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        if let Some(input) = input {
+            stdin.write_all(input.as_bytes())?;
+        }
+        drop(stdin);
+        Ok(child.wait_with_output()?)
+    })?;
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    if !output.status.success() {
+        return Err(format!(
+            "pfctl {} exited with {}: {}",
+            args.join(" "),
+            output.status,
+            stderr
+        )
+        .into());
+    }
+    Ok((String::from_utf8_lossy(&output.stdout).into_owned(), stderr))
+}
+
+/// The pfctl arguments creating the table `name` of `anchor`, or leaving it alone if it exists.
+/// Tables created by pfctl are persistent, loading the rules of the anchor keeps them.
+pub fn create_args<'a>(anchor: &'a str, name: &'a str) -> [&'a str; 6] {
+    ["-a", anchor, "-t", name, "-T", "add"]
+}
+
+/// The rules of `plan` as they are loaded into the anchor, pf plans hold a single chain.
+pub fn anchor_script(plan: &Plan) -> String {
+    plan.chains
+        .iter()
+        .flat_map(|(_, rules)| rules)
+        .map(|rule| format!("{}\n", rule))
+        .collect()
+}
+
+/// A table of the mortis anchor, the counterpart of an ipset. One table holds both families.
+struct Table {
+    anchor: String,
+    name: String,
+    /// Seconds after which an entry is removed, restarted when it is added again
+    timeout: Option<u32>,
+    /// Prefix of IPv6 entries, `None` when IPv6 is disabled
+    ipv6_prefix: Option<u8>,
+    /// Entries can be networks, see [`SetOptions::nets`]
+    nets: bool,
+    /// When each entry with a timeout is due
+    expiry: HashMap<Cidr, Instant>,
+}
+
+impl Table {
+    /// A table left behind by a previous run is emptied, unless it is adopted. Adopted entries
+    /// get a full timeout, pf doesn't tell when they were added.
+    fn create(
+        anchor: &str,
+        options: &SetOptions,
+        ipv6_prefix: Option<u8>,
+        adopt: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        pfctl(&create_args(anchor, &options.name), None)?;
+        if !adopt {
+            pfctl(&["-a", anchor, "-t", &options.name, "-T", "flush"], None)?;
+        }
+
+        let mut table = Self {
+            anchor: anchor.to_string(),
+            name: options.name.clone(),
+            timeout: options.timeout,
+            ipv6_prefix: ipv6_prefix.map(|prefix| if options.units { prefix } else { 128 }),
+            nets: options.nets,
+            expiry: HashMap::new(),
+        };
+        if adopt && let Some(timeout) = table.timeout {
+            let due = Instant::now() + Duration::from_secs(timeout.into());
+            table.expiry = table.show()?.into_iter().map(|net| (net, due)).collect();
+        }
+        Ok(table)
+    }
+
+    fn command(&self, command: &str, entries: &[String]) -> Result<String> {
+        let mut args = vec!["-a", &self.anchor, "-t", &self.name, "-T", command];
+        args.extend(entries.iter().map(String::as_str));
+        let (_, stderr) = pfctl(&args, None).map_err(|e| anyhow!("{}", e))?;
+        Ok(stderr)
+    }
+
+    /// `ip` as an entry, IPv6 addresses of unit tables stand for their network.
+    fn element(&self, ip: IpAddr) -> Result<Cidr> {
+        let ip = ip.to_canonical();
+        match (Family::of(ip), self.ipv6_prefix) {
+            (Family::V4, _) => Ok(Cidr::host(ip)),
+            (Family::V6, Some(prefix)) => Cidr::new(ip, prefix).map_err(|e| anyhow!(e)),
+            (Family::V6, None) => Err(anyhow!("Can't add IPv6 address {}, IPv6 is disabled", ip)),
+        }
+    }
+
+    /// `net` as an entry, networks that aren't a single address only go into tables of
+    /// networks.
+    fn net_element(&self, net: Cidr) -> Result<Cidr> {
+        if net.is_host() {
+            return self.element(net.addr());
+        }
+        if !self.nets {
+            anyhow::bail!(
+                "{} is a network, table {} only holds addresses",
+                net,
+                self.name
+            );
+        }
+        match (Family::of(net.addr()), self.ipv6_prefix) {
+            (Family::V6, None) => Err(anyhow!("Can't add IPv6 network {}, IPv6 is disabled", net)),
+            _ => Ok(net),
+        }
+    }
+
+    /// Remove the entries whose timeout ran out.
+    fn expire(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let due: Vec<Cidr> = self
+            .expiry
+            .iter()
+            .filter(|(_, due)| **due <= now)
+            .map(|(net, _)| *net)
+            .collect();
+        if due.is_empty() {
+            return Ok(());
+        }
+        let entries: Vec<String> = due.iter().map(Cidr::to_string).collect();
+        self.command("delete", &entries)?;
+        for net in &due {
+            self.expiry.remove(net);
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, net: Cidr, timeout: Option<u32>) -> Result<()> {
+        self.expire()?;
+        self.command("add", &[net.to_string()])?;
+        match timeout {
+            Some(timeout) => {
+                let due = Instant::now() + Duration::from_secs(timeout.into());
+                self.expiry.insert(net, due);
+            }
+            None => {
+                self.expiry.remove(&net);
+            }
+        }
+        Ok(())
+    }
+
+    /// Entries in the kernel, in the form they were added.
+    fn show(&self) -> Result<Vec<Cidr>> {
+        let stdout = pfctl(&["-a", &self.anchor, "-t", &self.name, "-T", "show"], None)
+            .map_err(|e| anyhow!("{}", e))?
+            .0;
+        Ok(stdout
+            .lines()
+            .filter_map(|line| line.trim().parse().ok())
+            .collect())
+    }
+}
+
+impl AddressSet for Table {
+    fn add_ip(&mut self, ip: IpAddr) -> Result<()> {
+        self.add(self.element(ip)?, self.timeout)
+    }
+
+    fn add_ip_for(&mut self, ip: IpAddr, timeout: u32) -> Result<()> {
+        self.add(self.element(ip)?, (timeout > 0).then_some(timeout))
+    }
+
+    /// Fails for missing elements, like ipset does.
+    fn del_ip(&mut self, ip: IpAddr) -> Result<()> {
+        self.del_net(Cidr::host(ip))
+    }
+
+    fn add_net(&mut self, net: Cidr) -> Result<()> {
+        self.add(self.net_element(net)?, self.timeout)
+    }
+
+    /// pfctl succeeds for missing entries as well, it only reports deleting none of them.
+    fn del_net(&mut self, net: Cidr) -> Result<()> {
+        let net = self.net_element(net)?;
+        let stderr = self.command("delete", &[net.to_string()])?;
+        self.expiry.remove(&net);
+        if stderr.starts_with("0/") {
+            anyhow::bail!("{} is not in table {}", net, self.name);
+        }
+        Ok(())
+    }
+
+    /// The tables of the anchor are listed by name, a gone one is created again empty.
+    fn ensure(&mut self) -> Result<bool> {
+        let (tables, _) =
+            pfctl(&["-a", &self.anchor, "-s", "Tables"], None).map_err(|e| anyhow!("{}", e))?;
+        if tables.lines().any(|table| table.trim() == self.name) {
+            return Ok(false);
+        }
+        pfctl(&create_args(&self.anchor, &self.name), None).map_err(|e| anyhow!("{}", e))?;
+        self.expiry.clear();
+        Ok(true)
+    }
+
+    /// pfctl replaces the entries of a table at once.
+    fn replace(&mut self, nets: &[Cidr], timeout: Option<u32>) -> Result<()> {
+        let timeout = match timeout {
+            Some(timeout) => (timeout > 0).then_some(timeout),
+            None => self.timeout,
+        };
+        let nets = nets
+            .iter()
+            .map(|net| self.net_element(*net))
+            .collect::<Result<Vec<_>>>()?;
+        let list: String = nets.iter().map(|net| format!("{}\n", net)).collect();
+        pfctl(
+            &[
+                "-a",
+                &self.anchor,
+                "-t",
+                &self.name,
+                "-T",
+                "replace",
+                "-f",
+                "-",
+            ],
+            Some(&list),
+        )
+        .map_err(|e| anyhow!("{}", e))?;
+
+        self.expiry.clear();
+        if let Some(timeout) = timeout {
+            let due = Instant::now() + Duration::from_secs(timeout.into());
+            self.expiry = nets.into_iter().map(|net| (net, due)).collect();
+        }
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<Vec<Listed>> {
+        self.expire()?;
+        let now = Instant::now();
+        Ok(self
+            .show()?
+            .into_iter()
+            .map(|net| Listed {
+                ip: net.addr(),
+                timeout: self.expiry.get(&net).map_or(0, |due| {
+                    // Rounded up, like the kernel reports the timeouts of ipsets
+                    (due.saturating_duration_since(now)
+                        .as_millis()
+                        .div_ceil(1000) as u32)
+                        .max(1)
+                }),
+                comment: None,
+            })
+            .collect())
+    }
+
+    /// Nothing to do, the table went with the anchor in [`Pf::teardown`] already.
+    fn teardown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Pf {
+    anchor: String,
+    /// Keep the entries of existing tables, see [`crate::firewall::backend`]
+    adopt: bool,
+    ipv6_prefix: Option<u8>,
+    /// Rules of the last applied plan of each family, the anchor holds both
+    v4: Vec<String>,
+    v6: Vec<String>,
+}
+
+impl Pf {
+    /// `ipv6_prefix` is the `--ipv6-prefix` of IPv6 units, `None` to leave IPv6 alone.
+    pub fn new(ipv6_prefix: Option<u8>, anchor: String, adopt: bool) -> Self {
+        Self {
+            anchor,
+            adopt,
+            ipv6_prefix,
+            v4: Vec::new(),
+            v6: Vec::new(),
+        }
+    }
+
+    /// Load the rules of both families, replacing those in the anchor at once.
+    fn load(&self) -> Result<(), Box<dyn Error>> {
+        let script: String = self
+            .v4
+            .iter()
+            .chain(&self.v6)
+            .map(|rule| format!("{}\n", rule))
+            .collect();
+        pfctl(&["-a", &self.anchor, "-f", "-"], Some(&script))?;
+        Ok(())
+    }
+}
+
+impl FirewallBackend for Pf {
+    fn syntax(&self) -> Backend {
+        Backend::Pf
+    }
+
+    fn families(&self) -> Vec<Family> {
+        match self.ipv6_prefix {
+            Some(_) => vec![Family::V4, Family::V6],
+            None => vec![Family::V4],
+        }
+    }
+
+    /// pf sizes its tables by the global `table-entries` limit, there is nothing to force.
+    fn setup_set(&mut self, options: &SetOptions) -> Result<Box<dyn AddressSet>> {
+        let table = Table::create(&self.anchor, options, self.ipv6_prefix, self.adopt)
+            .map_err(|e| anyhow!("{}", e))?;
+        Ok(Box::new(table))
+    }
+
+    fn apply(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        let rules: Vec<String> = anchor_script(plan).lines().map(str::to_string).collect();
+        let previous = match family {
+            Family::V4 => std::mem::replace(&mut self.v4, rules),
+            Family::V6 => std::mem::replace(&mut self.v6, rules),
+        };
+        if let Err(e) = self.load() {
+            match family {
+                Family::V4 => self.v4 = previous,
+                Family::V6 => self.v6 = previous,
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// pf lists the rules in its own normalized form, so they are only counted. The anchor holds
+    /// the rules of both families, they are all checked with IPv4.
+    fn verify(&self, family: Family) -> Result<Vec<String>, Box<dyn Error>> {
+        if family == Family::V6 {
+            return Ok(Vec::new());
+        }
+        let (rules, _) = pfctl(&["-a", &self.anchor, "-s", "rules"], None)?;
+        let listed = rules.lines().filter(|line| !line.trim().is_empty()).count();
+        let count = self.v4.len() + self.v6.len();
+        Ok(match listed {
+            0 if count > 0 => vec![self.anchor.clone()],
+            listed if listed < count => {
+                vec![format!("{}: {} of {} rules", self.anchor, listed, count)]
+            }
+            _ => Vec::new(),
+        })
+    }
+
+    /// Every apply loads the whole anchor already.
+    fn reinstall(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        self.apply(family, plan)
+    }
+
+    /// The counters of the [`Counted`] rules, found by their label. Those of both families go
+    /// with IPv4, pf adds up the rules sharing a label.
+    fn counters(&self, family: Family) -> Result<Vec<RuleCounter>, Box<dyn Error>> {
+        if family == Family::V6 {
+            return Ok(Vec::new());
+        }
+        // label, evaluations, packets, bytes and then those by direction
+        let (labels, _) = pfctl(&["-a", &self.anchor, "-s", "labels"], None)?;
+        Ok(labels
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                Some(RuleCounter {
+                    chain: self.anchor.clone(),
+                    counted: Counted::from_comment(fields.first()?)?,
+                    packets: fields.get(2)?.parse().ok()?,
+                    bytes: fields.get(3)?.parse().ok()?,
+                })
+            })
+            .collect())
+    }
+
+    /// Flush the rules and tables of the anchor. Flushing everything would take the states of
+    /// the whole host with it.
+    fn teardown(&mut self) -> Result<(), Box<dyn Error>> {
+        pfctl(&["-a", &self.anchor, "-F", "rules"], None)?;
+        pfctl(&["-a", &self.anchor, "-F", "Tables"], None)?;
+        self.v4.clear();
+        self.v6.clear();
+        Ok(())
+    }
+
+    /// The tables are in the anchor as well, flushing it is all there is to do.
+    fn purge(&mut self, _names: &Names) -> Result<(), Box<dyn Error>> {
+        self.teardown()
+    }
+}
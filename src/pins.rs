@@ -0,0 +1,56 @@
+use std::{collections::HashSet, net::IpAddr};
+
+use anyhow::Result;
+use tokio::time::Instant;
+
+use crate::{client, comment::Comment, journal::EventKind, state::AppState};
+
+/// Whitelist `ip` and keep it whitelisted until it is unpinned again.
+pub async fn pin(state: &AppState, ip: IpAddr) -> Result<()> {
+    let ip = client::unit(ip, state.args.ipv6_prefix);
+    let mut whitelist = state.whitelist.lock().await;
+    let mut pinned = state.pinned.lock().await;
+
+    // Added again even if it is whitelisted already, to take away its timeout
+    state
+        .ipset_session
+        .lock()
+        .await
+        .add_commented(ip, Some(0), &Comment::whitelist(state, true, None))
+        .inspect_err(|e| state.metrics.record_add_error(e))?;
+    whitelist.insert(ip, Instant::now());
+    if pinned.insert(ip) {
+        state.journal.record(ip, EventKind::Pinned);
+    }
+    state.metrics.set_whitelist_entries(whitelist.len());
+
+    Ok(())
+}
+
+/// Turn a pinned entry back into a regular one, it expires like any other from now on.
+/// Returns whether `ip` was pinned.
+pub async fn unpin(state: &AppState, ip: IpAddr) -> Result<bool> {
+    let ip = client::unit(ip, state.args.ipv6_prefix);
+    let mut whitelist = state.whitelist.lock().await;
+    let mut pinned = state.pinned.lock().await;
+
+    if !pinned.contains(&ip) {
+        return Ok(false);
+    }
+    // Adding it again with the default timeout starts its expiry in the kernel
+    state
+        .ipset_session
+        .lock()
+        .await
+        .add_commented(ip, None, &Comment::whitelist(state, false, None))
+        .inspect_err(|e| state.metrics.record_add_error(e))?;
+    pinned.remove(&ip);
+    whitelist.insert(ip, Instant::now());
+    state.journal.record(ip, EventKind::Unpinned);
+
+    Ok(true)
+}
+
+pub async fn list(state: &AppState) -> HashSet<IpAddr> {
+    state.pinned.lock().await.clone()
+}
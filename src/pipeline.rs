@@ -0,0 +1,352 @@
+//! Admission pipeline. A request is checked by the stages of its profile in order, the first
+//! one that refuses it decides the response, and a request no stage refused gets whitelisted.
+//! New checks are new [`Stage`]s, their order is set per profile in the `[pipeline]` config.
+
+use std::net::IpAddr;
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::{
+    cleaner, comment::Comment, journal::EventKind, metrics::Outcome, overload::Tier,
+    snapshot::unix_now, state::AppState, steam::Verdict,
+};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// The admission policy, including a canary, see [`crate::policy`]
+    UserAgent,
+    /// Refuses new entries while overloaded, see [`crate::overload`]
+    Shed,
+    /// Refuses new entries from subnets out of quota, see [`crate::quota`]
+    SubnetQuota,
+    /// Refuses requests without a valid token or signature in their URL, see
+    /// [`crate::tokens`] and [`crate::auth`]. Only web requests carry one, it refuses every other
+    Token,
+    /// Refuses new entries without a Steam ticket Steam accepts, see [`crate::steam`]
+    SteamTicket,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Profile {
+    /// `/` and the redirects
+    Web,
+    /// `/sdk/session`
+    Sdk,
+    /// `/sdk/refresh`, which is authenticated by its signed URL instead of a User-Agent
+    Refresh,
+}
+
+/// Stages of each profile, defaults apply to profiles that aren't listed.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineConfig {
+    pub web: Option<Vec<Stage>>,
+    pub sdk: Option<Vec<Stage>>,
+    pub refresh: Option<Vec<Stage>>,
+}
+
+pub struct Pipelines {
+    web: Vec<Stage>,
+    sdk: Vec<Stage>,
+    refresh: Vec<Stage>,
+}
+
+impl Pipelines {
+    /// With tokens or signed URLs configured, web requests are checked for one first unless
+    /// their stages are set. With `steam` validation, web and SDK requests are checked for a
+    /// ticket last, once the cheap stages let them through.
+    pub fn new(config: &PipelineConfig, credentials: bool, steam: bool) -> Self {
+        let mut checked = vec![Stage::UserAgent, Stage::Shed, Stage::SubnetQuota];
+        if steam {
+            checked.push(Stage::SteamTicket);
+        }
+        let web = match credentials {
+            true => [vec![Stage::Token], checked.clone()].concat(),
+            false => checked.clone(),
+        };
+        Self {
+            web: config.web.clone().unwrap_or(web),
+            sdk: config.sdk.clone().unwrap_or(checked),
+            refresh: config
+                .refresh
+                .clone()
+                .unwrap_or_else(|| vec![Stage::Shed, Stage::SubnetQuota]),
+        }
+    }
+
+    fn stages(&self, profile: Profile) -> &[Stage] {
+        match profile {
+            Profile::Web => &self.web,
+            Profile::Sdk => &self.sdk,
+            Profile::Refresh => &self.refresh,
+        }
+    }
+}
+
+/// What the URL of a web request carries to show it was handed out by the game server.
+#[derive(Clone, Copy)]
+pub enum Credential<'a> {
+    /// `/t/<token>/...`, see [`crate::tokens`]
+    Token(&'a str),
+    /// `/auth/<expiry>/<signature>/...`, see [`crate::auth`]. `client` is the address the
+    /// request came from, before it is reduced to its unit
+    Signed {
+        client: IpAddr,
+        expiry: u64,
+        signature: &'a str,
+    },
+}
+
+/// What stages get to look at.
+struct Request<'a> {
+    ip: IpAddr,
+    user_agent: Option<&'a str>,
+    credential: Option<Credential<'a>>,
+    /// From the [`crate::steam::TICKET_HEADER`] of web and SDK requests
+    steam_ticket: Option<&'a str>,
+    /// Whether `ip` was whitelisted when the request came in, so it only needs a refresh
+    whitelisted: bool,
+}
+
+/// Run a request from `ip` through the stages of `profile` and whitelist it if none refuses.
+/// Returns the status to refuse the request with otherwise.
+pub async fn admit(
+    state: &AppState,
+    profile: Profile,
+    ip: IpAddr,
+    user_agent: Option<&str>,
+    credential: Option<Credential<'_>>,
+    steam_ticket: Option<&str>,
+) -> Result<Option<StatusCode>> {
+    let request = Request {
+        ip,
+        user_agent,
+        credential,
+        steam_ticket,
+        whitelisted: cleaner::is_whitelisted(state, ip).await,
+    };
+    if !request.whitelisted {
+        grant_grace(state, ip).await;
+    }
+
+    let stages = state.pipelines.read().unwrap().stages(profile).to_vec();
+    for stage in stages {
+        if let Some(status) = check(state, stage, &request).await {
+            end_grace(state, ip).await;
+            return Ok(Some(status));
+        }
+    }
+
+    whitelist(state, ip, user_agent).await?;
+    Ok(None)
+}
+
+async fn check(state: &AppState, stage: Stage, request: &Request<'_>) -> Option<StatusCode> {
+    let ip = request.ip;
+    match stage {
+        Stage::UserAgent => {
+            // Refreshes are checked by their signed URL and pass no User-Agent
+            let user_agent = request.user_agent?;
+            let (track, allowed) = state.admission.read().unwrap().check(ip, user_agent);
+            state.metrics.record_policy(track, allowed);
+            if allowed {
+                return None;
+            }
+            state.metrics.record(Outcome::RejectedUa);
+            state
+                .journal
+                .record_request(ip, EventKind::RejectedUa, Some(user_agent));
+            Some(StatusCode::FORBIDDEN)
+        }
+        Stage::Shed => {
+            if request.whitelisted || !state.degradation.sheds(Tier::ShedAdmissions) {
+                return None;
+            }
+            state.metrics.record(Outcome::Shed);
+            Some(StatusCode::SERVICE_UNAVAILABLE)
+        }
+        Stage::SubnetQuota => {
+            let quota = state.quota.as_ref()?;
+            if request.whitelisted || quota.take(ip) {
+                return None;
+            }
+            state.metrics.record(Outcome::RejectedQuota);
+            state
+                .journal
+                .record_request(ip, EventKind::RejectedQuota, request.user_agent);
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        }
+        Stage::Token => {
+            match request.credential {
+                Some(Credential::Token(token)) => {
+                    if let Some(name) = state.tokens.read().unwrap().verify(token) {
+                        state.metrics.record_token(name);
+                        return None;
+                    }
+                }
+                Some(Credential::Signed {
+                    client,
+                    expiry,
+                    signature,
+                }) => {
+                    if let Some(auth) = &state.auth {
+                        let verified = auth.verify(client, expiry, signature, unix_now());
+                        state.metrics.record_signed(match verified {
+                            Ok(()) => "accepted",
+                            Err(rejection) => rejection.as_str(),
+                        });
+                        if verified.is_ok() {
+                            return None;
+                        }
+                    }
+                }
+                None => {}
+            }
+            state.metrics.record(Outcome::RejectedToken);
+            state
+                .journal
+                .record_request(ip, EventKind::RejectedToken, request.user_agent);
+            Some(StatusCode::FORBIDDEN)
+        }
+        Stage::SteamTicket => {
+            let steam = state.steam.as_ref()?;
+            if request.whitelisted {
+                return None;
+            }
+            let Some(ticket) = request.steam_ticket else {
+                state.metrics.record_steam("missing");
+                return reject_steam(state, request, StatusCode::FORBIDDEN);
+            };
+            match steam.check(ticket).await {
+                Ok((verdict, cached)) => {
+                    state
+                        .metrics
+                        .record_steam(if cached { "cached" } else { verdict.as_str() });
+                    if let Verdict::Valid { steam_id } = verdict {
+                        tracing::debug!("{} is Steam user {}", ip, steam_id);
+                        return None;
+                    }
+                    reject_steam(state, request, StatusCode::FORBIDDEN)
+                }
+                Err(e) => {
+                    state.metrics.record_steam("error");
+                    tracing::warn!("Failed to check the Steam ticket of {}: {:#}", ip, e);
+                    if steam.fail_open() {
+                        return None;
+                    }
+                    reject_steam(state, request, StatusCode::SERVICE_UNAVAILABLE)
+                }
+            }
+        }
+    }
+}
+
+fn reject_steam(state: &AppState, request: &Request, status: StatusCode) -> Option<StatusCode> {
+    state.metrics.record(Outcome::RejectedSteam);
+    state
+        .journal
+        .record_request(request.ip, EventKind::RejectedSteam, request.user_agent);
+    Some(status)
+}
+
+/// Let the first packets of a joining player through at the grace limit while the request is
+/// still being validated. Best effort, a failure only loses the head start.
+async fn grant_grace(state: &AppState, ip: IpAddr) {
+    let Some(grace) = &state.grace_session else {
+        return;
+    };
+    if let Err(e) = grace.lock().await.add_ip(ip) {
+        state.metrics.record_add_error(&e);
+        tracing::debug!("Failed to add {} to the grace set: {}", ip, e);
+    }
+}
+
+/// Take back the grace of a rejected request. Admitted sources keep theirs until it times out,
+/// which covers an admission queued on the slow path, and the whitelist rules come first anyway.
+async fn end_grace(state: &AppState, ip: IpAddr) {
+    if let Some(grace) = &state.grace_session {
+        // Already gone if it timed out or was never added
+        let _ = grace.lock().await.del_ip(ip);
+    }
+}
+
+/// Add a new admission to the whitelist set, and the probation set when enabled.
+async fn add(state: &AppState, ip: IpAddr, user_agent: Option<&str>) -> Result<()> {
+    state.ipset_session.lock().await.add_commented(
+        ip,
+        None,
+        &Comment::whitelist(state, false, user_agent),
+    )?;
+    if let Some(probation) = &state.probation_session {
+        probation.lock().await.add_ip(ip)?;
+    }
+    Ok(())
+}
+
+/// Whitelist `ip`, or push back its expiry if it already is. `user_agent` only goes into the
+/// journal. Failed ipset operations are retried on the slow path rather than failing the request.
+pub async fn whitelist(state: &AppState, ip: IpAddr, user_agent: Option<&str>) -> Result<()> {
+    let mut whitelist = state.whitelist.lock().await;
+    let pinned = state.pinned.lock().await;
+    let live = whitelist
+        .get(&ip)
+        .is_some_and(|last_seen| cleaner::is_live(*last_seen, pinned.contains(&ip)));
+    if !live && whitelist.remove(&ip).is_some() {
+        state.metrics.record(Outcome::Expired);
+        state.journal.record(ip, EventKind::Expired);
+    }
+
+    if !live {
+        if state.slow_path.is_engaged() {
+            state.slow_path.enqueue(ip);
+        } else {
+            let started = Instant::now();
+            if let Err(e) = add(state, ip, user_agent).await {
+                state.metrics.record_add_error(&e);
+                state.metrics.record_admission_retry("retried");
+                tracing::warn!("Failed to admit {}, retrying in the background: {}", ip, e);
+                state.slow_path.retry(ip);
+            }
+            state.slow_path.observe(started.elapsed());
+        }
+        state.metrics.record(Outcome::Admitted);
+        state
+            .journal
+            .record_request(ip, EventKind::Admitted, user_agent);
+    } else {
+        // Adding it again restarts the timeout in the kernel, pinned entries have none
+        if !pinned.contains(&ip)
+            && let Err(e) = state.ipset_session.lock().await.add_commented(
+                ip,
+                None,
+                &Comment::whitelist(state, false, user_agent),
+            )
+        {
+            state.metrics.record_add_error(&e);
+            state.metrics.record_admission_retry("retried");
+            tracing::warn!(
+                "Failed to refresh {}, retrying in the background: {}",
+                ip,
+                e
+            );
+            state.slow_path.retry(ip);
+        }
+        state.metrics.record(Outcome::Refreshed);
+        state
+            .journal
+            .record_request(ip, EventKind::Refreshed, user_agent);
+    }
+
+    let expired = cleaner::prune(state, &mut whitelist, &pinned);
+    whitelist.insert(ip, Instant::now());
+    state.metrics.set_whitelist_entries(whitelist.len());
+    drop(pinned);
+    drop(whitelist);
+    cleaner::flush(state, &expired).await;
+
+    Ok(())
+}
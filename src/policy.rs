@@ -0,0 +1,128 @@
+//! Which requests get admitted. A new policy can be tried as a canary on a share of the
+//! clients first, with its decisions counted apart from those of the baseline.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer, de};
+
+use crate::{
+    config::{Config, UserAgentConfig},
+    game::Game,
+};
+
+/// Regex the User-Agent must match, written as a string in the config.
+#[derive(Clone, Debug)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Regex::new(pattern).map(Self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::new(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// Conditions on the User-Agent of a request.
+pub struct Policy {
+    /// Substrings that must all occur
+    contains: Vec<String>,
+    /// Substrings that may not occur
+    excludes: Vec<String>,
+    /// Regexes that must all match
+    matches: Vec<Pattern>,
+}
+
+impl Policy {
+    fn new(config: &UserAgentConfig) -> Self {
+        Self {
+            contains: config.contains.clone(),
+            excludes: config.excludes.clone(),
+            matches: config.matches.clone(),
+        }
+    }
+
+    fn allows(&self, user_agent: &str) -> bool {
+        self.contains
+            .iter()
+            .all(|s| user_agent.contains(s.as_str()))
+            && !self
+                .excludes
+                .iter()
+                .any(|s| user_agent.contains(s.as_str()))
+            && self
+                .matches
+                .iter()
+                .all(|pattern| pattern.0.is_match(user_agent))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Track {
+    Baseline,
+    Canary,
+}
+
+impl Track {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Track::Baseline => "baseline",
+            Track::Canary => "canary",
+        }
+    }
+}
+
+pub struct Admission {
+    baseline: Policy,
+    /// Percentage of clients checked against the canary policy instead
+    canary: Option<(u8, Policy)>,
+}
+
+impl Admission {
+    /// The baseline is the `[user_agent]` section of `config`, or what `game` is recognized by.
+    pub fn new(config: &Config, game: Game) -> Self {
+        let baseline = match &config.user_agent {
+            Some(user_agent) => Policy::new(user_agent),
+            None => Policy::new(&game.user_agent()),
+        };
+        Self {
+            baseline,
+            canary: config.canary.as_ref().map(|canary| {
+                (
+                    canary.percent,
+                    Policy {
+                        contains: canary.user_agent_contains.clone(),
+                        excludes: canary.user_agent_excludes.clone(),
+                        matches: canary.user_agent_matches.clone(),
+                    },
+                )
+            }),
+        }
+    }
+
+    /// Check a request from `ip`. Clients are assigned by address, so a player keeps getting the
+    /// same policy across requests and restarts.
+    pub fn check(&self, ip: IpAddr, user_agent: &str) -> (Track, bool) {
+        match &self.canary {
+            Some((percent, canary)) if bucket(ip) < *percent => {
+                (Track::Canary, canary.allows(user_agent))
+            }
+            _ => (Track::Baseline, self.baseline.allows(user_agent)),
+        }
+    }
+}
+
+/// 0 to 99, stable for `ip`.
+fn bucket(ip: IpAddr) -> u8 {
+    // Not randomly keyed, unlike the hasher of a HashMap
+    let mut hasher = DefaultHasher::new();
+    ip.hash(&mut hasher);
+    (hasher.finish() % 100) as u8
+}
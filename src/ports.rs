@@ -0,0 +1,234 @@
+//! Port lists in the multiport syntax of `--protect` and friends, e.g. `27015,27020:27030`.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, de};
+
+/// The most ports a single multiport match takes, a range counts as two.
+const MULTIPORT_MAX: usize = 15;
+
+/// Inclusive range of ports, a single port when `first == last`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}:{}", self.first, self.last)
+        }
+    }
+}
+
+/// Sorted ranges without overlaps. An empty string parses into an empty list.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ports(Vec<PortRange>);
+
+impl Ports {
+    /// All ports of `lists`, e.g. of a repeated flag.
+    pub fn merge<'a>(lists: impl IntoIterator<Item = &'a Ports>) -> Self {
+        let mut ranges: Vec<PortRange> = lists
+            .into_iter()
+            .flat_map(|ports| ports.0.iter().copied())
+            .collect();
+        ranges.sort();
+
+        let mut merged: Vec<PortRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.first <= last.last.saturating_add(1) => {
+                    last.last = last.last.max(range.last);
+                }
+                _ => merged.push(range),
+            }
+        }
+        Self(merged)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    #[cfg(all(feature = "xdp", target_os = "linux"))]
+    pub fn ranges(&self) -> &[PortRange] {
+        &self.0
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.0
+            .iter()
+            .any(|range| (range.first..=range.last).contains(&port))
+    }
+
+    /// The list split into as many multiport lists as it takes to stay within the 15 ports
+    /// a multiport match allows.
+    pub fn multiport(&self) -> Vec<String> {
+        let mut lists: Vec<Vec<String>> = Vec::new();
+        let mut used = MULTIPORT_MAX;
+        for range in &self.0 {
+            let cost = if range.first == range.last { 1 } else { 2 };
+            if used + cost > MULTIPORT_MAX {
+                lists.push(Vec::new());
+                used = 0;
+            }
+            used += cost;
+            lists
+                .last_mut()
+                .expect("a list was just pushed")
+                .push(range.to_string());
+        }
+        lists.into_iter().map(|list| list.join(",")).collect()
+    }
+
+    /// Elements of an anonymous nft set, e.g. `27015, 27020-27030`.
+    pub fn nft(&self) -> String {
+        self.0
+            .iter()
+            .map(|range| range.to_string().replace(':', "-"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Elements of a pf list, e.g. `27015, 27020:27030`.
+    pub fn pf(&self) -> String {
+        self.0
+            .iter()
+            .map(PortRange::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl fmt::Display for Ports {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<String> = self.0.iter().map(PortRange::to_string).collect();
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+/// Written like on the command line, e.g. `ports = "27015,27020:27030"` in the config.
+impl<'de> Deserialize<'de> for Ports {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl FromStr for Ports {
+    type Err = String;
+
+    /// Ranges are written `first:last` like in multiport or `first-last` like in nft.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Ok(Self::default());
+        }
+
+        let ranges = s
+            .split(',')
+            .map(|range| {
+                let range = range.trim();
+                let (first, last) = range.split_once([':', '-']).unwrap_or((range, range));
+                let parse = |port: &str| match port.trim().parse::<u16>() {
+                    Ok(0) | Err(_) => Err(format!("invalid port {:?} in {:?}", port, s)),
+                    Ok(port) => Ok(port),
+                };
+                let range = PortRange {
+                    first: parse(first)?,
+                    last: parse(last)?,
+                };
+                if range.first > range.last {
+                    return Err(format!("range {} in {:?} is backwards", range, s));
+                }
+                Ok(range)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::merge([&Self(ranges)]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports(s: &str) -> Ports {
+        s.parse().unwrap()
+    }
+
+    /// `count` single ports that don't merge, 1000, 1002, ...
+    fn singles(count: u16) -> String {
+        (0..count)
+            .map(|n| (1000 + n * 2).to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    #[test]
+    fn merges_ranges() {
+        assert_eq!(ports("27020:27030,27025:27040").to_string(), "27020:27040");
+        // Adjacent ranges and ports join up
+        assert_eq!(ports("27015,27016,27017:27020").to_string(), "27015:27020");
+        assert_eq!(
+            ports("27030,27015, 27020-27025").to_string(),
+            "27015,27020:27025,27030"
+        );
+        assert_eq!(ports("27015,27015").to_string(), "27015");
+        assert_eq!(ports("65534:65535,1").to_string(), "1,65534:65535");
+        assert!(ports(" ").is_empty());
+
+        let merged = Ports::merge([&ports("27015"), &ports("27016,30120")]);
+        assert_eq!(merged.to_string(), "27015:27016,30120");
+        assert!(merged.contains(27016));
+        assert!(!merged.contains(27017));
+    }
+
+    #[test]
+    fn refuses_invalid_ports() {
+        for s in [
+            "0",
+            "0:10",
+            "27030:27020",
+            "27030-27020",
+            "65536",
+            "-1",
+            "27015,",
+            "27015:",
+            "a-b",
+            "a:b",
+            "27015:27020:27030",
+        ] {
+            assert!(s.parse::<Ports>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn writes_ranges_per_syntax() {
+        let ports = ports("27015,27020-27030");
+        assert_eq!(ports, "27015,27020:27030".parse().unwrap());
+        assert_eq!(ports.multiport(), vec!["27015,27020:27030"]);
+        assert_eq!(ports.nft(), "27015, 27020-27030");
+        assert_eq!(ports.pf(), "27015, 27020:27030");
+    }
+
+    #[test]
+    fn splits_multiport_lists() {
+        assert_eq!(ports(&singles(15)).multiport(), vec![singles(15)]);
+
+        let lists = ports(&singles(16)).multiport();
+        assert_eq!(lists.len(), 2);
+        assert_eq!(lists[0], singles(15));
+        assert_eq!(lists[1], "1030");
+
+        // Ranges count as two ports
+        let lists = ports("1:2,4:5,7:8,10:11,13:14,16:17,19:20,22").multiport();
+        assert_eq!(lists, vec!["1:2,4:5,7:8,10:11,13:14,16:17,19:20,22"]);
+        let lists = ports("1:2,4:5,7:8,10:11,13:14,16:17,19:20,22:23").multiport();
+        assert_eq!(lists, vec!["1:2,4:5,7:8,10:11,13:14,16:17,19:20", "22:23"]);
+
+        assert!(Ports::default().multiport().is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//! Pass-through for web content served next to mortis, e.g. a loading screen on the same port.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{
+    Router,
+    body::{Body, to_bytes},
+    extract::{ConnectInfo, Request},
+    http::{HeaderMap, HeaderName, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::any,
+};
+use serde::Deserialize;
+
+use crate::{AppError, client::ClientInfo, state::AppState};
+
+/// Largest request body forwarded upstream, and largest response body passed back. Both are
+/// held in memory whole.
+const MAX_BODY: usize = 10 * 1024 * 1024;
+
+/// Headers that only describe the connection to mortis and must not be forwarded.
+const HOP_BY_HOP: [HeaderName; 6] = [
+    header::CONNECTION,
+    header::HOST,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyRoute {
+    /// Path prefix to forward, e.g. `/loading`
+    pub prefix: String,
+    /// Base URL requests are sent to, e.g. `http://127.0.0.1:8080`
+    pub upstream: String,
+    /// Remove the prefix from the path before forwarding
+    #[serde(default)]
+    pub strip_prefix: bool,
+}
+
+struct Upstream {
+    route: ProxyRoute,
+    client: reqwest::Client,
+}
+
+/// Add a route for every configured prefix, they take precedence over the mortis `/{*key}`
+/// redirect route.
+pub fn routes(
+    mut router: Router<Arc<AppState>>,
+    routes: Vec<ProxyRoute>,
+    client: reqwest::Client,
+) -> Router<Arc<AppState>> {
+    for route in routes {
+        let prefix = route.prefix.clone();
+        let upstream = Arc::new(Upstream {
+            route,
+            client: client.clone(),
+        });
+
+        let handler = move |connect_info: ConnectInfo<ClientInfo>, request: Request| {
+            let upstream = upstream.clone();
+            async move { forward(&upstream, connect_info.0, request).await }
+        };
+        router = router
+            .route(&prefix, any(handler.clone()))
+            .route(&format!("{}/{{*rest}}", prefix), any(handler));
+    }
+
+    router
+}
+
+async fn forward(
+    upstream: &Upstream,
+    client: ClientInfo,
+    request: Request,
+) -> std::result::Result<Response, AppError> {
+    let (parts, body) = request.into_parts();
+
+    let path_and_query = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+    let Some(path_and_query) = upstream_path(&upstream.route, path_and_query) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let url = format!(
+        "{}{}",
+        upstream.route.upstream.trim_end_matches('/'),
+        path_and_query
+    );
+
+    let mut headers = strip_hop_by_hop(parts.headers);
+    let forwarded_for = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+        Some(previous) => format!("{}, {}", previous, client.addr.ip()),
+        None => client.addr.ip().to_string(),
+    };
+    headers.insert("x-forwarded-for", forwarded_for.parse()?);
+
+    let body = to_bytes(body, MAX_BODY).await?;
+    let response = match upstream
+        .client
+        .request(parts.method, url)
+        .headers(headers)
+        .body(body)
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::warn!("Upstream {} failed: {}", upstream.route.upstream, e);
+            return Ok(StatusCode::BAD_GATEWAY.into_response());
+        }
+    };
+
+    into_response(response).await
+}
+
+/// The path and query to request from the upstream of `route` for `path_and_query`, `None` when
+/// the path isn't under the prefix, e.g. `/loadingfoo` for `/loading`.
+fn upstream_path(route: &ProxyRoute, path_and_query: &str) -> Option<String> {
+    let rest = path_and_query.strip_prefix(route.prefix.as_str())?;
+    if !rest.is_empty() && !rest.starts_with(['/', '?']) {
+        return None;
+    }
+    if !route.strip_prefix {
+        return Some(path_and_query.to_string());
+    }
+    match rest.starts_with('/') {
+        true => Some(rest.to_string()),
+        false => Some(format!("/{}", rest)),
+    }
+}
+
+async fn into_response(mut upstream: reqwest::Response) -> std::result::Result<Response, AppError> {
+    let status = upstream.status();
+    let headers = strip_hop_by_hop(upstream.headers().clone());
+    let too_large = || {
+        tracing::warn!("Upstream response over {} bytes", MAX_BODY);
+        Ok(StatusCode::BAD_GATEWAY.into_response())
+    };
+    if upstream
+        .content_length()
+        .is_some_and(|length| length > MAX_BODY as u64)
+    {
+        return too_large();
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = upstream.chunk().await? {
+        if body.len() + chunk.len() > MAX_BODY {
+            return too_large();
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+
+    Ok(response)
+}
+
+fn strip_hop_by_hop(mut headers: HeaderMap) -> HeaderMap {
+    for name in HOP_BY_HOP.iter() {
+        headers.remove(name);
+    }
+    headers
+}
+
+pub fn validate(routes: &[ProxyRoute]) -> Result<()> {
+    for route in routes {
+        if !route.prefix.starts_with('/') || route.prefix.ends_with('/') {
+            anyhow::bail!(
+                "proxy prefix {:?} must start with / and not end with it",
+                route.prefix
+            );
+        }
+        if route.prefix.contains(['{', '}', '*']) {
+            anyhow::bail!(
+                "proxy prefix {:?} may not contain route captures",
+                route.prefix
+            );
+        }
+        if !route.upstream.starts_with("http://") && !route.upstream.starts_with("https://") {
+            anyhow::bail!("proxy upstream {:?} must be an http(s) URL", route.upstream);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(prefix: &str, strip_prefix: bool) -> ProxyRoute {
+        ProxyRoute {
+            prefix: prefix.to_string(),
+            upstream: "http://127.0.0.1:8080".to_string(),
+            strip_prefix,
+        }
+    }
+
+    #[test]
+    fn strips_prefix() {
+        let route = route("/loading", true);
+        let path = |path| upstream_path(&route, path);
+        assert_eq!(path("/loading").as_deref(), Some("/"));
+        assert_eq!(path("/loading/").as_deref(), Some("/"));
+        assert_eq!(path("/loading?x=1").as_deref(), Some("/?x=1"));
+        assert_eq!(
+            path("/loading/index.html?x=1").as_deref(),
+            Some("/index.html?x=1")
+        );
+        assert_eq!(path("/loadingfoo"), None);
+        assert_eq!(path("/other"), None);
+    }
+
+    #[test]
+    fn keeps_prefix() {
+        let route = route("/loading", false);
+        let path = |path| upstream_path(&route, path);
+        assert_eq!(path("/loading").as_deref(), Some("/loading"));
+        assert_eq!(path("/loading/").as_deref(), Some("/loading/"));
+        assert_eq!(path("/loading?x=1").as_deref(), Some("/loading?x=1"));
+        assert_eq!(path("/loadingfoo"), None);
+    }
+
+    #[test]
+    fn validates_prefixes() {
+        assert!(validate(&[route("/loading", false), route("/a/b", true)]).is_ok());
+        for prefix in ["", "/", "/loading/", "loading", "/{key}", "/*rest"] {
+            assert!(validate(&[route(prefix, false)]).is_err(), "{}", prefix);
+        }
+        let mut upstream = route("/loading", false);
+        upstream.upstream = "ftp://127.0.0.1".to_string();
+        assert!(validate(&[upstream]).is_err());
+    }
+}
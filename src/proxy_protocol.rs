@@ -0,0 +1,271 @@
+//! The PROXY protocol header, v1 and v2, that HAProxy and other load balancers send ahead of a
+//! connection with `--proxy-protocol`, so the client is the one they accepted the connection
+//! from rather than the balancer itself. Only the headers of `--proxy-protocol-from` are
+//! believed.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::cidr::Cidr;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header, including its CRLF.
+const V1_MAX: usize = 107;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The client named by the header of a connection from `peer`, `None` to keep the peer. Only
+/// connections from the `balancers` are read from.
+pub async fn client<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    peer: IpAddr,
+    balancers: &[Cidr],
+) -> io::Result<Option<SocketAddr>> {
+    if !balancers.iter().any(|net| net.contains(peer)) {
+        return Ok(None);
+    }
+    read(stream).await
+}
+
+/// Read the header off `stream`, leaving the connection's own data unread. `None` for headers
+/// without an address, e.g. the balancer's own health checks.
+pub async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Shorter than the shortest header of either version, `PROXY UNKNOWN\r\n`
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid(
+            "connection doesn't start with a PROXY protocol header",
+        ));
+    }
+
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX {
+            return Err(invalid("PROXY protocol v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol v1 header isn't ASCII"))?;
+    parse_v1(line)
+}
+
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid source address in PROXY protocol v1 header"))?;
+            let port: u16 = port
+                .parse()
+                .map_err(|_| invalid("invalid source port in PROXY protocol v1 header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY protocol v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await? as usize;
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, sent by the balancer on its own behalf
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol v2 command")),
+    }
+    // TLVs after the addresses are of no interest
+    match family {
+        // TCP and UDP over IPv4
+        0x11 | 0x12 if length >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[..4]).unwrap());
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // TCP and UDP over IPv6
+        0x21 | 0x22 if length >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // UNSPEC and unix sockets carry no address to admit
+        0x00 | 0x31 | 0x32 => Ok(None),
+        _ => Err(invalid("malformed PROXY protocol v2 header")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read the header off `data`, returning what is left for HTTP as well.
+    async fn parse(data: &[u8]) -> io::Result<(Option<SocketAddr>, &[u8])> {
+        let mut stream = data;
+        let addr = read(&mut stream).await?;
+        Ok((addr, stream))
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((body.len() as u16).to_be_bytes());
+        header.extend(body);
+        header
+    }
+
+    #[tokio::test]
+    async fn believes_balancers_only() {
+        let balancers: Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        let data = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+
+        let mut stream = &data[..];
+        let peer = "203.0.113.1".parse().unwrap();
+        assert_eq!(client(&mut stream, peer, &balancers).await.unwrap(), None);
+        assert_eq!(stream, data);
+
+        let mut stream = &data[..];
+        let balancer = "10.0.0.1".parse().unwrap();
+        assert_eq!(
+            client(&mut stream, balancer, &balancers).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET /");
+        let mut stream = &b"GET / HTTP/1.1\r\n"[..];
+        assert!(client(&mut stream, balancer, &balancers).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v1() {
+        let (addr, rest) = parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (addr, rest) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\nGET /")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (addr, rest) = parse(b"PROXY UNKNOWN\r\nGET /").await.unwrap();
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn reads_v2() {
+        let mut body = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        body.extend(56324u16.to_be_bytes());
+        body.extend(443u16.to_be_bytes());
+        // A TLV after the addresses is skipped
+        body.extend([0x04, 0x00, 0x01, 0xff]);
+        let mut data = v2(1, 0x11, &body);
+        data.extend(b"GET /");
+        let (addr, rest) = parse(&data).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let mut body = Ipv6Addr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1])
+            .octets()
+            .to_vec();
+        body.extend(Ipv6Addr::LOCALHOST.octets());
+        body.extend(56324u16.to_be_bytes());
+        body.extend(443u16.to_be_bytes());
+        let (addr, _) = parse(&v2(1, 0x21, &body)).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn reads_v2_local() {
+        let mut data = v2(0, 0x00, &[]);
+        data.extend(b"GET /");
+        let (addr, rest) = parse(&data).await.unwrap();
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated() {
+        for data in [
+            &b"PROXY TCP4"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r",
+            &V2_SIGNATURE[..],
+            &v2(1, 0x11, &[192, 0, 2, 1])[..18],
+        ] {
+            let e = parse(data).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof, "{:?}", data);
+        }
+        // Complete, but too short for the addresses of its family
+        let e = parse(&v2(1, 0x11, &[192, 0, 2, 1])).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_signature() {
+        let mut signature = V2_SIGNATURE.to_vec();
+        signature[11] = b'X';
+        for data in [
+            &b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"[..],
+            b"proxy TCP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            &signature,
+        ] {
+            let e = parse(data).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", data);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed() {
+        for data in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 port 443\r\n",
+            b"PROXY TCP4 192.0.2 198.51.100.1 56324 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+        ] {
+            let e = parse(data).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", data);
+        }
+        let mut data = v2(1, 0x11, &[0; 12]);
+        data[12] = 0x11;
+        assert!(parse(&data).await.is_err(), "version 1 in a v2 header");
+        assert!(parse(&v2(2, 0x11, &[0; 12])).await.is_err(), "command 2");
+        assert!(parse(&v2(1, 0x41, &[0; 12])).await.is_err(), "family 4");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized() {
+        let mut line = b"PROXY TCP4 ".to_vec();
+        line.resize(200, b'1');
+        line.extend(b"\r\n");
+        let e = parse(&line).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // A length past the data there is
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([0x21, 0x11, 0xff, 0xff]);
+        data.extend([0; 64]);
+        let e = parse(&data).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
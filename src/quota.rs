@@ -0,0 +1,221 @@
+//! Per-subnet quota on new whitelist entries. Botnets tend to control many hosts in few
+//! subnets, while real players rarely share one with more than a handful of others, so each
+//! subnet gets a token bucket holding an hour's worth of admissions.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    net::IpAddr,
+    sync::Mutex,
+    time::Instant,
+};
+
+/// Most subnets tracked at once. Past it the least recently seen ones are forgotten, and start
+/// over with a full bucket.
+const MAX_BUCKETS: usize = 65536;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_subnet: HashMap<IpAddr, Bucket>,
+    /// Every bucket by when it was last updated, oldest first
+    by_age: BTreeSet<(Instant, IpAddr)>,
+}
+
+pub struct SubnetQuota {
+    /// Tokens per bucket, refilled evenly over an hour
+    per_hour: u32,
+    /// IPv4 prefix length addresses are grouped by
+    prefix: u8,
+    /// IPv6 prefix length addresses are grouped by
+    prefix6: u8,
+    buckets: Mutex<Buckets>,
+    /// Subnets an admin exempted from the quota
+    exempt: Mutex<HashSet<IpAddr>>,
+}
+
+impl SubnetQuota {
+    /// `prefix6` is at most `ipv6_prefix`, the prefix addresses are admitted by.
+    pub fn new(per_hour: u32, prefix: u8, prefix6: u8, ipv6_prefix: u8) -> Self {
+        Self {
+            per_hour,
+            prefix,
+            prefix6: prefix6.min(ipv6_prefix),
+            buckets: Mutex::default(),
+            exempt: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn prefix(&self, ip: IpAddr) -> u8 {
+        match ip {
+            IpAddr::V4(_) => self.prefix,
+            IpAddr::V6(_) => self.prefix6,
+        }
+    }
+
+    /// Network address of the subnet `ip` is counted in.
+    fn subnet(&self, ip: IpAddr) -> IpAddr {
+        let prefix = self.prefix(ip) as u32;
+        match ip {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                IpAddr::V4((u32::from(ip) & mask).into())
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                IpAddr::V6((u128::from(ip) & mask).into())
+            }
+        }
+    }
+
+    /// `ip`'s subnet in CIDR notation.
+    pub fn describe(&self, ip: IpAddr) -> String {
+        format!("{}/{}", self.subnet(ip), self.prefix(ip))
+    }
+
+    /// Take a token for a new entry from `ip`'s subnet. Returns false when it has none left.
+    pub fn take(&self, ip: IpAddr) -> bool {
+        self.take_at(ip, Instant::now())
+    }
+
+    /// [`SubnetQuota::take`] by the clock of a replay rather than the wall clock.
+    pub fn take_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let subnet = self.subnet(ip);
+        if self.exempt.lock().unwrap().contains(&subnet) {
+            return true;
+        }
+
+        let capacity = self.per_hour as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets { by_subnet, by_age } = &mut *buckets;
+
+        // Forget buckets that refilled, they are recreated full anyway, and the least recently
+        // seen ones over the limit. Only the oldest are looked at, each bucket goes once.
+        while let Some(&(updated, oldest)) = by_age.first() {
+            let idle = now.saturating_duration_since(updated).as_secs_f64();
+            let refilled = by_subnet[&oldest].tokens + idle * capacity / 3600.0 >= capacity;
+            if !refilled && by_subnet.len() < MAX_BUCKETS {
+                break;
+            }
+            by_age.pop_first();
+            by_subnet.remove(&oldest);
+        }
+
+        let bucket = by_subnet.entry(subnet).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        by_age.remove(&(bucket.updated, subnet));
+        let tokens = refill(bucket, capacity, now);
+        by_age.insert((now, subnet));
+        if tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Let `ip`'s subnet whitelist as many addresses as it likes. Returns false if it already could.
+    pub fn exempt(&self, ip: IpAddr) -> bool {
+        self.exempt.lock().unwrap().insert(self.subnet(ip))
+    }
+
+    /// Put `ip`'s subnet back under the quota. Returns whether it was exempt.
+    pub fn unexempt(&self, ip: IpAddr) -> bool {
+        self.exempt.lock().unwrap().remove(&self.subnet(ip))
+    }
+
+    pub fn exempted(&self) -> Vec<String> {
+        self.exempt
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|subnet| self.describe(*subnet))
+            .collect()
+    }
+}
+
+/// Add the tokens accrued since the last update and return the new level.
+fn refill(bucket: &mut Bucket, capacity: f64, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * capacity / 3600.0).min(capacity);
+    bucket.updated = now;
+    bucket.tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn limits_each_subnet() {
+        let quota = SubnetQuota::new(2, 24, 48, 64);
+        let start = Instant::now();
+        assert!(quota.take_at(ip("192.0.2.1"), start));
+        assert!(quota.take_at(ip("192.0.2.2"), start));
+        assert!(!quota.take_at(ip("192.0.2.3"), start));
+        assert!(quota.take_at(ip("192.0.3.1"), start));
+
+        // One token back every half hour
+        assert!(!quota.take_at(ip("192.0.2.3"), start + Duration::from_secs(1700)));
+        assert!(quota.take_at(ip("192.0.2.3"), start + Duration::from_secs(1800)));
+        assert!(!quota.take_at(ip("192.0.2.3"), start + Duration::from_secs(1800)));
+    }
+
+    #[test]
+    fn groups_ipv6_by_its_own_prefix() {
+        let quota = SubnetQuota::new(1, 24, 48, 64);
+        assert_eq!(quota.describe(ip("2001:db8:1:2::1")), "2001:db8:1::/48");
+        assert!(quota.take(ip("2001:db8:1:2::1")));
+        assert!(!quota.take(ip("2001:db8:1:3::1")));
+        assert!(quota.take(ip("2001:db8:2::1")));
+
+        // Never finer than what addresses are admitted by
+        let quota = SubnetQuota::new(1, 24, 64, 56);
+        assert_eq!(
+            quota.describe(ip("2001:db8:1:2ff::1")),
+            "2001:db8:1:200::/56"
+        );
+        let quota = SubnetQuota::new(1, 0, 0, 64);
+        assert_eq!(quota.describe(ip("192.0.2.1")), "0.0.0.0/0");
+        assert_eq!(quota.describe(ip("2001:db8::1")), "::/0");
+    }
+
+    #[test]
+    fn exempts_subnets() {
+        let quota = SubnetQuota::new(1, 24, 48, 64);
+        assert!(quota.take(ip("192.0.2.1")));
+        assert!(quota.exempt(ip("192.0.2.200")));
+        assert!(!quota.exempt(ip("192.0.2.1")));
+        assert_eq!(quota.exempted(), vec!["192.0.2.0/24"]);
+        assert!(quota.take(ip("192.0.2.2")));
+
+        assert!(quota.unexempt(ip("192.0.2.1")));
+        assert!(!quota.unexempt(ip("192.0.2.1")));
+        assert!(!quota.take(ip("192.0.2.2")));
+    }
+
+    #[test]
+    fn caps_subnets() {
+        let quota = SubnetQuota::new(10, 32, 48, 64);
+        let start = Instant::now();
+        for n in 0..MAX_BUCKETS as u32 + 100 {
+            assert!(quota.take_at(IpAddr::V4(Ipv4Addr::from(n)), start));
+        }
+        let buckets = quota.buckets.lock().unwrap();
+        assert_eq!(buckets.by_subnet.len(), MAX_BUCKETS);
+        assert_eq!(buckets.by_age.len(), MAX_BUCKETS);
+        // The least recently seen go first
+        let first = IpAddr::V4(Ipv4Addr::from(0));
+        assert!(!buckets.by_subnet.contains_key(&first));
+    }
+}
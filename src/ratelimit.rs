@@ -0,0 +1,182 @@
+//! Per-client rate limit on the public listener with `--rate-limit`. Every request does kernel
+//! work behind the whitelist locks, so a single client flooding the endpoint could hold up
+//! everyone else's admission. Requests over the limit get a 429 before reaching a handler. Each
+//! client, see [`crate::client::unit`], gets a token bucket of `--rate-limit-burst` requests
+//! refilled at `--rate-limit` per second.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{client::ClientInfo, metrics::Outcome, state::AppState};
+
+/// Most clients tracked at once. Past it the least recently seen ones are forgotten, and start
+/// over with a full bucket.
+const MAX_BUCKETS: usize = 65536;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_ip: HashMap<IpAddr, Bucket>,
+    /// Every bucket by when it was last updated, oldest first
+    by_age: BTreeSet<(Instant, IpAddr)>,
+}
+
+pub struct RateLimiter {
+    /// Tokens added per second
+    rate: f64,
+    /// Tokens per bucket
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Take a token from `ip`'s bucket. Returns the seconds until it has one again when empty.
+    pub fn take(&self, ip: IpAddr) -> Result<(), u64> {
+        self.take_at(ip, Instant::now())
+    }
+
+    fn take_at(&self, ip: IpAddr, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets { by_ip, by_age } = &mut *buckets;
+
+        // Forget buckets that refilled, they are recreated full anyway, and the least recently
+        // seen ones over the limit. Only the oldest are looked at, each bucket goes once.
+        while let Some(&(updated, oldest)) = by_age.first() {
+            let idle = now.saturating_duration_since(updated).as_secs_f64();
+            let refilled = by_ip[&oldest].tokens + idle * self.rate >= self.burst;
+            if !refilled && by_ip.len() < MAX_BUCKETS {
+                break;
+            }
+            by_age.pop_first();
+            by_ip.remove(&oldest);
+        }
+
+        let bucket = by_ip.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        by_age.remove(&(bucket.updated, ip));
+        let tokens = self.refill(bucket, now);
+        by_age.insert((now, ip));
+        if tokens < 1.0 {
+            return Err(((1.0 - tokens) / self.rate).ceil() as u64);
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Add the tokens accrued since the last update and return the new level.
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+/// Refuse requests of clients over the limit. Runs after [`crate::forwarded::real_client`], so
+/// clients behind a trusted proxy are limited rather than the proxy.
+pub async fn limit(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(limiter) = &state.rate_limiter
+        && let Err(retry_after) = limiter.take(client.unit(state.args.ipv6_prefix))
+    {
+        // Not journaled, a flood would drown out everything else in it
+        state.metrics.record(Outcome::RateLimited);
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(n))
+    }
+
+    #[test]
+    fn limits_each_client() {
+        let limiter = RateLimiter::new(2, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.take_at(ip(1), start), Ok(()));
+        }
+        assert_eq!(limiter.take_at(ip(1), start), Err(1));
+        // Others have buckets of their own
+        assert_eq!(limiter.take_at(ip(2), start), Ok(()));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.take_at(ip(1), later), Ok(()));
+        assert_eq!(limiter.take_at(ip(1), later), Err(1));
+    }
+
+    #[test]
+    fn forgets_refilled_buckets() {
+        let limiter = RateLimiter::new(1, 2);
+        let start = Instant::now();
+        limiter.take_at(ip(1), start).unwrap();
+        limiter
+            .take_at(ip(2), start + Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().by_ip.len(), 2);
+
+        // ip(1) has refilled by then, ip(2) hasn't
+        limiter
+            .take_at(ip(3), start + Duration::from_millis(1200))
+            .unwrap();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.by_ip.contains_key(&ip(1)));
+        assert!(buckets.by_ip.contains_key(&ip(2)));
+        assert_eq!(buckets.by_age.len(), buckets.by_ip.len());
+    }
+
+    #[test]
+    fn caps_clients() {
+        // Nobody refills within the test, so only the cap evicts
+        let limiter = RateLimiter::new(1, 10);
+        let start = Instant::now();
+        for n in 0..MAX_BUCKETS as u32 + 100 {
+            limiter.take_at(ip(n), start).unwrap();
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_ip.len(), MAX_BUCKETS);
+        assert_eq!(buckets.by_age.len(), MAX_BUCKETS);
+        // The most recent clients are kept
+        assert!(buckets.by_ip.contains_key(&ip(MAX_BUCKETS as u32 + 99)));
+    }
+}
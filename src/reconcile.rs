@@ -0,0 +1,69 @@
+//! Reconciliation of the whitelist map with the kernel set. The map only caches what the set
+//! holds, so an add that failed without anyone noticing or someone flushing the set by hand
+//! would leave them apart for good. Every `--reconcile-interval` seconds the set is listed,
+//! live entries it is missing are added back and entries the map doesn't know are removed.
+
+use std::{collections::HashSet, net::IpAddr, sync::Arc, time::Duration};
+
+use anyhow::Result;
+
+use crate::{cleaner, comment::Comment, state::AppState};
+
+/// Entries this close to their expiry may be gone from one side already and not the other.
+const SLACK: Duration = Duration::from_secs(5);
+
+pub async fn task(state: Arc<AppState>) {
+    let interval = Duration::from_secs(state.args.reconcile_interval);
+    loop {
+        tokio::time::sleep(interval).await;
+        match reconcile(&state).await {
+            Ok((0, 0)) => {}
+            Ok((missing, stray)) => tracing::warn!(
+                "Whitelist set drifted from the map: added back {} missing entries, removed {} stray ones",
+                missing,
+                stray
+            ),
+            Err(e) => tracing::error!("Failed to reconcile the whitelist set: {:#}", e),
+        }
+    }
+}
+
+/// Make the kernel set hold the live entries of the whitelist map, returns how many were
+/// missing from it and how many it held that the map doesn't. Admissions queued on the slow path
+/// count as missing, adding them early does no harm.
+async fn reconcile(state: &AppState) -> Result<(usize, usize)> {
+    let whitelist = state.whitelist.lock().await;
+    let pinned = state.pinned.lock().await;
+    let mut ipset = state.ipset_session.lock().await;
+
+    let listed: HashSet<IpAddr> = ipset.list()?.into_iter().map(|entry| entry.ip).collect();
+    let mut missing = 0;
+    for (ip, last_seen) in whitelist.iter() {
+        let pin = pinned.contains(ip);
+        if listed.contains(ip) || !pin && last_seen.elapsed() + SLACK >= cleaner::ENTRY_TTL {
+            continue;
+        }
+        ipset
+            .add_commented(
+                *ip,
+                Some(cleaner::timeout_left(*last_seen, pin)),
+                &Comment::whitelist(state, pin, None),
+            )
+            .inspect_err(|e| state.metrics.record_add_error(e))?;
+        missing += 1;
+    }
+    // Expired entries stay in the map until it fills up, the kernel may keep them a moment longer
+    let stray: Vec<IpAddr> = listed
+        .into_iter()
+        .filter(|ip| !whitelist.contains_key(ip))
+        .collect();
+    for ip in &stray {
+        ipset
+            .del_ip(*ip)
+            .inspect_err(|_| state.metrics.record_netlink_error("del"))?;
+    }
+
+    state.metrics.record_reconcile_drift("missing", missing);
+    state.metrics.record_reconcile_drift("stray", stray.len());
+    Ok((missing, stray.len()))
+}
@@ -0,0 +1,294 @@
+//! Where `/<key>` sends a client once it is admitted. Keys are looked up in the `[redirects]`
+//! config, e.g. `fastdl = "https://fastdl.example.com/"`, and unknown ones get a 404. Without
+//! any redirects configured the key is the target itself, as long as it is a relative path or
+//! matches `redirect_allow`, so mortis can't be used to send people anywhere else.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use anyhow::{Result, bail};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use serde::{Deserialize, Deserializer, de};
+
+use crate::{config::Config, state::AppState};
+
+/// Target a key may be redirected to, e.g. `steam:` for every `steam://connect/...` URL,
+/// `https://fastdl.example.com/maps/` for that host and path or `https://*.example.com` for
+/// its subdomains.
+#[derive(Clone, Debug)]
+pub struct AllowedTarget {
+    scheme: String,
+    /// `None` allows every target of the scheme
+    host: Option<String>,
+    /// Prefix of the path
+    path: String,
+}
+
+/// A target split into its scheme, authority and the rest, `None` when it has no scheme.
+fn split(target: &str) -> Option<(&str, Option<(&str, &str)>)> {
+    let (scheme, rest) = target.split_once(':')?;
+    if !scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    {
+        return None;
+    }
+    let authority = rest.strip_prefix("//").map(|rest| {
+        let end = rest.find(['/', '\\', '?', '#']).unwrap_or(rest.len());
+        rest.split_at(end)
+    });
+    Some((scheme, authority))
+}
+
+/// An authority split into its host and port, `None` when the port isn't a number.
+fn host_port(authority: &str) -> Option<(&str, Option<&str>)> {
+    match authority.rsplit_once(':') {
+        // The colons of an IPv6 address
+        _ if authority.ends_with(']') => Some((authority, None)),
+        Some((host, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            Some((host, Some(port)))
+        }
+        Some(_) => None,
+        None => Some((authority, None)),
+    }
+}
+
+impl AllowedTarget {
+    fn allows(&self, scheme: &str, authority: Option<(&str, &str)>) -> bool {
+        if !scheme.eq_ignore_ascii_case(&self.scheme) {
+            return false;
+        }
+        let Some(host) = &self.host else {
+            return true;
+        };
+        let Some((authority, rest)) = authority else {
+            return false;
+        };
+        // Whatever comes before an @ is only credentials
+        if authority.contains('@') || !rest.starts_with(&self.path) {
+            return false;
+        }
+        let authority = authority.to_ascii_lowercase();
+        let (Some((host, port)), Some((allowed, allowed_port))) =
+            (host_port(&authority), host_port(host))
+        else {
+            return false;
+        };
+        port == allowed_port
+            && match allowed.strip_prefix("*.") {
+                Some(parent) => host.ends_with(&format!(".{}", parent)),
+                None => host == allowed,
+            }
+    }
+}
+
+impl FromStr for AllowedTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{:?} is neither a scheme like steam: nor a URL", s);
+        let (scheme, authority) = split(s).ok_or_else(invalid)?;
+        let (host, path) = match authority {
+            Some((host, path)) if !host.is_empty() && !host.contains('@') => {
+                (Some(host.to_ascii_lowercase()), path.to_string())
+            }
+            None if s.len() == scheme.len() + 1 => (None, String::new()),
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            scheme: scheme.to_ascii_lowercase(),
+            host,
+            path,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for AllowedTarget {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+pub struct Redirects {
+    targets: BTreeMap<String, String>,
+    allow: Vec<AllowedTarget>,
+}
+
+impl Redirects {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            targets: config.redirects.clone(),
+            allow: config.redirect_allow.clone(),
+        }
+    }
+
+    /// Whether a client may be sent to the `target` it asked for.
+    fn allows(&self, target: &str) -> bool {
+        // Browsers drop tabs and newlines from URLs, and they wouldn't fit in a header anyway
+        if target.chars().any(|c| c.is_ascii_control() || c == ' ') {
+            return false;
+        }
+        match split(target) {
+            Some((scheme, authority)) => self
+                .allow
+                .iter()
+                .any(|allowed| allowed.allows(scheme, authority)),
+            // `//host` and `/\host` are other hosts to a browser
+            None => !target.starts_with(['/', '\\']) || !target[1..].starts_with(['/', '\\']),
+        }
+    }
+}
+
+pub fn respond(state: &AppState, key: &str) -> Response {
+    let redirects = state.redirects.read().unwrap();
+    if redirects.targets.is_empty() {
+        if !redirects.allows(key) {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        return Redirect::temporary(key).into_response();
+    }
+    match redirects.targets.get(key) {
+        Some(target) => Redirect::temporary(target).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Targets must be absolute http(s) URLs or paths on this host.
+pub fn validate(redirects: &BTreeMap<String, String>) -> Result<()> {
+    for (key, target) in redirects {
+        if key.is_empty() || key.starts_with('/') {
+            bail!("redirect key {:?} may not be empty or start with /", key);
+        }
+        if !target.starts_with("http://")
+            && !target.starts_with("https://")
+            && !target.starts_with('/')
+        {
+            bail!(
+                "redirect target {:?} of {} must be an http(s) URL or start with /",
+                target,
+                key
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirects(allow: &[&str]) -> Redirects {
+        Redirects {
+            targets: BTreeMap::new(),
+            allow: allow.iter().map(|target| target.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn allows_relative_paths_only() {
+        let redirects = redirects(&[]);
+        for target in [
+            "/maps/",
+            "maps/gm_construct.bsp",
+            "/",
+            "a//b",
+            "%2F%2Fevil.com",
+        ] {
+            assert!(redirects.allows(target), "{}", target);
+        }
+        // What /%2F%2Fevil.com is decoded to, browsers take all of these for other hosts
+        for target in [
+            "//evil.com",
+            "/\\evil.com",
+            "\\/evil.com",
+            "\\\\evil.com",
+            "https://evil.com",
+            "javascript:alert(1)",
+            "JavaScript:alert(1)",
+            "steam://connect/192.0.2.1",
+        ] {
+            assert!(!redirects.allows(target), "{}", target);
+        }
+    }
+
+    #[test]
+    fn refuses_control_characters() {
+        let redirects = redirects(&["https://fastdl.example.com"]);
+        for target in [
+            "/maps/\n",
+            "/\t/evil.com",
+            "java\tscript:alert(1)",
+            " //evil.com",
+            "https://fastdl.example.com/\r\nSet-Cookie: a=b",
+        ] {
+            assert!(!redirects.allows(target), "{:?}", target);
+        }
+    }
+
+    #[test]
+    fn matches_hosts() {
+        let redirects = redirects(&["https://fastdl.example.com/maps/", "steam:"]);
+        assert!(redirects.allows("https://fastdl.example.com/maps/gm_construct.bsp"));
+        assert!(redirects.allows("HTTPS://FASTDL.example.com/maps/"));
+        assert!(redirects.allows("steam://connect/192.0.2.1:27015"));
+        for target in [
+            "https://fastdl.example.com/other/",
+            "https://fastdl.example.com",
+            "http://fastdl.example.com/maps/",
+            "https://fastdl.example.com.evil.com/maps/",
+            "https://user@fastdl.example.com/maps/",
+            "https://fastdl.example.com@evil.com/maps/",
+            "https://fastdl.example.com:8080/maps/",
+            "https:fastdl.example.com/maps/",
+            "https:/\\fastdl.example.com/maps/",
+            "javascript:alert(1)",
+        ] {
+            assert!(!redirects.allows(target), "{}", target);
+        }
+    }
+
+    #[test]
+    fn matches_subdomains() {
+        let redirects = redirects(&["https://*.example.com", "https://cdn.example.net:8443"]);
+        assert!(redirects.allows("https://fastdl.example.com/"));
+        assert!(redirects.allows("https://a.b.example.com"));
+        assert!(redirects.allows("https://cdn.example.net:8443/maps/"));
+        for target in [
+            "https://example.com/",
+            "https://evilexample.com/",
+            "https://example.com.evil.com/",
+            "https://evil.com:1.example.com/",
+            "https://fastdl.example.com:8080/",
+            "https://cdn.example.net/maps/",
+            "https://cdn.example.net:443/maps/",
+        ] {
+            assert!(!redirects.allows(target), "{}", target);
+        }
+    }
+
+    #[test]
+    fn parses_allowed_targets() {
+        for target in [
+            "steam:",
+            "https://fastdl.example.com/maps/",
+            "https://*.example.com",
+        ] {
+            assert!(target.parse::<AllowedTarget>().is_ok(), "{}", target);
+        }
+        for target in [
+            "",
+            "fastdl.example.com",
+            "https://",
+            "https://user@fastdl.example.com",
+            "steam:connect",
+            "1http://example.com",
+        ] {
+            assert!(target.parse::<AllowedTarget>().is_err(), "{}", target);
+        }
+    }
+}
@@ -0,0 +1,543 @@
+//! Sessions of SDK clients and the signed, single-use refresh URLs that carry them.
+//!
+//! A session starts at the first admission and may span several addresses, e.g. a player
+//! roaming between WiFi and LTE. All of its addresses share the session's expiry. A refresh URL
+//! only works from the address it was issued to, so a scraped one is of no use elsewhere. To
+//! refresh from a new address the client also sends the session key, which only
+//! `/sdk/session` hands out, in [`SESSION_KEY_HEADER`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    Json,
+    extract::{self, ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use axum_extra::{TypedHeader, headers};
+use hmac::Mac;
+use serde::Serialize;
+
+use crate::{
+    AppError,
+    cleaner::{self, ENTRY_TTL},
+    client::ClientInfo,
+    comment::Comment,
+    pipeline::{self, Profile},
+    signing::{self, HmacSha256},
+    snapshot::unix_now,
+    state::AppState,
+    steam,
+};
+
+pub const SESSION_KEY_HEADER: &str = "X-Mortis-Session-Key";
+
+/// Clients should refresh well before their entry expires.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(ENTRY_TTL.as_secs() / 2);
+
+struct Session {
+    ips: HashSet<IpAddr>,
+    /// Address the current refresh URL was issued to
+    issued_to: IpAddr,
+    /// Bumped on every refresh, so each issued URL works only once
+    generation: u64,
+    expires_at: u64,
+}
+
+pub struct Refresher {
+    secret: Vec<u8>,
+    max_ips: usize,
+    sessions: Mutex<HashMap<u128, Session>>,
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    ips: HashSet<IpAddr>,
+    expires_at: u64,
+}
+
+enum Refresh {
+    Denied,
+    /// The session already has as many live addresses as it may
+    Full,
+    Accepted {
+        response: RefreshResponse,
+        /// The session's other addresses, which share its expiry
+        others: Vec<IpAddr>,
+    },
+}
+
+#[derive(Serialize)]
+pub struct RefreshResponse {
+    session: String,
+    /// Secret to send in [`SESSION_KEY_HEADER`] when refreshing from another address, only
+    /// handed out by `/sdk/session`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_key: Option<String>,
+    /// Path to request for the next refresh
+    refresh_url: String,
+    /// Seconds to wait before refreshing
+    interval: u64,
+    /// Unix timestamp after which `refresh_url` stops working
+    expires_at: u64,
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Format as a version 4 UUID, the version and variant bits are set by [`new_session_id`].
+fn uuid(id: u128) -> String {
+    let hex = format!("{:032x}", id);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn parse_uuid(s: &str) -> Option<u128> {
+    if s.len() != 36 {
+        return None;
+    }
+    u128::from_str_radix(&s.replace('-', ""), 16).ok()
+}
+
+fn new_session_id() -> Result<u128> {
+    let id = u128::from_be_bytes(random_bytes()?);
+    Ok((id & !(0xf << 76) | (0x4 << 76)) & !(0x3 << 62) | (0x2 << 62))
+}
+
+/// Session, generation, expiry and signature of a refresh URL token.
+fn parse_token(token: &str) -> Option<(u128, u64, u64, Vec<u8>)> {
+    let mut parts = token.split('.');
+    let session = parse_uuid(parts.next()?)?;
+    let generation = parts.next()?.parse().ok()?;
+    let expires_at = parts.next()?.parse().ok()?;
+    let signature = signing::unhex(parts.next()?)?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((session, generation, expires_at, signature))
+}
+
+impl Refresher {
+    /// Use the secret in `secret_file`, so URLs survive restarts and work across instances,
+    /// or a random one.
+    pub fn new(secret_file: Option<&Path>, max_ips: usize) -> Result<Self> {
+        let secret = match secret_file {
+            Some(path) => {
+                std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?
+            }
+            None => random_bytes::<32>()?.to_vec(),
+        };
+
+        Ok(Self {
+            secret,
+            max_ips,
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn sign(&self, session: u128, generation: u64, expires_at: u64, ip: IpAddr) -> HmacSha256 {
+        let mut mac = signing::mac(&self.secret);
+        mac.update(format!("{}.{}.{}.{}", uuid(session), generation, expires_at, ip).as_bytes());
+        mac
+    }
+
+    /// The session key is derived from the secret, so it needn't be kept.
+    fn key(&self, session: u128) -> HmacSha256 {
+        let mut mac = signing::mac(&self.secret);
+        mac.update(format!("key.{}", uuid(session)).as_bytes());
+        mac
+    }
+
+    /// Move `session` on to its next generation, issued to `ip`, and build its refresh URL.
+    fn next_url(&self, id: u128, session: &mut Session, ip: IpAddr) -> RefreshResponse {
+        let expires_at = unix_now() + ENTRY_TTL.as_secs();
+        if !session.ips.is_empty() {
+            session.generation += 1;
+        }
+        session.ips.insert(ip);
+        session.issued_to = ip;
+        session.expires_at = expires_at;
+
+        let signature = self
+            .sign(id, session.generation, expires_at, ip)
+            .finalize()
+            .into_bytes();
+
+        RefreshResponse {
+            session: uuid(id),
+            session_key: None,
+            refresh_url: format!(
+                "/sdk/refresh/{}.{}.{}.{}",
+                uuid(id),
+                session.generation,
+                expires_at,
+                signing::hex(&signature)
+            ),
+            interval: REFRESH_INTERVAL.as_secs(),
+            expires_at,
+        }
+    }
+
+    /// Start `session` for its first address and hand out its key and first refresh URL.
+    fn issue(&self, id: u128, ip: IpAddr) -> RefreshResponse {
+        let now = unix_now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.expires_at > now);
+        let session = sessions.entry(id).or_insert_with(|| Session {
+            ips: HashSet::new(),
+            issued_to: ip,
+            generation: 0,
+            expires_at: now,
+        });
+        let response = self.next_url(id, session, ip);
+
+        RefreshResponse {
+            session_key: Some(signing::hex(&self.key(id).finalize().into_bytes())),
+            ..response
+        }
+    }
+
+    /// Use up the refresh URL token presented from `ip` with the session key `key`, if sent,
+    /// and hand out the next one. Addresses for which `live` is false have left the whitelist
+    /// and no longer count towards the session's limit. The checks and the update are made
+    /// under one lock, so a token can't be used twice by concurrent requests.
+    fn refresh(
+        &self,
+        token: &str,
+        ip: IpAddr,
+        key: Option<&str>,
+        live: impl Fn(IpAddr) -> bool,
+    ) -> Refresh {
+        let Some((id, generation, expires_at, signature)) = parse_token(token) else {
+            return Refresh::Denied;
+        };
+        let now = unix_now();
+        if expires_at <= now {
+            return Refresh::Denied;
+        }
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.expires_at > now);
+        let Some(session) = sessions.get_mut(&id) else {
+            return Refresh::Denied;
+        };
+        if session.generation != generation
+            || self
+                .sign(id, generation, expires_at, session.issued_to)
+                .verify_slice(&signature)
+                .is_err()
+        {
+            return Refresh::Denied;
+        }
+        // Taking the session to another address needs more than its URL
+        if ip != session.issued_to
+            && key
+                .and_then(signing::unhex)
+                .is_none_or(|key| self.key(id).verify_slice(&key).is_err())
+        {
+            return Refresh::Denied;
+        }
+
+        session.ips.retain(|other| *other == ip || live(*other));
+        if !session.ips.contains(&ip) && session.ips.len() >= self.max_ips {
+            return Refresh::Full;
+        }
+        let response = self.next_url(id, session, ip);
+        let others = session
+            .ips
+            .iter()
+            .copied()
+            .filter(|other| *other != ip)
+            .collect();
+
+        Refresh::Accepted { response, others }
+    }
+
+    pub fn session(&self, session: &str) -> Option<SessionInfo> {
+        let session = parse_uuid(session)?;
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(&session).map(|s| SessionInfo {
+            ips: s.ips.clone(),
+            expires_at: s.expires_at,
+        })
+    }
+
+    /// Invalidate every URL issued for `session`. Returns whether it existed.
+    pub fn revoke(&self, session: &str) -> bool {
+        parse_uuid(session)
+            .is_some_and(|session| self.sessions.lock().unwrap().remove(&session).is_some())
+    }
+}
+
+/// Whitelist the client like `/` does and start a refresh session for it.
+pub async fn start(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
+    TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
+    request_headers: HeaderMap,
+) -> std::result::Result<Response, AppError> {
+    let ip = client.unit(state.args.ipv6_prefix);
+    let ticket = steam::ticket(&request_headers);
+    if let Some(status) = pipeline::admit(
+        &state,
+        Profile::Sdk,
+        ip,
+        Some(user_agent.as_str()),
+        None,
+        ticket,
+    )
+    .await?
+    {
+        return Ok(status.into_response());
+    }
+    let response = state.refresher.issue(new_session_id()?, ip);
+
+    Ok(Json(response).into_response())
+}
+
+/// Refresh through a URL issued by [`start`] or a previous refresh, handing out the next one.
+/// The URL is used up even when the admission is then refused.
+pub async fn refresh(
+    extract::Path(token): extract::Path<String>,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
+    request_headers: HeaderMap,
+) -> std::result::Result<Response, AppError> {
+    let ip = client.unit(state.args.ipv6_prefix);
+    let key = request_headers
+        .get(SESSION_KEY_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let refreshed = {
+        let whitelist = state.whitelist.lock().await;
+        let pinned = state.pinned.lock().await;
+        state.refresher.refresh(&token, ip, key, |ip| {
+            whitelist
+                .get(&ip)
+                .is_some_and(|last_seen| cleaner::is_live(*last_seen, pinned.contains(&ip)))
+        })
+    };
+    let (response, others) = match refreshed {
+        Refresh::Accepted { response, others } => (response, others),
+        Refresh::Full => return Ok(StatusCode::TOO_MANY_REQUESTS.into_response()),
+        Refresh::Denied => return Ok(StatusCode::FORBIDDEN.into_response()),
+    };
+
+    if let Some(status) = pipeline::admit(&state, Profile::Refresh, ip, None, None, None).await? {
+        return Ok(status.into_response());
+    }
+    extend(&state, &others).await;
+
+    Ok(Json(response).into_response())
+}
+
+/// Push back the expiry of the session's other addresses that are still whitelisted.
+async fn extend(state: &AppState, ips: &[IpAddr]) {
+    let mut whitelist = state.whitelist.lock().await;
+    let pinned = state.pinned.lock().await;
+    let mut ipset = state.ipset_session.lock().await;
+    for ip in ips {
+        let Some(last_seen) = whitelist.get_mut(ip) else {
+            continue;
+        };
+        if pinned.contains(ip) || !cleaner::is_live(*last_seen, false) {
+            continue;
+        }
+        // Only pushed back if the kernel restarted its timeout as well
+        match ipset.add_commented(*ip, None, &Comment::whitelist(state, false, None)) {
+            Ok(()) => *last_seen = tokio::time::Instant::now(),
+            Err(e) => {
+                state.metrics.record_add_error(&e);
+                tracing::debug!("Failed to extend {}: {}", ip, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refresher(max_ips: usize) -> Refresher {
+        Refresher {
+            secret: b"refresh-secret".to_vec(),
+            max_ips,
+            sessions: Mutex::default(),
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn token(response: &RefreshResponse) -> &str {
+        response.refresh_url.strip_prefix("/sdk/refresh/").unwrap()
+    }
+
+    fn live(_: IpAddr) -> bool {
+        true
+    }
+
+    /// Refresh with the URL of `response` and hand out the next response.
+    fn accept(
+        refresher: &Refresher,
+        response: &RefreshResponse,
+        ip: IpAddr,
+        key: Option<&str>,
+    ) -> (RefreshResponse, Vec<IpAddr>) {
+        match refresher.refresh(token(response), ip, key, live) {
+            Refresh::Accepted { response, others } => (response, others),
+            Refresh::Full => panic!("refused as full"),
+            Refresh::Denied => panic!("denied"),
+        }
+    }
+
+    #[test]
+    fn issues_single_use_urls() {
+        let refresher = refresher(3);
+        let first = refresher.issue(1, ip("192.0.2.1"));
+        assert!(first.session_key.is_some());
+
+        let (second, others) = accept(&refresher, &first, ip("192.0.2.1"), None);
+        assert!(others.is_empty());
+        assert_eq!(second.session, first.session);
+        // Only /sdk/session hands out the key
+        assert!(second.session_key.is_none());
+        assert_ne!(second.refresh_url, first.refresh_url);
+
+        assert!(matches!(
+            refresher.refresh(token(&first), ip("192.0.2.1"), None, live),
+            Refresh::Denied
+        ));
+        accept(&refresher, &second, ip("192.0.2.1"), None);
+    }
+
+    #[test]
+    fn denies_bad_tokens() {
+        let refresher = refresher(3);
+        let response = refresher.issue(1, ip("192.0.2.1"));
+        let token = token(&response);
+
+        let mut tampered = token.to_string();
+        let last = tampered.pop().unwrap();
+        tampered.push(if last == '0' { '1' } else { '0' });
+        let (session, rest) = token.split_once('.').unwrap();
+        let (generation, rest) = rest.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let longer = format!("{}.{}.{}.{}", session, generation, u64::MAX, signature);
+        let expired = format!(
+            "{}.{}.{}.{}",
+            session,
+            generation,
+            1,
+            signing::hex(
+                &refresher
+                    .sign(1, 0, 1, ip("192.0.2.1"))
+                    .finalize()
+                    .into_bytes()
+            )
+        );
+
+        for token in [
+            tampered.as_str(),
+            &longer,
+            &expired,
+            "",
+            "garbage",
+            &format!("{}.extra", token),
+        ] {
+            assert!(
+                matches!(
+                    refresher.refresh(token, ip("192.0.2.1"), None, live),
+                    Refresh::Denied
+                ),
+                "{}",
+                token
+            );
+        }
+        accept(&refresher, &response, ip("192.0.2.1"), None);
+    }
+
+    #[test]
+    fn moves_only_with_the_session_key() {
+        let refresher = refresher(3);
+        let response = refresher.issue(1, ip("192.0.2.1"));
+        let key = response.session_key.clone().unwrap();
+
+        for key in [None, Some("00"), Some("not hex")] {
+            assert!(matches!(
+                refresher.refresh(token(&response), ip("198.51.100.1"), key, live),
+                Refresh::Denied
+            ));
+        }
+        let other_key = refresher.issue(2, ip("203.0.113.1")).session_key.unwrap();
+        assert!(matches!(
+            refresher.refresh(token(&response), ip("198.51.100.1"), Some(&other_key), live),
+            Refresh::Denied
+        ));
+
+        let (moved, others) = accept(&refresher, &response, ip("198.51.100.1"), Some(&key));
+        assert_eq!(others, vec![ip("192.0.2.1")]);
+        // The next URL belongs to the new address
+        assert!(matches!(
+            refresher.refresh(token(&moved), ip("192.0.2.1"), None, live),
+            Refresh::Denied
+        ));
+        accept(&refresher, &moved, ip("198.51.100.1"), None);
+    }
+
+    #[test]
+    fn limits_live_addresses() {
+        let refresher = refresher(2);
+        let response = refresher.issue(1, ip("192.0.2.1"));
+        let key = response.session_key.clone().unwrap();
+        let (response, _) = accept(&refresher, &response, ip("192.0.2.2"), Some(&key));
+
+        assert!(matches!(
+            refresher.refresh(token(&response), ip("192.0.2.3"), Some(&key), live),
+            Refresh::Full
+        ));
+        // Refused refreshes don't use up the URL, and addresses that left the whitelist don't
+        // count
+        match refresher.refresh(token(&response), ip("192.0.2.3"), Some(&key), |ip| {
+            ip != "192.0.2.1".parse::<IpAddr>().unwrap()
+        }) {
+            Refresh::Accepted { others, .. } => assert_eq!(others, vec![ip("192.0.2.2")]),
+            _ => panic!("not accepted"),
+        }
+        let info = refresher.session(&uuid(1)).unwrap();
+        assert_eq!(info.ips, HashSet::from([ip("192.0.2.2"), ip("192.0.2.3")]));
+    }
+
+    #[test]
+    fn revokes_sessions() {
+        let refresher = refresher(3);
+        let response = refresher.issue(1, ip("192.0.2.1"));
+        assert!(refresher.session(&response.session).is_some());
+
+        assert!(refresher.revoke(&response.session));
+        assert!(!refresher.revoke(&response.session));
+        assert!(!refresher.revoke("garbage"));
+        assert!(refresher.session(&response.session).is_none());
+        assert!(matches!(
+            refresher.refresh(token(&response), ip("192.0.2.1"), None, live),
+            Refresh::Denied
+        ));
+    }
+}
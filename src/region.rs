@@ -0,0 +1,29 @@
+use std::{net::IpAddr, sync::Arc};
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+};
+use serde::Serialize;
+
+use crate::{client::ClientInfo, state::AppState};
+
+#[derive(Serialize)]
+pub struct RegionResponse {
+    region: Option<String>,
+    client: IpAddr,
+    /// Round trip time to the client in milliseconds, if the kernel measured one
+    rtt_ms: Option<f64>,
+}
+
+/// Lets clients of multi-region communities compare endpoints before pinging one of them.
+pub async fn region(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
+) -> Json<RegionResponse> {
+    Json(RegionResponse {
+        region: state.args.region.clone(),
+        client: client.addr.ip(),
+        rtt_ms: client.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+    })
+}
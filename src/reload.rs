@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use tokio::signal::unix::{SignalKind, signal};
+
+use crate::{
+    config, firewall, notify::Kind, pipeline::Pipelines, policy::Admission, redirect::Redirects,
+    state::AppState, tokens::Tokens,
+};
+
+/// Re-read `--config` on SIGHUP and swap in the admission pipelines and policies and the
+/// rebuilt ruleset chains. An invalid config is logged and everything running stays untouched.
+/// The `--tls-cert` is read again as well, with or without a config.
+pub async fn task(state: Arc<AppState>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        if let Some(tls) = &state.tls {
+            match tls.reload() {
+                Ok(()) => tracing::info!("Reloaded the TLS certificate"),
+                Err(e) => {
+                    let message = format!("Not reloading the TLS certificate, {:#}", e);
+                    tracing::error!("{}", message);
+                    state.notifier.notify(Kind::ReloadFailed, message);
+                }
+            }
+        }
+
+        let Some(path) = &state.args.config else {
+            tracing::info!("Received SIGHUP but no --config is set, nothing to reload");
+            continue;
+        };
+
+        let config = match config::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                let message = format!("Not reloading, {:#}", e);
+                tracing::error!("{}", message);
+                state.notifier.notify(Kind::ReloadFailed, message);
+                continue;
+            }
+        };
+
+        *state.admission.write().unwrap() = Admission::new(&config, state.args.game);
+        *state.pipelines.write().unwrap() = Pipelines::new(
+            &config.pipeline,
+            !config.tokens.is_empty() || state.args.auth_secret_file.is_some(),
+            state.steam.is_some(),
+        );
+        *state.tokens.write().unwrap() = Tokens::new(&config.tokens);
+        *state.redirects.write().unwrap() = Redirects::new(&config);
+
+        let options =
+            firewall::ChainOptions::new(&state.args, &config, state.probation_session.is_some());
+        let reloaded = state
+            .with_firewall(move |firewall| firewall.reload(&options).map_err(|e| e.to_string()))
+            .await;
+        match reloaded {
+            Ok(()) => {
+                let message = format!("Reloaded {}", path.display());
+                tracing::info!("{}", message);
+                state.notifier.notify(Kind::Reloaded, message);
+            }
+            Err(e) => {
+                let message = format!("Failed to reload iptables rules: {}", e);
+                tracing::error!("{}", message);
+                state.notifier.notify(Kind::ReloadFailed, message);
+            }
+        }
+    }
+}
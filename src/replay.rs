@@ -0,0 +1,165 @@
+//! `mortis-rs replay`: run the admission requests of a journal file through a config and report
+//! how its policies and thresholds would have decided them, compared to what was decided then.
+//! Whitelist entries are simulated in memory, nothing touches the firewall.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::{
+    cleaner::ENTRY_TTL,
+    client, config,
+    game::Game,
+    journal::{self, EventKind},
+    policy::Admission,
+    quota::SubnetQuota,
+};
+
+/// Addresses listed per changed decision.
+const MAX_EXAMPLES: usize = 5;
+
+#[derive(clap::Args, Debug)]
+pub struct ReplayArgs {
+    /// File written to --journal-file
+    journal: PathBuf,
+
+    /// Config whose admission policies to replay, the baseline policy only when unset
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Like --game of a running instance, whose User-Agent applies unless the config sets one
+    #[arg(long, value_enum, default_value_t = Game::Gmod)]
+    game: Game,
+
+    /// Like --subnet-quota of a running instance
+    #[arg(long, default_value_t = 0)]
+    subnet_quota: u32,
+
+    /// Like --subnet-quota-prefix of a running instance
+    #[arg(long, default_value_t = 24, value_parser = clap::value_parser!(u8).range(0..=32))]
+    subnet_quota_prefix: u8,
+
+    /// Like --subnet-quota-prefix6 of a running instance
+    #[arg(long, default_value_t = 48, value_parser = clap::value_parser!(u8).range(0..=128))]
+    subnet_quota_prefix6: u8,
+
+    /// Like --ipv6-prefix of a running instance
+    #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u8).range(1..=128))]
+    ipv6_prefix: u8,
+}
+
+#[derive(Default)]
+struct Change {
+    count: usize,
+    examples: Vec<IpAddr>,
+}
+
+pub fn run(args: ReplayArgs) -> Result<()> {
+    let config = match &args.config {
+        Some(path) => config::load(path)?,
+        None => config::Config::default(),
+    };
+    let admission = Admission::new(&config, args.game);
+    let quota = (args.subnet_quota > 0).then(|| {
+        SubnetQuota::new(
+            args.subnet_quota,
+            args.subnet_quota_prefix,
+            args.subnet_quota_prefix6,
+            args.ipv6_prefix,
+        )
+    });
+
+    let mut events = journal::read(&args.journal)?;
+    events.sort_by_key(|event| event.at);
+    let Some(first) = events.first().map(|event| event.at) else {
+        println!("{} holds no events", args.journal.display());
+        return Ok(());
+    };
+    let clock = Instant::now();
+
+    let mut whitelist: HashMap<IpAddr, u64> = HashMap::new();
+    let mut decisions: BTreeMap<(&'static str, &'static str), Change> = BTreeMap::new();
+    let mut peak = 0;
+    let mut requests = 0;
+
+    for event in &events {
+        let ip = client::unit(event.ip, args.ipv6_prefix);
+        whitelist.retain(|_, last_seen| event.at < *last_seen + ENTRY_TTL.as_secs());
+
+        let recorded = match event.kind {
+            EventKind::Admitted => "admitted",
+            EventKind::Refreshed => "refreshed",
+            EventKind::RejectedUa => "rejected_ua",
+            EventKind::RejectedQuota => "rejected_quota",
+            // Decided by packets or operators, which the journal doesn't hold
+            EventKind::Evicted | EventKind::Unpinned => {
+                whitelist.remove(&ip);
+                continue;
+            }
+            EventKind::Pinned | EventKind::Restored => {
+                whitelist.insert(ip, event.at);
+                continue;
+            }
+            // The journal doesn't hold the token or ticket that was presented
+            EventKind::RejectedToken | EventKind::RejectedSteam => continue,
+            // Bans don't touch the whitelist
+            EventKind::Expired | EventKind::Banned | EventKind::Pardoned => continue,
+        };
+        requests += 1;
+
+        let replayed = if whitelist.contains_key(&ip) {
+            "refreshed"
+        } else if event
+            .user_agent
+            .as_deref()
+            .is_some_and(|user_agent| !admission.check(ip, user_agent).1)
+        {
+            "rejected_ua"
+        } else if quota
+            .as_ref()
+            .is_some_and(|quota| !quota.take_at(ip, clock + Duration::from_secs(event.at - first)))
+        {
+            "rejected_quota"
+        } else {
+            "admitted"
+        };
+        if matches!(replayed, "admitted" | "refreshed") {
+            whitelist.insert(ip, event.at);
+            peak = peak.max(whitelist.len());
+        }
+
+        let change = decisions.entry((recorded, replayed)).or_default();
+        change.count += 1;
+        if recorded != replayed && change.examples.len() < MAX_EXAMPLES {
+            change.examples.push(ip);
+        }
+    }
+
+    println!(
+        "Replayed {} admission requests over {}s, at most {} whitelisted at once",
+        requests,
+        events.last().map_or(0, |event| event.at) - first,
+        peak
+    );
+    for ((recorded, replayed), change) in &decisions {
+        if recorded == replayed {
+            println!("  {:<15} unchanged  {}", recorded, change.count);
+        } else {
+            let examples: Vec<String> = change.examples.iter().map(IpAddr::to_string).collect();
+            println!(
+                "  {:<15} -> {:<15} {}  e.g. {}",
+                recorded,
+                replayed,
+                change.count,
+                examples.join(", ")
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::{nflog, state::AppState};
+
+/// Enough for the IP and UDP headers plus the start of the Source engine payload.
+const COPY_RANGE: u32 = 64;
+
+#[derive(Deserialize)]
+pub struct SamplingRequest {
+    /// Packets per second to sample
+    pub rate: u32,
+    /// Seconds to keep sampling for
+    pub duration: u64,
+}
+
+pub struct SamplingSession {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+/// Start sampling packets hitting the mortis chain, replacing a running session. Returns once
+/// the NFLOG group is bound and the sampling rule inserted.
+pub async fn start(state: &Arc<AppState>, request: SamplingRequest) -> Result<()> {
+    let mut session = state.sampling.lock().await;
+    if let Some(running) = session.take() {
+        running.finish().await;
+    }
+
+    let rate = request.rate.max(1);
+    let group = state.args.sampling_nflog_group;
+    let socket = nflog::NflogSocket::bind(group, COPY_RANGE)
+        .with_context(|| format!("Failed to bind NFLOG group {}", group))?;
+    state
+        .with_firewall(move |firewall| {
+            firewall
+                .start_sampling(rate, group)
+                .map_err(|e| anyhow!("Failed to insert sampling rule: {}", e))
+        })
+        .await?;
+
+    let (stop, stopped) = oneshot::channel();
+    let handle = tokio::spawn(run(
+        state.clone(),
+        socket,
+        rate,
+        Duration::from_secs(request.duration),
+        stopped,
+    ));
+    *session = Some(SamplingSession { stop, handle });
+    Ok(())
+}
+
+pub async fn stop(state: &AppState) {
+    if let Some(running) = state.sampling.lock().await.take() {
+        running.finish().await;
+    }
+}
+
+impl SamplingSession {
+    async fn finish(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.await;
+    }
+}
+
+async fn run(
+    state: Arc<AppState>,
+    socket: nflog::NflogSocket,
+    rate: u32,
+    duration: Duration,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let group = state.args.sampling_nflog_group;
+    tracing::info!("Sampling {} packets/sec for {}s", rate, duration.as_secs());
+
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    let mut buf = vec![0u8; 65536];
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = &mut stopped => break,
+            received = socket.recv(&mut buf) => match received {
+                Ok(len) => {
+                    for packet in nflog::packets(&buf[..len]) {
+                        log_packet(packet.payload);
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to receive sampled packets: {}", e);
+                    break;
+                }
+            },
+        }
+    }
+
+    let stopped = state
+        .with_firewall(move |firewall| {
+            firewall
+                .stop_sampling(rate, group)
+                .map_err(|e| e.to_string())
+        })
+        .await;
+    if let Err(e) = stopped {
+        tracing::error!("Failed to delete sampling rule: {}", e);
+    }
+    tracing::info!("Packet sampling stopped");
+}
+
+fn log_packet(payload: &[u8]) {
+    match summarize(payload) {
+        Some(summary) => tracing::info!(target: "mortis::sample", "{}", summary),
+        None => {
+            tracing::info!(target: "mortis::sample", "unparsable packet, {} bytes", payload.len())
+        }
+    }
+}
+
+/// Describe an IPv4/UDP packet, classifying the start of the payload the way srcds would.
+fn summarize(packet: &[u8]) -> Option<String> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 || packet[9] != nflog::IPPROTO_UDP {
+        return None;
+    }
+    let header_len = ((packet[0] & 0x0f) as usize) * 4;
+    let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]);
+
+    let udp = packet.get(header_len..header_len + 8)?;
+    let sport = u16::from_be_bytes([udp[0], udp[1]]);
+    let dport = u16::from_be_bytes([udp[2], udp[3]]);
+    let data = &packet[header_len + 8..];
+
+    let header = match data.get(..4) {
+        Some([0xff, 0xff, 0xff, 0xff]) => "connectionless",
+        Some([0xfe, 0xff, 0xff, 0xff]) => "split",
+        Some(_) => "netchan",
+        None => "invalid",
+    };
+
+    Some(format!(
+        "{}:{} -> {}:{} len={} header={}",
+        src, sport, dst, dport, total_len, header
+    ))
+}
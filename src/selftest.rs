@@ -0,0 +1,142 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use tokio::{net::UdpSocket, time::Instant};
+
+/// A2S_INFO request, answered by every Source engine server (possibly with a challenge).
+const A2S_INFO: &[u8] = b"\xFF\xFF\xFF\xFFTSource Engine Query\0";
+
+/// Replies may arrive this long after the last probe was sent.
+const REPLY_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(clap::Args, Debug)]
+pub struct SelftestArgs {
+    /// mortis HTTP endpoint to ping, e.g. http://203.0.113.5:3030/
+    #[arg(long)]
+    url: String,
+
+    /// Protected UDP address of the game server
+    #[arg(long)]
+    target: SocketAddr,
+
+    /// Probes to send in each phase
+    #[arg(long, default_value_t = 50)]
+    probes: u32,
+
+    /// Probes per second, should sit between the unknown and whitelisted rate limits
+    #[arg(long, default_value_t = 50)]
+    probe_rate: u32,
+
+    /// User-Agent to send with the ping
+    #[arg(long, default_value = "Valve/Steam HTTP Client 1.0 (GMod)")]
+    user_agent: String,
+
+    /// Run the test from inside this network namespace (via `ip netns exec`)
+    #[arg(long)]
+    netns: Option<String>,
+}
+
+pub async fn run(args: SelftestArgs) -> Result<()> {
+    if let Some(netns) = &args.netns {
+        return run_in_netns(netns).await;
+    }
+
+    let before = probe(&args).await?;
+    println!("UDP before ping ... {}/{} replies", before, args.probes);
+
+    let status = reqwest::Client::new()
+        .get(&args.url)
+        .header(reqwest::header::USER_AGENT, &args.user_agent)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .context("HTTP ping failed")?
+        .status();
+    println!("HTTP ping ......... {}", status);
+
+    let after = probe(&args).await?;
+    println!("UDP after ping .... {}/{} replies", after, args.probes);
+
+    // Whitelisted sources must get (nearly) everything through, unknown ones must have been limited
+    let passed = status.is_success() && after * 10 >= args.probes * 9 && before < after;
+    if !passed {
+        bail!("selftest FAILED");
+    }
+    println!("selftest PASSED");
+
+    Ok(())
+}
+
+/// Send paced probes to the target and count the replies.
+async fn probe(args: &SelftestArgs) -> Result<u32> {
+    let bind: SocketAddr = if args.target.is_ipv6() {
+        "[::]:0".parse()?
+    } else {
+        "0.0.0.0:0".parse()?
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(args.target).await?;
+
+    let spacing = Duration::from_secs(1) / args.probe_rate.max(1);
+    let deadline = Instant::now() + spacing * args.probes + REPLY_GRACE;
+    let mut next_probe = Instant::now();
+    let mut sent = 0;
+    let mut replies = 0;
+    let mut buf = [0u8; 1500];
+
+    loop {
+        let wake = if sent < args.probes {
+            next_probe
+        } else {
+            deadline
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep_until(wake) => {
+                if sent < args.probes {
+                    socket.send(A2S_INFO).await?;
+                    sent += 1;
+                    next_probe += spacing;
+                } else {
+                    break;
+                }
+            }
+            received = socket.recv(&mut buf) => {
+                // An ICMP unreachable surfaces as an error here, the port isn't served at all
+                received.context("Target rejected the probe")?;
+                replies += 1;
+            }
+        }
+    }
+
+    Ok(replies.min(args.probes))
+}
+
+/// Re-execute this selftest inside `netns`, so the probes come from a source address that
+/// is actually subject to the mortis rules.
+async fn run_in_netns(netns: &str) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let mut forwarded = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--netns" {
+            args.next();
+        } else if !arg.starts_with("--netns=") {
+            forwarded.push(arg);
+        }
+    }
+
+    let status = tokio::process::Command::new("ip")
+        .args(["netns", "exec", netns])
+        .arg(exe)
+        .args(forwarded)
+        .status()
+        .await
+        .context("Failed to run ip netns exec")?;
+
+    if !status.success() {
+        bail!("selftest in network namespace {} failed", netns);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,178 @@
+//! Cleanup on graceful shutdown. Every step runs even if an earlier one failed, and the outcome
+//! is summarized in a single report instead of panicking halfway through.
+
+use std::{
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+
+use crate::{notify::Kind, snapshot, state::AppState};
+
+/// How long undelivered notifications, including the report itself, may hold up the exit.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+pub struct Report {
+    /// Whitelist entries written to the final snapshot, `None` without `--snapshot-dir`
+    entries_persisted: Option<usize>,
+    /// Queued admissions that never reached the kernel
+    pending_discarded: usize,
+    /// The rules and sets were left in place for the next run, see `--no-clean-on-exit`
+    kept: bool,
+    steps: Vec<Step>,
+    duration_ms: u64,
+}
+
+#[derive(Serialize)]
+struct Step {
+    name: &'static str,
+    /// `None` when the step succeeded
+    error: Option<String>,
+}
+
+impl Report {
+    fn step(&mut self, name: &'static str, result: Result<(), String>) {
+        if let Err(e) = &result {
+            tracing::error!("Shutdown step {} failed: {}", name, e);
+        }
+        self.steps.push(Step {
+            name,
+            error: result.err(),
+        });
+    }
+
+    fn failed(&self) -> usize {
+        self.steps.iter().filter(|s| s.error.is_some()).count()
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Shut down in {}ms: {}/{} cleanup steps succeeded",
+            self.duration_ms,
+            self.steps.len() - self.failed(),
+            self.steps.len()
+        )?;
+        let failed: Vec<&str> = self
+            .steps
+            .iter()
+            .filter(|s| s.error.is_some())
+            .map(|s| s.name)
+            .collect();
+        if !failed.is_empty() {
+            write!(f, " (failed: {})", failed.join(", "))?;
+        }
+        if self.kept {
+            write!(f, ", rules and sets kept")?;
+        }
+        if let Some(entries) = self.entries_persisted {
+            write!(f, ", {} entries persisted", entries)?;
+        }
+        write!(
+            f,
+            ", {} queued admissions discarded",
+            self.pending_discarded
+        )
+    }
+}
+
+/// Persist the whitelist and remove every rule and set mortis created, unless they are to be
+/// kept for the next run.
+pub async fn clean(state: &Arc<AppState>) -> Report {
+    let started = Instant::now();
+    let kept = state.keep_on_exit.load(Ordering::Relaxed);
+    let mut report = Report {
+        entries_persisted: None,
+        pending_discarded: state.slow_path.queued(),
+        kept,
+        steps: Vec::new(),
+        duration_ms: 0,
+    };
+
+    if let Some(dir) = &state.args.snapshot_dir {
+        match snapshot::write(state, dir).await {
+            Ok(entries) => {
+                report.entries_persisted = Some(entries);
+                report.step("snapshot", Ok(()));
+            }
+            Err(e) => report.step("snapshot", Err(format!("{:#}", e))),
+        }
+    }
+
+    // The SYN proxy settings stay as well, the kept rules rely on them
+    if kept {
+        report.duration_ms = started.elapsed().as_millis() as u64;
+        return report;
+    }
+
+    let cleaned = state
+        .with_firewall(|firewall| firewall.clean().map_err(|e| e.to_string()))
+        .await;
+    report.step("iptables", cleaned);
+
+    if let Some(tuning) = &state.synproxy {
+        report.step("sysctl", tuning.restore().map_err(|e| format!("{:#}", e)));
+    }
+
+    let sets = [
+        ("whitelist_ipset", Some(&state.ipset_session)),
+        ("allow_ipset", Some(&state.allow_session)),
+        ("blacklist_ipset", Some(&state.blacklist_session)),
+        ("probation_ipset", state.probation_session.as_ref()),
+        ("grace_ipset", state.grace_session.as_ref()),
+    ];
+    for (name, session) in sets {
+        if let Some(session) = session {
+            let result = session.lock().await.teardown();
+            report.step(name, result.map_err(|e| e.to_string()));
+        }
+    }
+
+    report.duration_ms = started.elapsed().as_millis() as u64;
+    report
+}
+
+/// Flip `--no-clean-on-exit` on SIGUSR2, e.g. right before an upgrade restarts mortis.
+#[cfg(unix)]
+pub async fn toggle_task(state: Arc<AppState>) {
+    let mut user2 = match signal(SignalKind::user_defined2()) {
+        Ok(user2) => user2,
+        Err(e) => {
+            tracing::error!("Failed to install SIGUSR2 handler: {}", e);
+            return;
+        }
+    };
+
+    while user2.recv().await.is_some() {
+        let kept = !state.keep_on_exit.fetch_xor(true, Ordering::Relaxed);
+        match kept {
+            true => tracing::info!("Rules and sets will be left in place on exit"),
+            false => tracing::info!("Rules and sets will be removed on exit"),
+        }
+    }
+}
+
+/// Log the report and give the notification sinks a chance to receive it.
+pub async fn finish(state: &AppState, report: Report) {
+    match serde_json::to_string(&report) {
+        Ok(json) => tracing::info!(target: "mortis::shutdown", "{}", json),
+        Err(e) => tracing::error!("Failed to serialize shutdown report: {}", e),
+    }
+    if report.failed() > 0 {
+        tracing::error!("{}", report);
+    } else {
+        tracing::info!("{}", report);
+    }
+
+    state.notifier.notify(Kind::Shutdown, report.to_string());
+    let delivered = state.notifier.drain(NOTIFY_TIMEOUT).await;
+    if !delivered {
+        tracing::warn!("Exiting with notifications still undelivered");
+    }
+}
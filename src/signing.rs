@@ -0,0 +1,146 @@
+//! HMAC-SHA256 helpers shared by refresh URLs and outbound webhooks.
+
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub type HmacSha256 = Hmac<Sha256>;
+
+/// Headers carrying the webhook signature, see [`sign_webhook`].
+pub const TIMESTAMP_HEADER: &str = "X-Mortis-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Mortis-Signature";
+
+pub fn mac(secret: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length")
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn unhex(s: &str) -> Option<Vec<u8>> {
+    // from_str_radix would take a sign as well
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Signature value for a webhook `body` sent at `timestamp`: `sha256=<hex HMAC of
+/// "<timestamp>.<body>">`. Covering the timestamp lets receivers reject replayed events.
+pub fn sign_webhook(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+    let mut mac = mac(secret);
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    format!("sha256={}", hex(&mac.finalize().into_bytes()))
+}
+
+/// Check a webhook signature produced by [`sign_webhook`], rejecting timestamps further than
+/// `tolerance` from `now`.
+pub fn verify_webhook(
+    secret: &[u8],
+    timestamp: u64,
+    body: &[u8],
+    signature: &str,
+    now: u64,
+    tolerance: Duration,
+) -> bool {
+    if now.abs_diff(timestamp) > tolerance.as_secs() {
+        return false;
+    }
+    let Some(tag) = signature.strip_prefix("sha256=").and_then(unhex) else {
+        return false;
+    };
+
+    let mut mac = mac(secret);
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    mac.verify_slice(&tag).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"webhook-secret";
+    const BODY: &[u8] = br#"{"event":"test"}"#;
+    const SENT: u64 = 1_700_000_000;
+
+    /// Whether `signature` verifies for [`BODY`] sent at [`SENT`], received at `now`.
+    fn verify(secret: &[u8], body: &[u8], signature: &str, now: u64) -> bool {
+        verify_webhook(secret, SENT, body, signature, now, Duration::from_secs(300))
+    }
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        assert_eq!(
+            sign_webhook(SECRET, SENT, BODY),
+            "sha256=8d2e356ec80cfb7b20c9b8335f36df9d249ab4f79e9e43bd220effd9972ffbee"
+        );
+    }
+
+    #[test]
+    fn verifies_own_signature() {
+        let signature = sign_webhook(SECRET, SENT, BODY);
+        assert!(verify(SECRET, BODY, &signature, SENT));
+        assert!(verify(SECRET, BODY, &signature, SENT + 300));
+    }
+
+    #[test]
+    fn rejects_wrong_secret() {
+        let signature = sign_webhook(b"another-secret", SENT, BODY);
+        assert!(!verify(SECRET, BODY, &signature, SENT));
+    }
+
+    #[test]
+    fn rejects_other_body_or_timestamp() {
+        let signature = sign_webhook(SECRET, SENT, BODY);
+        assert!(!verify(SECRET, b"{}", &signature, SENT));
+        let signature = sign_webhook(SECRET, SENT + 1, BODY);
+        assert!(!verify(SECRET, BODY, &signature, SENT));
+    }
+
+    #[test]
+    fn rejects_stale_timestamp() {
+        let signature = sign_webhook(SECRET, SENT, BODY);
+        assert!(!verify(SECRET, BODY, &signature, SENT + 301));
+        // Nor one from the future
+        assert!(!verify(SECRET, BODY, &signature, SENT - 301));
+    }
+
+    #[test]
+    fn rejects_malformed_signature() {
+        let signature = sign_webhook(SECRET, SENT, BODY);
+        let hex = signature.strip_prefix("sha256=").unwrap();
+        for malformed in [
+            "",
+            "sha256=",
+            hex,
+            &format!("sha1={}", hex),
+            &format!("sha256={}", &hex[1..]),
+            &format!("sha256=zz{}", &hex[2..]),
+            &format!("sha256={}", &hex[..32]),
+            &format!("{} ", signature),
+        ] {
+            assert!(
+                !verify(SECRET, BODY, malformed, SENT),
+                "{:?} verified",
+                malformed
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips_hex() {
+        assert_eq!(hex(&[0x00, 0xab, 0xff]), "00abff");
+        assert_eq!(unhex("00abff"), Some(vec![0x00, 0xab, 0xff]));
+        assert_eq!(unhex("00ABFF"), Some(vec![0x00, 0xab, 0xff]));
+        assert_eq!(unhex("abc"), None);
+        assert_eq!(unhex("zz"), None);
+        assert_eq!(unhex("+1"), None);
+    }
+}
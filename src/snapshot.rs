@@ -0,0 +1,237 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, time::Instant};
+
+use crate::{
+    cleaner::{self, ENTRY_TTL},
+    comment::Comment,
+    journal::EventKind,
+    pins,
+    state::AppState,
+};
+
+const SNAPSHOT_PREFIX: &str = "whitelist-";
+const SNAPSHOT_SUFFIX: &str = ".json";
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Unix timestamp the snapshot was taken at
+    pub taken_at: u64,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub ip: IpAddr,
+    /// Unix timestamp of the last successful ping
+    pub last_seen: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    pub skipped: usize,
+}
+
+pub async fn task(state: Arc<AppState>, dir: PathBuf) {
+    let interval = Duration::from_secs(state.args.snapshot_interval);
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = write(&state, &dir).await {
+            tracing::error!("Failed to write whitelist snapshot: {:#}", e);
+        }
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+async fn take(state: &AppState) -> Snapshot {
+    let now = unix_now();
+    let whitelist = state.whitelist.lock().await;
+
+    Snapshot {
+        taken_at: now,
+        entries: whitelist
+            .iter()
+            .map(|(ip, instant)| Entry {
+                ip: *ip,
+                last_seen: now.saturating_sub(instant.elapsed().as_secs()),
+            })
+            .collect(),
+    }
+}
+
+/// Returns the number of entries written.
+pub async fn write(state: &AppState, dir: &Path) -> Result<usize> {
+    let snapshot = take(state).await;
+    let data = serde_json::to_vec(&snapshot)?;
+
+    let name = format!(
+        "{}{}{}",
+        SNAPSHOT_PREFIX, snapshot.taken_at, SNAPSHOT_SUFFIX
+    );
+    write_atomic(&dir.join(name), &data).await?;
+
+    prune(dir, state.args.snapshot_retention).await?;
+    Ok(snapshot.entries.len())
+}
+
+/// Write to a temporary file next to `path` and rename it into place, so a crash never
+/// leaves a truncated file behind and readers never see a partial one.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file path", path.display()))?;
+    let tmp = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+
+    let mut file = tokio::fs::File::create(&tmp)
+        .await
+        .with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to move {} into place", path.display()))?;
+
+    Ok(())
+}
+
+/// Remove all but the newest `retention` snapshots.
+async fn prune(dir: &Path, retention: usize) -> Result<()> {
+    let mut snapshots = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let taken_at = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(SNAPSHOT_PREFIX))
+            .and_then(|name| name.strip_suffix(SNAPSHOT_SUFFIX))
+            .and_then(|ts| ts.parse::<u64>().ok());
+        if let Some(taken_at) = taken_at {
+            snapshots.push((taken_at, entry.path()));
+        }
+    }
+
+    snapshots.sort_unstable_by_key(|(taken_at, _)| std::cmp::Reverse(*taken_at));
+    for (_, path) in snapshots.into_iter().skip(retention) {
+        tokio::fs::remove_file(&path)
+            .await
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Re-add the entries of `snapshot` that have not expired yet and are not already whitelisted.
+pub async fn restore(state: &AppState, snapshot: Snapshot) -> Result<RestoreSummary> {
+    let now = unix_now();
+    let mut summary = RestoreSummary {
+        restored: 0,
+        skipped: 0,
+    };
+
+    let mut whitelist = state.whitelist.lock().await;
+    let mut ipset = state.ipset_session.lock().await;
+
+    for entry in snapshot.entries {
+        let age = Duration::from_secs(now.saturating_sub(entry.last_seen));
+        let last_seen = Instant::now().checked_sub(age);
+
+        match last_seen {
+            Some(last_seen)
+                if age < ENTRY_TTL
+                    && whitelist
+                        .get(&entry.ip)
+                        .is_none_or(|seen| !cleaner::is_live(*seen, false)) =>
+            {
+                // Expires in the kernel when it would have without the restart
+                ipset
+                    .add_commented(
+                        entry.ip,
+                        Some((ENTRY_TTL - age).as_secs().max(1) as u32),
+                        &Comment::whitelist(state, false, None),
+                    )
+                    .inspect_err(|e| state.metrics.record_add_error(e))?;
+                whitelist.insert(entry.ip, last_seen);
+                state.journal.record(entry.ip, EventKind::Restored);
+                summary.restored += 1;
+            }
+            _ => summary.skipped += 1,
+        }
+    }
+
+    state.metrics.set_whitelist_entries(whitelist.len());
+
+    Ok(summary)
+}
+
+/// Take over the entries a previous run left in the kernel whitelist. Permanent entries were
+/// pinned by it, they start expiring unless they are pinned again or their comment says they
+/// were pinned, see [`crate::comment`].
+pub async fn adopt(state: &AppState) -> Result<RestoreSummary> {
+    let now = unix_now();
+    let entries = state.ipset_session.lock().await.list()?;
+    let mut pinned = Vec::new();
+    let snapshot = Snapshot {
+        taken_at: now,
+        entries: entries
+            .into_iter()
+            .map(|entry| {
+                let comment = entry.comment.as_deref().and_then(Comment::parse);
+                if entry.timeout == 0 && comment.is_some_and(|comment| comment.pinned) {
+                    pinned.push(entry.ip);
+                }
+                Entry {
+                    ip: entry.ip,
+                    last_seen: match entry.timeout {
+                        0 => now,
+                        remaining => {
+                            now.saturating_sub(ENTRY_TTL.as_secs().saturating_sub(remaining.into()))
+                        }
+                    },
+                }
+            })
+            .collect(),
+    };
+    let summary = restore(state, snapshot).await?;
+    for ip in pinned {
+        pins::pin(state, ip).await?;
+    }
+    Ok(summary)
+}
+
+/// Send a snapshot file to the admin API of a running instance.
+pub async fn restore_remote(admin_url: &str, token: Option<&str>, path: &Path) -> Result<()> {
+    let data = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let snapshot: Snapshot = serde_json::from_slice(&data)
+        .with_context(|| format!("{} is not a valid snapshot", path.display()))?;
+
+    let mut request = reqwest::Client::new()
+        .post(format!("{}/admin/restore", admin_url.trim_end_matches('/')))
+        .json(&snapshot);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let summary: RestoreSummary = request.send().await?.error_for_status()?.json().await?;
+
+    println!(
+        "Restored {} entries, skipped {} expired or already whitelisted",
+        summary.restored, summary.skipped
+    );
+
+    Ok(())
+}
@@ -1,15 +1,121 @@
 use std::{
-    collections::HashMap, net::IpAddr,
+    collections::{BTreeSet, HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, atomic::AtomicBool},
 };
 
 use tokio::{sync::Mutex, time::Instant};
 
-use crate::Args;
+use crate::{
+    Args,
+    auth::Verifier,
+    capture::CaptureSession,
+    cidr::Cidr,
+    counters::Counters,
+    dns::Resolver,
+    drops::DropSampler,
+    firewall::{AddressSet, Firewall},
+    journal::Journal,
+    metrics::Metrics,
+    monitor::Monitor,
+    notify::Notifier,
+    overload::Degradation,
+    pending::SlowPath,
+    pipeline::Pipelines,
+    policy::Admission,
+    quota::SubnetQuota,
+    ratelimit::RateLimiter,
+    redirect::Redirects,
+    refresh::Refresher,
+    sampling::SamplingSession,
+    steam::Steam,
+    synproxy::Tuning,
+    tls::Certificates,
+    tokens::Tokens,
+};
 
 pub struct AppState {
-    pub iptables: iptables::IPTables,
-    pub ipset_session: Mutex<ipset::Session<ipset::types::HashIp>>,
+    pub firewall: std::sync::Mutex<Firewall>,
+    pub ipset_session: Mutex<Box<dyn AddressSet>>,
+    pub allow_session: Mutex<Box<dyn AddressSet>>,
+    pub blacklist_session: Mutex<Box<dyn AddressSet>>,
+    /// Set of newly admitted sources, `None` when probation is disabled
+    pub probation_session: Option<Mutex<Box<dyn AddressSet>>>,
+    /// Sources with an HTTP request in flight, `None` when the grace set is disabled
+    pub grace_session: Option<Mutex<Box<dyn AddressSet>>>,
     pub args: Args,
+    pub metrics: Metrics,
+    pub journal: Journal,
+    pub refresher: Refresher,
+    pub slow_path: SlowPath,
+    pub notifier: Notifier,
+    pub resolver: Arc<Resolver>,
+    /// Would-be drops, only in monitor-only mode
+    pub monitor: Option<Monitor>,
+    /// Sampled drops, only with `--drop-sample-rate`
+    pub drops: Option<DropSampler>,
+    /// Traffic through the mortis rules, see `--counter-interval`
+    pub counters: Counters,
+    /// Replaced when the config is reloaded
+    pub admission: std::sync::RwLock<Admission>,
+    /// Replaced when the config is reloaded
+    pub pipelines: std::sync::RwLock<Pipelines>,
+    /// Replaced when the config is reloaded
+    pub tokens: std::sync::RwLock<Tokens>,
+    /// Replaced when the config is reloaded
+    pub redirects: std::sync::RwLock<Redirects>,
+    /// `None` without `--auth-secret-file`
+    pub auth: Option<Verifier>,
+    /// `None` without a `[steam]` section in the config
+    pub steam: Option<Steam>,
+    pub started: Instant,
+    /// When expired entries were last dropped from the whitelist, `None` before the first sweep
+    pub last_sweep: std::sync::Mutex<Option<Instant>>,
+    pub degradation: Degradation,
+    /// `None` when `--subnet-quota` is 0
+    pub quota: Option<SubnetQuota>,
+    /// Slots of the requests in flight on the public listener, `None` when `--max-requests` is 0
+    pub requests: Option<tokio::sync::Semaphore>,
+    /// `None` when `--rate-limit` is 0
+    pub rate_limiter: Option<RateLimiter>,
+    /// What `--synproxy` changed in the kernel settings, put back on shutdown
+    pub synproxy: Option<Tuning>,
+    /// Leave the rules and sets behind on shutdown, `--no-clean-on-exit` toggled by SIGUSR2
+    pub keep_on_exit: AtomicBool,
+    /// `None` without `--tls-cert`
+    pub tls: Option<Arc<Certificates>>,
 
     pub whitelist: Mutex<HashMap<IpAddr, Instant>>,
-}
\ No newline at end of file
+    /// Whitelist entries the cleaner never removes
+    pub pinned: Mutex<HashSet<IpAddr>>,
+    /// Networks of `--allow-net` and `/admin/allow`, locked before the allow set
+    pub allowed_nets: Mutex<BTreeSet<Cidr>>,
+    /// Addresses of `--allow-host` names in the allow set, locked after the allowed networks and
+    /// before the allow set
+    pub allowed_hosts: Mutex<HashSet<IpAddr>>,
+    /// Cloudflare ranges in the allow set with `--cloudflare-allow`, locked after the allowed
+    /// hosts and before the allow set
+    pub allowed_cloudflare: Mutex<BTreeSet<Cidr>>,
+    /// Ranges Cloudflare publishes, trusted like `--trusted-proxies`. Empty without `--cloudflare`
+    pub cloudflare_ranges: std::sync::RwLock<Vec<Cidr>>,
+    /// When each ban in the blacklist set ends, `None` for permanent ones. Locked before the
+    /// blacklist set
+    pub bans: Mutex<HashMap<IpAddr, Option<Instant>>>,
+    pub sampling: Mutex<Option<SamplingSession>>,
+    pub capture: Mutex<Option<CaptureSession>>,
+}
+
+impl AppState {
+    /// Run `f` on the firewall in the blocking pool. Its changes wait for `iptables-restore`,
+    /// and locking it on a runtime worker would stall the worker along with every task waiting
+    /// for the lock.
+    pub async fn with_firewall<T: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&mut Firewall) -> T + Send + 'static,
+    ) -> T {
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || f(&mut state.firewall.lock().unwrap()))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+}
@@ -0,0 +1,181 @@
+//! Health summary of a running instance, served at `/admin/status` and printed by
+//! `mortis-rs status` for deployment tooling to assert on.
+
+use std::{fmt, sync::Arc};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{firewall::RULE_SCHEMA_VERSION, overload::Tier, state::AppState};
+
+/// Bumped on incompatible changes to [`Status`], fields may be added without bumping it.
+const SCHEMA_VERSION: u32 = 1;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum StatusFormat {
+    Text,
+    Json,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Status {
+    pub schema_version: u32,
+    /// mortis-rs version of the instance
+    pub version: String,
+    pub uptime_secs: u64,
+    pub protect: String,
+    pub backend: Backend,
+    pub whitelist: Counts,
+    /// Seconds since expired entries were last dropped from the whitelist, `None` before the
+    /// first sweep a few seconds after startup
+    pub last_sweep_secs_ago: Option<u64>,
+    pub degradation_tier: Tier,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Backend {
+    /// `--backend` in use, empty from instances older than this field
+    #[serde(default)]
+    pub name: String,
+    /// Whether every chain and rule mortis added is still in place
+    pub healthy: bool,
+    /// Chains and rules that went missing, or why they couldn't be checked
+    pub problems: Vec<String>,
+    pub rule_schema_version: u32,
+    /// False while the kill switch is engaged
+    pub armed: bool,
+    pub active_ruleset: String,
+    pub monitor_only: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Counts {
+    pub entries: usize,
+    pub pinned: usize,
+    /// Admissions waiting on the slow path
+    pub queued: usize,
+}
+
+pub async fn collect(state: &Arc<AppState>) -> Status {
+    let name = state
+        .args
+        .backend
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default();
+    let monitor_only = state.args.monitor_only;
+    let backend = state
+        .with_firewall(move |firewall| {
+            let problems = match firewall.missing() {
+                Ok(missing) => missing
+                    .into_iter()
+                    .map(|artifact| format!("missing {}", artifact))
+                    .collect(),
+                Err(e) => vec![format!("Failed to list iptables rules: {}", e)],
+            };
+            Backend {
+                name,
+                healthy: problems.is_empty(),
+                problems,
+                rule_schema_version: RULE_SCHEMA_VERSION,
+                armed: firewall.is_armed(),
+                active_ruleset: firewall.active_ruleset().to_string(),
+                monitor_only,
+            }
+        })
+        .await;
+
+    Status {
+        schema_version: SCHEMA_VERSION,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_secs: state.started.elapsed().as_secs(),
+        protect: state.args.protected_ports().to_string(),
+        backend,
+        whitelist: Counts {
+            entries: state.whitelist.lock().await.len(),
+            pinned: state.pinned.lock().await.len(),
+            queued: state.slow_path.queued(),
+        },
+        last_sweep_secs_ago: state
+            .last_sweep
+            .lock()
+            .unwrap()
+            .map(|at| at.elapsed().as_secs()),
+        degradation_tier: state.degradation.tier(),
+    }
+}
+
+/// Fetch the status of a running instance and print it, failing if its backend is unhealthy.
+pub async fn print_remote(
+    admin_url: &str,
+    token: Option<&str>,
+    format: StatusFormat,
+) -> Result<()> {
+    let mut request =
+        reqwest::Client::new().get(format!("{}/admin/status", admin_url.trim_end_matches('/')));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?.error_for_status()?;
+
+    let healthy = match format {
+        StatusFormat::Json => {
+            // Passed through as is, so fields of newer instances aren't lost
+            let body: serde_json::Value = response.json().await?;
+            println!("{}", body);
+            body["backend"]["healthy"].as_bool().unwrap_or(false)
+        }
+        StatusFormat::Text => {
+            let status: Status = response.json().await?;
+            print!("{}", status);
+            status.backend.healthy
+        }
+    };
+
+    if !healthy {
+        anyhow::bail!("Firewall backend is unhealthy");
+    }
+    Ok(())
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let backend = &self.backend;
+        writeln!(f, "mortis-rs {}, up {}s", self.version, self.uptime_secs)?;
+        writeln!(f, "Protecting:  {}", self.protect)?;
+        writeln!(
+            f,
+            "Firewall:    {}, {}, {}, ruleset {}{}",
+            backend.name,
+            if backend.healthy {
+                "healthy"
+            } else {
+                "UNHEALTHY"
+            },
+            if backend.armed {
+                "armed"
+            } else {
+                "kill switch engaged"
+            },
+            backend.active_ruleset,
+            if backend.monitor_only {
+                ", monitor only"
+            } else {
+                ""
+            }
+        )?;
+        for problem in &backend.problems {
+            writeln!(f, "  {}", problem)?;
+        }
+        writeln!(
+            f,
+            "Whitelist:   {} entries, {} pinned, {} queued",
+            self.whitelist.entries, self.whitelist.pinned, self.whitelist.queued
+        )?;
+        if let Some(secs) = self.last_sweep_secs_ago {
+            writeln!(f, "Last sweep:  {}s ago", secs)?;
+        }
+        writeln!(f, "Degradation: {:?}", self.degradation_tier)
+    }
+}
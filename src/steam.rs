@@ -0,0 +1,189 @@
+//! Validation of Steam session tickets with the `ISteamUserAuth/AuthenticateUserTicket` web API,
+//! for servers that want proof a client is a Steam user running the game before whitelisting
+//! it. The Lua client sends the ticket from `GetAuthSessionTicket` as hex in the
+//! [`TICKET_HEADER`] header. Verdicts are cached so reconnects and refreshes of the same
+//! session don't each cost a call to Steam.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use axum::http::HeaderMap;
+use serde::Deserialize;
+
+pub const TICKET_HEADER: &str = "X-Mortis-Steam-Ticket";
+
+const API_URL: &str = "https://api.steampowered.com/ISteamUserAuth/AuthenticateUserTicket/v1/";
+/// Tickets are a few hundred bytes, anything much longer isn't one.
+const MAX_TICKET: usize = 4096;
+/// Verdicts kept at most, expired ones are dropped first when it fills up.
+const MAX_CACHED: usize = 100_000;
+const DEFAULT_CACHE: Duration = Duration::from_secs(3600);
+
+/// The `[steam]` section of the config, read at startup.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SteamConfig {
+    /// Steam Web API key
+    pub api_key: String,
+    pub app_id: u32,
+    /// Identity the client passed to `GetAuthTicketForWebApi`, if any
+    pub identity: Option<String>,
+    /// Seconds a verdict is reused for the same ticket, an hour if unset
+    pub cache_seconds: Option<u64>,
+    /// Admit clients while the Steam API can't be reached instead of refusing them
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+/// The ticket a request carries, if any.
+pub fn ticket(headers: &HeaderMap) -> Option<&str> {
+    headers.get(TICKET_HEADER)?.to_str().ok()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Valid {
+        steam_id: u64,
+    },
+    /// Steam didn't accept the ticket
+    Invalid,
+    /// The account is banned by the publisher
+    Banned,
+}
+
+impl Verdict {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Valid { .. } => "valid",
+            Verdict::Invalid => "invalid",
+            Verdict::Banned => "banned",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    response: ApiResult,
+}
+
+#[derive(Deserialize)]
+struct ApiResult {
+    params: Option<ApiParams>,
+    error: Option<ApiError>,
+}
+
+#[derive(Deserialize)]
+struct ApiParams {
+    result: String,
+    steamid: String,
+    #[serde(default)]
+    publisherbanned: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    errorcode: i32,
+    errordesc: String,
+}
+
+pub struct Steam {
+    config: SteamConfig,
+    client: reqwest::Client,
+    cache: Mutex<HashMap<String, (Verdict, Instant)>>,
+}
+
+impl Steam {
+    pub fn new(config: SteamConfig, client: reqwest::Client) -> Self {
+        Self {
+            config,
+            client,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn fail_open(&self) -> bool {
+        self.config.fail_open
+    }
+
+    /// Verdict on `ticket` and whether it came from the cache. Errs when Steam couldn't be asked.
+    pub async fn check(&self, ticket: &str) -> Result<(Verdict, bool)> {
+        if ticket.len() > MAX_TICKET || !ticket.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok((Verdict::Invalid, false));
+        }
+        let ttl = self
+            .config
+            .cache_seconds
+            .map_or(DEFAULT_CACHE, Duration::from_secs);
+        if let Some((verdict, at)) = self.cache.lock().unwrap().get(ticket)
+            && at.elapsed() < ttl
+        {
+            return Ok((*verdict, true));
+        }
+
+        let verdict = self.authenticate(ticket).await?;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, (_, at)| at.elapsed() < ttl);
+            if cache.len() >= MAX_CACHED {
+                cache.clear();
+            }
+        }
+        cache.insert(ticket.to_string(), (verdict, Instant::now()));
+        Ok((verdict, false))
+    }
+
+    async fn authenticate(&self, ticket: &str) -> Result<Verdict> {
+        let mut query = vec![
+            ("key", self.config.api_key.clone()),
+            ("appid", self.config.app_id.to_string()),
+            ("ticket", ticket.to_string()),
+        ];
+        if let Some(identity) = &self.config.identity {
+            query.push(("identity", identity.clone()));
+        }
+        let response: ApiResponse = self
+            .client
+            .get(API_URL)
+            .query(&query)
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await
+            .context("Failed to reach the Steam Web API")?
+            .error_for_status()
+            .context("Steam Web API refused the request")?
+            .json()
+            .await
+            .context("Unexpected response from the Steam Web API")?;
+
+        match response.response {
+            ApiResult {
+                params: Some(params),
+                ..
+            } if params.result == "OK" => {
+                if params.publisherbanned {
+                    return Ok(Verdict::Banned);
+                }
+                let steam_id = params
+                    .steamid
+                    .parse()
+                    .with_context(|| format!("Invalid SteamID {:?}", params.steamid))?;
+                Ok(Verdict::Valid { steam_id })
+            }
+            ApiResult {
+                error: Some(error), ..
+            } => {
+                tracing::debug!(
+                    "Steam refused a ticket: {} ({})",
+                    error.errordesc,
+                    error.errorcode
+                );
+                Ok(Verdict::Invalid)
+            }
+            _ => bail!("Steam Web API answered with neither params nor an error"),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Kernel settings SYNPROXY depends on, see `--synproxy`. Its rules are part of the firewall
+//! plans, see [`crate::firewall`].
+
+use anyhow::{Context, Result};
+
+/// With loose pickup conntrack turns the ACK completing a proxied handshake into a flow of its
+/// own, instead of leaving it INVALID for SYNPROXY to check against its cookie.
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+const TCP_LOOSE: &str = "/proc/sys/net/netfilter/nf_conntrack_tcp_loose";
+/// SYNPROXY keeps the client's TCP options in the timestamp of its cookie, the server has to
+/// send timestamps too.
+#[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+const TCP_TIMESTAMPS: &str = "/proc/sys/net/ipv4/tcp_timestamps";
+
+/// The settings mortis changed, with the values they had before.
+pub struct Tuning {
+    previous: Vec<(&'static str, String)>,
+}
+
+impl Tuning {
+    /// Settings that are already right are left alone, and aren't touched by
+    /// [`Tuning::restore`] either.
+    #[cfg(not(any(feature = "mock", not(target_os = "linux"))))]
+    pub fn apply() -> Result<Self> {
+        let mut previous = Vec::new();
+        for (path, value) in [(TCP_LOOSE, "0"), (TCP_TIMESTAMPS, "1")] {
+            let old = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path))?;
+            let old = old.trim();
+            if old == value {
+                continue;
+            }
+            std::fs::write(path, value).with_context(|| format!("Failed to write {}", path))?;
+            tracing::info!("Set {} to {}, was {}", path, value, old);
+            previous.push((path, old.to_string()));
+        }
+        Ok(Self { previous })
+    }
+
+    /// The mock leaves the host's settings alone.
+    #[cfg(any(feature = "mock", not(target_os = "linux")))]
+    pub fn apply() -> Result<Self> {
+        Ok(Self {
+            previous: Vec::new(),
+        })
+    }
+
+    /// Put the previous values back, e.g. loose pickup for flows that predate conntrack.
+    pub fn restore(&self) -> Result<()> {
+        for (path, value) in &self.previous {
+            std::fs::write(path, value).with_context(|| format!("Failed to write {}", path))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+//! HTTPS for the public listener with `--tls-cert` and `--tls-key`, so whitelist URLs and their
+//! tokens don't travel in the clear. The certificate is read again on SIGHUP, e.g. after certbot
+//! renewed it, and connections made from then on get the new one. Handshakes are done by
+//! [`crate::listener`].
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Context, Result};
+use tokio_rustls::{TlsAcceptor, rustls::ServerConfig};
+
+pub struct Certificates {
+    cert: PathBuf,
+    key: PathBuf,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl Certificates {
+    pub fn load(cert: &Path, key: &Path) -> Result<Self> {
+        Ok(Self {
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+            acceptor: RwLock::new(acceptor(cert, key)?),
+        })
+    }
+
+    /// For connections made from now on.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// Read the certificate and key again, the current ones stay in use when that fails.
+    pub fn reload(&self) -> Result<()> {
+        *self.acceptor.write().unwrap() = acceptor(&self.cert, &self.key)?;
+        Ok(())
+    }
+}
+
+fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("Failed to read {}", path.display()))
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert)?)
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("Failed to parse the certificates in {}", cert.display()))?;
+    let key = rustls_pemfile::private_key(&mut open(key)?)
+        .with_context(|| format!("Failed to parse the private key in {}", key.display()))?
+        .with_context(|| format!("No private key in {}", key.display()))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or private key")?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
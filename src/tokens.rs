@@ -0,0 +1,68 @@
+//! Shared-secret tokens the game server embeds in the URL it hands to clients, `/t/<token>/...`,
+//! so a spoofed User-Agent alone doesn't get a source whitelisted. Tokens are named and any of
+//! them is accepted, which is how they are rotated: add the new one, move the servers over to
+//! it, then remove the old one and reload.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Result, bail};
+use hmac::Mac;
+
+use crate::signing;
+
+/// Shorter tokens could be guessed.
+const MIN_LENGTH: usize = 16;
+/// Key of the MACs tokens are compared by, it only has to be the same for both sides.
+const KEY: &[u8] = b"mortis-token";
+
+pub struct Tokens {
+    /// Name and MAC of every token, compared by MAC so their lengths don't show
+    tokens: Vec<(String, Vec<u8>)>,
+}
+
+fn mac(token: &str) -> signing::HmacSha256 {
+    let mut mac = signing::mac(KEY);
+    mac.update(token.as_bytes());
+    mac
+}
+
+impl Tokens {
+    pub fn new(tokens: &BTreeMap<String, String>) -> Self {
+        Self {
+            tokens: tokens
+                .iter()
+                .map(|(name, token)| (name.clone(), mac(token).finalize().into_bytes().to_vec()))
+                .collect(),
+        }
+    }
+
+    /// Name of the token `presented` is, if any. Every token is compared in full, so the time
+    /// taken doesn't tell how close a guess came.
+    pub fn verify(&self, presented: &str) -> Option<&str> {
+        let mut matched = None;
+        for (name, tag) in &self.tokens {
+            if mac(presented).verify_slice(tag).is_ok() {
+                matched = Some(name.as_str());
+            }
+        }
+        matched
+    }
+}
+
+/// Tokens go into URL paths as they are.
+pub fn validate(tokens: &BTreeMap<String, String>) -> Result<()> {
+    for (name, token) in tokens {
+        if token.len() < MIN_LENGTH
+            || !token
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~'))
+        {
+            bail!(
+                "token {} must be at least {} letters, digits, -, _, . or ~",
+                name,
+                MIN_LENGTH
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Result, anyhow};
+use tokio::sync::Mutex;
+
+use crate::{
+    allow, blacklist, cleaner,
+    comment::Comment,
+    firewall::{self, AddressSet},
+    state::AppState,
+};
+
+/// Periodically check that the sets, chains and hooks mortis installed are still in the kernel,
+/// and put back whatever another tool (e.g. a firewalld reload) removed.
+pub async fn task(state: Arc<AppState>) {
+    let interval = Duration::from_secs(state.args.watchdog_interval);
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = check(&state).await {
+            tracing::error!("Watchdog failed to repair the firewall: {:#}", e);
+        }
+    }
+}
+
+async fn check(state: &Arc<AppState>) -> Result<()> {
+    // Sets first, the rules refer to them
+    if ensure(state, &state.ipset_session, firewall::MORTIS_IPSET).await? {
+        refill_whitelist(state).await?;
+    }
+    if ensure(state, &state.allow_session, firewall::MORTIS_ALLOW_IPSET).await? {
+        allow::refill(state).await?;
+    }
+    if ensure(
+        state,
+        &state.blacklist_session,
+        firewall::MORTIS_BLACKLIST_IPSET,
+    )
+    .await?
+    {
+        blacklist::refill(state).await?;
+    }
+    if let Some(probation) = &state.probation_session {
+        ensure(state, probation, firewall::MORTIS_PROBATION_IPSET).await?;
+    }
+    if let Some(grace) = &state.grace_session {
+        ensure(state, grace, firewall::MORTIS_GRACE_IPSET).await?;
+    }
+
+    let missing = state
+        .with_firewall(|firewall| firewall.repair().map_err(|e| anyhow!("{}", e)))
+        .await?;
+    if !missing.is_empty() {
+        tracing::warn!("Reinstalled missing firewall rules: {}", missing.join(", "));
+        state.metrics.record_watchdog_repair("rules");
+        state.journal.record_repair("rules", missing);
+    }
+    Ok(())
+}
+
+async fn ensure(state: &AppState, set: &Mutex<Box<dyn AddressSet>>, name: &str) -> Result<bool> {
+    let recreated = set.lock().await.ensure()?;
+    if recreated {
+        tracing::warn!("Set {} was gone, created it again", name);
+        state.metrics.record_watchdog_repair("set");
+        state.journal.record_repair("set", vec![name.to_string()]);
+    }
+    Ok(recreated)
+}
+
+/// Put the live entries of the whitelist map back into a recreated kernel set, each with the
+/// time it had left.
+async fn refill_whitelist(state: &AppState) -> Result<()> {
+    let whitelist = state.whitelist.lock().await;
+    let pinned = state.pinned.lock().await;
+    let mut ipset = state.ipset_session.lock().await;
+    for (ip, last_seen) in whitelist.iter() {
+        let pin = pinned.contains(ip);
+        if !cleaner::is_live(*last_seen, pin) {
+            continue;
+        }
+        let timeout = cleaner::timeout_left(*last_seen, pin);
+        ipset
+            .add_commented(*ip, Some(timeout), &Comment::whitelist(state, pin, None))
+            .inspect_err(|e| state.metrics.record_add_error(e))?;
+    }
+    Ok(())
+}
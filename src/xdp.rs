@@ -0,0 +1,335 @@
+//! XDP prefilter, built with the `xdp` feature and enabled with `--xdp-interface`. The program in
+//! `bpf/mortis.bpf.c` drops UDP floods to the protected ports in the driver, before iptables or
+//! nftables ever see them. It wraps the actual backend: the whitelist, allow and grace sets are
+//! mirrored into eBPF maps with the same timeouts, and everything the program lets through still
+//! goes through the chains. Only IPv4 is filtered, the limits are those of the ruleset active at
+//! startup.
+
+use std::{error::Error, net::IpAddr, path::Path};
+
+use anyhow::{Context, Result, anyhow, bail};
+use aya::{
+    Ebpf, Pod,
+    maps::{Array, HashMap, LpmTrie, MapData, lpm_trie::Key},
+    programs::{self, XdpFlags, xdp::XdpLinkId},
+};
+
+use crate::{
+    cidr::Cidr,
+    engine::Plan,
+    firewall::{
+        AddressSet, Backend, ChainOptions, Family, FirewallBackend, Listed, MORTIS_ALLOW_IPSET,
+        MORTIS_GRACE_IPSET, MORTIS_IPSET, Names, RuleCounter, SetOptions,
+    },
+    ports::Ports,
+};
+
+const PROGRAM: &str = "mortis";
+const MAX_PORT_RANGES: usize = 16;
+
+/// Laid out like `struct config` in the eBPF program.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Config {
+    ports: [[u16; 2]; MAX_PORT_RANGES],
+    port_ranges: u32,
+    amplification: [[u16; 2]; MAX_PORT_RANGES],
+    amplification_ranges: u32,
+    whitelist_limit: u32,
+    grace_limit: u32,
+    unknown_limit: u32,
+    burst: u32,
+}
+
+// Plain integers without padding
+unsafe impl Pod for Config {}
+
+pub struct Xdp {
+    inner: Box<dyn FirewallBackend>,
+    ebpf: Ebpf,
+    link: Option<XdpLinkId>,
+    names: Names,
+}
+
+impl Xdp {
+    /// Load the compiled program at `object` and attach it to `interface` in front of `inner`.
+    pub fn attach(
+        inner: Box<dyn FirewallBackend>,
+        object: &Path,
+        interface: &str,
+        protected_port: &Ports,
+        options: &ChainOptions,
+    ) -> Result<Self> {
+        let mut ebpf = Ebpf::load_file(object)
+            .with_context(|| format!("Failed to load {}", object.display()))?;
+
+        let ruleset = options
+            .rulesets
+            .iter()
+            .find(|ruleset| ruleset.name == options.initial)
+            .context("Unknown initial ruleset")?;
+        let mut config = Config {
+            ports: [[0; 2]; MAX_PORT_RANGES],
+            port_ranges: 0,
+            amplification: [[0; 2]; MAX_PORT_RANGES],
+            amplification_ranges: 0,
+            whitelist_limit: ruleset.whitelist_limit,
+            grace_limit: options.grace_limit.unwrap_or(0),
+            unknown_limit: ruleset.unknown_limit,
+            burst: ruleset.burst,
+        };
+        config.port_ranges = fill(&mut config.ports, protected_port)?;
+        if let Some(ports) = &options.amplification_ports {
+            config.amplification_ranges = fill(&mut config.amplification, ports)?;
+        }
+        let mut configs: Array<MapData, Config> =
+            Array::try_from(ebpf.take_map("config").context("No config map")?)?;
+        configs.set(0, config, 0)?;
+
+        let program: &mut programs::Xdp = ebpf
+            .program_mut(PROGRAM)
+            .with_context(|| format!("No {} program in {}", PROGRAM, object.display()))?
+            .try_into()?;
+        program.load()?;
+        let link = program
+            .attach(interface, XdpFlags::default())
+            .with_context(|| format!("Failed to attach to {}", interface))?;
+
+        Ok(Self {
+            inner,
+            ebpf,
+            link: Some(link),
+            names: options.names.clone(),
+        })
+    }
+}
+
+impl FirewallBackend for Xdp {
+    fn syntax(&self) -> Backend {
+        self.inner.syntax()
+    }
+
+    fn families(&self) -> Vec<Family> {
+        self.inner.families()
+    }
+
+    fn setup_set(&mut self, options: &SetOptions) -> Result<Box<dyn AddressSet>> {
+        let set = self.inner.setup_set(options)?;
+        let map = [
+            (MORTIS_IPSET, "whitelist"),
+            (MORTIS_ALLOW_IPSET, "allow"),
+            (MORTIS_GRACE_IPSET, "grace"),
+        ]
+        .into_iter()
+        .find(|(base, _)| self.names.of(base) == options.name);
+        let Some((_, map)) = map else {
+            return Ok(set);
+        };
+
+        let map = HashMap::try_from(
+            self.ebpf
+                .take_map(map)
+                .with_context(|| format!("No {} map", map))?,
+        )?;
+        let nets = match options.nets {
+            true => Some(LpmTrie::try_from(
+                self.ebpf
+                    .take_map("allow_nets")
+                    .context("No allow_nets map")?,
+            )?),
+            false => None,
+        };
+        Ok(Box::new(Mirrored {
+            set,
+            map,
+            nets,
+            timeout: options.timeout,
+        }))
+    }
+
+    fn apply(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        self.inner.apply(family, plan)
+    }
+
+    fn verify(&self, family: Family) -> Result<Vec<String>, Box<dyn Error>> {
+        self.inner.verify(family)
+    }
+
+    fn reinstall(&mut self, family: Family, plan: &Plan) -> Result<(), Box<dyn Error>> {
+        self.inner.reinstall(family, plan)
+    }
+
+    /// Only the chains count, not what the program drops before them.
+    fn counters(&self, family: Family) -> Result<Vec<RuleCounter>, Box<dyn Error>> {
+        self.inner.counters(family)
+    }
+
+    fn teardown(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(link) = self.link.take() {
+            let program: &mut programs::Xdp = self
+                .ebpf
+                .program_mut(PROGRAM)
+                .ok_or("XDP program is gone")?
+                .try_into()?;
+            program.detach(link)?;
+        }
+        self.inner.teardown()
+    }
+
+    fn purge(&mut self, names: &Names) -> Result<(), Box<dyn Error>> {
+        self.inner.purge(names)
+    }
+}
+
+/// A set of the backend with its IPv4 entries mirrored into an eBPF map.
+struct Mirrored {
+    set: Box<dyn AddressSet>,
+    /// Address in network byte order to when it expires, see [`monotonic_ns`]
+    map: HashMap<MapData, u32, u64>,
+    /// IPv4 networks by their address in network byte order, for sets of networks
+    nets: Option<LpmTrie<MapData, u32, u8>>,
+    timeout: Option<u32>,
+}
+
+impl Mirrored {
+    /// Where `net` goes in the trie, `None` for single addresses and IPv6 networks.
+    fn net_key(&self, net: Cidr) -> Option<Key<u32>> {
+        match net.addr() {
+            IpAddr::V4(ip) if !net.is_host() => Some(Key::new(
+                u32::from(net.prefix()),
+                u32::from_ne_bytes(ip.octets()),
+            )),
+            _ => None,
+        }
+    }
+
+    fn mirror(&mut self, ip: IpAddr, timeout: Option<u32>) -> Result<()> {
+        let IpAddr::V4(ip) = ip.to_canonical() else {
+            return Ok(());
+        };
+        let expires = match timeout {
+            Some(timeout) if timeout > 0 => monotonic_ns()? + u64::from(timeout) * 1_000_000_000,
+            _ => 0,
+        };
+        self.map
+            .insert(u32::from_ne_bytes(ip.octets()), expires, 0)
+            .map_err(|e| anyhow!("Failed to add {} to the XDP map: {}", ip, e))
+    }
+}
+
+impl AddressSet for Mirrored {
+    fn add_ip(&mut self, ip: IpAddr) -> Result<()> {
+        self.set.add_ip(ip)?;
+        self.mirror(ip, self.timeout)
+    }
+
+    fn add_ip_for(&mut self, ip: IpAddr, timeout: u32) -> Result<()> {
+        self.set.add_ip_for(ip, timeout)?;
+        self.mirror(ip, Some(timeout))
+    }
+
+    fn del_ip(&mut self, ip: IpAddr) -> Result<()> {
+        self.set.del_ip(ip)?;
+        if let IpAddr::V4(ip) = ip.to_canonical() {
+            // Possibly evicted from the LRU map already
+            let _ = self.map.remove(&u32::from_ne_bytes(ip.octets()));
+        }
+        Ok(())
+    }
+
+    fn add_net(&mut self, net: Cidr) -> Result<()> {
+        self.set.add_net(net)?;
+        match (self.net_key(net), &mut self.nets) {
+            (Some(key), Some(nets)) => nets
+                .insert(&key, 1, 0)
+                .map_err(|e| anyhow!("Failed to add {} to the XDP map: {}", net, e)),
+            _ => self.mirror(net.addr(), self.timeout),
+        }
+    }
+
+    fn del_net(&mut self, net: Cidr) -> Result<()> {
+        self.set.del_net(net)?;
+        match (self.net_key(net), &mut self.nets) {
+            (Some(key), Some(nets)) => {
+                let _ = nets.remove(&key);
+            }
+            _ => {
+                if let IpAddr::V4(ip) = net.addr() {
+                    let _ = self.map.remove(&u32::from_ne_bytes(ip.octets()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The map lives as long as the program, only the set can be gone.
+    fn ensure(&mut self) -> Result<bool> {
+        self.set.ensure()
+    }
+
+    fn add_commented(&mut self, ip: IpAddr, timeout: Option<u32>, comment: &str) -> Result<()> {
+        self.set.add_commented(ip, timeout, comment)?;
+        self.mirror(ip, timeout.or(self.timeout))
+    }
+
+    /// The maps can't be swapped, they are emptied and filled again after the set. Whatever a
+    /// stale entry lets through in between still goes through the chains.
+    fn replace(&mut self, nets: &[Cidr], timeout: Option<u32>) -> Result<()> {
+        self.set.replace(nets, timeout)?;
+        let keys: Vec<u32> = self.map.keys().collect::<Result<_, _>>()?;
+        for key in keys {
+            let _ = self.map.remove(&key);
+        }
+        if let Some(trie) = &mut self.nets {
+            let keys: Vec<Key<u32>> = trie.keys().collect::<Result<_, _>>()?;
+            for key in keys {
+                let _ = trie.remove(&key);
+            }
+        }
+        for net in nets {
+            match (self.net_key(*net), &mut self.nets) {
+                (Some(key), Some(trie)) => trie
+                    .insert(&key, 1, 0)
+                    .map_err(|e| anyhow!("Failed to add {} to the XDP map: {}", net, e))?,
+                _ => self.mirror(net.addr(), timeout.or(self.timeout))?,
+            }
+        }
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<Vec<Listed>> {
+        self.set.list()
+    }
+
+    /// The map goes away with the program.
+    fn teardown(&mut self) -> Result<()> {
+        self.set.teardown()
+    }
+}
+
+/// The clock `bpf_ktime_get_ns` reads.
+fn monotonic_ns() -> Result<u64> {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64)
+}
+
+/// Put the ranges of `ports` into `slots`, returns how many there are.
+fn fill(slots: &mut [[u16; 2]; MAX_PORT_RANGES], ports: &Ports) -> Result<u32> {
+    let ranges = ports.ranges();
+    if ranges.len() > MAX_PORT_RANGES {
+        bail!(
+            "XDP supports up to {} port ranges per list",
+            MAX_PORT_RANGES
+        );
+    }
+    for (slot, range) in slots.iter_mut().zip(ranges) {
+        *slot = [range.first, range.last];
+    }
+    Ok(ranges.len() as u32)
+}